@@ -12,6 +12,12 @@ pub mod ip {
     }
 }
 
+pub mod ipam {
+    pub mod v1 {
+        tonic::include_proto!("grpc.ipam.v1");
+    }
+}
+
 pub mod service {
     pub mod v1 {
         tonic::include_proto!("grpc.service.v1");
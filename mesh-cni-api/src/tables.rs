@@ -39,7 +39,7 @@ impl Tabled for crate::service::v1::ServiceWithEndpoints {
 }
 
 impl Tabled for crate::conntrack::v1::Connection {
-    const LENGTH: usize = 3;
+    const LENGTH: usize = 4;
 
     fn fields(&self) -> Vec<Cow<'_, str>> {
         let source = format!("{}:{}", self.src_ip, self.src_port);
@@ -49,6 +49,7 @@ impl Tabled for crate::conntrack::v1::Connection {
             Cow::Owned(source),
             Cow::Owned(destination),
             Cow::Borrowed(&self.proto),
+            Cow::Borrowed(&self.direction),
         ]
     }
 
@@ -57,12 +58,13 @@ impl Tabled for crate::conntrack::v1::Connection {
             Cow::Borrowed("SOURCE"),
             Cow::Borrowed("DESTINATION"),
             Cow::Borrowed("PROTO"),
+            Cow::Borrowed("DIRECTION"),
         ]
     }
 }
 
 impl Tabled for crate::policy::v1::PolicySet {
-    const LENGTH: usize = 5;
+    const LENGTH: usize = 6;
 
     fn fields(&self) -> Vec<Cow<'_, str>> {
         vec![
@@ -70,6 +72,7 @@ impl Tabled for crate::policy::v1::PolicySet {
             Cow::Owned(self.dst_id.to_string()),
             Cow::Owned(self.dst_port.to_string()),
             Cow::Borrowed(&self.proto),
+            Cow::Borrowed(&self.direction),
             Cow::Borrowed(&self.action),
         ]
     }
@@ -80,7 +83,48 @@ impl Tabled for crate::policy::v1::PolicySet {
             Cow::Borrowed("DESTINATION ID"),
             Cow::Borrowed("DESTINATION PORT"),
             Cow::Borrowed("PROTO"),
+            Cow::Borrowed("DIRECTION"),
             Cow::Borrowed("ACTION"),
         ]
     }
 }
+
+impl Tabled for crate::ipam::v1::Allocation {
+    const LENGTH: usize = 3;
+
+    fn fields(&self) -> Vec<Cow<'_, str>> {
+        vec![
+            Cow::Borrowed(&self.ip),
+            Cow::Borrowed(&self.container_id),
+            Cow::Borrowed(&self.iface),
+        ]
+    }
+
+    fn headers() -> Vec<Cow<'static, str>> {
+        vec![
+            Cow::Borrowed("IP"),
+            Cow::Borrowed("CONTAINER ID"),
+            Cow::Borrowed("IFACE"),
+        ]
+    }
+}
+
+impl Tabled for crate::ipam::v1::Pool {
+    const LENGTH: usize = 3;
+
+    fn fields(&self) -> Vec<Cow<'_, str>> {
+        vec![
+            Cow::Borrowed(&self.cidr),
+            Cow::Owned(self.allocated.to_string()),
+            Cow::Owned(self.capacity.to_string()),
+        ]
+    }
+
+    fn headers() -> Vec<Cow<'static, str>> {
+        vec![
+            Cow::Borrowed("CIDR"),
+            Cow::Borrowed("ALLOCATED"),
+            Cow::Borrowed("CAPACITY"),
+        ]
+    }
+}
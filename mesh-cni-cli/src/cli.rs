@@ -13,6 +13,10 @@ pub enum Commands {
     #[command(subcommand)]
     Ip(IpCommands),
 
+    /// Used to interact with the IPAM subsystem
+    #[command(subcommand)]
+    Ipam(IpamCommands),
+
     /// Used to interact with the Service subsystem
     #[command(subcommand)]
     Service(ServiceCommands),
@@ -32,6 +36,15 @@ pub enum IpCommands {
     List,
 }
 
+#[derive(Clone, Subcommand, Debug)]
+pub enum IpamCommands {
+    /// List the allocated pod IPs and the containers they belong to
+    List,
+
+    /// List the pod CIDRs addresses are allocated from
+    Pools,
+}
+
 #[derive(Clone, Subcommand, Debug)]
 pub enum ServiceCommands {
     /// List the Service and their associated IDs
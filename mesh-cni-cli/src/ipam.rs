@@ -0,0 +1,36 @@
+use mesh_cni_api::ipam::v1::{ListAllocationsRequest, ListPoolsRequest, ipam_client::IpamClient};
+use tabled::{Table, settings::Style};
+use tonic::{Request, transport::Channel};
+
+use crate::{cli::IpamCommands, client::MESH_CNI_SOCKET};
+
+pub(crate) async fn run(cmd: IpamCommands) -> anyhow::Result<()> {
+    let client = IpamClient::connect(MESH_CNI_SOCKET).await?;
+    match cmd {
+        IpamCommands::List => list(client).await?,
+        IpamCommands::Pools => pools(client).await?,
+    }
+    Ok(())
+}
+
+async fn list(mut client: IpamClient<Channel>) -> anyhow::Result<()> {
+    let response = client
+        .list_allocations(Request::new(ListAllocationsRequest::default()))
+        .await?;
+    let allocations = response.into_inner().allocations;
+
+    let table = Table::new(allocations).with(Style::empty()).to_string();
+    println!("{table}");
+    Ok(())
+}
+
+async fn pools(mut client: IpamClient<Channel>) -> anyhow::Result<()> {
+    let response = client
+        .list_pools(Request::new(ListPoolsRequest::default()))
+        .await?;
+    let pools = response.into_inner().pools;
+
+    let table = Table::new(pools).with(Style::empty()).to_string();
+    println!("{table}");
+    Ok(())
+}
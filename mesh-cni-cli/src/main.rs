@@ -2,6 +2,7 @@ mod cli;
 mod client;
 mod conntrack;
 mod ip;
+mod ipam;
 mod policy;
 mod service;
 
@@ -15,6 +16,7 @@ async fn main() -> anyhow::Result<()> {
     let cli = Cli::parse();
     match cli.command {
         crate::cli::Commands::Ip(ip_commands) => ip::run(ip_commands).await?,
+        crate::cli::Commands::Ipam(ipam_commands) => ipam::run(ipam_commands).await?,
         crate::cli::Commands::Service(service_commands) => service::run(service_commands).await?,
         crate::cli::Commands::Conntrack(conntrack_commands) => {
             conntrack::run(conntrack_commands).await?
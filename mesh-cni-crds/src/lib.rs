@@ -38,11 +38,20 @@ pub fn crd_gen_cluster() -> Result<()> {
     Ok(())
 }
 
+pub fn crd_gen_podcidrallocation() -> Result<()> {
+    print!(
+        "---\n{}",
+        serde_yaml::to_string(&v1alpha1::podcidrallocation::PodCidrAllocation::crd())?
+    );
+    Ok(())
+}
+
 pub fn crd_gen_all() -> Result<()> {
     let crds = vec![
         v1alpha1::meshendpoint::MeshEndpoint::crd(),
         v1alpha1::identity::Identity::crd(),
         v1alpha1::cluster::Cluster::crd(),
+        v1alpha1::podcidrallocation::PodCidrAllocation::crd(),
     ];
     for crd in crds {
         print!("---\n{}", serde_yaml::to_string(&crd)?);
@@ -3,7 +3,7 @@ use std::{net::IpAddr, sync::Arc};
 use ahash::HashMap;
 use k8s_openapi::api::{
     core::v1::Service,
-    discovery::v1::{EndpointConditions, EndpointSlice},
+    discovery::v1::{Endpoint, EndpointConditions, EndpointSlice},
 };
 use kube::{
     CustomResource, KubeSchema, ResourceExt,
@@ -12,7 +12,7 @@ use kube::{
 };
 use mesh_cni_ebpf_common::{
     KubeProtocol,
-    service::{EndpointValue, EndpointValueV4, EndpointValueV6, ServiceKey},
+    service::{EndpointValue, EndpointValueV4, EndpointValueV6, ServiceKey, ServiceOptions},
 };
 use serde::{Deserialize, Serialize};
 use tracing::warn;
@@ -21,6 +21,15 @@ use crate::SERVICE_OWNER_LABEL;
 
 pub const NAME_GROUP_MESHENDPOINT: &str = "meshendpoints.mesh-cni.dev";
 
+/// Service annotation selecting how backends are picked, see [`LoadBalancing`]
+pub const LOAD_BALANCING_ANNOTATION: &str = "mesh-cni.dev/load-balancing";
+
+/// Kubernetes default for `sessionAffinityConfig.clientIP.timeoutSeconds`
+const DEFAULT_AFFINITY_TIMEOUT_SECONDS: u32 = 10800;
+
+/// Annotation enabling topology aware routing before `trafficDistribution` existed
+const TOPOLOGY_MODE_ANNOTATION: &str = "service.kubernetes.io/topology-mode";
+
 #[derive(
     CustomResource, KubeSchema, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Debug,
 )]
@@ -34,7 +43,104 @@ pub const NAME_GROUP_MESHENDPOINT: &str = "meshendpoints.mesh-cni.dev";
 )]
 pub struct MeshEndpointSpec {
     pub service_ips: Vec<IpAddr>,
+    /// Addresses of `service_ips` reachable from outside the cluster, the load balancer
+    /// ingress IPs and `externalIPs` of the service
+    #[serde(default)]
+    pub external_ips: Vec<IpAddr>,
+    /// CIDRs allowed to reach the external addresses, from `loadBalancerSourceRanges`
+    #[serde(default)]
+    pub source_ranges: Vec<String>,
     pub backend_port_mappings: Vec<BackendPortMapping>,
+    /// Pins each client to a backend, set for `sessionAffinity: ClientIP`
+    #[serde(default)]
+    pub session_affinity: bool,
+    #[serde(default)]
+    pub session_affinity_timeout_seconds: u32,
+    #[serde(default)]
+    pub load_balancing: LoadBalancing,
+    /// Only send traffic to backends on the client's node, set for
+    /// `internalTrafficPolicy: Local`
+    #[serde(default)]
+    pub node_local: bool,
+    /// Prefer backends in the client's zone, set for `trafficDistribution: PreferClose` or
+    /// topology aware routing
+    #[serde(default)]
+    pub prefer_close: bool,
+    /// Only send NodePort traffic to backends on the node it arrived at, set for
+    /// `externalTrafficPolicy: Local`
+    #[serde(default)]
+    pub external_node_local: bool,
+}
+
+/// Where the agent programming the backends runs
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Topology {
+    pub node_name: String,
+    pub zone: Option<String>,
+    /// Addresses NodePorts are exposed on
+    pub node_ips: Vec<IpAddr>,
+}
+
+/// Backends of a frontend as programmed on one node
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrontendBackends {
+    pub endpoints: Vec<EndpointValue>,
+    /// How many of the leading endpoints are on this node, set for frontends with
+    /// `externalTrafficPolicy: Local`, see [`ServiceOptions::local_backends`]
+    pub local: Option<u16>,
+}
+
+/// Algorithm used to pick the backend of a new connection
+#[derive(KubeSchema, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum LoadBalancing {
+    /// Any backend, chosen at random
+    #[default]
+    Random,
+    /// Maglev consistent hashing, backend changes only move the clients of the changed
+    /// backends
+    Maglev,
+}
+
+/// Network of a `loadBalancerSourceRanges` entry
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SourceRange {
+    pub network: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl std::str::FromStr for SourceRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (network, prefix_len) = s
+            .trim()
+            .split_once('/')
+            .ok_or_else(|| format!("source range {s} is not a CIDR"))?;
+        let network: IpAddr = network
+            .parse()
+            .map_err(|_| format!("invalid source range address {network}"))?;
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        match prefix_len.parse::<u8>() {
+            Ok(prefix_len) if prefix_len <= max_len => Ok(SourceRange {
+                network,
+                prefix_len,
+            }),
+            _ => Err(format!("invalid source range prefix length {prefix_len}")),
+        }
+    }
+}
+
+impl std::str::FromStr for LoadBalancing {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "random" => Ok(LoadBalancing::Random),
+            "maglev" => Ok(LoadBalancing::Maglev),
+            _ => Err(format!("unknown load balancing algorithm {s}")),
+        }
+    }
 }
 
 #[derive(KubeSchema, Serialize, Deserialize, PartialEq, Eq, Clone, Debug)]
@@ -43,53 +149,262 @@ pub struct BackendPortMapping {
     pub service_port: u16,
     pub backend_port: u16,
     pub protocol: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_port: Option<u16>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    /// Zones the EndpointSlice controller hinted this backend should serve
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub for_zones: Vec<String>,
+    /// Whether the backend accepts new connections, regardless of it terminating
+    #[serde(default = "default_serving")]
+    pub serving: bool,
+    #[serde(default)]
+    pub terminating: bool,
+}
+
+/// Specs written before `serving` existed only carried ready backends
+fn default_serving() -> bool {
+    true
 }
 
 impl MeshEndpoint {
-    pub fn generate_bpf_service_endpoints(&self) -> HashMap<ServiceKey, Vec<EndpointValue>> {
-        let mut result: HashMap<ServiceKey, Vec<EndpointValue>> = HashMap::default();
-        for service_ip in &self.spec.service_ips {
-            for mapping in &self.spec.backend_port_mappings {
-                let protocol = kube_proto_from_str(&Some(mapping.protocol.clone())) as u8;
-
-                let (service_key, endpoint_value) = match (service_ip, mapping.ip) {
-                    (IpAddr::V4(svc_v4), IpAddr::V4(ep_v4)) => (
-                        ServiceKey::v4(svc_v4.to_bits(), mapping.service_port, protocol),
-                        EndpointValue::V4(EndpointValueV4 {
-                            ip: ep_v4.to_bits(),
-                            port: mapping.backend_port,
-                            _protocol: protocol,
-                        }),
-                    ),
-                    (IpAddr::V6(svc_v6), IpAddr::V6(ep_v6)) => (
-                        ServiceKey::v6(svc_v6.to_bits(), mapping.service_port, protocol),
-                        EndpointValue::V6(EndpointValueV6 {
-                            ip: ep_v6.to_bits(),
-                            port: mapping.backend_port,
-                            _protocol: protocol,
-                        }),
-                    ),
-                    _ => {
-                        continue;
+    pub fn service_options(&self) -> ServiceOptions {
+        ServiceOptions {
+            affinity_timeout_secs: self
+                .spec
+                .session_affinity
+                .then_some(self.spec.session_affinity_timeout_seconds),
+            maglev: self.spec.load_balancing == LoadBalancing::Maglev,
+            local_backends: None,
+        }
+    }
+
+    /// Backends of each frontend as programmed on the node described by `topology`. When a
+    /// topology filter leaves a frontend without backends it falls back to all of them.
+    /// Terminating backends are only used once no ready backend is left.
+    ///
+    /// NodePorts are exposed on every address of the node. Like the external addresses they
+    /// keep every backend for clients in the cluster, `externalTrafficPolicy: Local` only
+    /// limits traffic from outside the node to the local ones and drops it when there are
+    /// none rather than hiding the client.
+    pub fn generate_bpf_service_endpoints(
+        &self,
+        topology: &Topology,
+    ) -> HashMap<ServiceKey, FrontendBackends> {
+        type Backends<'a> = (bool, Vec<(EndpointValue, &'a BackendPortMapping)>);
+        let mut result: HashMap<ServiceKey, Backends> = HashMap::default();
+        for mapping in &self.spec.backend_port_mappings {
+            let cluster = self.spec.service_ips.iter().map(|service_ip| {
+                let external = self.spec.external_ips.contains(service_ip);
+                (*service_ip, mapping.service_port, external)
+            });
+            let node_ports = mapping.node_port.into_iter().flat_map(|node_port| {
+                topology
+                    .node_ips
+                    .iter()
+                    .map(move |node_ip| (*node_ip, node_port, true))
+            });
+
+            for (frontend_ip, frontend_port, external) in cluster.chain(node_ports) {
+                let Some((service_key, endpoint_value)) =
+                    bpf_pair(frontend_ip, frontend_port, mapping)
+                else {
+                    continue;
+                };
+                result
+                    .entry(service_key)
+                    .or_insert_with(|| (external, vec![]))
+                    .1
+                    .push((endpoint_value, mapping));
+            }
+        }
+        result
+            .into_iter()
+            .map(|(service_key, (external, backends))| {
+                let backends = ready_backends(backends);
+                let backends = if external {
+                    self.select_external_backends(backends, topology)
+                } else {
+                    FrontendBackends {
+                        endpoints: self.select_backends(backends, topology),
+                        local: None,
                     }
                 };
+                (service_key, backends)
+            })
+            .collect()
+    }
 
-                if let Some(eps) = result.get_mut(&service_key) {
-                    eps.push(endpoint_value);
-                } else {
-                    result.insert(service_key, vec![endpoint_value]);
-                }
+    /// Source ranges of each external frontend, frontends without an entry accept any
+    /// source. Ranges of the other address family are left out.
+    pub fn source_ranges(&self) -> HashMap<ServiceKey, Vec<SourceRange>> {
+        let ranges: Vec<SourceRange> = self
+            .spec
+            .source_ranges
+            .iter()
+            .filter_map(|range| {
+                range
+                    .parse()
+                    .inspect_err(|e| warn!("{} in MeshEndpoint {}", e, self.name_any()))
+                    .ok()
+            })
+            .collect();
+
+        let mut result: HashMap<ServiceKey, Vec<SourceRange>> = HashMap::default();
+        if ranges.is_empty() {
+            return result;
+        }
+        for mapping in &self.spec.backend_port_mappings {
+            for external_ip in &self.spec.external_ips {
+                let Some((service_key, _)) = bpf_pair(*external_ip, mapping.service_port, mapping)
+                else {
+                    continue;
+                };
+                let family_ranges = ranges
+                    .iter()
+                    .filter(|range| range.network.is_ipv4() == external_ip.is_ipv4())
+                    .copied()
+                    .collect();
+                result.insert(service_key, family_ranges);
             }
         }
         result
     }
+
+    /// Every backend, those on this node first for `externalTrafficPolicy: Local`
+    fn select_external_backends(
+        &self,
+        backends: Vec<(EndpointValue, &BackendPortMapping)>,
+        topology: &Topology,
+    ) -> FrontendBackends {
+        if !self.spec.external_node_local {
+            return FrontendBackends {
+                endpoints: backends.into_iter().map(|(endpoint, _)| endpoint).collect(),
+                local: None,
+            };
+        }
+        let (local, remote): (Vec<_>, Vec<_>) = backends
+            .into_iter()
+            .partition(|(_, mapping)| mapping.node_name.as_ref() == Some(&topology.node_name));
+        FrontendBackends {
+            local: Some(u16::try_from(local.len()).unwrap_or(u16::MAX)),
+            endpoints: local
+                .into_iter()
+                .chain(remote)
+                .map(|(endpoint, _)| endpoint)
+                .collect(),
+        }
+    }
+
+    fn select_backends(
+        &self,
+        backends: Vec<(EndpointValue, &BackendPortMapping)>,
+        topology: &Topology,
+    ) -> Vec<EndpointValue> {
+        let local: Vec<EndpointValue> = if self.spec.node_local {
+            backends
+                .iter()
+                .filter(|(_, mapping)| mapping.node_name.as_ref() == Some(&topology.node_name))
+                .map(|(endpoint, _)| *endpoint)
+                .collect()
+        } else if self.spec.prefer_close
+            && let Some(zone) = &topology.zone
+        {
+            // hints are only trusted when every backend has them, as kube-proxy does
+            let hinted = backends
+                .iter()
+                .all(|(_, mapping)| !mapping.for_zones.is_empty());
+            backends
+                .iter()
+                .filter(|(_, mapping)| {
+                    if hinted {
+                        mapping.for_zones.contains(zone)
+                    } else {
+                        mapping.zone.as_ref() == Some(zone)
+                    }
+                })
+                .map(|(endpoint, _)| *endpoint)
+                .collect()
+        } else {
+            vec![]
+        };
+
+        if local.is_empty() {
+            backends.into_iter().map(|(endpoint, _)| endpoint).collect()
+        } else {
+            local
+        }
+    }
+}
+
+/// Keeps the ready backends, or the serving terminating ones when nothing is ready.
+/// kube-proxy does the same so a service does not go dark while a rollout replaces every
+/// backend.
+fn ready_backends(
+    backends: Vec<(EndpointValue, &BackendPortMapping)>,
+) -> Vec<(EndpointValue, &BackendPortMapping)> {
+    let ready: Vec<(EndpointValue, &BackendPortMapping)> = backends
+        .iter()
+        .filter(|(_, mapping)| mapping.serving && !mapping.terminating)
+        .copied()
+        .collect();
+    if !ready.is_empty() {
+        return ready;
+    }
+    backends
+        .into_iter()
+        .filter(|(_, mapping)| mapping.serving)
+        .collect()
+}
+
+/// Service map entry of `mapping` behind `frontend_ip:frontend_port`, `None` when the
+/// address families differ
+fn bpf_pair(
+    frontend_ip: IpAddr,
+    frontend_port: u16,
+    mapping: &BackendPortMapping,
+) -> Option<(ServiceKey, EndpointValue)> {
+    let protocol = kube_proto_from_str(&Some(mapping.protocol.clone())) as u8;
+    match (frontend_ip, mapping.ip) {
+        (IpAddr::V4(svc_v4), IpAddr::V4(ep_v4)) => Some((
+            ServiceKey::v4(svc_v4.to_bits(), frontend_port, protocol),
+            EndpointValue::V4(EndpointValueV4 {
+                ip: ep_v4.to_bits(),
+                port: mapping.backend_port,
+                _protocol: protocol,
+            }),
+        )),
+        (IpAddr::V6(svc_v6), IpAddr::V6(ep_v6)) => Some((
+            ServiceKey::v6(svc_v6.to_bits(), frontend_port, protocol),
+            EndpointValue::V6(EndpointValueV6 {
+                ip: ep_v6.to_bits(),
+                port: mapping.backend_port,
+                _protocol: protocol,
+            }),
+        )),
+        _ => None,
+    }
 }
 
 pub fn generate_mesh_endpoint_spec(
     store: &Store<EndpointSlice>,
     service: &Service,
 ) -> MeshEndpointSpec {
-    let service_ips = service_ips_from_service(service);
+    let mut service_ips = service_ips_from_service(service);
+    let external_ips = external_ips_from_service(service);
+    for ip in &external_ips {
+        if !service_ips.contains(ip) {
+            service_ips.push(*ip);
+        }
+    }
+    let source_ranges = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.load_balancer_source_ranges.clone())
+        .unwrap_or_default();
     let service_names_ports_protocols = service_names_ports_protocols(service);
 
     let slices = endpoint_slices_owned_by_service(store, service);
@@ -97,9 +412,9 @@ pub fn generate_mesh_endpoint_spec(
     let mut backend_port_mappings = Vec::new();
 
     for slice in &slices {
-        let backend_ips = backend_ips_from_ep_slice(slice);
-        for ip in backend_ips {
-            for (name, service_port, protocol) in &service_names_ports_protocols {
+        let backends = backends_from_ep_slice(slice);
+        for (ip, endpoint, terminating) in backends {
+            for (name, service_port, node_port, protocol) in &service_names_ports_protocols {
                 let Some(backend_port) = backend_port_from_ep_slice(slice, name, *protocol) else {
                     continue;
                 };
@@ -109,15 +424,88 @@ pub fn generate_mesh_endpoint_spec(
                     service_port: *service_port,
                     backend_port,
                     protocol: protocol.to_string(),
+                    node_port: *node_port,
+                    node_name: endpoint.node_name.clone(),
+                    zone: endpoint.zone.clone(),
+                    for_zones: endpoint
+                        .hints
+                        .iter()
+                        .flat_map(|hints| hints.for_zones.iter().flatten())
+                        .map(|zone| zone.name.clone())
+                        .collect(),
+                    serving: true,
+                    terminating,
                 });
             }
         }
     }
 
+    let (session_affinity, session_affinity_timeout_seconds) = session_affinity(service);
+    let (node_local, prefer_close) = traffic_policy(service);
+    let external_node_local = service
+        .spec
+        .as_ref()
+        .is_some_and(|spec| spec.external_traffic_policy.as_deref() == Some("Local"));
+
     MeshEndpointSpec {
         service_ips,
+        external_ips,
+        source_ranges,
         backend_port_mappings,
+        session_affinity,
+        session_affinity_timeout_seconds,
+        load_balancing: load_balancing(service),
+        node_local,
+        prefer_close,
+        external_node_local,
+    }
+}
+
+/// Whether the service keeps traffic on the client's node and whether it prefers the
+/// client's zone
+fn traffic_policy(service: &Service) -> (bool, bool) {
+    let Some(spec) = &service.spec else {
+        return (false, false);
+    };
+    let node_local = spec.internal_traffic_policy.as_deref() == Some("Local");
+    let topology_mode = service
+        .annotations()
+        .get(TOPOLOGY_MODE_ANNOTATION)
+        .is_some_and(|mode| mode == "Auto");
+    let prefer_close = spec.traffic_distribution.as_deref() == Some("PreferClose") || topology_mode;
+    (node_local, prefer_close)
+}
+
+fn load_balancing(service: &Service) -> LoadBalancing {
+    let Some(value) = service.annotations().get(LOAD_BALANCING_ANNOTATION) else {
+        return LoadBalancing::default();
+    };
+    value.parse().unwrap_or_else(|e| {
+        warn!(
+            "{} in Service {}/{}, using random",
+            e,
+            service.namespace().unwrap_or_default(),
+            service.name_any()
+        );
+        LoadBalancing::default()
+    })
+}
+
+fn session_affinity(service: &Service) -> (bool, u32) {
+    let Some(spec) = &service.spec else {
+        return (false, 0);
+    };
+    if spec.session_affinity.as_deref() != Some("ClientIP") {
+        return (false, 0);
     }
+    let timeout = spec
+        .session_affinity_config
+        .as_ref()
+        .and_then(|config| config.client_ip.as_ref())
+        .and_then(|client_ip| client_ip.timeout_seconds)
+        .and_then(|timeout| u32::try_from(timeout).ok())
+        .unwrap_or(DEFAULT_AFFINITY_TIMEOUT_SECONDS);
+    (true, timeout)
 }
 
 fn endpoint_slices_owned_by_service(
@@ -140,8 +528,15 @@ fn endpoint_slices_owned_by_service(
         .collect()
 }
 
-fn endpoint_ready(ep_cond: &EndpointConditions) -> bool {
-    (ep_cond.ready == Some(true) || ep_cond.ready.is_none()) && (ep_cond.terminating != Some(true))
+/// `serving` is `ready` without the terminating check, older clusters only set `ready`
+fn endpoint_serving(ep_cond: &EndpointConditions) -> bool {
+    ep_cond
+        .serving
+        .unwrap_or(ep_cond.ready == Some(true) || ep_cond.ready.is_none())
+}
+
+fn endpoint_terminating(ep_cond: &EndpointConditions) -> bool {
+    ep_cond.terminating == Some(true)
 }
 
 fn service_ips_from_service(service: &Service) -> Vec<IpAddr> {
@@ -165,21 +560,57 @@ fn service_ips_from_service(service: &Service) -> Vec<IpAddr> {
     result
 }
 
-fn backend_ips_from_ep_slice(slice: &EndpointSlice) -> Vec<IpAddr> {
-    let mut ips = Vec::new();
+/// Load balancer ingress IPs and `externalIPs` of the service. Ingress entries with only
+/// a hostname cannot be served and are skipped.
+fn external_ips_from_service(service: &Service) -> Vec<IpAddr> {
+    let ingress_ips = service
+        .status
+        .as_ref()
+        .and_then(|status| status.load_balancer.as_ref())
+        .and_then(|load_balancer| load_balancer.ingress.as_ref())
+        .into_iter()
+        .flatten()
+        .filter_map(|ingress| ingress.ip.as_ref());
+    let external_ips = service
+        .spec
+        .as_ref()
+        .and_then(|spec| spec.external_ips.as_ref())
+        .into_iter()
+        .flatten();
+
+    let mut result = Vec::new();
+    for ip in ingress_ips.chain(external_ips) {
+        match ip.parse() {
+            Ok(ip) if !result.contains(&ip) => result.push(ip),
+            Ok(_) => {}
+            Err(_) => warn!(
+                "failed to parse external IP {} in Service {}/{}",
+                ip,
+                service.namespace().unwrap_or_default(),
+                service.name_any()
+            ),
+        }
+    }
+    result
+}
+
+/// Addresses of the serving endpoints of `slice` along with whether they are terminating
+fn backends_from_ep_slice(slice: &EndpointSlice) -> Vec<(IpAddr, &Endpoint, bool)> {
+    let mut backends = Vec::new();
     for endpoint in &slice.endpoints {
         if let Some(conditions) = &endpoint.conditions
-            && endpoint_ready(conditions)
+            && endpoint_serving(conditions)
         {
+            let terminating = endpoint_terminating(conditions);
             for ip in &endpoint.addresses {
                 let Ok(ip) = ip.parse() else {
                     continue;
                 };
-                ips.push(ip);
+                backends.push((ip, endpoint, terminating));
             }
         }
     }
-    ips
+    backends
 }
 
 fn backend_port_from_ep_slice(
@@ -201,7 +632,10 @@ fn backend_port_from_ep_slice(
     None
 }
 
-fn service_names_ports_protocols(service: &Service) -> Vec<(String, u16, KubeProtocol)> {
+/// Name, port, node port and protocol of each port of `service`
+fn service_names_ports_protocols(
+    service: &Service,
+) -> Vec<(String, u16, Option<u16>, KubeProtocol)> {
     let mut names = Vec::new();
     if let Some(spec) = &service.spec
         && let Some(service_ports) = &spec.ports
@@ -214,10 +648,11 @@ fn service_names_ports_protocols(service: &Service) -> Vec<(String, u16, KubePro
             } else {
                 continue;
             };
+            let node_port = sp.node_port.and_then(|port| u16::try_from(port).ok());
             if let Some(name) = &sp.name {
-                names.push((name.clone(), sp.port as u16, protocol));
+                names.push((name.clone(), sp.port as u16, node_port, protocol));
             } else {
-                names.push((String::new(), sp.port as u16, protocol));
+                names.push((String::new(), sp.port as u16, node_port, protocol));
             }
         }
     }
@@ -230,3 +665,309 @@ fn kube_proto_from_str(proto: &Option<String>) -> KubeProtocol {
         None => KubeProtocol::Tcp,
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, net::IpAddr};
+
+    use k8s_openapi::api::core::v1::{ClientIPConfig, Service, ServiceSpec, SessionAffinityConfig};
+    use kube::api::ObjectMeta;
+
+    use mesh_cni_ebpf_common::service::{
+        EndpointValue, EndpointValueV4, ServiceKey, ServiceOptions, ServiceValue,
+    };
+
+    use super::{
+        BackendPortMapping, DEFAULT_AFFINITY_TIMEOUT_SECONDS, LOAD_BALANCING_ANNOTATION,
+        LoadBalancing, MeshEndpoint, MeshEndpointSpec, SourceRange, Topology, load_balancing,
+        session_affinity,
+    };
+
+    fn make_service(affinity: Option<&str>, timeout: Option<i32>) -> Service {
+        Service {
+            spec: Some(ServiceSpec {
+                session_affinity: affinity.map(Into::into),
+                session_affinity_config: timeout.map(|timeout| SessionAffinityConfig {
+                    client_ip: Some(ClientIPConfig {
+                        timeout_seconds: Some(timeout),
+                    }),
+                }),
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_session_affinity_from_service() {
+        assert_eq!(session_affinity(&make_service(None, None)), (false, 0));
+        assert_eq!(
+            session_affinity(&make_service(Some("None"), Some(60))),
+            (false, 0)
+        );
+        assert_eq!(
+            session_affinity(&make_service(Some("ClientIP"), None)),
+            (true, DEFAULT_AFFINITY_TIMEOUT_SECONDS)
+        );
+        assert_eq!(
+            session_affinity(&make_service(Some("ClientIP"), Some(60))),
+            (true, 60)
+        );
+    }
+
+    #[test]
+    fn test_load_balancing_from_annotation() {
+        let annotated = |value: &str| Service {
+            metadata: ObjectMeta {
+                annotations: Some(BTreeMap::from([(
+                    LOAD_BALANCING_ANNOTATION.to_string(),
+                    value.to_string(),
+                )])),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+
+        assert_eq!(
+            load_balancing(&make_service(None, None)),
+            LoadBalancing::Random
+        );
+        assert_eq!(load_balancing(&annotated("maglev")), LoadBalancing::Maglev);
+        assert_eq!(load_balancing(&annotated("random")), LoadBalancing::Random);
+        assert_eq!(load_balancing(&annotated("ring")), LoadBalancing::Random);
+    }
+
+    fn mapping(last: u8, node: &str, zone: &str, for_zones: &[&str]) -> BackendPortMapping {
+        BackendPortMapping {
+            ip: [10, 0, 0, last].into(),
+            service_port: 80,
+            backend_port: 8080,
+            protocol: "TCP".into(),
+            node_port: None,
+            node_name: Some(node.into()),
+            zone: Some(zone.into()),
+            for_zones: for_zones.iter().map(|zone| zone.to_string()).collect(),
+            serving: true,
+            terminating: false,
+        }
+    }
+
+    fn backends(spec: MeshEndpointSpec, node_name: &str, zone: &str) -> Vec<u8> {
+        let topology = Topology {
+            node_name: node_name.into(),
+            zone: Some(zone.into()),
+            ..Default::default()
+        };
+        let mep = MeshEndpoint::new("test", spec);
+        let service_key = ServiceKey::v4(u32::from_be_bytes([192, 168, 0, 1]), 80, 6);
+        let mut backends: Vec<u8> = mep.generate_bpf_service_endpoints(&topology)[&service_key]
+            .endpoints
+            .iter()
+            .map(|endpoint| match endpoint {
+                EndpointValue::V4(endpoint) => endpoint.ip.to_be_bytes()[3],
+                EndpointValue::V6(_) => unreachable!(),
+            })
+            .collect();
+        backends.sort();
+        backends
+    }
+
+    #[test]
+    fn test_backends_filtered_by_topology() {
+        let spec = MeshEndpointSpec {
+            service_ips: vec![[192, 168, 0, 1].into()],
+            backend_port_mappings: vec![
+                mapping(1, "node-a", "zone-a", &[]),
+                mapping(2, "node-b", "zone-a", &[]),
+                mapping(3, "node-c", "zone-b", &[]),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(backends(spec.clone(), "node-a", "zone-a"), vec![1, 2, 3]);
+
+        let node_local = MeshEndpointSpec {
+            node_local: true,
+            ..spec.clone()
+        };
+        assert_eq!(backends(node_local.clone(), "node-c", "zone-b"), vec![3]);
+        // nothing on this node, every backend is used rather than dropping traffic
+        assert_eq!(backends(node_local, "node-d", "zone-b"), vec![1, 2, 3]);
+
+        let prefer_close = MeshEndpointSpec {
+            prefer_close: true,
+            ..spec
+        };
+        assert_eq!(
+            backends(prefer_close.clone(), "node-d", "zone-a"),
+            vec![1, 2]
+        );
+        assert_eq!(backends(prefer_close, "node-d", "zone-c"), vec![1, 2, 3]);
+    }
+
+    #[test]
+    fn test_topology_hints_override_zones() {
+        let spec = MeshEndpointSpec {
+            service_ips: vec![[192, 168, 0, 1].into()],
+            backend_port_mappings: vec![
+                mapping(1, "node-a", "zone-a", &["zone-a"]),
+                mapping(2, "node-b", "zone-a", &["zone-b"]),
+                mapping(3, "node-c", "zone-b", &["zone-b"]),
+            ],
+            prefer_close: true,
+            ..Default::default()
+        };
+        assert_eq!(backends(spec.clone(), "node-d", "zone-a"), vec![1]);
+        assert_eq!(backends(spec.clone(), "node-d", "zone-b"), vec![2, 3]);
+
+        // a backend without hints makes the whole set fall back to zones
+        let mut partial = spec;
+        partial.backend_port_mappings[0].for_zones.clear();
+        assert_eq!(backends(partial, "node-d", "zone-a"), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_node_port_frontends_follow_external_policy() {
+        let mut spec = MeshEndpointSpec {
+            service_ips: vec![[192, 168, 0, 1].into()],
+            backend_port_mappings: vec![
+                mapping(1, "node-a", "zone-a", &[]),
+                mapping(2, "node-b", "zone-a", &[]),
+            ],
+            ..Default::default()
+        };
+        for mapping in &mut spec.backend_port_mappings {
+            mapping.node_port = Some(30080);
+        }
+        let topology = |node_name: &str| Topology {
+            node_name: node_name.into(),
+            zone: None,
+            node_ips: vec![[172, 16, 0, 1].into()],
+        };
+        let node_port = ServiceKey::v4(u32::from_be_bytes([172, 16, 0, 1]), 30080, 6);
+
+        let mep = MeshEndpoint::new("test", spec.clone());
+        let endpoints = mep.generate_bpf_service_endpoints(&topology("node-a"));
+        assert_eq!(endpoints[&node_port].endpoints.len(), 2);
+        assert_eq!(endpoints[&node_port].local, None);
+
+        let mep = MeshEndpoint::new(
+            "test",
+            MeshEndpointSpec {
+                external_node_local: true,
+                ..spec
+            },
+        );
+        let endpoints = mep.generate_bpf_service_endpoints(&topology("node-b"));
+        assert_eq!(endpoints[&node_port].local, Some(1));
+        // local backends come first
+        assert_eq!(
+            endpoints[&node_port].endpoints,
+            vec![endpoint(2), endpoint(1)]
+        );
+        // no local backend drops NodePort traffic rather than hiding the client address
+        let endpoints = mep.generate_bpf_service_endpoints(&topology("node-c"));
+        assert_eq!(endpoints[&node_port].local, Some(0));
+        let cluster_ip = ServiceKey::v4(u32::from_be_bytes([192, 168, 0, 1]), 80, 6);
+        assert_eq!(endpoints[&cluster_ip].endpoints.len(), 2);
+    }
+
+    fn endpoint(last: u8) -> EndpointValue {
+        EndpointValue::V4(EndpointValueV4 {
+            ip: u32::from_be_bytes([10, 0, 0, last]),
+            port: 8080,
+            _protocol: 6,
+        })
+    }
+
+    #[test]
+    fn test_in_cluster_clients_reach_external_local_frontends() {
+        // a MetalLB address of an `externalTrafficPolicy: Local` service, seen from a node
+        // without any of its backends
+        let lb_ip: IpAddr = [203, 0, 113, 10].into();
+        let spec = MeshEndpointSpec {
+            service_ips: vec![[192, 168, 0, 1].into(), lb_ip],
+            external_ips: vec![lb_ip],
+            backend_port_mappings: vec![
+                mapping(1, "node-a", "zone-a", &[]),
+                mapping(2, "node-b", "zone-a", &[]),
+            ],
+            external_node_local: true,
+            ..Default::default()
+        };
+        let mep = MeshEndpoint::new("test", spec);
+        let lb_key = ServiceKey::v4(u32::from_be_bytes([203, 0, 113, 10]), 80, 6);
+        let endpoints = mep.generate_bpf_service_endpoints(&Topology {
+            node_name: "node-c".into(),
+            ..Default::default()
+        });
+        let backends = &endpoints[&lb_key];
+        assert_eq!(backends.endpoints, vec![endpoint(1), endpoint(2)]);
+
+        // the connect hooks pick from every backend, the host datapath from none
+        let options = ServiceOptions {
+            local_backends: backends.local,
+            ..mep.service_options()
+        };
+        let value = ServiceValue::new(1, backends.endpoints.len() as u16, options);
+        assert_eq!(value.count, 2);
+        assert_eq!(value.external_count(), 0);
+        assert_eq!(value.options(), options);
+    }
+
+    #[test]
+    fn test_external_ips_filtered_by_source_ranges() {
+        let external_ip: IpAddr = [203, 0, 113, 10].into();
+        let spec = MeshEndpointSpec {
+            service_ips: vec![[192, 168, 0, 1].into(), external_ip],
+            external_ips: vec![external_ip],
+            source_ranges: vec!["198.51.100.0/24".into(), "2001:db8::/32".into()],
+            backend_port_mappings: vec![
+                mapping(1, "node-a", "zone-a", &[]),
+                mapping(2, "node-b", "zone-a", &[]),
+            ],
+            external_node_local: true,
+            ..Default::default()
+        };
+        let mep = MeshEndpoint::new("test", spec);
+        let external_key = ServiceKey::v4(u32::from_be_bytes([203, 0, 113, 10]), 80, 6);
+        let cluster_key = ServiceKey::v4(u32::from_be_bytes([192, 168, 0, 1]), 80, 6);
+
+        let endpoints = mep.generate_bpf_service_endpoints(&Topology {
+            node_name: "node-a".into(),
+            ..Default::default()
+        });
+        assert_eq!(endpoints[&external_key].local, Some(1));
+        assert_eq!(endpoints[&cluster_key].endpoints.len(), 2);
+
+        let source_ranges = mep.source_ranges();
+        assert_eq!(source_ranges.len(), 1);
+        assert_eq!(
+            source_ranges[&external_key],
+            vec![SourceRange {
+                network: [198, 51, 100, 0].into(),
+                prefix_len: 24,
+            }]
+        );
+        assert!("198.51.100.0/33".parse::<SourceRange>().is_err());
+    }
+
+    #[test]
+    fn test_terminating_backends_used_when_none_ready() {
+        let mut spec = MeshEndpointSpec {
+            service_ips: vec![[192, 168, 0, 1].into()],
+            backend_port_mappings: vec![
+                mapping(1, "node-a", "zone-a", &[]),
+                mapping(2, "node-b", "zone-a", &[]),
+                mapping(3, "node-c", "zone-b", &[]),
+            ],
+            ..Default::default()
+        };
+        spec.backend_port_mappings[0].terminating = true;
+        spec.backend_port_mappings[1].terminating = true;
+        spec.backend_port_mappings[1].serving = false;
+        assert_eq!(backends(spec.clone(), "node-a", "zone-a"), vec![3]);
+
+        spec.backend_port_mappings[2].terminating = true;
+        assert_eq!(backends(spec, "node-a", "zone-a"), vec![1, 3]);
+    }
+}
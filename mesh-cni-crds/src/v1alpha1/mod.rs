@@ -1,3 +1,4 @@
 pub mod cluster;
 pub mod identity;
 pub mod meshendpoint;
+pub mod podcidrallocation;
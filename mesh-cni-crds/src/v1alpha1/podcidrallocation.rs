@@ -0,0 +1,88 @@
+use kube::{CustomResource, KubeSchema};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+/// Pod CIDRs carved out of the cluster pool for a node, named after the node it belongs to
+/// and owned by it so it is released when the node is deleted.
+#[derive(
+    CustomResource, KubeSchema, Serialize, Deserialize, Default, PartialEq, Eq, Clone, Debug,
+)]
+#[kube(
+    group = "mesh-cni.dev",
+    version = "v1alpha1",
+    kind = "PodCidrAllocation",
+    derive = "Default",
+    derive = "PartialEq",
+    status = "PodCidrAllocationStatus"
+)]
+#[serde(rename_all = "camelCase")]
+pub struct PodCidrAllocationSpec {
+    /// CIDRs allocated to the node, in the order they were allocated
+    pub cidrs: Vec<String>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PodCidrAllocationStatus {
+    /// Usage of each CIDR, reported by the agent of the node
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub pools: Vec<PodCidrUsage>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct PodCidrUsage {
+    pub cidr: String,
+    pub allocated: u64,
+    pub capacity: u64,
+}
+
+impl PodCidrAllocation {
+    /// Free addresses of the CIDRs in `cidrs` according to the last usage reported, `None`
+    /// while the agent has not reported the usage of all of them yet
+    pub fn free_addresses<'a>(&self, cidrs: impl IntoIterator<Item = &'a String>) -> Option<u64> {
+        let pools = &self.status.as_ref()?.pools;
+        let mut free = 0u64;
+        for cidr in cidrs {
+            let usage = pools.iter().find(|usage| &usage.cidr == cidr)?;
+            free = free.saturating_add(usage.capacity.saturating_sub(usage.allocated));
+        }
+        Some(free)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(cidr: &str, allocated: u64, capacity: u64) -> PodCidrUsage {
+        PodCidrUsage {
+            cidr: cidr.into(),
+            allocated,
+            capacity,
+        }
+    }
+
+    #[test]
+    fn test_free_addresses_requires_reported_usage() {
+        let cidrs = vec!["10.0.0.0/24".to_string(), "10.0.1.0/24".to_string()];
+        let mut allocation = PodCidrAllocation::new(
+            "node-a",
+            PodCidrAllocationSpec {
+                cidrs: cidrs.clone(),
+            },
+        );
+        assert_eq!(allocation.free_addresses(&cidrs), None);
+
+        allocation.status = Some(PodCidrAllocationStatus {
+            pools: vec![usage("10.0.0.0/24", 250, 254)],
+        });
+        assert_eq!(allocation.free_addresses(&cidrs), None);
+        assert_eq!(allocation.free_addresses(&cidrs[..1]), Some(4));
+
+        allocation.status = Some(PodCidrAllocationStatus {
+            pools: vec![usage("10.0.0.0/24", 250, 254), usage("10.0.1.0/24", 4, 254)],
+        });
+        assert_eq!(allocation.free_addresses(&cidrs), Some(254));
+    }
+}
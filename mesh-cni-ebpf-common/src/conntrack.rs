@@ -10,12 +10,34 @@ pub struct ConntrackKeyV4 {
     /// Stored in host order
     pub dst_port: u16,
     pub proto: u8,
-    pub _pad: [u8; 3],
+    /// Direction of the hook that first saw the flow, 0 for ingress and 1 for egress
+    pub direction: u8,
+    pub _pad: [u8; 2],
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ConntrackKeyV4 {}
 
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ConntrackKeyV6 {
+    /// Stored in network order
+    pub src_ip: [u8; 16],
+    /// Stored in network order
+    pub dst_ip: [u8; 16],
+    /// Stored in host order
+    pub src_port: u16,
+    /// Stored in host order
+    pub dst_port: u16,
+    pub proto: u8,
+    /// Direction of the hook that first saw the flow, 0 for ingress and 1 for egress
+    pub direction: u8,
+    pub _pad: [u8; 2],
+}
+
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ConntrackKeyV6 {}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct ConntrackValue {
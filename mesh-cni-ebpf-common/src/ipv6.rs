@@ -0,0 +1,122 @@
+/// Extension headers walked before giving up on finding the transport header. The loop
+/// has to be bounded for the verifier and real traffic rarely carries more than two.
+pub const MAX_EXT_HDRS: usize = 6;
+
+const HOP_BY_HOP: u8 = 0;
+const ROUTING: u8 = 43;
+const FRAGMENT: u8 = 44;
+const AUTHENTICATION: u8 = 51;
+const DESTINATION_OPTIONS: u8 = 60;
+
+/// Offset of the fragment offset field within the fragment header
+const FRAG_OFFSET_OFF: usize = 2;
+/// Fragment offset bits of the fragment offset field
+const FRAG_OFFSET_MASK: u16 = 0xfff8;
+
+/// Where the extension header chain of a packet leads
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Transport {
+    /// Offset and protocol of the transport header
+    Header { offset: usize, proto: u8 },
+    /// Only the first fragment carries the transport header
+    Fragment,
+    /// The chain is longer than `MAX_EXT_HDRS`
+    TooManyHeaders,
+}
+
+/// Walks the extension header chain following the fixed header at `offset`, `next_hdr`
+/// being its next header field. `load` reads the byte at an offset into the packet.
+#[inline(always)]
+pub fn transport_header<E>(
+    mut offset: usize,
+    mut next_hdr: u8,
+    load: impl Fn(usize) -> Result<u8, E>,
+) -> Result<Transport, E> {
+    for _ in 0..MAX_EXT_HDRS {
+        // every extension header starts with the next header and a length byte
+        let len = match next_hdr {
+            HOP_BY_HOP | ROUTING | DESTINATION_OPTIONS => (load(offset + 1)? as usize + 1) * 8,
+            AUTHENTICATION => (load(offset + 1)? as usize + 2) * 4,
+            FRAGMENT => {
+                let frag = u16::from_be_bytes([
+                    load(offset + FRAG_OFFSET_OFF)?,
+                    load(offset + FRAG_OFFSET_OFF + 1)?,
+                ]);
+                if frag & FRAG_OFFSET_MASK != 0 {
+                    return Ok(Transport::Fragment);
+                }
+                8
+            }
+            proto => return Ok(Transport::Header { offset, proto }),
+        };
+
+        next_hdr = load(offset)?;
+        offset += len;
+    }
+
+    Ok(Transport::TooManyHeaders)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::{vec, vec::Vec};
+
+    use super::*;
+
+    const TCP: u8 = 6;
+
+    /// Packet of a fixed header followed by the given extension headers, each of the
+    /// minimum 8 bytes and pointing at the next, then a TCP header. Returns the next header
+    /// field of the fixed header along with the packet.
+    fn build_packet(ext_hdrs: &[u8]) -> (u8, Vec<u8>) {
+        let mut packet = vec![0; 40];
+        let next_protos = ext_hdrs.iter().skip(1).chain([&TCP]);
+        for next in next_protos.take(ext_hdrs.len()) {
+            let mut ext = [0; 8];
+            ext[0] = *next;
+            packet.extend(ext);
+        }
+        packet.extend([0; 20]);
+        (ext_hdrs.first().copied().unwrap_or(TCP), packet)
+    }
+
+    fn walk(next_hdr: u8, packet: &[u8]) -> Transport {
+        transport_header(40, next_hdr, |offset| packet.get(offset).copied().ok_or(())).unwrap()
+    }
+
+    #[test]
+    fn test_transport_header_follows_extension_headers() {
+        let (next_hdr, packet) = build_packet(&[]);
+        assert_eq!(
+            walk(next_hdr, &packet),
+            Transport::Header {
+                offset: 40,
+                proto: TCP
+            }
+        );
+
+        let (next_hdr, packet) = build_packet(&[HOP_BY_HOP, FRAGMENT, DESTINATION_OPTIONS]);
+        assert_eq!(
+            walk(next_hdr, &packet),
+            Transport::Header {
+                offset: 64,
+                proto: TCP
+            }
+        );
+    }
+
+    #[test]
+    fn test_transport_header_gives_up_after_max_ext_hdrs() {
+        let (next_hdr, packet) = build_packet(&[DESTINATION_OPTIONS; MAX_EXT_HDRS + 1]);
+        assert_eq!(walk(next_hdr, &packet), Transport::TooManyHeaders);
+    }
+
+    #[test]
+    fn test_transport_header_stops_at_non_initial_fragments() {
+        let (next_hdr, mut packet) = build_packet(&[FRAGMENT]);
+        packet[40 + FRAG_OFFSET_OFF] = 0x01;
+        assert_eq!(walk(next_hdr, &packet), Transport::Fragment);
+    }
+}
@@ -1,6 +1,7 @@
 #![no_std]
 
 pub mod conntrack;
+pub mod ipv6;
 pub mod policy;
 pub mod service;
 
@@ -13,6 +14,17 @@ use core::{
 pub type IdentityId = u32;
 pub type Id = u16;
 
+/// Identity for addresses not covered by any more specific prefix
+pub const WORLD_ID: IdentityId = 2;
+/// Identity assigned to the IPs of the node the agent runs on
+pub const LOCAL_NODE_ID: IdentityId = 10;
+/// Identity assigned to the IPs of every other node in the cluster
+pub const REMOTE_NODE_ID: IdentityId = 11;
+/// Pod identities are allocated from `MIN_POD_ID..MIN_CIDR_ID`, lower values are reserved
+pub const MIN_POD_ID: IdentityId = 256;
+/// CIDR-derived identities are allocated locally by each agent from `MIN_CIDR_ID..=u32::MAX`
+pub const MIN_CIDR_ID: IdentityId = 0xFF00_0000;
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct Ip {
@@ -56,6 +68,42 @@ impl From<Ip> for IpAddr {
     }
 }
 
+/// Direction of traffic from the point of view of the pod
+#[repr(u8)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Default)]
+pub enum Direction {
+    #[default]
+    Ingress = 0,
+    Egress = 1,
+}
+
+impl Direction {
+    pub const fn reverse(self) -> Self {
+        match self {
+            Direction::Ingress => Direction::Egress,
+            Direction::Egress => Direction::Ingress,
+        }
+    }
+}
+
+impl From<u8> for Direction {
+    fn from(value: u8) -> Self {
+        match value {
+            0 => Direction::Ingress,
+            _ => Direction::Egress,
+        }
+    }
+}
+
+impl Display for Direction {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        match self {
+            Direction::Ingress => write!(f, "INGRESS"),
+            Direction::Egress => write!(f, "EGRESS"),
+        }
+    }
+}
+
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, Default)]
 pub enum KubeProtocol {
@@ -1,5 +1,20 @@
 use core::fmt::Display;
 
+use crate::{Direction, IdentityId};
+
+/// Source identity of the isolation marker. A key of
+/// `{ src_id: ISOLATED_SRC_ID, dst_id, dst_port: 0, proto: 0, direction }` marks `dst_id` as
+/// selected by at least one policy of that direction, in which case traffic without a
+/// matching allow entry is dropped.
+pub const ISOLATED_SRC_ID: IdentityId = 0;
+
+/// Size of the policy map, shared by the entries of every identity on a node
+pub const POLICY_MAP_ENTRIES: u32 = 65535;
+
+/// Ingress entries describe traffic into the `dst_id` pod, egress entries traffic out of
+/// the `src_id` pod. Keys are looked up from most to least specific:
+/// `(port, proto)`, `(0, proto)`, `(port, 0)` and finally `(0, 0)`.
+/// The first entry found decides the action.
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct PolicyKey {
@@ -9,12 +24,53 @@ pub struct PolicyKey {
     pub dst_port: u16,
     /// Value of 0 is used for wildcard
     pub proto: u8,
-    pub _pad: [u8; 3],
+    /// Value of 0 indicates ingress, 1 indicates egress
+    pub direction: u8,
+    pub _pad: [u8; 4],
 }
 
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for PolicyKey {}
 
+impl PolicyKey {
+    pub const fn new(
+        src_id: IdentityId,
+        dst_id: IdentityId,
+        dst_port: u16,
+        proto: u8,
+        direction: Direction,
+    ) -> Self {
+        Self {
+            src_id,
+            dst_id,
+            dst_port,
+            proto,
+            direction: direction as u8,
+            _pad: [0; 4],
+        }
+    }
+
+    /// Isolation marker for the selected identity `id`
+    pub const fn isolation(id: IdentityId, direction: Direction) -> Self {
+        Self::new(ISOLATED_SRC_ID, id, 0, 0, direction)
+    }
+
+    pub const fn is_isolation(&self) -> bool {
+        self.src_id == ISOLATED_SRC_ID && self.dst_port == 0 && self.proto == 0
+    }
+
+    /// Identity the policy entry was generated for
+    pub const fn selected_id(&self) -> IdentityId {
+        if self.is_isolation() {
+            return self.dst_id;
+        }
+        match self.direction {
+            0 => self.dst_id,
+            _ => self.src_id,
+        }
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
 pub struct PolicyValue {
@@ -51,31 +51,226 @@ impl ServiceKeyV6 {
     }
 }
 
+/// Backends of the service are pinned per client for `affinity_timeout_secs`
+pub const SERVICE_FLAG_AFFINITY: u8 = 1 << 0;
+/// Backends of the service are picked through its Maglev lookup table
+pub const SERVICE_FLAG_MAGLEV: u8 = 1 << 1;
+/// Traffic arriving from outside the node only goes to the first `local_count` backends
+pub const SERVICE_FLAG_EXTERNAL_LOCAL: u8 = 1 << 2;
+
+/// Slots in a Maglev lookup table. Prime as the algorithm requires, and large enough to
+/// keep the spread even for services with up to a few hundred backends.
+pub const MAGLEV_TABLE_SIZE: usize = 1021;
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct ServiceValue {
     pub id: Id,
     pub count: u16,
+    /// `SERVICE_FLAG_*` bits
+    pub flags: u8,
+    /// Which backend set of the service is live. Updates fill the slots of the next
+    /// revision and publish them by writing this, so readers never see a half written set.
+    pub revision: u8,
+    /// Leading backends which are on this node, see [`ServiceOptions::local_backends`]
+    pub local_count: u16,
+    /// Idle time after which a client's affinity expires
+    pub affinity_timeout_secs: u32,
 }
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for ServiceValue {}
 
+impl ServiceValue {
+    pub const fn new(id: Id, count: u16, options: ServiceOptions) -> Self {
+        let mut flags = 0;
+        if options.affinity_timeout_secs.is_some() {
+            flags |= SERVICE_FLAG_AFFINITY;
+        }
+        if options.maglev {
+            flags |= SERVICE_FLAG_MAGLEV;
+        }
+        if options.local_backends.is_some() {
+            flags |= SERVICE_FLAG_EXTERNAL_LOCAL;
+        }
+        Self {
+            id,
+            count,
+            flags,
+            revision: 0,
+            local_count: match options.local_backends {
+                Some(local) => local,
+                None => 0,
+            },
+            affinity_timeout_secs: match options.affinity_timeout_secs {
+                Some(timeout) => timeout,
+                None => 0,
+            },
+        }
+    }
+
+    pub const fn with_revision(self, revision: u8) -> Self {
+        Self { revision, ..self }
+    }
+
+    pub const fn endpoint_key(&self, position: u16) -> EndpointKey {
+        EndpointKey::new(self.id, self.revision, position)
+    }
+
+    pub const fn maglev_key(&self) -> MaglevKey {
+        MaglevKey {
+            id: self.id,
+            revision: self.revision,
+            _pad: 0,
+        }
+    }
+
+    pub const fn options(&self) -> ServiceOptions {
+        let affinity_timeout_secs = if self.flags & SERVICE_FLAG_AFFINITY != 0 {
+            Some(self.affinity_timeout_secs)
+        } else {
+            None
+        };
+        let local_backends = if self.flags & SERVICE_FLAG_EXTERNAL_LOCAL != 0 {
+            Some(self.local_count)
+        } else {
+            None
+        };
+        ServiceOptions {
+            affinity_timeout_secs,
+            maglev: self.flags & SERVICE_FLAG_MAGLEV != 0,
+            local_backends,
+        }
+    }
+
+    /// Backends traffic arriving from outside the node may be sent to, the leading
+    /// `count` positions
+    pub const fn external_count(&self) -> u16 {
+        if self.flags & SERVICE_FLAG_EXTERNAL_LOCAL != 0 {
+            self.local_count
+        } else {
+            self.count
+        }
+    }
+}
+
+/// Load balancing settings of a frontend. All but `local_backends` are shared by every
+/// frontend of a service.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct ServiceOptions {
+    /// ClientIP session affinity timeout, `None` when affinity is disabled
+    pub affinity_timeout_secs: Option<u32>,
+    /// Pick backends with Maglev consistent hashing instead of at random
+    pub maglev: bool,
+    /// For frontends with `externalTrafficPolicy: Local`, how many of the leading backends
+    /// are on this node. Clients outside the node only reach those, clients in the cluster
+    /// still reach every backend as with kube-proxy.
+    pub local_backends: Option<u16>,
+}
+
+/// Maglev table of one backend set of a service
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct MaglevKey {
+    pub id: Id,
+    pub revision: u8,
+    pub _pad: u8,
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MaglevKey {}
+
+/// Backend position for each slot of a service's Maglev lookup table
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct MaglevTable {
+    pub positions: [u16; MAGLEV_TABLE_SIZE],
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for MaglevTable {}
+
+/// Spreads a socket cookie over the slots of a Maglev table. The finalizer of splitmix64,
+/// cookies are sequential so their low bits alone would cluster.
+#[inline]
+pub const fn maglev_slot(cookie: u64) -> usize {
+    let mut x = cookie;
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^= x >> 31;
+    (x % MAGLEV_TABLE_SIZE as u64) as usize
+}
+
+/// Client a service backend was pinned to. Clients are identified by their network
+/// namespace since the source address is not known yet when the socket connects.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct AffinityKey {
+    pub netns_cookie: u64,
+    pub id: Id,
+    pub _pad: [u8; 6],
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AffinityKey {}
+
+impl AffinityKey {
+    pub const fn new(netns_cookie: u64, id: Id) -> Self {
+        Self {
+            netns_cookie,
+            id,
+            _pad: [0; 6],
+        }
+    }
+}
+
+/// Backend a client is pinned to. The backend is kept rather than its slot, which a later
+/// backend set may give to another one.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct AffinityValue {
+    /// Backend ip in host order, IPv4 addresses in the low 32 bits
+    pub ip: u128,
+    pub last_seen_ns: u64,
+    pub port: u16,
+    /// Slot the backend was found in last, checked first
+    pub position: u16,
+    pub _pad: [u8; 4],
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for AffinityValue {}
+
+impl AffinityValue {
+    pub const fn new(last_seen_ns: u64, ip: u128, port: u16, position: u16) -> Self {
+        Self {
+            ip,
+            last_seen_ns,
+            port,
+            position,
+            _pad: [0; 4],
+        }
+    }
+
+    pub const fn is_backend(&self, ip: u128, port: u16) -> bool {
+        self.ip == ip && self.port == port
+    }
+}
+
 #[repr(C)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
 pub struct EndpointKey {
     pub id: u16,
     pub position: u16,
-    pub _pad: u32,
+    /// Backend set the slot belongs to, see [`ServiceValue::revision`]
+    pub revision: u8,
+    pub _pad: [u8; 3],
 }
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for EndpointKey {}
 
 impl EndpointKey {
-    pub const fn new(id: u16, position: u16) -> Self {
+    pub const fn new(id: u16, revision: u8, position: u16) -> Self {
         Self {
             id,
             position,
-            _pad: 0,
+            revision,
+            _pad: [0; 3],
         }
     }
 }
@@ -115,3 +310,257 @@ pub struct EndpointValueV6 {
 }
 #[cfg(feature = "user")]
 unsafe impl aya::Pod for EndpointValueV6 {}
+
+/// Reverse translation for a socket whose destination was rewritten from a service address
+/// to a backend. Keyed by socket cookie and backend so a single unconnected UDP socket can
+/// talk to several services at once.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ReverseNatKeyV4 {
+    pub cookie: u64,
+    /// Backend ip, stored in host order
+    pub ip: u32,
+    /// Backend port, stored in host order
+    pub port: u16,
+    pub _pad: u16,
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ReverseNatKeyV4 {}
+
+impl ReverseNatKeyV4 {
+    pub const fn new(cookie: u64, ip: u32, port: u16) -> Self {
+        Self {
+            cookie,
+            ip,
+            port,
+            _pad: 0,
+        }
+    }
+}
+
+/// Service address a backend is reported as
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ReverseNatValueV4 {
+    pub ip: u32,
+    pub port: u16,
+    pub _pad: u16,
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ReverseNatValueV4 {}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ReverseNatKeyV6 {
+    /// Backend ip, stored in host order
+    pub ip: u128,
+    pub cookie: u64,
+    /// Backend port, stored in host order
+    pub port: u16,
+    pub _pad: [u8; 6],
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ReverseNatKeyV6 {}
+
+impl ReverseNatKeyV6 {
+    pub const fn new(cookie: u64, ip: u128, port: u16) -> Self {
+        Self {
+            ip,
+            cookie,
+            port,
+            _pad: [0; 6],
+        }
+    }
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct ReverseNatValueV6 {
+    pub ip: u128,
+    pub port: u16,
+    pub _pad: [u8; 14],
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for ReverseNatValueV6 {}
+
+/// Flow of a NodePort packet as it crosses the external interface. Addresses and ports are
+/// stored in host order.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct NatKeyV4 {
+    pub src_ip: u32,
+    pub dst_ip: u32,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    pub _pad: [u8; 3],
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for NatKeyV4 {}
+
+impl NatKeyV4 {
+    pub const fn new(src_ip: u32, dst_ip: u32, src_port: u16, dst_port: u16, proto: u8) -> Self {
+        Self {
+            src_ip,
+            dst_ip,
+            src_port,
+            dst_port,
+            proto,
+            _pad: [0; 3],
+        }
+    }
+}
+
+/// Addresses a NodePort packet is rewritten to. Packets whose source becomes the node
+/// itself cannot be handed back to the stack and are redirected out of the interface.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub struct NatValueV4 {
+    pub src_ip: u32,
+    pub dst_ip: u32,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub redirect: u8,
+    pub _pad: [u8; 3],
+    /// Refreshed by every packet of the flow in this direction
+    pub last_seen_ns: u64,
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for NatValueV4 {}
+
+impl NatValueV4 {
+    /// Whether the flow went quiet for long enough that its SNAT port may be handed out
+    /// again
+    #[inline]
+    pub const fn expired(&self, now_ns: u64) -> bool {
+        now_ns.saturating_sub(self.last_seen_ns) > SNAT_IDLE_TIMEOUT_NS
+    }
+}
+
+/// Source ports NodePort flows to remote backends are translated to. Above the default
+/// `ip_local_port_range` of 32768-60999 so they do not clash with sockets of the node.
+pub const SNAT_PORT_MIN: u16 = 61000;
+pub const SNAT_PORT_MAX: u16 = 65535;
+/// Ports tried for a new flow before it is dropped
+pub const SNAT_PORT_ATTEMPTS: u32 = 16;
+/// Idle time after which the SNAT port of a flow may be reused by another one
+pub const SNAT_IDLE_TIMEOUT_NS: u64 = 10 * 60 * 1_000_000_000;
+
+/// Picks the source port a NodePort flow to a remote backend is translated to. Replies are
+/// told apart by that port alone, so clients using the same source port must not share
+/// one. `reserve` claims the reply entry of a port and fails when a flow that has not
+/// [expired](NatValueV4::expired) holds it, ports are probed from `seed` on.
+#[inline]
+pub fn allocate_snat_port(seed: u32, mut reserve: impl FnMut(u16) -> bool) -> Option<u16> {
+    let span = (SNAT_PORT_MAX - SNAT_PORT_MIN) as u32 + 1;
+    for attempt in 0..SNAT_PORT_ATTEMPTS {
+        let port = SNAT_PORT_MIN + (seed.wrapping_add(attempt) % span) as u16;
+        if reserve(port) {
+            return Some(port);
+        }
+    }
+    None
+}
+
+/// Source ranges a single frontend accepts, ranges beyond this are rejected by the agent
+pub const MAX_SOURCE_RANGES: usize = 16;
+
+/// Network and netmask of a source range, stored in host order
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SourceRangeV4 {
+    pub network: u32,
+    pub mask: u32,
+}
+
+/// Source addresses allowed to reach an external frontend, from the service's
+/// `loadBalancerSourceRanges`. Frontends without an entry accept any source.
+#[repr(C)]
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, Hash)]
+pub struct SourceRangesV4 {
+    pub count: u32,
+    pub ranges: [SourceRangeV4; MAX_SOURCE_RANGES],
+}
+#[cfg(feature = "user")]
+unsafe impl aya::Pod for SourceRangesV4 {}
+
+impl SourceRangesV4 {
+    #[inline]
+    pub fn allows(&self, ip: u32) -> bool {
+        for (i, range) in self.ranges.iter().enumerate() {
+            if i as u32 >= self.count {
+                break;
+            }
+            if ip & range.mask == range.network {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate std;
+
+    use std::collections::HashMap;
+
+    use super::*;
+
+    const NODE: u32 = 0x0a00_0001;
+    const BACKEND: u32 = 0x0a01_0002;
+
+    /// Connects `client` from source port 40000 to a remote backend through the node,
+    /// reply entries are only replaced once they expired as in the datapath
+    fn connect(nat: &mut HashMap<NatKeyV4, NatValueV4>, client: u32, seed: u32, now: u64) -> u16 {
+        allocate_snat_port(seed, |port| {
+            let key = NatKeyV4::new(BACKEND, NODE, 8080, port, 6);
+            if nat.get(&key).is_some_and(|reply| !reply.expired(now)) {
+                return false;
+            }
+            let reply = NatValueV4 {
+                src_ip: NODE,
+                dst_ip: client,
+                src_port: 30080,
+                dst_port: 40000,
+                redirect: 1,
+                _pad: [0; 3],
+                last_seen_ns: now,
+            };
+            nat.insert(key, reply);
+            true
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn test_clients_sharing_a_source_port_get_separate_replies() {
+        let mut nat = HashMap::new();
+        // both clients draw the same seed
+        let first = connect(&mut nat, 0xc0a8_0001, 7, 0);
+        let second = connect(&mut nat, 0xc0a8_0002, 7, 0);
+        assert_ne!(first, second);
+        assert!((SNAT_PORT_MIN..=SNAT_PORT_MAX).contains(&first));
+        assert!((SNAT_PORT_MIN..=SNAT_PORT_MAX).contains(&second));
+        let reply = |nat: &HashMap<_, NatValueV4>, port| {
+            nat[&NatKeyV4::new(BACKEND, NODE, 8080, port, 6)].dst_ip
+        };
+        assert_eq!(reply(&nat, first), 0xc0a8_0001);
+        assert_eq!(reply(&nat, second), 0xc0a8_0002);
+
+        // the port of a flow which went quiet is handed out again
+        let third = connect(&mut nat, 0xc0a8_0003, 7, SNAT_IDLE_TIMEOUT_NS + 1);
+        assert_eq!(third, first);
+        assert_eq!(reply(&nat, first), 0xc0a8_0003);
+
+        // the flow is dropped once every candidate is taken
+        assert_eq!(allocate_snat_port(u32::MAX, |_| false), None);
+        let mut tried = std::vec::Vec::new();
+        allocate_snat_port(u32::MAX, |port| {
+            tried.push(port);
+            false
+        });
+        assert_eq!(tried.len(), SNAT_PORT_ATTEMPTS as usize);
+        assert!(tried.iter().all(|port| *port >= SNAT_PORT_MIN));
+    }
+}
@@ -17,6 +17,8 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 pub trait IdentityBpfState {
     fn update(&self, key: ipnetwork::IpNetwork, value: u32) -> Result<()>;
+    /// Deletes every network `keep` returns false for
+    fn retain(&self, keep: impl Fn(&ipnetwork::IpNetwork, u32) -> bool) -> Result<()>;
 }
 
 pub(crate) trait IdentityControllerExt {
@@ -2,6 +2,7 @@ use std::{net::IpAddr, str::FromStr, sync::Arc};
 
 use k8s_openapi::api::core::v1::Node;
 use kube::{ResourceExt, runtime::controller::Action};
+use mesh_cni_ebpf_common::{LOCAL_NODE_ID, REMOTE_NODE_ID};
 use tracing::{debug, info};
 
 use crate::{
@@ -9,9 +10,6 @@ use crate::{
     controller::DEFAULT_REQUEUE_DURATION,
 };
 
-const LOCAL_NODE_ID: u32 = 10;
-const REMOTE_NODE_ID: u32 = 11;
-
 impl IdentityControllerExt for Node {
     async fn reconcile<B: IdentityBpfState>(&self, ctx: Arc<Context<B>>) -> Result<Action> {
         let node_name = self.name_any();
@@ -39,7 +37,7 @@ impl IdentityControllerExt for Node {
     }
 }
 
-fn node_ips(node: &Node) -> Vec<IpAddr> {
+pub(crate) fn node_ips(node: &Node) -> Vec<IpAddr> {
     let Some(status) = node.status.as_ref() else {
         return Vec::new();
     };
@@ -68,7 +68,7 @@ impl IdentityControllerExt for Pod {
     }
 }
 
-fn pod_ips(pod: &Pod) -> Vec<IpAddr> {
+pub(crate) fn pod_ips(pod: &Pod) -> Vec<IpAddr> {
     let Some(status) = pod.status.as_ref() else {
         return Vec::new();
     };
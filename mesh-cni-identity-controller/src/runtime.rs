@@ -1,23 +1,29 @@
-use std::sync::Arc;
+use std::{collections::HashSet, sync::Arc};
 
 use futures::StreamExt;
+use ipnetwork::IpNetwork;
 use k8s_openapi::api::core::v1::{Namespace, Node, Pod};
 use kube::{Api, Client, runtime::Controller};
 use mesh_cni_crds::v1alpha1::identity::Identity;
-use mesh_cni_k8s_utils::create_store_and_subscriber;
+use mesh_cni_ebpf_common::{IdentityId, LOCAL_NODE_ID, MIN_CIDR_ID, MIN_POD_ID, REMOTE_NODE_ID};
+use mesh_cni_k8s_utils::{InitialReconcile, create_store_and_subscriber, join_reconciled};
 use tokio::time::Duration;
 use tokio_util::sync::CancellationToken;
+use tracing::info;
 
 use crate::{
     IdentityBpfState, Result,
     context::Context,
     controller::{error_policy, reconcile},
+    node::node_ips,
+    pod::pod_ips,
 };
 
 pub async fn start_identity_controllers<B>(
     client: Client,
     node_name: String,
     cancel: CancellationToken,
+    reconciled: CancellationToken,
     bpf_maps: B,
 ) -> Result<()>
 where
@@ -49,6 +55,15 @@ where
         (node_store, node_subscriber),
     ) = store_init;
 
+    info!("sweeping addresses of deleted Pods and Nodes");
+    sweep(&pod_store.state(), &node_store.state(), &bpf_maps)?;
+
+    let node_reconciled = CancellationToken::new();
+    let pod_reconciled = CancellationToken::new();
+    let mut initial_nodes = InitialReconcile::from_store(&node_store, node_reconciled.clone());
+    let mut initial_pods = InitialReconcile::from_store(&pod_store, pod_reconciled.clone());
+    join_reconciled([node_reconciled, pod_reconciled], reconciled);
+
     let context = Arc::new(Context {
         node_name,
         identity_store,
@@ -62,19 +77,41 @@ where
         Controller::for_shared_stream(node_subscriber, node_store)
             .graceful_shutdown_on(shutdown(cancel.clone()))
             .run(reconcile, error_policy, context.clone())
-            .filter_map(|x| async move { std::result::Result::ok(x) })
-            .for_each(|_| futures::future::ready(())),
+            .for_each(move |result| {
+                initial_nodes.observe(&result);
+                futures::future::ready(())
+            }),
     );
     Controller::for_shared_stream(pod_subscriber, pod_store)
         .graceful_shutdown_on(shutdown(cancel))
         .run(reconcile, error_policy, context)
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
+        .for_each(move |result| {
+            initial_pods.observe(&result);
+            futures::future::ready(())
+        })
         .await;
 
     Ok(())
 }
 
+/// Deletes the addresses of Pods and Nodes that went away while the agent was not
+/// running. Runs on the initial list, before any reconcile. CIDR identities are owned by
+/// the policy controller and left alone.
+fn sweep<B: IdentityBpfState>(pods: &[Arc<Pod>], nodes: &[Arc<Node>], bpf_maps: &B) -> Result<()> {
+    let live: HashSet<IpNetwork> = pods
+        .iter()
+        .flat_map(|pod| pod_ips(pod))
+        .chain(nodes.iter().flat_map(|node| node_ips(node)))
+        .map(IpNetwork::from)
+        .collect();
+    bpf_maps.retain(|network, id| !owned_identity(id) || live.contains(network))
+}
+
+/// Identities this controller programs addresses for
+fn owned_identity(id: IdentityId) -> bool {
+    id == LOCAL_NODE_ID || id == REMOTE_NODE_ID || (MIN_POD_ID..MIN_CIDR_ID).contains(&id)
+}
+
 async fn shutdown(cancel: CancellationToken) {
     cancel.cancelled().await;
 }
@@ -7,6 +7,7 @@ use kube::{
     runtime::{controller::Action, reflector::ObjectRef},
 };
 use mesh_cni_crds::v1alpha1::identity::{Identity, IdentitySpec};
+use mesh_cni_ebpf_common::{IdentityId, MIN_CIDR_ID, MIN_POD_ID};
 use rand::Rng;
 use serde::de::DeserializeOwned;
 use sha2::{Digest, Sha256};
@@ -100,22 +101,38 @@ fn get_or_generate_identity(ctx: &Context, ns: &Namespace, pod: &Pod) -> Result<
 
         // SSA requires managedFields to be omitted from the payload.
         ident.metadata.managed_fields = None;
+        // ids generated before the pod range was narrowed collide with reserved and CIDR
+        // identities, they are replaced by the patch applying the identity
+        if !(MIN_POD_ID..MIN_CIDR_ID).contains(&ident.spec.id) {
+            let id = generate_id(ctx);
+            tracing::info!(
+                "reallocating out of range identity {} {} as {}",
+                name,
+                ident.spec.id,
+                id
+            );
+            ident.spec.id = id;
+        }
         return Ok(ident);
     }
 
+    spec.id = generate_id(ctx);
+    Ok(Identity::new(&name, spec))
+}
+
+/// Picks an id in the pod range no identity uses
+fn generate_id(ctx: &Context) -> IdentityId {
     let mut used_ids = HashSet::new();
     for identity in ctx.identities.state() {
         used_ids.insert(identity.spec.id);
     }
     let mut rng = rand::rng();
-    spec.id = loop {
-        let candidate = rng.random();
+    loop {
+        let candidate = rng.random_range(MIN_POD_ID..MIN_CIDR_ID);
         if !used_ids.contains(&candidate) {
             break candidate;
         }
-    };
-
-    Ok(Identity::new(&name, spec))
+    }
 }
 
 #[cfg(test)]
@@ -213,6 +230,7 @@ mod tests {
 
         let identity = get_or_generate_identity(&ctx, &ns, &pod).expect("identity");
         assert_ne!(identity.spec.id, 123);
+        assert!((MIN_POD_ID..MIN_CIDR_ID).contains(&identity.spec.id));
 
         let mut expected_pod_labels = pod.labels().to_owned();
         sanitize_pod_labels(&mut expected_pod_labels);
@@ -254,4 +272,34 @@ mod tests {
         assert_eq!(identity.metadata.name.as_deref(), Some(name.as_str()));
         assert_eq!(identity.spec, existing.spec);
     }
+
+    #[tokio::test]
+    async fn test_generate_identity_reallocates_out_of_range_id() {
+        let ns = make_namespace("ns-a");
+        let pod = make_pod("pod-a", "ns-a");
+        let mut pod_labels = pod.labels().to_owned();
+        sanitize_pod_labels(&mut pod_labels);
+        let spec = IdentitySpec {
+            namespace_labels: ns.labels().to_owned(),
+            pod_labels,
+            id: 0,
+        };
+        let name = hash_identity_spec(&spec);
+
+        for id in [5, MIN_CIDR_ID] {
+            let existing = Identity {
+                metadata: ObjectMeta {
+                    name: Some(name.clone()),
+                    namespace: Some("ns-a".into()),
+                    ..Default::default()
+                },
+                spec: IdentitySpec { id, ..spec.clone() },
+            };
+            let ctx = make_context(vec![pod.clone()], vec![existing]);
+
+            let identity = get_or_generate_identity(&ctx, &ns, &pod).expect("identity");
+            assert_eq!(identity.metadata.name.as_deref(), Some(name.as_str()));
+            assert!((MIN_POD_ID..MIN_CIDR_ID).contains(&identity.spec.id));
+        }
+    }
 }
@@ -0,0 +1,12 @@
+use kube::{Client, runtime::reflector::Store};
+use mesh_cni_crds::v1alpha1::podcidrallocation::PodCidrAllocation;
+
+use crate::pool::ClusterPool;
+
+pub struct Context {
+    pub client: Client,
+    pub allocations: Store<PodCidrAllocation>,
+    pub pool: ClusterPool,
+    /// Free addresses of a family below which a node is given another CIDR
+    pub min_free_addresses: u64,
+}
@@ -0,0 +1,207 @@
+use std::{sync::Arc, time::Duration};
+
+use ipnetwork::IpNetwork;
+use k8s_openapi::api::core::v1::Node;
+use kube::{
+    Api, Resource, ResourceExt,
+    api::{DeleteParams, ListParams, ObjectMeta, Patch, PatchParams},
+    runtime::{controller::Action, reflector::ObjectRef},
+};
+use mesh_cni_crds::v1alpha1::podcidrallocation::{PodCidrAllocation, PodCidrAllocationSpec};
+use serde::de::DeserializeOwned;
+
+use crate::{Error, Result, context::Context, pool::Family};
+
+const MANAGER: &str = "ipam-controller";
+
+#[tracing::instrument(skip(ctx, node))]
+pub(crate) async fn reconcile_node(node: Arc<Node>, ctx: Arc<Context>) -> Result<Action> {
+    let name = node.name_any();
+    tracing::info!("reconcile node {}", name);
+
+    let api: Api<PodCidrAllocation> = Api::all(ctx.client.clone());
+    // the store may not have caught up with an allocation created by the last reconcile
+    let existing = match ctx.allocations.get(&ObjectRef::new(&name)) {
+        Some(allocation) => Some(allocation),
+        None => api.get_opt(&name).await?.map(Arc::new),
+    };
+
+    if let Some(allocation) = &existing
+        && !owned_by(allocation, &node)
+    {
+        tracing::info!("removing allocation of a previous node named {}", name);
+        api.delete(&name, &DeleteParams::default()).await?;
+        return Ok(Action::requeue(Duration::from_secs(1)));
+    }
+
+    let mut cidrs = existing
+        .as_ref()
+        .map(|allocation| allocation.spec.cidrs.clone())
+        .unwrap_or_default();
+    let needed: Vec<Family> = ctx
+        .pool
+        .families()
+        .into_iter()
+        .filter(|family| needs_cidr(existing.as_deref(), *family, ctx.min_free_addresses))
+        .collect();
+    if needed.is_empty() {
+        return Ok(Action::requeue(Duration::from_secs(300)));
+    }
+
+    // listed rather than read from the store so a CIDR is never handed out twice
+    let mut used = used_cidrs(&api.list(&ListParams::default()).await?.items);
+    let before = cidrs.len();
+    for family in needed {
+        match ctx.pool.allocate(family, &used) {
+            Some(cidr) => {
+                tracing::info!(%cidr, "allocating pod cidr to node {}", name);
+                used.push(cidr);
+                cidrs.push(cidr.to_string());
+            }
+            None => tracing::warn!(?family, "cluster pool exhausted"),
+        }
+    }
+    if cidrs.len() == before {
+        return Ok(Action::requeue(Duration::from_secs(60)));
+    }
+
+    let owner = node
+        .controller_owner_ref(&())
+        .ok_or(Error::InvalidResource)?;
+    let allocation = PodCidrAllocation {
+        metadata: ObjectMeta {
+            name: Some(name.clone()),
+            owner_references: Some(vec![owner]),
+            ..Default::default()
+        },
+        spec: PodCidrAllocationSpec { cidrs },
+        status: None,
+    };
+    api.patch(
+        &name,
+        &PatchParams::apply(MANAGER).force(),
+        &Patch::Apply(&allocation),
+    )
+    .await?;
+
+    Ok(Action::requeue(Duration::from_secs(300)))
+}
+
+pub(crate) fn error_policy<K>(k: Arc<K>, error: &Error, _ctx: Arc<Context>) -> Action
+where
+    K: ResourceExt<DynamicType = ()>,
+    K: DeserializeOwned + Clone + Send + Sync + std::fmt::Debug + 'static,
+{
+    let name = k.name_any();
+    tracing::error!(?error, "reconcile error for {}", name);
+    Action::requeue(Duration::from_secs(1))
+}
+
+/// Removes the allocations of nodes deleted while the controller was not running and which
+/// the garbage collector did not get to
+pub(crate) async fn remove_orphans(ctx: &Context, nodes: &[Arc<Node>]) -> Result<()> {
+    let api: Api<PodCidrAllocation> = Api::all(ctx.client.clone());
+    for allocation in ctx.allocations.state() {
+        let name = allocation.name_any();
+        if nodes
+            .iter()
+            .any(|node| node.name_any() == name && owned_by(&allocation, node))
+        {
+            continue;
+        }
+        tracing::info!("releasing pod cidrs of deleted node {}", name);
+        api.delete(&name, &DeleteParams::default()).await?;
+    }
+    Ok(())
+}
+
+fn owned_by(allocation: &PodCidrAllocation, node: &Node) -> bool {
+    let Some(uid) = node.meta().uid.as_deref() else {
+        return false;
+    };
+    allocation
+        .owner_references()
+        .iter()
+        .any(|owner| owner.kind == "Node" && owner.uid == uid)
+}
+
+/// A node needs a CIDR of a family when it has none yet, or when its agent reported that
+/// fewer than `min_free` addresses are left in the ones it has
+fn needs_cidr(allocation: Option<&PodCidrAllocation>, family: Family, min_free: u64) -> bool {
+    let Some(allocation) = allocation else {
+        return true;
+    };
+    let cidrs: Vec<&String> = allocation
+        .spec
+        .cidrs
+        .iter()
+        .filter(|cidr| {
+            cidr.parse::<IpNetwork>()
+                .is_ok_and(|cidr| Family::of(&cidr) == family)
+        })
+        .collect();
+    if cidrs.is_empty() {
+        return true;
+    }
+    // wait for the agent to pick up the last CIDR before handing out another one
+    allocation
+        .free_addresses(cidrs)
+        .is_some_and(|free| free < min_free)
+}
+
+fn used_cidrs(allocations: &[PodCidrAllocation]) -> Vec<IpNetwork> {
+    allocations
+        .iter()
+        .flat_map(|allocation| allocation.spec.cidrs.iter())
+        .filter_map(|cidr| cidr.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use mesh_cni_crds::v1alpha1::podcidrallocation::{PodCidrAllocationStatus, PodCidrUsage};
+
+    use super::*;
+
+    fn allocation(cidrs: &[&str], pools: &[(&str, u64, u64)]) -> PodCidrAllocation {
+        let mut allocation = PodCidrAllocation::new(
+            "node-a",
+            PodCidrAllocationSpec {
+                cidrs: cidrs.iter().map(|cidr| cidr.to_string()).collect(),
+            },
+        );
+        allocation.status = Some(PodCidrAllocationStatus {
+            pools: pools
+                .iter()
+                .map(|(cidr, allocated, capacity)| PodCidrUsage {
+                    cidr: cidr.to_string(),
+                    allocated: *allocated,
+                    capacity: *capacity,
+                })
+                .collect(),
+        });
+        allocation
+    }
+
+    #[test]
+    fn test_needs_cidr() {
+        assert!(needs_cidr(None, Family::V4, 8));
+
+        let dual = allocation(
+            &["10.0.0.0/24", "fd00::/120"],
+            &[("10.0.0.0/24", 250, 254), ("fd00::/120", 10, 255)],
+        );
+        assert!(needs_cidr(Some(&dual), Family::V4, 8));
+        assert!(!needs_cidr(Some(&dual), Family::V6, 8));
+
+        let v4 = allocation(&["10.0.0.0/24"], &[("10.0.0.0/24", 10, 254)]);
+        assert!(needs_cidr(Some(&v4), Family::V6, 8));
+
+        // the usage of the second CIDR was not reported yet
+        let grown = allocation(
+            &["10.0.0.0/24", "10.0.1.0/24"],
+            &[("10.0.0.0/24", 254, 254)],
+        );
+        assert!(!needs_cidr(Some(&grown), Family::V4, 8));
+    }
+}
@@ -0,0 +1,19 @@
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub enum Error {
+    #[error("kube error: {0}")]
+    KubeError(#[from] kube::Error),
+
+    #[error("timeout")]
+    Timeout,
+
+    #[error("utils error: {0}")]
+    UtilsError(#[from] mesh_cni_k8s_utils::Error),
+
+    #[error("invalid cluster pool: {0}")]
+    InvalidPool(String),
+
+    #[error("invalid resource reconciled")]
+    InvalidResource,
+}
@@ -0,0 +1,11 @@
+mod context;
+mod controller;
+mod error;
+mod pool;
+mod runtime;
+
+pub use error::Error;
+pub use pool::ClusterPool;
+pub use runtime::start_ipam_controller;
+
+pub type Result<T> = std::result::Result<T, Error>;
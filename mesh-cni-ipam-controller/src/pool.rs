@@ -0,0 +1,174 @@
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use ipnetwork::IpNetwork;
+
+use crate::{Error, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Family {
+    V4,
+    V6,
+}
+
+impl Family {
+    pub(crate) fn of(cidr: &IpNetwork) -> Self {
+        match cidr {
+            IpNetwork::V4(_) => Family::V4,
+            IpNetwork::V6(_) => Family::V6,
+        }
+    }
+
+    fn bits(self) -> u8 {
+        match self {
+            Family::V4 => 32,
+            Family::V6 => 128,
+        }
+    }
+}
+
+/// Cluster wide CIDRs that per-node pod CIDRs of a fixed size are carved out of
+#[derive(Debug, Clone)]
+pub struct ClusterPool {
+    cidrs: Vec<IpNetwork>,
+    mask_v4: u8,
+    mask_v6: u8,
+}
+
+impl ClusterPool {
+    pub fn new(cidrs: Vec<IpNetwork>, mask_v4: u8, mask_v6: u8) -> Result<Self> {
+        for cidr in &cidrs {
+            let family = Family::of(cidr);
+            let mask = match family {
+                Family::V4 => mask_v4,
+                Family::V6 => mask_v6,
+            };
+            if mask < cidr.prefix() || mask > family.bits() || mask == 0 {
+                return Err(Error::InvalidPool(format!(
+                    "node mask size /{mask} does not fit in {cidr}"
+                )));
+            }
+        }
+        Ok(Self {
+            cidrs,
+            mask_v4,
+            mask_v6,
+        })
+    }
+
+    /// Address families the pool hands out CIDRs of, in the order they were configured
+    pub(crate) fn families(&self) -> Vec<Family> {
+        let mut families = Vec::new();
+        for family in self.cidrs.iter().map(Family::of) {
+            if !families.contains(&family) {
+                families.push(family);
+            }
+        }
+        families
+    }
+
+    /// Lowest CIDR of `family` which overlaps none of `used`
+    pub(crate) fn allocate(&self, family: Family, used: &[IpNetwork]) -> Option<IpNetwork> {
+        let mask = match family {
+            Family::V4 => self.mask_v4,
+            Family::V6 => self.mask_v6,
+        };
+        for cidr in self.cidrs.iter().filter(|cidr| Family::of(cidr) == family) {
+            let (mut start, last) = span(cidr);
+            while start <= last {
+                let block = network(family, start, mask);
+                let (_, end) = span(&block);
+                let overlapping = used
+                    .iter()
+                    .filter(|used| overlaps(used, &block))
+                    .map(|used| span(used).1)
+                    .max();
+                let Some(used_end) = overlapping else {
+                    return Some(block);
+                };
+                // blocks are aligned to their size, so the one after the used range is too
+                let Some(next) = used_end.max(end).checked_add(1) else {
+                    break;
+                };
+                let Some(size) = (end - start).checked_add(1) else {
+                    break;
+                };
+                match next.div_ceil(size).checked_mul(size) {
+                    Some(next) => start = next,
+                    None => break,
+                }
+            }
+        }
+        None
+    }
+}
+
+fn overlaps(a: &IpNetwork, b: &IpNetwork) -> bool {
+    a.contains(b.network()) || b.contains(a.network())
+}
+
+/// First and last address of a CIDR as integers
+fn span(cidr: &IpNetwork) -> (u128, u128) {
+    let host_bits = (Family::of(cidr).bits() - cidr.prefix()) as u32;
+    let first = match cidr.network() {
+        IpAddr::V4(ip) => ip.to_bits() as u128,
+        IpAddr::V6(ip) => ip.to_bits(),
+    };
+    let size = 1u128.checked_shl(host_bits).unwrap_or(0);
+    (first, first.wrapping_add(size.wrapping_sub(1)))
+}
+
+fn network(family: Family, start: u128, mask: u8) -> IpNetwork {
+    let ip = match family {
+        Family::V4 => IpAddr::V4(Ipv4Addr::from_bits(start as u32)),
+        Family::V6 => IpAddr::V6(Ipv6Addr::from_bits(start)),
+    };
+    // the mask was validated against the family when the pool was created
+    IpNetwork::new(ip, mask).expect("valid node mask")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cidr(cidr: &str) -> IpNetwork {
+        cidr.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allocate_skips_used_cidrs() {
+        let pool = ClusterPool::new(
+            vec![cidr("10.0.0.0/22"), cidr("10.1.0.0/24"), cidr("fd00::/112")],
+            24,
+            120,
+        )
+        .unwrap();
+        assert_eq!(pool.families(), vec![Family::V4, Family::V6]);
+
+        assert_eq!(pool.allocate(Family::V4, &[]), Some(cidr("10.0.0.0/24")));
+        assert_eq!(
+            pool.allocate(Family::V4, &[cidr("10.0.0.0/24"), cidr("10.0.2.0/24")]),
+            Some(cidr("10.0.1.0/24"))
+        );
+        // used CIDRs of another size, e.g. from before the mask size changed
+        assert_eq!(
+            pool.allocate(Family::V4, &[cidr("10.0.0.0/23"), cidr("10.0.2.128/25")]),
+            Some(cidr("10.0.3.0/24"))
+        );
+        assert_eq!(
+            pool.allocate(Family::V4, &[cidr("10.0.0.0/22")]),
+            Some(cidr("10.1.0.0/24"))
+        );
+        assert_eq!(pool.allocate(Family::V4, &[cidr("10.0.0.0/8")]), None);
+        assert_eq!(
+            pool.allocate(Family::V6, &[cidr("fd00::/120")]),
+            Some(cidr("fd00::100/120"))
+        );
+    }
+
+    #[test]
+    fn test_mask_must_fit_the_pool() {
+        assert!(ClusterPool::new(vec![cidr("10.0.0.0/16")], 8, 120).is_err());
+        assert!(ClusterPool::new(vec![cidr("10.0.0.0/16")], 33, 120).is_err());
+        assert!(ClusterPool::new(vec![cidr("10.0.0.0/16")], 16, 120).is_ok());
+    }
+}
@@ -0,0 +1,62 @@
+use std::{sync::Arc, time::Duration};
+
+use futures::StreamExt;
+use kube::{
+    Api, Client,
+    runtime::{Config, Controller},
+};
+use mesh_cni_k8s_utils::create_store_and_subscriber;
+use tokio::time::timeout;
+use tokio_util::sync::CancellationToken;
+
+use crate::{
+    Error, Result,
+    context::Context,
+    controller::{error_policy, reconcile_node, remove_orphans},
+    pool::ClusterPool,
+};
+
+/// Allocates pod CIDRs out of `pool` to every node. Allocations are only ever read back from
+/// the cluster, so they survive restarts of the controller.
+pub async fn start_ipam_controller(
+    client: Client,
+    pool: ClusterPool,
+    min_free_addresses: u64,
+    cancel: CancellationToken,
+) -> Result<()> {
+    let store_init = timeout(Duration::from_secs(30), async {
+        tokio::try_join!(
+            create_store_and_subscriber(Api::all(client.clone()), Some(Duration::from_secs(30))),
+            create_store_and_subscriber(Api::all(client.clone()), Some(Duration::from_secs(30))),
+        )
+    })
+    .await
+    .map_err(|_| Error::Timeout)??;
+
+    let ((nodes, node_subscriber), (allocations, allocation_subscriber)) = store_init;
+    let context = Arc::new(Context {
+        client,
+        allocations,
+        pool,
+        min_free_addresses,
+    });
+    remove_orphans(&context, &nodes.state()).await?;
+
+    let config = Config::default();
+    let config = config.debounce(Duration::from_secs(2));
+    // allocations are made one at a time so no CIDR is handed out twice
+    let config = config.concurrency(1);
+    Controller::for_shared_stream(node_subscriber, nodes)
+        .owns_shared_stream(allocation_subscriber)
+        .graceful_shutdown_on(shutdown(cancel))
+        .with_config(config)
+        .run(reconcile_node, error_policy, context)
+        .filter_map(|x| async move { std::result::Result::ok(x) })
+        .for_each(|_| futures::future::ready(()))
+        .await;
+    Ok(())
+}
+
+async fn shutdown(cancel: CancellationToken) {
+    cancel.cancelled().await;
+}
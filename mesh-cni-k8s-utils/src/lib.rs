@@ -1,17 +1,21 @@
-use std::{fmt::Debug, hash::Hash, time::Duration};
+use std::{collections::HashSet, fmt::Debug, hash::Hash, time::Duration};
 
 use futures::StreamExt;
 use k8s_openapi::serde::de::DeserializeOwned;
 use kube::{
     Api, Resource,
+    api::DynamicObject,
     runtime::{
-        WatchStreamExt, reflector,
-        reflector::{ReflectHandle, Store},
+        WatchStreamExt, controller,
+        controller::Action,
+        reflector,
+        reflector::{ObjectRef, ReflectHandle, Store},
         watcher,
     },
 };
 use thiserror::Error;
-use tracing::{error, trace};
+use tokio_util::sync::CancellationToken;
+use tracing::{error, info, trace};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -67,6 +71,83 @@ where
     Ok((store, subscriber))
 }
 
+/// Tracks the objects of a controller's initial list until each went through a reconcile,
+/// from then on the state the controller writes reflects the cluster. A failed reconcile
+/// counts as well as the controller keeps retrying it either way.
+pub struct InitialReconcile {
+    pending: HashSet<ObjectRef<DynamicObject>>,
+    reconciled: CancellationToken,
+}
+
+impl InitialReconcile {
+    /// Cancels `reconciled` once every object in `objects` was reconciled, right away when
+    /// there is none
+    pub fn new(
+        objects: impl IntoIterator<Item = ObjectRef<DynamicObject>>,
+        reconciled: CancellationToken,
+    ) -> Self {
+        let pending: HashSet<_> = objects.into_iter().collect();
+        if pending.is_empty() {
+            reconciled.cancel();
+        }
+        Self {
+            pending,
+            reconciled,
+        }
+    }
+
+    /// Tracks every object of a store which completed its initial list
+    pub fn from_store<K>(store: &Store<K>, reconciled: CancellationToken) -> Self
+    where
+        K: Resource + Clone + 'static,
+        K::DynamicType: Default + Eq + Hash + Clone,
+    {
+        let objects = store
+            .state()
+            .into_iter()
+            .map(|object| ObjectRef::from_obj(object.as_ref()).erase());
+        Self::new(objects, reconciled)
+    }
+
+    /// Records the outcome of a reconcile as returned by `Controller::run`
+    pub fn observe<K, E, Q>(
+        &mut self,
+        result: &std::result::Result<(ObjectRef<K>, Action), controller::Error<E, Q>>,
+    ) where
+        K: Resource,
+        K::DynamicType: Clone,
+    {
+        if self.pending.is_empty() {
+            return;
+        }
+        let object = match result {
+            Ok((object, _)) => object.clone().erase(),
+            Err(controller::Error::ReconcilerFailed(_, object))
+            | Err(controller::Error::ObjectNotFound(object)) => object.clone(),
+            Err(_) => return,
+        };
+        if self.pending.remove(&object) && self.pending.is_empty() {
+            info!(kind = object.dyntype.kind, "initial reconcile complete");
+            self.reconciled.cancel();
+        }
+    }
+}
+
+/// Cancels `reconciled` once every one of `parts` is, for components running several
+/// controllers
+pub fn join_reconciled(
+    parts: impl IntoIterator<Item = CancellationToken>,
+    reconciled: CancellationToken,
+) {
+    let parts: Vec<_> = parts.into_iter().collect();
+    tokio::spawn(async move {
+        for part in parts {
+            part.cancelled().await;
+        }
+        reconciled.cancel();
+    });
+}
+
 pub fn sanitize_pod_labels(labels: &mut std::collections::BTreeMap<String, String>) {
     let removal_list = [
         "controller-revision-hash",
@@ -78,3 +159,52 @@ pub fn sanitize_pod_labels(labels: &mut std::collections::BTreeMap<String, Strin
         labels.remove(*i);
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use k8s_openapi::api::core::v1::Pod;
+
+    use super::*;
+
+    type Reconciled = std::result::Result<
+        (ObjectRef<Pod>, Action),
+        controller::Error<std::io::Error, watcher::Error>,
+    >;
+
+    fn pod(name: &str) -> ObjectRef<Pod> {
+        ObjectRef::new(name).within("default")
+    }
+
+    #[test]
+    fn test_initial_reconcile_waits_for_every_object() {
+        let reconciled = CancellationToken::new();
+        let objects = ["a", "b", "c"].map(|name| pod(name).erase());
+        let mut initial = InitialReconcile::new(objects, reconciled.clone());
+
+        let ok: Reconciled = Ok((pod("a"), Action::await_change()));
+        initial.observe(&ok);
+        // a second reconcile of the same object or one created since does not count
+        initial.observe(&ok);
+        let created: Reconciled = Ok((pod("d"), Action::await_change()));
+        initial.observe(&created);
+        assert!(!reconciled.is_cancelled());
+
+        let failed: Reconciled = Err(controller::Error::ReconcilerFailed(
+            std::io::Error::other("failed"),
+            pod("b").erase(),
+        ));
+        initial.observe(&failed);
+        assert!(!reconciled.is_cancelled());
+
+        let deleted: Reconciled = Err(controller::Error::ObjectNotFound(pod("c").erase()));
+        initial.observe(&deleted);
+        assert!(reconciled.is_cancelled());
+    }
+
+    #[test]
+    fn test_initial_reconcile_of_empty_list_is_complete() {
+        let reconciled = CancellationToken::new();
+        InitialReconcile::new([], reconciled.clone());
+        assert!(reconciled.is_cancelled());
+    }
+}
@@ -41,11 +41,17 @@ pub fn add(args: &Args, input: Input) -> Response {
             )
             .into_response(CNI_VERSION);
         };
+        let ips = input
+            .runtime_config
+            .map(|config| config.ips.iter().map(|ip| ip.to_string()).collect())
+            .unwrap_or_default();
         let req = AddPodRequest {
             iface: args.ifname.clone(),
             net_namespace: Some(net_namespace),
             container_id: args.container_id.clone(),
             chained: false,
+            ips,
+            ifname: args.ifname.clone(),
         };
         let resp = tokio::runtime::Runtime::new()
             .unwrap()
@@ -98,6 +104,8 @@ pub fn add(args: &Args, input: Input) -> Response {
             net_namespace: None,
             container_id: args.container_id.clone(),
             chained: true,
+            ips: Vec::new(),
+            ifname: args.ifname.clone(),
         };
         let resp = tokio::runtime::Runtime::new()
             .unwrap()
@@ -1,8 +1,118 @@
-use tracing::info;
+use mesh_cni_api::cni::v1::{CheckPodReply, CheckPodRequest, cni_client::CniClient};
+use serde::Deserialize;
+use tonic::Code;
+use tracing::{error, info};
 
-use crate::{config::Args, response::Response, types::Input};
+use crate::{
+    CNI_VERSION, Error,
+    config::Args,
+    response::{Response, Success},
+    types::Input,
+};
 
-pub fn check(_args: &Args, input: Input) -> Response {
+// https://www.cni.dev/docs/spec/#check-check-containers-networking-is-as-expected
+//Input:
+//
+//The runtime will provide a json-serialized plugin configuration object (defined below) on standard in.
+//
+//Required environment parameters:
+//
+//    CNI_COMMAND
+//    CNI_CONTAINERID
+//    CNI_NETNS
+//    CNI_IFNAME
+//
+//Optional environment parameters:
+//
+//    CNI_ARGS
+//    CNI_PATH
+//
+pub fn check(args: &Args, input: Input) -> Response {
     info!("check called, received input {:?}", input);
+    // CHECK was added in 0.4.0
+    if input.cni_version < CNI_VERSION {
+        return Error::IncompatibleVersion(input.cni_version).into_response(CNI_VERSION);
+    }
+    let chained = input.chained.unwrap_or(true);
+
+    let Some(prev) = input.previous_result else {
+        return Error::NoPreviousResult("prevResult is required on check".into())
+            .into_response(CNI_VERSION);
+    };
+    let prev = match Success::deserialize(prev) {
+        Ok(prev) => prev,
+        Err(e) => {
+            error!(%e, "failed to deserialize previous results");
+            return Error::from(e).into_response(CNI_VERSION);
+        }
+    };
+
+    let net_namespace = match &args.net_ns {
+        Some(net_ns) => match net_ns.clone().into_os_string().into_string() {
+            Ok(net_namespace) => Some(net_namespace),
+            Err(_) => {
+                return Error::InvalidRequiredEnvVariables(
+                    "failed to convert network namespace to string".into(),
+                )
+                .into_response(CNI_VERSION);
+            }
+        },
+        None => None,
+    };
+
+    // Host interfaces of the chain the programs are attached to. When unchained the agent
+    // knows the host end of the veth from what it cached on ADD.
+    let mut ifaces: Vec<String> = if chained {
+        prev.interfaces
+            .iter()
+            .filter(|interface| interface.sandbox.is_none())
+            .map(|interface| interface.name.clone())
+            .collect()
+    } else {
+        Vec::new()
+    };
+    if ifaces.is_empty() {
+        ifaces.push(String::new());
+    }
+
+    for iface in ifaces {
+        let req = CheckPodRequest {
+            iface,
+            net_namespace: net_namespace.clone(),
+            container_id: args.container_id.clone(),
+            chained,
+            ifname: args.ifname.clone(),
+            ips: prev.ips.iter().map(|ip| ip.address.clone()).collect(),
+        };
+        let resp = tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(request(req));
+        match resp {
+            Ok(r) => {
+                info!("received reply {:?}", &r);
+            }
+            Err(e) => {
+                error!(%e, "failed check against mesh socket");
+                return e.into_response(CNI_VERSION);
+            }
+        }
+    }
+
     Response::Check
 }
+
+async fn request(req: CheckPodRequest) -> Result<CheckPodReply, Error> {
+    let path = "unix:///var/run/mesh/mesh.sock";
+    let mut client = CniClient::connect(path)
+        .await
+        .map_err(|e| Error::Transient(e.to_string()))?;
+    match client.check_pod(req).await {
+        Ok(resp) => Ok(resp.into_inner()),
+        Err(status) => Err(match status.code() {
+            Code::FailedPrecondition => Error::Drift(status.message().to_string()),
+            Code::InvalidArgument => Error::InvalidNetworkConfig(status.message().to_string()),
+            Code::Unavailable => Error::Transient(status.message().to_string()),
+            _ => Error::Tonic(status),
+        }),
+    }
+}
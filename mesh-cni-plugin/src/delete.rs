@@ -28,15 +28,9 @@ use crate::{
 //
 pub fn delete(args: &Args, input: Input) -> Response {
     info!("delete called, received input {:?}", input);
-    let Some(prev) = input.previous_result else {
-        return Error::NoPreviousResult("no previous result found".into())
-            .into_response(CNI_VERSION);
-    };
-
-    // TODO: implemented unchained
+    let chained = input.chained.unwrap_or(input.previous_result.is_some());
 
-    // Chained
-    let prev = match Success::deserialize(prev) {
+    let prev = match input.previous_result.map(Success::deserialize).transpose() {
         Ok(prev) => prev,
         Err(e) => {
             error!(%e, "failed to deserialize previous results");
@@ -44,22 +38,28 @@ pub fn delete(args: &Args, input: Input) -> Response {
         }
     };
 
-    if prev.interfaces.is_empty() {
-        error!("previous response is missing interfaces");
-        return Error::MissingInterfaces.into_response(CNI_VERSION);
+    // Host interfaces of the chain the programs were attached to. Without a previous result
+    // the agent falls back to what it cached on ADD.
+    let mut ifaces: Vec<String> = match (&prev, chained) {
+        (Some(prev), true) => prev
+            .interfaces
+            .iter()
+            .filter(|interface| interface.sandbox.is_none())
+            .map(|interface| interface.name.clone())
+            .collect(),
+        _ => Vec::new(),
+    };
+    if ifaces.is_empty() {
+        ifaces.push(String::new());
     }
 
-    for interface in &prev.interfaces {
-        if interface.sandbox.is_some() {
-            continue;
-        };
-
-        let iface = interface.name.clone();
+    for iface in ifaces {
         let req = DeletePodRequest {
             iface,
             net_namespace: None,
             container_id: args.container_id.clone(),
-            chained: true,
+            chained,
+            ifname: args.ifname.clone(),
         };
         let resp = tokio::runtime::Runtime::new()
             .unwrap()
@@ -75,14 +75,10 @@ pub fn delete(args: &Args, input: Input) -> Response {
         }
     }
 
-    Response::Success(Success {
-        cni_version: prev.cni_version,
-        interfaces: prev.interfaces,
-        ips: prev.ips,
-        routes: prev.routes,
-        dns: prev.dns,
-        custom: prev.custom,
-    })
+    match prev {
+        Some(prev) => Response::Success(prev),
+        None => Response::Delete,
+    }
 }
 
 async fn request(req: DeletePodRequest) -> Result<DeletePodReply, Error> {
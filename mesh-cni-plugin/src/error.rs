@@ -42,6 +42,9 @@ pub enum Error {
     #[error("cni must be chained after interfaces are created")]
     MissingInterfaces,
 
+    #[error("pod drifted from the result of add: {0}")]
+    Drift(String),
+
     #[error("{0}")]
     Tonic(#[from] tonic::Status),
 
@@ -124,6 +127,12 @@ impl Error {
                 msg: "No Interfaces".into(),
                 details: self.to_string(),
             },
+            Error::Drift(_) => CniErrorResponse {
+                cni_version,
+                code: 106,
+                msg: "Network Drift".into(),
+                details: self.to_string(),
+            },
             Error::Tonic(_) => CniErrorResponse {
                 cni_version,
                 code: 104,
@@ -1,7 +1,9 @@
 use std::{io::Read, process::ExitCode};
 
 use clap::Parser;
-use mesh_cni_plugin::{CNI_VERSION, Result, add::add, config::Args, delete::delete, types::Input};
+use mesh_cni_plugin::{
+    CNI_VERSION, Result, add::add, check::check, config::Args, delete::delete, types::Input,
+};
 use tracing_appender::non_blocking::WorkerGuard;
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 
@@ -23,7 +25,13 @@ fn main() -> ExitCode {
                 Err(e) => e.into_response(CNI_VERSION),
             }
         }
-        mesh_cni_plugin::config::Command::Check => todo!(),
+        mesh_cni_plugin::config::Command::Check => {
+            let input = read_input();
+            match input {
+                Ok(input) => check(&args, input),
+                Err(e) => e.into_response(CNI_VERSION),
+            }
+        }
         mesh_cni_plugin::config::Command::Status => todo!(),
         mesh_cni_plugin::config::Command::Version => todo!(),
         mesh_cni_plugin::config::Command::Gc => todo!(),
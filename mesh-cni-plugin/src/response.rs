@@ -11,6 +11,7 @@ pub enum Response {
     Success(Success),
     Error(CniErrorResponse),
     Version(VersionResponse),
+    Delete,
     Gc,
     Check,
     Status,
@@ -31,6 +32,7 @@ impl Response {
                 Ok(out) => (out, ExitCode::SUCCESS),
                 Err(e) => (e.to_string().into_bytes(), ExitCode::FAILURE),
             },
+            Response::Delete => (vec![], ExitCode::SUCCESS),
             Response::Check => (vec![], ExitCode::SUCCESS),
             Response::Gc => (vec![], ExitCode::SUCCESS),
             Response::Status => (vec![], ExitCode::SUCCESS),
@@ -17,6 +17,11 @@ pub struct Input {
     #[serde(default)]
     pub name: String,
 
+    /// Set by the agent in the plugin configuration, when missing mesh-cni is assumed to be
+    /// chained whenever there is a previous result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chained: Option<bool>,
+
     #[serde(default, skip_serializing_if = "Option::is_none")]
     pub runtime_config: Option<RuntimeConfig>,
 
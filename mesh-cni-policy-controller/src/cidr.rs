@@ -0,0 +1,269 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    str::FromStr,
+};
+
+use ipnetwork::IpNetwork;
+use k8s_openapi::api::networking::v1::{IPBlock, NetworkPolicy};
+use mesh_cni_ebpf_common::{IdentityId, MIN_CIDR_ID, WORLD_ID};
+use tracing::warn;
+
+/// Node-local identities for the prefixes referenced by `ipBlock` peers.
+///
+/// Every `cidr` and `except` prefix gets its own identity so the LPM lookup always resolves
+/// an address to the most specific prefix any policy cares about. A block then selects the
+/// identities of all known prefixes inside its `cidr` that are not inside one of its
+/// `except` prefixes. The default routes map to the world identity.
+pub(crate) struct CidrIdentities {
+    ids: BTreeMap<IpNetwork, IdentityId>,
+    next: IdentityId,
+}
+
+impl Default for CidrIdentities {
+    fn default() -> Self {
+        Self {
+            ids: BTreeMap::new(),
+            next: MIN_CIDR_ID,
+        }
+    }
+}
+
+impl CidrIdentities {
+    /// Allocates identities for `prefixes` and releases the ones no longer referenced.
+    /// An allocation is only kept once `update` programmed it, and a release once `delete`
+    /// removed it, so failed operations are retried on the next sync.
+    pub fn sync<E>(
+        &mut self,
+        prefixes: &BTreeSet<IpNetwork>,
+        mut update: impl FnMut(IpNetwork, IdentityId) -> Result<(), E>,
+        mut delete: impl FnMut(IpNetwork) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let stale: Vec<IpNetwork> = self
+            .ids
+            .keys()
+            .filter(|network| !prefixes.contains(network))
+            .copied()
+            .collect();
+        for network in stale {
+            delete(network)?;
+            self.ids.remove(&network);
+        }
+
+        for network in prefixes {
+            if self.ids.contains_key(network) {
+                continue;
+            }
+            let id = if network.prefix() == 0 {
+                WORLD_ID
+            } else {
+                self.allocate()
+            };
+            update(*network, id)?;
+            self.ids.insert(*network, id);
+        }
+
+        Ok(())
+    }
+
+    pub fn ids(&self) -> impl Iterator<Item = IdentityId> + '_ {
+        self.ids.values().copied()
+    }
+
+    /// Identities of every known prefix selected by `block`
+    pub fn selected_ids(&self, block: &IPBlock) -> Vec<IdentityId> {
+        let Some(cidr) = parse_network(&block.cidr) else {
+            return vec![];
+        };
+        let excepts: Vec<IpNetwork> = block
+            .except
+            .iter()
+            .flatten()
+            .filter_map(|except| parse_network(except))
+            .collect();
+
+        self.ids
+            .iter()
+            .filter(|(network, _)| contains_network(&cidr, network))
+            .filter(|(network, _)| !excepts.iter().any(|e| contains_network(e, network)))
+            .map(|(_, id)| *id)
+            .collect()
+    }
+
+    // ids are handed out round robin so a released id is not reused while stale
+    // policy entries may still reference it
+    fn allocate(&mut self) -> IdentityId {
+        let used: BTreeSet<IdentityId> = self.ids.values().copied().collect();
+        loop {
+            let candidate = self.next;
+            self.next = match self.next.checked_add(1) {
+                Some(next) => next,
+                None => MIN_CIDR_ID,
+            };
+            if !used.contains(&candidate) {
+                return candidate;
+            }
+        }
+    }
+}
+
+/// Every prefix referenced by `ipBlock` peers of `policies`, plus the default routes
+pub(crate) fn policy_prefixes<'a>(
+    policies: impl Iterator<Item = &'a NetworkPolicy>,
+) -> BTreeSet<IpNetwork> {
+    let mut prefixes = BTreeSet::from([
+        IpNetwork::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0).expect("valid v4 default route"),
+        IpNetwork::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), 0).expect("valid v6 default route"),
+    ]);
+
+    for policy in policies {
+        let Some(spec) = &policy.spec else {
+            continue;
+        };
+        let ingress_peers = spec
+            .ingress
+            .iter()
+            .flatten()
+            .flat_map(|rule| rule.from.iter().flatten());
+        let egress_peers = spec
+            .egress
+            .iter()
+            .flatten()
+            .flat_map(|rule| rule.to.iter().flatten());
+
+        for block in ingress_peers
+            .chain(egress_peers)
+            .filter_map(|peer| peer.ip_block.as_ref())
+        {
+            let networks = std::iter::once(&block.cidr).chain(block.except.iter().flatten());
+            prefixes.extend(networks.filter_map(|network| parse_network(network)));
+        }
+    }
+
+    prefixes
+}
+
+/// Parses a CIDR and masks off any host bits
+fn parse_network(cidr: &str) -> Option<IpNetwork> {
+    let network = match IpNetwork::from_str(cidr) {
+        Ok(network) => network,
+        Err(e) => {
+            warn!(%e, "ignoring invalid ipBlock cidr {}", cidr);
+            return None;
+        }
+    };
+    IpNetwork::new(network.network(), network.prefix()).ok()
+}
+
+fn contains_network(outer: &IpNetwork, inner: &IpNetwork) -> bool {
+    outer.prefix() <= inner.prefix() && outer.contains(inner.network())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeSet, str::FromStr};
+
+    use ipnetwork::IpNetwork;
+    use k8s_openapi::api::networking::v1::IPBlock;
+    use mesh_cni_ebpf_common::{MIN_CIDR_ID, WORLD_ID};
+
+    use super::{CidrIdentities, parse_network};
+
+    fn networks(cidrs: &[&str]) -> BTreeSet<IpNetwork> {
+        cidrs
+            .iter()
+            .map(|cidr| IpNetwork::from_str(cidr).unwrap())
+            .collect()
+    }
+
+    fn id_of(cidrs: &CidrIdentities, cidr: &str) -> u32 {
+        cidrs.ids[&IpNetwork::from_str(cidr).unwrap()]
+    }
+
+    fn sync(
+        cidrs: &mut CidrIdentities,
+        prefixes: &[&str],
+    ) -> (Vec<(IpNetwork, u32)>, Vec<IpNetwork>) {
+        let mut added = Vec::new();
+        let mut removed = Vec::new();
+        cidrs
+            .sync::<()>(
+                &networks(prefixes),
+                |network, id| {
+                    added.push((network, id));
+                    Ok(())
+                },
+                |network| {
+                    removed.push(network);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        (added, removed)
+    }
+
+    #[test]
+    fn sync_allocates_and_releases() {
+        let mut cidrs = CidrIdentities::default();
+        let (added, removed) = sync(&mut cidrs, &["0.0.0.0/0", "10.0.0.0/8"]);
+        assert_eq!(added.len(), 2);
+        assert!(removed.is_empty());
+        assert_eq!(id_of(&cidrs, "0.0.0.0/0"), WORLD_ID);
+        assert_eq!(id_of(&cidrs, "10.0.0.0/8"), MIN_CIDR_ID);
+
+        let (added, removed) = sync(&mut cidrs, &["0.0.0.0/0", "192.168.0.0/16"]);
+        assert_eq!(added.len(), 1);
+        assert_eq!(removed, vec![IpNetwork::from_str("10.0.0.0/8").unwrap()]);
+        // released ids are not immediately reused
+        assert_eq!(id_of(&cidrs, "192.168.0.0/16"), MIN_CIDR_ID + 1);
+    }
+
+    #[test]
+    fn sync_retries_failed_updates() {
+        let mut cidrs = CidrIdentities::default();
+        let result = cidrs.sync(&networks(&["10.0.0.0/8"]), |_, _| Err(()), |_| Ok(()));
+        assert!(result.is_err());
+        assert!(cidrs.ids.is_empty());
+
+        let (added, _) = sync(&mut cidrs, &["10.0.0.0/8"]);
+        assert_eq!(added.len(), 1);
+    }
+
+    #[test]
+    fn selected_ids_carve_out_excepts() {
+        let mut cidrs = CidrIdentities::default();
+        sync(
+            &mut cidrs,
+            &[
+                "0.0.0.0/0",
+                "10.0.0.0/8",
+                "10.1.0.0/16",
+                "10.1.2.0/24",
+                "10.2.0.0/16",
+            ],
+        );
+
+        let block = IPBlock {
+            cidr: "10.0.0.0/8".into(),
+            except: Some(vec!["10.1.0.0/16".into()]),
+        };
+        let selected: BTreeSet<u32> = cidrs.selected_ids(&block).into_iter().collect();
+        let expected = BTreeSet::from([id_of(&cidrs, "10.0.0.0/8"), id_of(&cidrs, "10.2.0.0/16")]);
+        assert_eq!(selected, expected);
+
+        let block = IPBlock {
+            cidr: "0.0.0.0/0".into(),
+            except: Some(vec!["10.0.0.0/8".into()]),
+        };
+        assert_eq!(cidrs.selected_ids(&block), vec![WORLD_ID]);
+    }
+
+    #[test]
+    fn parse_network_masks_host_bits() {
+        assert_eq!(
+            parse_network("10.1.2.3/8"),
+            Some(IpNetwork::from_str("10.0.0.0/8").unwrap())
+        );
+        assert_eq!(parse_network("not-a-cidr"), None);
+    }
+}
@@ -1,17 +1,27 @@
+use std::sync::Mutex;
+
 use k8s_openapi::api::{
     core::v1::{Namespace, Pod},
     networking::v1::NetworkPolicy,
 };
-use kube::runtime::reflector::Store;
+use kube::runtime::{events::Recorder, reflector::Store};
 use mesh_cni_crds::v1alpha1::identity::Identity;
 
-use crate::PolicyControllerBpf;
+use crate::{
+    CidrIdentityBpf, PolicyControllerBpf, cidr::CidrIdentities, programmed::ProgrammedEntries,
+    rules::ReportedRanges,
+};
 
 #[allow(unused)]
-pub struct Context<P: PolicyControllerBpf> {
+pub struct Context<P: PolicyControllerBpf, C: CidrIdentityBpf> {
     pub pod_store: Store<Pod>,
     pub policy_store: Store<NetworkPolicy>,
     pub namespace_store: Store<Namespace>,
     pub identity_store: Store<Identity>,
     pub policy_bpf_state: P,
+    pub cidr_bpf_state: C,
+    pub cidr_identities: Mutex<CidrIdentities>,
+    pub programmed: Mutex<ProgrammedEntries>,
+    pub recorder: Recorder,
+    pub reported_ranges: ReportedRanges,
 }
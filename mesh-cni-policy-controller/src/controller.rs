@@ -4,18 +4,21 @@ use kube::{ResourceExt, runtime::controller::Action};
 use serde::de::DeserializeOwned;
 use tracing::{error, info};
 
-use crate::{Error, PolicyControllerBpf, PolicyControllerExt, Result, context::Context};
+use crate::{
+    CidrIdentityBpf, Error, PolicyControllerBpf, PolicyControllerExt, Result, context::Context,
+};
 
 pub(crate) const DEFAULT_REQUEUE_DURATION: Duration = Duration::from_secs(300);
 const ERROR_REQUEUE_DURATION: Duration = Duration::from_secs(5);
 
 #[tracing::instrument(skip(ctx, k))]
-pub(crate) async fn reconcile<K, P>(k: Arc<K>, ctx: Arc<Context<P>>) -> Result<Action>
+pub(crate) async fn reconcile<K, P, C>(k: Arc<K>, ctx: Arc<Context<P, C>>) -> Result<Action>
 where
-    K: PolicyControllerExt<P>,
+    K: PolicyControllerExt<P, C>,
     K: ResourceExt<DynamicType = ()>,
     K: DeserializeOwned + Clone + Sync + Debug + Send + 'static,
     P: PolicyControllerBpf,
+    C: CidrIdentityBpf,
 {
     info!(
         "Started reconciling {} {}/{}",
@@ -27,11 +30,12 @@ where
 }
 
 // TODO: revisit error handling and backoff strategy once controller logic is defined.
-pub(crate) fn error_policy<K, P>(k: Arc<K>, error: &Error, _ctx: Arc<Context<P>>) -> Action
+pub(crate) fn error_policy<K, P, C>(k: Arc<K>, error: &Error, _ctx: Arc<Context<P, C>>) -> Action
 where
     K: ResourceExt<DynamicType = ()>,
     K: DeserializeOwned + Clone + Send + Sync + std::fmt::Debug + 'static,
     P: PolicyControllerBpf,
+    C: CidrIdentityBpf,
 {
     let name = k.name_any();
     let ns = k.namespace().unwrap_or_default();
@@ -1,22 +1,128 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{Arc, MutexGuard},
+};
 
 use k8s_openapi::api::networking::v1::NetworkPolicy;
-use kube::runtime::controller::Action;
+use kube::{
+    Resource, ResourceExt,
+    runtime::{
+        controller::Action,
+        events::{Event, EventType},
+    },
+};
 use mesh_cni_crds::v1alpha1::identity::Identity;
+use mesh_cni_ebpf_common::policy::POLICY_MAP_ENTRIES;
+use tracing::{debug, warn};
 
 use crate::{
-    PolicyControllerBpf, PolicyControllerExt, Result, context::Context,
-    controller::DEFAULT_REQUEUE_DURATION, selector::policy_selects_identity,
+    CidrIdentityBpf, PolicyControllerBpf, PolicyControllerExt, Result,
+    cidr::{CidrIdentities, policy_prefixes},
+    context::Context,
+    controller::DEFAULT_REQUEUE_DURATION,
+    ports::NamedPorts,
+    rules::{PolicyPeers, SkippedRange, egress_policy_entries, ingress_policy_entries},
+    selector::policy_selects_identity,
 };
 
-impl<P: PolicyControllerBpf> PolicyControllerExt<P> for Identity {
-    async fn reconcile(&self, ctx: Arc<Context<P>>) -> Result<Action> {
-        let policy_state = ctx.policy_store.state();
-        let selected_netpols: Vec<&Arc<NetworkPolicy>> = policy_state
-            .iter()
+impl<P: PolicyControllerBpf, C: CidrIdentityBpf> PolicyControllerExt<P, C> for Identity {
+    async fn reconcile(&self, ctx: Arc<Context<P, C>>) -> Result<Action> {
+        let policies = ctx.policy_store.state();
+        let cidrs = sync_cidr_identities(&ctx, &policies)?;
+
+        let selected_netpols: Vec<Arc<NetworkPolicy>> = policies
+            .into_iter()
             .filter(|np| policy_selects_identity(np, self))
             .collect();
+        let identities = ctx.identity_store.state();
+        let named_ports = NamedPorts::new(
+            &identities,
+            &ctx.pod_store.state(),
+            &ctx.namespace_store.state(),
+        );
+        let peers = PolicyPeers {
+            identities: &identities,
+            cidrs: &cidrs,
+            named_ports: &named_ports,
+        };
+
+        let mut desired = ingress_policy_entries(self, &selected_netpols, &peers);
+        desired.extend(egress_policy_entries(self, &selected_netpols, &peers));
+        drop(cidrs);
+        let live_ids: HashSet<u32> = identities.iter().map(|i| i.spec.id).collect();
+
+        let (skipped, len) = {
+            let mut programmed = ctx.programmed.lock().unwrap();
+            // entries for identities that no longer exist are swept here as well since
+            // deleted identities are never reconciled
+            programmed.retain(
+                |id| live_ids.contains(&id),
+                |key| ctx.policy_bpf_state.delete(key),
+            )?;
+
+            // port ranges only get the room the entries of other identities leave in the
+            // map, budgeted under the same lock the writes are made under
+            let room = (POLICY_MAP_ENTRIES as usize)
+                .saturating_sub(programmed.len_without(self.spec.id) + desired.entries.len());
+            let skipped = desired.admit_ranges(room);
+            let len = desired.entries.len();
+            programmed.sync(
+                self.spec.id,
+                desired.entries,
+                |key, value| ctx.policy_bpf_state.update(key, value),
+                |key| ctx.policy_bpf_state.delete(key),
+            )?;
+            (skipped, len)
+        };
+        report_skipped_ranges(&ctx, skipped).await;
+
+        debug!(
+            "programmed {} policy entries for Identity {}/{}",
+            len,
+            self.namespace().unwrap_or_default(),
+            self.name_any()
+        );
 
         Ok(Action::requeue(DEFAULT_REQUEUE_DURATION))
     }
 }
+
+/// Warns about each skipped port range and records it as an event on its NetworkPolicy,
+/// once rather than on every reconcile
+async fn report_skipped_ranges<P: PolicyControllerBpf, C: CidrIdentityBpf>(
+    ctx: &Context<P, C>,
+    skipped: Vec<SkippedRange>,
+) {
+    for skipped in ctx.reported_ranges.unreported(skipped) {
+        warn!("{}", skipped);
+        let Some(policy) = ctx.policy_store.get(&skipped.policy) else {
+            continue;
+        };
+        let event = Event {
+            type_: EventType::Warning,
+            reason: "PortRangeSkipped".into(),
+            note: Some(skipped.to_string()),
+            action: "ProgramPolicy".into(),
+            secondary: None,
+        };
+        if let Err(e) = ctx.recorder.publish(&event, &policy.object_ref(&())).await {
+            warn!("failed to record event for skipped port range: {}", e);
+        }
+    }
+}
+
+/// Programs the CIDR identities referenced by any policy. The lock is held while the
+/// caller builds its entries so the identities cannot be released underneath it.
+fn sync_cidr_identities<'a, P: PolicyControllerBpf, C: CidrIdentityBpf>(
+    ctx: &'a Context<P, C>,
+    policies: &[Arc<NetworkPolicy>],
+) -> Result<MutexGuard<'a, CidrIdentities>> {
+    let mut cidrs = ctx.cidr_identities.lock().unwrap();
+    let prefixes = policy_prefixes(policies.iter().map(|p| p.as_ref()));
+    cidrs.sync(
+        &prefixes,
+        |network, id| ctx.cidr_bpf_state.update(network, id),
+        |network| ctx.cidr_bpf_state.delete(network),
+    )?;
+    Ok(cidrs)
+}
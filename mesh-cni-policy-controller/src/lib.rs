@@ -1,26 +1,43 @@
+mod cidr;
 mod context;
 mod controller;
 mod error;
 mod identity;
+mod ports;
+mod programmed;
+mod rules;
 mod runtime;
 pub mod selector;
 
 use std::sync::Arc;
 
 pub use error::Error;
+use ipnetwork::IpNetwork;
 use kube::runtime::controller::Action;
-use mesh_cni_ebpf_common::policy::{PolicyKey, PolicyValue};
+use mesh_cni_ebpf_common::{
+    IdentityId,
+    policy::{PolicyKey, PolicyValue},
+};
 pub use runtime::start_policy_controllers;
 
 use crate::context::Context;
 
 pub type Result<T> = std::result::Result<T, Error>;
 
-pub(crate) trait PolicyControllerExt<P: PolicyControllerBpf> {
-    async fn reconcile(&self, ctx: Arc<Context<P>>) -> Result<Action>;
+pub(crate) trait PolicyControllerExt<P: PolicyControllerBpf, C: CidrIdentityBpf> {
+    async fn reconcile(&self, ctx: Arc<Context<P, C>>) -> Result<Action>;
 }
 
 pub trait PolicyControllerBpf {
     fn update(&self, key: PolicyKey, value: PolicyValue) -> Result<()>;
     fn delete(&self, key: &PolicyKey) -> Result<()>;
+    fn state(&self) -> Result<ahash::HashMap<PolicyKey, PolicyValue>>;
+}
+
+/// Programs the prefixes of CIDR-derived identities into the identity LPM tries
+pub trait CidrIdentityBpf {
+    fn update(&self, network: IpNetwork, id: IdentityId) -> Result<()>;
+    fn delete(&self, network: IpNetwork) -> Result<()>;
+    /// Deletes every prefix `keep` returns false for
+    fn retain(&self, keep: impl Fn(&IpNetwork, IdentityId) -> bool) -> Result<()>;
 }
@@ -0,0 +1,408 @@
+use std::{
+    collections::BTreeMap,
+    hash::{DefaultHasher, Hash, Hasher},
+    sync::Arc,
+};
+
+use k8s_openapi::{
+    api::{
+        core::v1::{Namespace, Pod},
+        networking::v1::NetworkPolicyPort,
+    },
+    apimachinery::pkg::util::intstr::IntOrString,
+};
+use kube::{
+    ResourceExt,
+    runtime::reflector::{ObjectRef, Store},
+};
+use mesh_cni_crds::v1alpha1::identity::Identity;
+use mesh_cni_ebpf_common::{IdentityId, KubeProtocol};
+use mesh_cni_k8s_utils::sanitize_pod_labels;
+use tracing::debug;
+
+/// Largest `port`..`endPort` range expanded into individual policy entries. Larger ranges
+/// are skipped rather than widened so a policy never allows more than it asks for.
+pub(crate) const MAX_PORT_RANGE: u32 = 1024;
+
+/// Ports `start`..=`end` of `proto` a policy port covers
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct PortRange {
+    pub start: u16,
+    pub end: u16,
+    pub proto: u8,
+}
+
+impl PortRange {
+    pub fn len(&self) -> u32 {
+        u32::from(self.end - self.start) + 1
+    }
+
+    pub fn ports(&self) -> impl Iterator<Item = u16> {
+        self.start..=self.end
+    }
+}
+
+type Labels = BTreeMap<String, String>;
+
+/// Named container ports of the pods running as each identity
+#[derive(Default)]
+pub(crate) struct NamedPorts {
+    ports: ahash::HashMap<IdentityId, Vec<(String, u8, u16)>>,
+}
+
+impl NamedPorts {
+    pub fn new(
+        identities: &[Arc<Identity>],
+        pods: &[Arc<Pod>],
+        namespaces: &[Arc<Namespace>],
+    ) -> Self {
+        let ids: ahash::HashMap<(String, &Labels, &Labels), IdentityId> = identities
+            .iter()
+            .filter_map(|identity| {
+                let key = (
+                    identity.namespace()?,
+                    &identity.spec.pod_labels,
+                    &identity.spec.namespace_labels,
+                );
+                Some((key, identity.spec.id))
+            })
+            .collect();
+        let namespace_labels: ahash::HashMap<String, &Labels> = namespaces
+            .iter()
+            .map(|namespace| (namespace.name_any(), namespace.labels()))
+            .collect();
+
+        let mut ports: ahash::HashMap<IdentityId, Vec<(String, u8, u16)>> =
+            ahash::HashMap::default();
+        for pod in pods {
+            let Some(spec) = &pod.spec else {
+                continue;
+            };
+            if spec.host_network.unwrap_or(false) {
+                continue;
+            }
+            let Some(namespace) = pod.namespace() else {
+                continue;
+            };
+            let Some(ns_labels) = namespace_labels.get(&namespace) else {
+                continue;
+            };
+            let mut pod_labels = pod.labels().clone();
+            sanitize_pod_labels(&mut pod_labels);
+            let Some(id) = ids.get(&(namespace, &pod_labels, *ns_labels)) else {
+                continue;
+            };
+
+            let container_ports = spec
+                .containers
+                .iter()
+                .chain(spec.init_containers.iter().flatten())
+                .flat_map(|container| container.ports.iter().flatten());
+            for port in container_ports {
+                let Some(name) = &port.name else {
+                    continue;
+                };
+                let proto = port.protocol.as_deref().unwrap_or("TCP");
+                let (Ok(proto), Ok(number)) = (
+                    KubeProtocol::try_from(proto),
+                    u16::try_from(port.container_port),
+                ) else {
+                    continue;
+                };
+                let named = (name.clone(), proto as u8, number);
+                let entry = ports.entry(*id).or_default();
+                if !entry.contains(&named) {
+                    entry.push(named);
+                }
+            }
+        }
+
+        Self { ports }
+    }
+
+    /// Port numbers `name` refers to for `proto`. Pods sharing an identity are not required
+    /// to agree on the number behind a name, so every distinct number is returned.
+    pub fn resolve<'a>(
+        &'a self,
+        id: IdentityId,
+        name: &'a str,
+        proto: u8,
+    ) -> impl Iterator<Item = u16> + 'a {
+        self.ports
+            .get(&id)
+            .into_iter()
+            .flatten()
+            .filter(move |(n, p, _)| n == name && *p == proto)
+            .map(|(_, _, number)| *number)
+    }
+}
+
+/// Tracks the named ports of pods so only pod events changing them trigger a reconcile of
+/// every identity
+#[derive(Default)]
+pub(crate) struct NamedPortChanges {
+    seen: ahash::HashMap<ObjectRef<Pod>, u64>,
+}
+
+impl NamedPortChanges {
+    /// Whether `pod` changed the named ports it exposes, or the labels it exposes them
+    /// under, since it was last seen. Pods no longer in `pods` are forgotten as new ones
+    /// show up.
+    pub fn changed(&mut self, pod: &Pod, pods: &Store<Pod>) -> bool {
+        let key = ObjectRef::from_obj(pod);
+        let Some(hash) = named_ports_hash(pod) else {
+            return self.seen.remove(&key).is_some();
+        };
+        if !self.seen.contains_key(&key) {
+            self.seen.retain(|pod, _| pods.get(pod).is_some());
+        }
+        self.seen.insert(key, hash) != Some(hash)
+    }
+}
+
+/// Hash of what `NamedPorts` reads from a pod, `None` when it exposes no named ports
+fn named_ports_hash(pod: &Pod) -> Option<u64> {
+    let spec = pod.spec.as_ref()?;
+    let ports: Vec<_> = spec
+        .containers
+        .iter()
+        .chain(spec.init_containers.iter().flatten())
+        .flat_map(|container| container.ports.iter().flatten())
+        .filter(|port| port.name.is_some())
+        .map(|port| (&port.name, &port.protocol, port.container_port))
+        .collect();
+    if ports.is_empty() {
+        return None;
+    }
+    let mut pod_labels = pod.labels().clone();
+    sanitize_pod_labels(&mut pod_labels);
+    let mut hasher = DefaultHasher::new();
+    (pod_labels, spec.host_network, ports).hash(&mut hasher);
+    Some(hasher.finish())
+}
+
+/// Expands a policy port into the `(port, protocol)` pairs it allows towards `dst_id`,
+/// using 0 for an omitted port. Named ports resolve against the pods of `dst_id`, so a
+/// name nothing exposes yields no pairs. Ranges are split off with [`port_range`] before
+/// and only expanded once admitted into the policy map.
+pub(crate) fn policy_ports(
+    port: &NetworkPolicyPort,
+    dst_id: IdentityId,
+    named_ports: &NamedPorts,
+) -> Vec<(u16, u8)> {
+    let proto = port.protocol.as_deref().unwrap_or("TCP");
+    let Ok(proto) = KubeProtocol::try_from(proto) else {
+        debug!("skipping policy port with unsupported protocol {}", proto);
+        return vec![];
+    };
+    let proto = proto as u8;
+
+    match &port.port {
+        None => vec![(0, proto)],
+        Some(IntOrString::String(name)) => named_ports
+            .resolve(dst_id, name, proto)
+            .map(|number| (number, proto))
+            .collect(),
+        Some(IntOrString::Int(start)) => {
+            match (u16::try_from(*start), port.end_port.map(u16::try_from)) {
+                (Ok(start), None | Some(Ok(_))) => vec![(start, proto)],
+                _ => vec![],
+            }
+        }
+    }
+}
+
+/// Range of a policy port with an `endPort` past its `port`, `None` for single, named and
+/// invalid ports
+pub(crate) fn port_range(port: &NetworkPolicyPort) -> Option<PortRange> {
+    let Some(IntOrString::Int(start)) = &port.port else {
+        return None;
+    };
+    let start = u16::try_from(*start).ok()?;
+    let end = u16::try_from(port.end_port?).ok()?;
+    let proto = KubeProtocol::try_from(port.protocol.as_deref().unwrap_or("TCP")).ok()?;
+    (end > start).then_some(PortRange {
+        start,
+        end,
+        proto: proto as u8,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use k8s_openapi::{
+        api::{
+            core::v1::{Container, ContainerPort, Namespace, Pod, PodSpec},
+            networking::v1::NetworkPolicyPort,
+        },
+        apimachinery::pkg::util::intstr::IntOrString,
+    };
+    use kube::{api::ObjectMeta, runtime::reflector};
+    use mesh_cni_crds::v1alpha1::identity::{Identity, IdentitySpec};
+
+    use super::{NamedPortChanges, NamedPorts, PortRange, policy_ports, port_range};
+
+    fn labels(app: &str) -> BTreeMap<String, String> {
+        BTreeMap::from([("app".to_string(), app.to_string())])
+    }
+
+    fn make_identity(app: &str, id: u32) -> Arc<Identity> {
+        let spec = IdentitySpec {
+            namespace_labels: BTreeMap::new(),
+            pod_labels: labels(app),
+            id,
+        };
+        let mut identity = Identity::new(app, spec);
+        identity.metadata.namespace = Some("default".into());
+        Arc::new(identity)
+    }
+
+    fn make_pod(app: &str, ports: Vec<(&str, i32, Option<&str>)>) -> Arc<Pod> {
+        let mut pod_labels = labels(app);
+        pod_labels.insert("pod-template-hash".into(), "abc123".into());
+        Arc::new(Pod {
+            metadata: ObjectMeta {
+                name: Some(app.into()),
+                namespace: Some("default".into()),
+                labels: Some(pod_labels),
+                ..Default::default()
+            },
+            spec: Some(PodSpec {
+                containers: vec![Container {
+                    name: "main".into(),
+                    ports: Some(
+                        ports
+                            .into_iter()
+                            .map(|(name, port, protocol)| ContainerPort {
+                                name: Some(name.into()),
+                                container_port: port,
+                                protocol: protocol.map(Into::into),
+                                ..Default::default()
+                            })
+                            .collect(),
+                    ),
+                    ..Default::default()
+                }],
+                ..Default::default()
+            }),
+            status: None,
+        })
+    }
+
+    fn make_namespace() -> Arc<Namespace> {
+        Arc::new(Namespace {
+            metadata: ObjectMeta {
+                name: Some("default".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        })
+    }
+
+    fn port(port: IntOrString, end_port: Option<i32>, protocol: Option<&str>) -> NetworkPolicyPort {
+        NetworkPolicyPort {
+            port: Some(port),
+            end_port,
+            protocol: protocol.map(Into::into),
+        }
+    }
+
+    #[test]
+    fn named_ports_resolve_per_identity_and_protocol() {
+        let identities = vec![make_identity("server", 100), make_identity("client", 200)];
+        let pods = vec![
+            make_pod(
+                "server",
+                vec![("http", 8080, None), ("dns", 53, Some("UDP"))],
+            ),
+            make_pod("server", vec![("http", 8081, None)]),
+            make_pod("client", vec![("http", 9090, None)]),
+        ];
+        let named = NamedPorts::new(&identities, &pods, &[make_namespace()]);
+
+        let mut http = policy_ports(
+            &port(IntOrString::String("http".into()), None, None),
+            100,
+            &named,
+        );
+        http.sort();
+        assert_eq!(http, vec![(8080, 6), (8081, 6)]);
+        assert_eq!(
+            policy_ports(
+                &port(IntOrString::String("dns".into()), None, Some("UDP")),
+                100,
+                &named
+            ),
+            vec![(53, 17)]
+        );
+        assert!(
+            policy_ports(
+                &port(IntOrString::String("dns".into()), None, None),
+                100,
+                &named
+            )
+            .is_empty()
+        );
+        assert_eq!(
+            policy_ports(
+                &port(IntOrString::String("http".into()), None, None),
+                200,
+                &named
+            ),
+            vec![(9090, 6)]
+        );
+    }
+
+    #[test]
+    fn end_port_splits_off_ranges() {
+        assert_eq!(
+            port_range(&port(IntOrString::Int(8000), Some(8002), Some("UDP"))),
+            Some(PortRange {
+                start: 8000,
+                end: 8002,
+                proto: 17
+            })
+        );
+        assert_eq!(port_range(&port(IntOrString::Int(8000), None, None)), None);
+        assert_eq!(
+            port_range(&port(IntOrString::Int(8000), Some(8000), None)),
+            None
+        );
+
+        let named = NamedPorts::default();
+        assert_eq!(
+            policy_ports(&port(IntOrString::Int(8000), Some(8000), None), 100, &named),
+            vec![(8000, 6)]
+        );
+        assert!(
+            policy_ports(
+                &port(IntOrString::Int(8000), Some(70000), None),
+                100,
+                &named
+            )
+            .is_empty()
+        );
+    }
+
+    #[test]
+    fn named_port_changes_ignore_unrelated_pod_updates() {
+        let (pods, _) = reflector::store::<Pod>();
+        let mut changes = NamedPortChanges::default();
+
+        assert!(!changes.changed(&make_pod("plain", vec![]), &pods));
+
+        let pod = make_pod("server", vec![("http", 8080, None)]);
+        assert!(changes.changed(&pod, &pods));
+        assert!(!changes.changed(&pod, &pods));
+
+        let mut restarted = (*pod).clone();
+        restarted.status = Some(Default::default());
+        assert!(!changes.changed(&restarted, &pods));
+
+        assert!(changes.changed(&make_pod("server", vec![("http", 9090, None)]), &pods));
+        assert!(changes.changed(&make_pod("server", vec![]), &pods));
+        assert!(!changes.changed(&make_pod("server", vec![]), &pods));
+    }
+}
@@ -0,0 +1,191 @@
+use mesh_cni_ebpf_common::{
+    IdentityId,
+    policy::{PolicyKey, PolicyValue},
+};
+
+type Entries = ahash::HashMap<PolicyKey, PolicyValue>;
+
+/// The entries programmed into the policy map, indexed by the identity they were generated
+/// for.
+///
+/// The map is read once when the controller starts, after which this is the only view of
+/// it reconciles use. Holding it locked serializes the writes of concurrent reconciles so
+/// the room they budget port ranges against cannot be taken by another one in between.
+#[derive(Default)]
+pub(crate) struct ProgrammedEntries {
+    entries: ahash::HashMap<IdentityId, Entries>,
+    len: usize,
+}
+
+impl ProgrammedEntries {
+    pub fn new(current: Entries) -> Self {
+        let mut programmed = Self::default();
+        for (key, value) in current {
+            programmed
+                .entries
+                .entry(key.selected_id())
+                .or_default()
+                .insert(key, value);
+            programmed.len += 1;
+        }
+        programmed
+    }
+
+    /// Entries every identity but `id` takes up
+    pub fn len_without(&self, id: IdentityId) -> usize {
+        self.len - self.entries.get(&id).map_or(0, |entries| entries.len())
+    }
+
+    /// Deletes the entries of every identity `live` returns false for. An entry is only
+    /// forgotten once `delete` removed it, so failed deletes are retried on the next call.
+    pub fn retain<E>(
+        &mut self,
+        live: impl Fn(IdentityId) -> bool,
+        mut delete: impl FnMut(&PolicyKey) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let dead: Vec<IdentityId> = self
+            .entries
+            .keys()
+            .filter(|id| !live(**id))
+            .copied()
+            .collect();
+        for id in dead {
+            self.sync(id, Entries::default(), |_, _| Ok(()), &mut delete)?;
+        }
+        Ok(())
+    }
+
+    /// Programs `desired` as the entries of `id`. The entries it no longer has are deleted
+    /// first so the room they take is free for the new ones.
+    pub fn sync<E>(
+        &mut self,
+        id: IdentityId,
+        desired: Entries,
+        mut update: impl FnMut(PolicyKey, PolicyValue) -> Result<(), E>,
+        mut delete: impl FnMut(&PolicyKey) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let current = self.entries.entry(id).or_default();
+
+        let stale: Vec<PolicyKey> = current
+            .keys()
+            .filter(|key| !desired.contains_key(*key))
+            .copied()
+            .collect();
+        for key in stale {
+            delete(&key)?;
+            current.remove(&key);
+            self.len -= 1;
+        }
+
+        for (key, value) in desired {
+            if current.get(&key) == Some(&value) {
+                continue;
+            }
+            update(key, value)?;
+            if current.insert(key, value).is_none() {
+                self.len += 1;
+            }
+        }
+
+        if current.is_empty() {
+            self.entries.remove(&id);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use mesh_cni_ebpf_common::{
+        Direction,
+        policy::{PolicyKey, PolicyValue},
+    };
+
+    use super::{Entries, ProgrammedEntries};
+
+    const ALLOW: PolicyValue = PolicyValue { action: 0 };
+
+    fn ingress(src_id: u32, dst_id: u32, port: u16) -> (PolicyKey, PolicyValue) {
+        (
+            PolicyKey::new(src_id, dst_id, port, 6, Direction::Ingress),
+            ALLOW,
+        )
+    }
+
+    #[test]
+    fn sync_programs_the_difference_and_tracks_room() {
+        let current: Entries = [ingress(200, 100, 80), ingress(200, 300, 80)]
+            .into_iter()
+            .collect();
+        let mut programmed = ProgrammedEntries::new(current.clone());
+        assert_eq!(programmed.len_without(100), 1);
+
+        let map = RefCell::new(current);
+        let writes = Cell::new(0);
+        let desired: Entries = [ingress(200, 100, 80), ingress(200, 100, 8080)]
+            .into_iter()
+            .collect();
+        programmed
+            .sync::<()>(
+                100,
+                desired.clone(),
+                |key, value| {
+                    writes.set(writes.get() + 1);
+                    map.borrow_mut().insert(key, value);
+                    Ok(())
+                },
+                |key| {
+                    writes.set(writes.get() + 1);
+                    map.borrow_mut().remove(key);
+                    Ok(())
+                },
+            )
+            .unwrap();
+
+        assert_eq!(writes.get(), 1);
+        assert_eq!(map.borrow().len(), 3);
+        assert!(desired.keys().all(|key| map.borrow().contains_key(key)));
+        assert_eq!(programmed.len_without(100), 1);
+        assert_eq!(programmed.len_without(300), 2);
+
+        programmed
+            .sync::<()>(
+                100,
+                Entries::default(),
+                |_, _| Ok(()),
+                |key| {
+                    map.borrow_mut().remove(key);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(map.borrow().len(), 1);
+        assert_eq!(programmed.len_without(300), 0);
+    }
+
+    #[test]
+    fn retain_deletes_dead_identities_and_retries_failures() {
+        let mut map: Entries = [ingress(200, 100, 80), ingress(200, 300, 80)]
+            .into_iter()
+            .collect();
+        let mut programmed = ProgrammedEntries::new(map.clone());
+
+        let failed = programmed.retain(|id| id == 100, |_| Err(()));
+        assert!(failed.is_err());
+        assert_eq!(programmed.len_without(100), 1);
+
+        programmed
+            .retain::<()>(
+                |id| id == 100,
+                |key| {
+                    map.remove(key);
+                    Ok(())
+                },
+            )
+            .unwrap();
+        assert_eq!(map.len(), 1);
+        assert_eq!(programmed.len_without(100), 0);
+    }
+}
@@ -0,0 +1,784 @@
+use std::{
+    collections::HashSet,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use k8s_openapi::api::networking::v1::{NetworkPolicy, NetworkPolicyPeer, NetworkPolicyPort};
+use kube::runtime::reflector::ObjectRef;
+use mesh_cni_crds::v1alpha1::identity::Identity;
+use mesh_cni_ebpf_common::{
+    Direction, IdentityId, LOCAL_NODE_ID, REMOTE_NODE_ID,
+    policy::{Action, PolicyKey, PolicyValue},
+};
+
+use crate::{
+    cidr::CidrIdentities,
+    ports::{MAX_PORT_RANGE, NamedPorts, PortRange, policy_ports, port_range},
+    selector::{
+        PolicyType, egress_rules_select_identity, ingress_rules_select_identity,
+        policy_affects_type,
+    },
+};
+
+const ALLOW: PolicyValue = PolicyValue {
+    action: Action::Allow as u8,
+};
+
+/// Cluster state policy peers and ports are resolved against
+pub(crate) struct PolicyPeers<'a> {
+    pub identities: &'a [Arc<Identity>],
+    pub cidrs: &'a CidrIdentities,
+    pub named_ports: &'a NamedPorts,
+}
+
+/// Policy entries of an identity. Port ranges are kept apart until they are admitted
+/// against the room left in the policy map, which every identity on the node shares.
+#[derive(Default)]
+pub(crate) struct PolicyEntries {
+    pub entries: ahash::HashMap<PolicyKey, PolicyValue>,
+    ranges: Vec<RangeEntries>,
+}
+
+struct RangeEntries {
+    policy: ObjectRef<NetworkPolicy>,
+    src_id: IdentityId,
+    dst_id: IdentityId,
+    direction: Direction,
+    range: PortRange,
+}
+
+/// A port range of a policy left out of the policy map
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub(crate) struct SkippedRange {
+    pub policy: ObjectRef<NetworkPolicy>,
+    pub range: PortRange,
+    pub reason: SkipReason,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub(crate) enum SkipReason {
+    TooLarge,
+    MapFull,
+}
+
+impl fmt::Display for SkippedRange {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "skipping port range {}-{} of NetworkPolicy {}/{}",
+            self.range.start,
+            self.range.end,
+            self.policy.namespace.as_deref().unwrap_or_default(),
+            self.policy.name
+        )?;
+        match self.reason {
+            SkipReason::TooLarge => write!(f, ", it is larger than {} ports", MAX_PORT_RANGE),
+            SkipReason::MapFull => write!(f, ", the policy map has no room left for it"),
+        }
+    }
+}
+
+/// Skipped port ranges already reported, each is reported once rather than on every
+/// reconcile
+#[derive(Default)]
+pub(crate) struct ReportedRanges {
+    reported: Mutex<HashSet<SkippedRange>>,
+}
+
+impl ReportedRanges {
+    /// Drops the ranges reported before and remembers the rest as reported
+    pub fn unreported(&self, skipped: Vec<SkippedRange>) -> Vec<SkippedRange> {
+        let mut reported = self.reported.lock().unwrap();
+        skipped
+            .into_iter()
+            .filter(|skipped| reported.insert(skipped.clone()))
+            .collect()
+    }
+}
+
+impl PolicyEntries {
+    pub fn extend(&mut self, other: PolicyEntries) {
+        self.entries.extend(other.entries);
+        self.ranges.extend(other.ranges);
+    }
+
+    /// Expands port ranges into entries while they fit in `room` more entries, returning
+    /// the ranges left out. A range is admitted whole or not at all so a policy never
+    /// allows part of a range it asked for.
+    pub fn admit_ranges(&mut self, mut room: usize) -> Vec<SkippedRange> {
+        let mut skipped = vec![];
+        for range in std::mem::take(&mut self.ranges) {
+            let reason = if range.range.len() > MAX_PORT_RANGE {
+                SkipReason::TooLarge
+            } else {
+                let keys: Vec<PolicyKey> = range
+                    .range
+                    .ports()
+                    .map(|port| {
+                        PolicyKey::new(
+                            range.src_id,
+                            range.dst_id,
+                            port,
+                            range.range.proto,
+                            range.direction,
+                        )
+                    })
+                    .filter(|key| !self.entries.contains_key(key))
+                    .collect();
+                if keys.len() <= room {
+                    room -= keys.len();
+                    self.entries
+                        .extend(keys.into_iter().map(|key| (key, ALLOW)));
+                    continue;
+                }
+                SkipReason::MapFull
+            };
+            skipped.push(SkippedRange {
+                policy: range.policy,
+                range: range.range,
+                reason,
+            });
+        }
+        skipped
+    }
+}
+
+/// Builds the full set of ingress entries for `dst` from the policies selecting it.
+/// Sources are resolved against `peers` so every entry references a concrete identity.
+pub(crate) fn ingress_policy_entries(
+    dst: &Identity,
+    policies: &[Arc<NetworkPolicy>],
+    peers: &PolicyPeers,
+) -> PolicyEntries {
+    let mut entries = PolicyEntries::default();
+    let dst_id = dst.spec.id;
+
+    if !is_isolated(policies, PolicyType::Ingress) {
+        return entries;
+    }
+
+    entries
+        .entries
+        .insert(PolicyKey::isolation(dst_id, Direction::Ingress), ALLOW);
+    // kubelet probes originate from the local node and must keep working once isolated
+    entries.entries.insert(
+        PolicyKey::new(LOCAL_NODE_ID, dst_id, 0, 0, Direction::Ingress),
+        ALLOW,
+    );
+
+    for src in peers.identities {
+        for policy in policies {
+            for rule in ingress_rules_select_identity(src, policy) {
+                insert_ports(
+                    &mut entries,
+                    policy,
+                    (src.spec.id, dst_id),
+                    rule.ports.as_ref(),
+                    Direction::Ingress,
+                    peers.named_ports,
+                );
+            }
+        }
+    }
+
+    for policy in policies {
+        let Some(spec) = &policy.spec else {
+            continue;
+        };
+        if !policy_affects_type(spec, PolicyType::Ingress) {
+            continue;
+        }
+        for rule in spec.ingress.iter().flatten() {
+            for src_id in external_peer_ids(rule.from.as_ref(), peers.cidrs) {
+                insert_ports(
+                    &mut entries,
+                    policy,
+                    (src_id, dst_id),
+                    rule.ports.as_ref(),
+                    Direction::Ingress,
+                    peers.named_ports,
+                );
+            }
+        }
+    }
+
+    entries
+}
+
+/// Builds the full set of egress entries for `src` from the policies selecting it.
+/// Peers are resolved the same way as ingress sources.
+pub(crate) fn egress_policy_entries(
+    src: &Identity,
+    policies: &[Arc<NetworkPolicy>],
+    peers: &PolicyPeers,
+) -> PolicyEntries {
+    let mut entries = PolicyEntries::default();
+    let src_id = src.spec.id;
+
+    if !is_isolated(policies, PolicyType::Egress) {
+        return entries;
+    }
+
+    entries
+        .entries
+        .insert(PolicyKey::isolation(src_id, Direction::Egress), ALLOW);
+
+    for dst in peers.identities {
+        for policy in policies {
+            for rule in egress_rules_select_identity(dst, policy) {
+                insert_ports(
+                    &mut entries,
+                    policy,
+                    (src_id, dst.spec.id),
+                    rule.ports.as_ref(),
+                    Direction::Egress,
+                    peers.named_ports,
+                );
+            }
+        }
+    }
+
+    for policy in policies {
+        let Some(spec) = &policy.spec else {
+            continue;
+        };
+        if !policy_affects_type(spec, PolicyType::Egress) {
+            continue;
+        }
+        for rule in spec.egress.iter().flatten() {
+            for dst_id in external_peer_ids(rule.to.as_ref(), peers.cidrs) {
+                insert_ports(
+                    &mut entries,
+                    policy,
+                    (src_id, dst_id),
+                    rule.ports.as_ref(),
+                    Direction::Egress,
+                    peers.named_ports,
+                );
+            }
+        }
+    }
+
+    entries
+}
+
+/// Identities outside of the identity store selected by a rule's peers. A rule without
+/// peers selects every address, including nodes and anything outside the cluster.
+fn external_peer_ids(
+    peers: Option<&Vec<NetworkPolicyPeer>>,
+    cidrs: &CidrIdentities,
+) -> Vec<IdentityId> {
+    match peers {
+        Some(peers) if !peers.is_empty() => peers
+            .iter()
+            .filter_map(|peer| peer.ip_block.as_ref())
+            .flat_map(|block| cidrs.selected_ids(block))
+            .collect(),
+        _ => cidrs.ids().chain([LOCAL_NODE_ID, REMOTE_NODE_ID]).collect(),
+    }
+}
+
+fn is_isolated(policies: &[Arc<NetworkPolicy>], policy_type: PolicyType) -> bool {
+    policies.iter().any(|policy| {
+        policy
+            .spec
+            .as_ref()
+            .is_some_and(|spec| policy_affects_type(spec, policy_type.clone()))
+    })
+}
+
+// named ports always resolve against the destination, which is the selected pod for
+// ingress and the peer for egress
+fn insert_ports(
+    entries: &mut PolicyEntries,
+    policy: &NetworkPolicy,
+    (src_id, dst_id): (IdentityId, IdentityId),
+    ports: Option<&Vec<NetworkPolicyPort>>,
+    direction: Direction,
+    named_ports: &NamedPorts,
+) {
+    let Some(ports) = ports.filter(|ports| !ports.is_empty()) else {
+        entries
+            .entries
+            .insert(PolicyKey::new(src_id, dst_id, 0, 0, direction), ALLOW);
+        return;
+    };
+
+    for port in ports {
+        if let Some(range) = port_range(port) {
+            entries.ranges.push(RangeEntries {
+                policy: ObjectRef::from_obj(policy),
+                src_id,
+                dst_id,
+                direction,
+                range,
+            });
+            continue;
+        }
+        for (dst_port, proto) in policy_ports(port, dst_id, named_ports) {
+            entries.entries.insert(
+                PolicyKey::new(src_id, dst_id, dst_port, proto, direction),
+                ALLOW,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, sync::Arc};
+
+    use k8s_openapi::{
+        api::{
+            core::v1::{Container, ContainerPort, Namespace, Pod, PodSpec},
+            networking::v1::{
+                IPBlock, NetworkPolicy, NetworkPolicyEgressRule, NetworkPolicyIngressRule,
+                NetworkPolicyPeer, NetworkPolicyPort, NetworkPolicySpec,
+            },
+        },
+        apimachinery::pkg::{apis::meta::v1::LabelSelector, util::intstr::IntOrString},
+    };
+    use kube::api::ObjectMeta;
+    use mesh_cni_crds::v1alpha1::identity::{Identity, IdentitySpec};
+    use mesh_cni_ebpf_common::{
+        Direction, LOCAL_NODE_ID, WORLD_ID,
+        policy::{PolicyKey, PolicyValue},
+    };
+
+    use super::{
+        PolicyPeers, ReportedRanges, SkipReason, egress_policy_entries, ingress_policy_entries,
+    };
+    use crate::{
+        cidr::{CidrIdentities, policy_prefixes},
+        ports::{MAX_PORT_RANGE, NamedPorts},
+    };
+
+    const INGRESS: Direction = Direction::Ingress;
+
+    fn ingress(
+        dst: &Identity,
+        policies: &[Arc<NetworkPolicy>],
+        identities: &[Arc<Identity>],
+        cidrs: &CidrIdentities,
+    ) -> ahash::HashMap<PolicyKey, PolicyValue> {
+        let peers = PolicyPeers {
+            identities,
+            cidrs,
+            named_ports: &NamedPorts::default(),
+        };
+        let mut entries = ingress_policy_entries(dst, policies, &peers);
+        entries.admit_ranges(usize::MAX);
+        entries.entries
+    }
+
+    fn egress(
+        src: &Identity,
+        policies: &[Arc<NetworkPolicy>],
+        identities: &[Arc<Identity>],
+        cidrs: &CidrIdentities,
+    ) -> ahash::HashMap<PolicyKey, PolicyValue> {
+        let peers = PolicyPeers {
+            identities,
+            cidrs,
+            named_ports: &NamedPorts::default(),
+        };
+        let mut entries = egress_policy_entries(src, policies, &peers);
+        entries.admit_ranges(usize::MAX);
+        entries.entries
+    }
+
+    fn make_identity(name: &str, app: &str, id: u32) -> Arc<Identity> {
+        let mut pod_labels = BTreeMap::new();
+        pod_labels.insert("app".into(), app.into());
+        let spec = IdentitySpec {
+            namespace_labels: BTreeMap::new(),
+            pod_labels,
+            id,
+        };
+        let mut identity = Identity::new(name, spec);
+        identity.metadata.namespace = Some("default".into());
+        Arc::new(identity)
+    }
+
+    fn app_selector(app: &str) -> LabelSelector {
+        LabelSelector {
+            match_labels: Some(BTreeMap::from([("app".to_string(), app.to_string())])),
+            match_expressions: None,
+        }
+    }
+
+    fn make_policy(rules: Option<Vec<NetworkPolicyIngressRule>>) -> Arc<NetworkPolicy> {
+        Arc::new(NetworkPolicy {
+            metadata: ObjectMeta {
+                name: Some("allow-client".into()),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            spec: Some(NetworkPolicySpec {
+                pod_selector: Some(app_selector("server")),
+                ingress: rules,
+                policy_types: Some(vec!["Ingress".into()]),
+                ..Default::default()
+            }),
+        })
+    }
+
+    #[test]
+    fn ingress_entries_resolve_peers_and_ports() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let other = make_identity("other", "other", 300);
+        let identities = vec![server.clone(), client.clone(), other.clone()];
+
+        let policy = make_policy(Some(vec![NetworkPolicyIngressRule {
+            from: Some(vec![NetworkPolicyPeer {
+                pod_selector: Some(app_selector("client")),
+                namespace_selector: None,
+                ip_block: None,
+            }]),
+            ports: Some(vec![
+                NetworkPolicyPort {
+                    port: Some(IntOrString::Int(8080)),
+                    end_port: None,
+                    protocol: None,
+                },
+                NetworkPolicyPort {
+                    port: Some(IntOrString::Int(53)),
+                    end_port: None,
+                    protocol: Some("UDP".into()),
+                },
+            ]),
+        }]));
+
+        let entries = ingress(&server, &[policy], &identities, &CidrIdentities::default());
+
+        assert_eq!(entries.len(), 4);
+        assert!(entries.contains_key(&PolicyKey::isolation(100, INGRESS)));
+        assert!(entries.contains_key(&PolicyKey::new(LOCAL_NODE_ID, 100, 0, 0, INGRESS)));
+        assert!(entries.contains_key(&PolicyKey::new(200, 100, 8080, 6, INGRESS)));
+        assert!(entries.contains_key(&PolicyKey::new(200, 100, 53, 17, INGRESS)));
+    }
+
+    #[test]
+    fn ingress_entries_deny_all_only_isolates() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let identities = vec![server.clone(), client];
+
+        let entries = ingress(
+            &server,
+            &[make_policy(None)],
+            &identities,
+            &CidrIdentities::default(),
+        );
+
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key(&PolicyKey::isolation(100, INGRESS)));
+    }
+
+    #[test]
+    fn ingress_entries_empty_from_allows_all_identities() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let identities = vec![server.clone(), client];
+
+        let policy = make_policy(Some(vec![NetworkPolicyIngressRule {
+            from: None,
+            ports: None,
+        }]));
+        let entries = ingress(&server, &[policy], &identities, &CidrIdentities::default());
+
+        assert!(entries.contains_key(&PolicyKey::new(100, 100, 0, 0, INGRESS)));
+        assert!(entries.contains_key(&PolicyKey::new(200, 100, 0, 0, INGRESS)));
+    }
+
+    #[test]
+    fn ingress_entries_empty_without_policies() {
+        let server = make_identity("server", "server", 100);
+        let entries = ingress(
+            &server,
+            &[],
+            std::slice::from_ref(&server),
+            &CidrIdentities::default(),
+        );
+        assert!(entries.is_empty());
+    }
+
+    #[test]
+    fn egress_entries_resolve_peers() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let identities = vec![server.clone(), client.clone()];
+
+        let policy = Arc::new(NetworkPolicy {
+            metadata: ObjectMeta {
+                name: Some("client-egress".into()),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            spec: Some(NetworkPolicySpec {
+                pod_selector: Some(app_selector("client")),
+                egress: Some(vec![NetworkPolicyEgressRule {
+                    to: Some(vec![NetworkPolicyPeer {
+                        pod_selector: Some(app_selector("server")),
+                        namespace_selector: None,
+                        ip_block: None,
+                    }]),
+                    ports: Some(vec![NetworkPolicyPort {
+                        port: Some(IntOrString::Int(8080)),
+                        end_port: None,
+                        protocol: None,
+                    }]),
+                }]),
+                policy_types: Some(vec!["Egress".into()]),
+                ..Default::default()
+            }),
+        });
+
+        let entries = egress(
+            &client,
+            &[policy.clone()],
+            &identities,
+            &CidrIdentities::default(),
+        );
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key(&PolicyKey::isolation(200, Direction::Egress)));
+        assert!(entries.contains_key(&PolicyKey::new(200, 100, 8080, 6, Direction::Egress)));
+
+        assert!(ingress(&client, &[policy], &identities, &CidrIdentities::default()).is_empty());
+    }
+
+    #[test]
+    fn ingress_entries_ip_block_peers() {
+        let server = make_identity("server", "server", 100);
+        let identities = vec![server.clone()];
+
+        let policy = make_policy(Some(vec![NetworkPolicyIngressRule {
+            from: Some(vec![NetworkPolicyPeer {
+                pod_selector: None,
+                namespace_selector: None,
+                ip_block: Some(IPBlock {
+                    cidr: "10.0.0.0/8".into(),
+                    except: Some(vec!["10.1.0.0/16".into()]),
+                }),
+            }]),
+            ports: None,
+        }]));
+
+        let mut cidrs = CidrIdentities::default();
+        let mut added = Vec::new();
+        cidrs
+            .sync::<()>(
+                &policy_prefixes(std::iter::once(policy.as_ref())),
+                |network, id| {
+                    added.push((network, id));
+                    Ok(())
+                },
+                |_| Ok(()),
+            )
+            .unwrap();
+        let id_of = |cidr: &str| {
+            added
+                .iter()
+                .find(|(network, _)| network.to_string() == cidr)
+                .map(|(_, id)| *id)
+                .unwrap()
+        };
+
+        let entries = ingress(&server, &[policy.clone()], &identities, &cidrs);
+        assert!(entries.contains_key(&PolicyKey::new(id_of("10.0.0.0/8"), 100, 0, 0, INGRESS)));
+        assert!(!entries.contains_key(&PolicyKey::new(id_of("10.1.0.0/16"), 100, 0, 0, INGRESS)));
+        assert!(!entries.contains_key(&PolicyKey::new(WORLD_ID, 100, 0, 0, INGRESS)));
+    }
+
+    #[test]
+    fn egress_entries_resolve_named_ports_against_peer() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let identities = vec![server.clone(), client.clone()];
+
+        let pod = |app: &str, port: i32| {
+            Arc::new(Pod {
+                metadata: ObjectMeta {
+                    name: Some(app.into()),
+                    namespace: Some("default".into()),
+                    labels: Some(BTreeMap::from([("app".to_string(), app.to_string())])),
+                    ..Default::default()
+                },
+                spec: Some(PodSpec {
+                    containers: vec![Container {
+                        name: "main".into(),
+                        ports: Some(vec![ContainerPort {
+                            name: Some("http".into()),
+                            container_port: port,
+                            ..Default::default()
+                        }]),
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }),
+                status: None,
+            })
+        };
+        let namespace = Arc::new(Namespace {
+            metadata: ObjectMeta {
+                name: Some("default".into()),
+                ..Default::default()
+            },
+            ..Default::default()
+        });
+        let named_ports = NamedPorts::new(
+            &identities,
+            &[pod("server", 8080), pod("client", 9090)],
+            &[namespace],
+        );
+
+        let policy = Arc::new(NetworkPolicy {
+            metadata: ObjectMeta {
+                name: Some("client-egress".into()),
+                namespace: Some("default".into()),
+                ..Default::default()
+            },
+            spec: Some(NetworkPolicySpec {
+                pod_selector: Some(app_selector("client")),
+                egress: Some(vec![NetworkPolicyEgressRule {
+                    to: Some(vec![NetworkPolicyPeer {
+                        pod_selector: Some(app_selector("server")),
+                        namespace_selector: None,
+                        ip_block: None,
+                    }]),
+                    ports: Some(vec![NetworkPolicyPort {
+                        port: Some(IntOrString::String("http".into())),
+                        end_port: None,
+                        protocol: None,
+                    }]),
+                }]),
+                policy_types: Some(vec!["Egress".into()]),
+                ..Default::default()
+            }),
+        });
+
+        let peers = PolicyPeers {
+            identities: &identities,
+            cidrs: &CidrIdentities::default(),
+            named_ports: &named_ports,
+        };
+        let entries = egress_policy_entries(&client, &[policy], &peers).entries;
+        assert_eq!(entries.len(), 2);
+        assert!(entries.contains_key(&PolicyKey::new(200, 100, 8080, 6, Direction::Egress)));
+    }
+
+    #[test]
+    fn ingress_entries_admit_port_ranges_within_room() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let identities = vec![server.clone(), client.clone()];
+
+        let range = |start: i32, end: i32| NetworkPolicyPort {
+            port: Some(IntOrString::Int(start)),
+            end_port: Some(end),
+            protocol: None,
+        };
+        let policy = make_policy(Some(vec![NetworkPolicyIngressRule {
+            from: Some(vec![NetworkPolicyPeer {
+                pod_selector: Some(app_selector("client")),
+                namespace_selector: None,
+                ip_block: None,
+            }]),
+            ports: Some(vec![
+                range(8000, 8009),
+                range(9000, 9019),
+                range(10000, 20000),
+            ]),
+        }]));
+        let peers = PolicyPeers {
+            identities: &identities,
+            cidrs: &CidrIdentities::default(),
+            named_ports: &NamedPorts::default(),
+        };
+
+        let mut entries = ingress_policy_entries(&server, &[policy], &peers);
+        assert_eq!(entries.entries.len(), 2);
+
+        let skipped = entries.admit_ranges(15);
+        let admitted = &entries.entries;
+        assert_eq!(admitted.len(), 12);
+        assert!(admitted.contains_key(&PolicyKey::new(200, 100, 8009, 6, INGRESS)));
+        assert!(!admitted.contains_key(&PolicyKey::new(200, 100, 9000, 6, INGRESS)));
+
+        let skipped: Vec<_> = skipped
+            .iter()
+            .map(|skipped| (skipped.range.start, skipped.reason))
+            .collect();
+        assert_eq!(
+            skipped,
+            vec![(9000, SkipReason::MapFull), (10000, SkipReason::TooLarge)]
+        );
+    }
+
+    #[test]
+    fn ingress_entries_expand_bounded_port_ranges() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let identities = vec![server.clone(), client.clone()];
+
+        let policy = make_policy(Some(vec![NetworkPolicyIngressRule {
+            from: Some(vec![NetworkPolicyPeer {
+                pod_selector: Some(app_selector("client")),
+                namespace_selector: None,
+                ip_block: None,
+            }]),
+            ports: Some(vec![
+                NetworkPolicyPort {
+                    port: Some(IntOrString::Int(8000)),
+                    end_port: Some(8002),
+                    protocol: None,
+                },
+                NetworkPolicyPort {
+                    port: Some(IntOrString::Int(9000)),
+                    end_port: Some(9000 + MAX_PORT_RANGE as i32),
+                    protocol: None,
+                },
+            ]),
+        }]));
+
+        let entries = ingress(&server, &[policy], &identities, &CidrIdentities::default());
+        assert_eq!(entries.len(), 5);
+        for port in 8000..=8002 {
+            assert!(entries.contains_key(&PolicyKey::new(200, 100, port, 6, INGRESS)));
+        }
+        assert!(!entries.contains_key(&PolicyKey::new(200, 100, 9000, 6, INGRESS)));
+    }
+
+    #[test]
+    fn skipped_port_ranges_are_reported_once() {
+        let server = make_identity("server", "server", 100);
+        let client = make_identity("client", "client", 200);
+        let identities = vec![server.clone(), client.clone()];
+
+        let policy = make_policy(Some(vec![NetworkPolicyIngressRule {
+            from: None,
+            ports: Some(vec![NetworkPolicyPort {
+                port: Some(IntOrString::Int(8000)),
+                end_port: Some(8000 + MAX_PORT_RANGE as i32),
+                protocol: None,
+            }]),
+        }]));
+        let peers = PolicyPeers {
+            identities: &identities,
+            cidrs: &CidrIdentities::default(),
+            named_ports: &NamedPorts::default(),
+        };
+        let skipped =
+            || ingress_policy_entries(&server, &[policy.clone()], &peers).admit_ranges(usize::MAX);
+
+        // every peer identity selects the same range of the same policy
+        let reported = ReportedRanges::default();
+        let first = reported.unreported(skipped());
+        assert_eq!(first.len(), 1);
+        assert_eq!(first[0].policy.name, "allow-client");
+        assert_eq!(first[0].reason, SkipReason::TooLarge);
+        assert!(reported.unreported(skipped()).is_empty());
+    }
+}
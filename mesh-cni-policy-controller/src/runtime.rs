@@ -1,24 +1,42 @@
-use std::sync::Arc;
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex},
+};
 
 use futures::StreamExt;
-use kube::{Api, Client, runtime::Controller};
-use mesh_cni_k8s_utils::create_store_and_subscriber;
+use kube::{
+    Api, Client,
+    runtime::{
+        Controller,
+        events::{Recorder, Reporter},
+    },
+};
+use mesh_cni_ebpf_common::{IdentityId, MIN_CIDR_ID, WORLD_ID};
+use mesh_cni_k8s_utils::{InitialReconcile, create_store_and_subscriber};
 use tokio::time::{Duration, timeout};
 use tokio_util::sync::CancellationToken;
+use tracing::info;
 
 use crate::{
-    Error, PolicyControllerBpf, Result,
+    CidrIdentityBpf, Error, PolicyControllerBpf, Result,
+    cidr::{CidrIdentities, policy_prefixes},
     context::Context,
     controller::{error_policy, reconcile},
+    ports::NamedPortChanges,
+    programmed::ProgrammedEntries,
+    rules::ReportedRanges,
 };
 
-pub async fn start_policy_controllers<P>(
+pub async fn start_policy_controllers<P, C>(
     client: Client,
     policy_bpf_state: P,
+    cidr_bpf_state: C,
     cancel: CancellationToken,
+    reconciled: CancellationToken,
 ) -> Result<()>
 where
     P: PolicyControllerBpf + Send + Sync + 'static,
+    C: CidrIdentityBpf + Send + Sync + 'static,
 {
     let store_init = timeout(Duration::from_secs(30), async {
         tokio::try_join!(
@@ -32,31 +50,86 @@ where
     .map_err(|_| Error::Timeout("store initialization".into()))??;
 
     let (
-        (pod_store, _pod_subscriber),
-        (policy_store, _policy_subscriber),
+        (pod_store, pod_subscriber),
+        (policy_store, policy_subscriber),
         (namespace_store, _namespace_subscriber),
         (identity_store, identity_subscriber),
     ) = store_init;
 
+    // the only read of the whole policy map, reconciles work off this index of it
+    let programmed = ProgrammedEntries::new(policy_bpf_state.state()?);
+    let reporter = Reporter {
+        controller: "mesh-cni-policy-controller".into(),
+        instance: None,
+    };
     let context = Arc::new(Context {
         pod_store: pod_store.clone(),
         policy_store: policy_store.clone(),
         namespace_store: namespace_store.clone(),
         identity_store: identity_store.clone(),
         policy_bpf_state,
+        cidr_bpf_state,
+        cidr_identities: Mutex::new(CidrIdentities::default()),
+        programmed: Mutex::new(programmed),
+        recorder: Recorder::new(client, reporter),
+        reported_ranges: ReportedRanges::default(),
     });
 
+    info!("sweeping policy entries and CIDR identities of deleted objects");
+    sweep(&context)?;
+
+    // Policy entries reference source identities and named ports resolve against pods,
+    // so any policy or identity change, and pods changing their named ports, can affect
+    // the entries of every identity
+    let mut named_port_changes = NamedPortChanges::default();
+    let named_port_pods = pod_subscriber
+        .filter(move |pod| futures::future::ready(named_port_changes.changed(pod, &pod_store)));
+    let triggers = futures::stream::select_all([
+        policy_subscriber.map(|_| ()).boxed(),
+        identity_subscriber.clone().map(|_| ()).boxed(),
+        named_port_pods.map(|_| ()).boxed(),
+    ]);
+
+    let mut initial = InitialReconcile::from_store(&identity_store, reconciled);
     tokio::spawn(
         Controller::for_shared_stream(identity_subscriber, identity_store)
+            .reconcile_all_on(triggers)
             .graceful_shutdown_on(shutdown(cancel))
             .run(reconcile, error_policy, context)
-            .filter_map(|x| async move { std::result::Result::ok(x) })
-            .for_each(|_| futures::future::ready(())),
+            .for_each(move |result| {
+                initial.observe(&result);
+                futures::future::ready(())
+            }),
     );
 
     Ok(())
 }
 
+/// Deletes what NetworkPolicies and Identities removed while the agent was not running
+/// left behind. Runs on the initial list, before any reconcile.
+fn sweep<P: PolicyControllerBpf, C: CidrIdentityBpf>(ctx: &Context<P, C>) -> Result<()> {
+    let policies = ctx.policy_store.state();
+    let prefixes = policy_prefixes(policies.iter().map(|p| p.as_ref()));
+    ctx.cidr_bpf_state
+        .retain(|network, id| !cidr_identity(id) || prefixes.contains(network))?;
+
+    let live_ids: HashSet<IdentityId> = ctx
+        .identity_store
+        .state()
+        .iter()
+        .map(|identity| identity.spec.id)
+        .collect();
+    ctx.programmed.lock().unwrap().retain(
+        |id| live_ids.contains(&id),
+        |key| ctx.policy_bpf_state.delete(key),
+    )
+}
+
+/// Identities the CIDR prefixes of policies are programmed with
+fn cidr_identity(id: IdentityId) -> bool {
+    id >= MIN_CIDR_ID || id == WORLD_ID
+}
+
 async fn shutdown(cancel: CancellationToken) {
     cancel.cancelled().await;
 }
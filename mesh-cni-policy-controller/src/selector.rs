@@ -53,16 +53,34 @@ pub(crate) fn policy_selects_identity(policy: &NetworkPolicy, identity: &Identit
     label_selector_matches(&policy_pod_selector, &identity.spec.pod_labels)
 }
 
-fn peers_select_identity(peers: Option<&Vec<NetworkPolicyPeer>>, identity: &Identity) -> bool {
+fn peers_select_identity(
+    peers: Option<&Vec<NetworkPolicyPeer>>,
+    identity: &Identity,
+    policy_ns: Option<&str>,
+) -> bool {
     match peers {
         None => true,
         Some(peers) if peers.is_empty() => true,
-        Some(peers) => peers
-            .iter()
-            .any(|peer| peer_selects_identity(peer, identity)),
+        Some(peers) => peers.iter().any(|peer| {
+            peer_selects_identity(peer, identity)
+                && peer_namespace_matches(peer, identity, policy_ns)
+        }),
+    }
+}
+
+/// Peers without a namespace selector only select pods in the namespace of the policy
+pub(crate) fn peer_namespace_matches(
+    peer: &NetworkPolicyPeer,
+    identity: &Identity,
+    policy_ns: Option<&str>,
+) -> bool {
+    if peer.namespace_selector.is_some() {
+        return true;
     }
+    policy_ns.is_some() && identity.namespace().as_deref() == policy_ns
 }
 
+/// `ipBlock` peers never select pod identities, they are resolved to CIDR identities instead
 pub(crate) fn peer_selects_identity(peer: &NetworkPolicyPeer, identity: &Identity) -> bool {
     if peer.ip_block.is_some() && peer.pod_selector.is_none() && peer.namespace_selector.is_none() {
         return false;
@@ -113,7 +131,7 @@ pub fn ingress_rules_select_identity(
         .iter()
         .filter(|rule| {
             let peers = rule.from.as_ref();
-            peers_select_identity(peers, identity)
+            peers_select_identity(peers, identity, policy.namespace().as_deref())
         })
         .cloned()
         .collect()
@@ -138,7 +156,7 @@ pub fn egress_rules_select_identity(
         .iter()
         .filter(|rule| {
             let peers = rule.to.as_ref();
-            peers_select_identity(peers, identity)
+            peers_select_identity(peers, identity, policy.namespace().as_deref())
         })
         .cloned()
         .collect()
@@ -165,13 +183,18 @@ mod tests {
     use std::collections::BTreeMap;
 
     use k8s_openapi::{
-        api::networking::v1::{IPBlock, NetworkPolicy, NetworkPolicyPeer, NetworkPolicySpec},
+        api::networking::v1::{
+            IPBlock, NetworkPolicy, NetworkPolicyIngressRule, NetworkPolicyPeer, NetworkPolicySpec,
+        },
         apimachinery::pkg::apis::meta::v1::{LabelSelector, LabelSelectorRequirement},
     };
     use kube::api::ObjectMeta;
     use mesh_cni_crds::v1alpha1::identity::{Identity, IdentitySpec};
 
-    use super::{peer_selects_identity, policy_selects_identity};
+    use super::{
+        ingress_rules_select_identity, peer_namespace_matches, peer_selects_identity,
+        policy_selects_identity,
+    };
 
     fn make_identity() -> Identity {
         let mut pod_labels = BTreeMap::new();
@@ -372,4 +395,38 @@ mod tests {
         let policy = make_policy("ns-a", Some(make_selector_eq("app", "demo")));
         assert!(!policy_selects_identity(&policy, &identity));
     }
+
+    #[test]
+    fn peer_namespace_matches_pod_selector_only_requires_policy_namespace() {
+        let mut identity = make_identity();
+        identity.metadata.namespace = Some("ns-a".into());
+        let peer = NetworkPolicyPeer {
+            pod_selector: Some(make_selector_eq("app", "demo")),
+            namespace_selector: None,
+            ip_block: None,
+        };
+
+        assert!(peer_namespace_matches(&peer, &identity, Some("ns-a")));
+        assert!(!peer_namespace_matches(&peer, &identity, Some("ns-b")));
+    }
+
+    #[test]
+    fn ingress_rules_select_identity_ignores_other_namespace_pods() {
+        let mut identity = make_identity();
+        identity.metadata.namespace = Some("ns-b".into());
+
+        let mut policy = make_policy("ns-a", Some(make_selector_eq("app", "api")));
+        policy.spec.as_mut().unwrap().ingress = Some(vec![NetworkPolicyIngressRule {
+            from: Some(vec![NetworkPolicyPeer {
+                pod_selector: Some(make_selector_eq("app", "demo")),
+                namespace_selector: None,
+                ip_block: None,
+            }]),
+            ports: None,
+        }]);
+        assert!(ingress_rules_select_identity(&identity, &policy).is_empty());
+
+        identity.metadata.namespace = Some("ns-a".into());
+        assert_eq!(ingress_rules_select_identity(&identity, &policy).len(), 1);
+    }
 }
@@ -0,0 +1,11 @@
+use aya_ebpf::programs::TcContext;
+use mesh_cni_ebpf_common::Direction;
+
+use crate::tc::classify;
+
+/// Enforces egress policy on traffic sent by the pod. Attached to the
+/// ingress hook of the host side interface.
+#[inline]
+pub fn try_mesh_cni_egress(ctx: TcContext) -> Result<i32, i32> {
+    classify(ctx, Direction::Egress)
+}
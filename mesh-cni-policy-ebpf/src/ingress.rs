@@ -1,20 +1,11 @@
-use aya_ebpf::{bindings::TC_ACT_PIPE, programs::TcContext};
-use network_types::eth::{EthHdr, EtherType};
+use aya_ebpf::programs::TcContext;
+use mesh_cni_ebpf_common::Direction;
 
-use crate::ipv4::handle_ipv4;
+use crate::tc::classify;
 
+/// Enforces ingress policy on traffic destined to the pod. Attached to the
+/// egress hook of the host side interface.
 #[inline]
 pub fn try_mesh_cni_ingress(ctx: TcContext) -> Result<i32, i32> {
-    let ethhdr: EthHdr = ctx.load(0).map_err(|_| TC_ACT_PIPE)?;
-
-    let Ok(ether_type) = ethhdr.ether_type() else {
-        return Ok(TC_ACT_PIPE);
-    };
-
-    // TODO: handle ipv6
-    if !matches!(ether_type, EtherType::Ipv4) {
-        return Ok(TC_ACT_PIPE);
-    }
-
-    handle_ipv4(ctx)
+    classify(ctx, Direction::Ingress)
 }
@@ -1,64 +1,68 @@
 use aya_ebpf::{
-    bindings::TC_ACT_PIPE, helpers::bpf_ktime_get_ns, maps::lpm_trie::Key as LpmKey,
+    bindings::{TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::bpf_ktime_get_ns,
+    maps::lpm_trie::Key as LpmKey,
     programs::TcContext,
 };
-use aya_log_ebpf::info;
-use mesh_cni_ebpf_common::conntrack::{ConntrackKeyV4, ConntrackValue};
-use network_types::{eth::EthHdr, ip::Ipv4Hdr, tcp::TcpHdr, udp::UdpHdr};
+use aya_log_ebpf::debug;
+use mesh_cni_ebpf_common::{
+    Direction,
+    conntrack::{ConntrackKeyV4, ConntrackValue},
+    policy::Action,
+};
+use network_types::{eth::EthHdr, ip::Ipv4Hdr};
+
+use crate::{
+    CONNTRACK_V4, id_v4,
+    l4::{L4, load_l4},
+    verdict,
+};
 
-use crate::{CONNTRACK_V4, id_v4};
+/// Fragment offset bits of the flags and fragment offset field
+const FRAG_OFFSET_MASK: u16 = 0x1fff;
 
 #[inline]
-pub fn handle_ipv4(ctx: TcContext) -> Result<i32, i32> {
+pub fn handle_ipv4(ctx: TcContext, direction: Direction) -> Result<i32, i32> {
     let ipv4hdr: Ipv4Hdr = ctx.load(EthHdr::LEN).map_err(|_| TC_ACT_PIPE)?;
 
     let src = u32::from_be_bytes(ipv4hdr.src_addr);
     let dst = u32::from_be_bytes(ipv4hdr.dst_addr);
 
     // LpmTrie expects big endian order for comparisons
-    let (Some(src_id), Some(dst_id)) = (
-        id_v4(LpmKey::new(32, src.to_be())),
-        id_v4(LpmKey::new(32, dst.to_be())),
-    ) else {
-        return Ok(TC_ACT_PIPE);
-    };
+    let src_id = id_v4(LpmKey::new(32, src.to_be()));
+    let dst_id = id_v4(LpmKey::new(32, dst.to_be()));
 
-    let (proto, src_port, dst_port, should_insert) = match ipv4hdr.proto {
-        network_types::ip::IpProto::Tcp => {
-            let tcphdr: TcpHdr = ctx
-                .load(EthHdr::LEN + Ipv4Hdr::LEN)
-                .map_err(|_| TC_ACT_PIPE)?;
-            let syn = tcphdr.syn() == 1;
-            let ack = tcphdr.ack() == 1;
-            (
-                ipv4hdr.proto as u8,
-                u16::from_be_bytes(tcphdr.source),
-                u16::from_be_bytes(tcphdr.dest),
-                syn && !ack,
-            )
-        }
-        network_types::ip::IpProto::Udp => {
-            let udphdr: UdpHdr = ctx
-                .load(EthHdr::LEN + Ipv4Hdr::LEN)
-                .map_err(|_| TC_ACT_PIPE)?;
-            (
-                ipv4hdr.proto as u8,
-                u16::from_be_bytes(udphdr.src),
-                u16::from_be_bytes(udphdr.dst),
-                true,
-            )
+    // without ports only rules allowing any port and protocol can let the packet through,
+    // see handle_ipv6
+    let ihl = (ipv4hdr.vihl & 0x0f) as usize;
+    if u16::from_be_bytes(ipv4hdr.frags) & FRAG_OFFSET_MASK != 0 || ihl < 5 {
+        if verdict(src_id, dst_id, 0, 0, direction) == Action::Deny {
+            return Ok(TC_ACT_SHOT);
         }
-        network_types::ip::IpProto::Sctp => return Ok(TC_ACT_PIPE),
-        _ => return Ok(TC_ACT_PIPE),
+        return Ok(TC_ACT_PIPE);
+    }
+
+    let Some(L4 {
+        proto,
+        src_port,
+        dst_port,
+        opens_flow,
+    }) = load_l4(&ctx, EthHdr::LEN + ihl * 4, ipv4hdr.proto as u8).map_err(|_| TC_ACT_PIPE)?
+    else {
+        return Ok(TC_ACT_PIPE);
     };
 
+    // Replies cross the hook of the opposite direction from the one that created the
+    // entry, which keeps a flow allowed by one pod's egress from bypassing another
+    // pod's ingress
     let ct_key = ConntrackKeyV4 {
         src_ip: src,
         dst_ip: dst,
         src_port,
         dst_port,
         proto,
-        _pad: [0; 3],
+        direction: direction as u8,
+        _pad: [0; 2],
     };
     let ct_rev = ConntrackKeyV4 {
         src_ip: dst,
@@ -66,7 +70,8 @@ pub fn handle_ipv4(ctx: TcContext) -> Result<i32, i32> {
         src_port: dst_port,
         dst_port: src_port,
         proto,
-        _pad: [0; 3],
+        direction: direction.reverse() as u8,
+        _pad: [0; 2],
     };
 
     let now = unsafe { bpf_ktime_get_ns() };
@@ -79,14 +84,27 @@ pub fn handle_ipv4(ctx: TcContext) -> Result<i32, i32> {
         return Ok(TC_ACT_PIPE);
     }
 
-    if should_insert {
-        let _ = CONNTRACK_V4.insert(ct_key, ConntrackValue { last_seen_ns: now }, 0);
-    }
+    // Only new flows are evaluated; established and reply traffic was let through above
+    let action = verdict(src_id, dst_id, dst_port, proto, direction);
 
-    info!(
+    debug!(
         &ctx,
-        "L4: src: {}:{}; dst: {}:{}", src_id, src_port, dst_id, dst_port
+        "L4: src: {}:{}; dst: {}:{}; direction: {}; action: {}",
+        src_id,
+        src_port,
+        dst_id,
+        dst_port,
+        direction as u8,
+        action as u8
     );
 
+    if action == Action::Deny {
+        return Ok(TC_ACT_SHOT);
+    }
+
+    if opens_flow {
+        let _ = CONNTRACK_V4.insert(ct_key, ConntrackValue { last_seen_ns: now }, 0);
+    }
+
     Ok(TC_ACT_PIPE)
 }
@@ -0,0 +1,114 @@
+use aya_ebpf::{
+    bindings::{TC_ACT_PIPE, TC_ACT_SHOT},
+    helpers::bpf_ktime_get_ns,
+    maps::lpm_trie::Key as LpmKey,
+    programs::TcContext,
+};
+use aya_log_ebpf::debug;
+use mesh_cni_ebpf_common::{
+    Direction,
+    conntrack::{ConntrackKeyV6, ConntrackValue},
+    ipv6::{Transport, transport_header},
+    policy::Action,
+};
+use network_types::{eth::EthHdr, ip::Ipv6Hdr};
+
+use crate::{
+    CONNTRACK_V6, id_v6,
+    l4::{L4, load_l4},
+    verdict,
+};
+
+#[inline]
+pub fn handle_ipv6(ctx: TcContext, direction: Direction) -> Result<i32, i32> {
+    let ipv6hdr: Ipv6Hdr = ctx.load(EthHdr::LEN).map_err(|_| TC_ACT_PIPE)?;
+
+    let src = u128::from_be_bytes(ipv6hdr.src_addr);
+    let dst = u128::from_be_bytes(ipv6hdr.dst_addr);
+
+    // LpmTrie expects big endian order for comparisons
+    let src_id = id_v6(LpmKey::new(128, src.to_be()));
+    let dst_id = id_v6(LpmKey::new(128, dst.to_be()));
+
+    let transport = transport_header(
+        EthHdr::LEN + Ipv6Hdr::LEN,
+        ipv6hdr.next_hdr as u8,
+        |offset| ctx.load::<u8>(offset),
+    )
+    .map_err(|_| TC_ACT_PIPE)?;
+    let (l4_offset, l4_proto) = match transport {
+        Transport::Header { offset, proto } => (offset, proto),
+        // without ports only rules allowing any port and protocol can let the packet through
+        Transport::Fragment | Transport::TooManyHeaders => {
+            if verdict(src_id, dst_id, 0, 0, direction) == Action::Deny {
+                return Ok(TC_ACT_SHOT);
+            }
+            return Ok(TC_ACT_PIPE);
+        }
+    };
+
+    let Some(L4 {
+        proto,
+        src_port,
+        dst_port,
+        opens_flow,
+    }) = load_l4(&ctx, l4_offset, l4_proto).map_err(|_| TC_ACT_PIPE)?
+    else {
+        return Ok(TC_ACT_PIPE);
+    };
+
+    // Replies cross the hook of the opposite direction from the one that created the
+    // entry, see handle_ipv4
+    let ct_key = ConntrackKeyV6 {
+        src_ip: ipv6hdr.src_addr,
+        dst_ip: ipv6hdr.dst_addr,
+        src_port,
+        dst_port,
+        proto,
+        direction: direction as u8,
+        _pad: [0; 2],
+    };
+    let ct_rev = ConntrackKeyV6 {
+        src_ip: ipv6hdr.dst_addr,
+        dst_ip: ipv6hdr.src_addr,
+        src_port: dst_port,
+        dst_port: src_port,
+        proto,
+        direction: direction.reverse() as u8,
+        _pad: [0; 2],
+    };
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    if unsafe { CONNTRACK_V6.get(ct_key) }.is_some() {
+        let _ = CONNTRACK_V6.insert(ct_key, ConntrackValue { last_seen_ns: now }, 0);
+        return Ok(TC_ACT_PIPE);
+    }
+    if unsafe { CONNTRACK_V6.get(ct_rev) }.is_some() {
+        let _ = CONNTRACK_V6.insert(ct_rev, ConntrackValue { last_seen_ns: now }, 0);
+        return Ok(TC_ACT_PIPE);
+    }
+
+    // Only new flows are evaluated; established and reply traffic was let through above
+    let action = verdict(src_id, dst_id, dst_port, proto, direction);
+
+    debug!(
+        &ctx,
+        "L4 v6: src: {}:{}; dst: {}:{}; direction: {}; action: {}",
+        src_id,
+        src_port,
+        dst_id,
+        dst_port,
+        direction as u8,
+        action as u8
+    );
+
+    if action == Action::Deny {
+        return Ok(TC_ACT_SHOT);
+    }
+
+    if opens_flow {
+        let _ = CONNTRACK_V6.insert(ct_key, ConntrackValue { last_seen_ns: now }, 0);
+    }
+
+    Ok(TC_ACT_PIPE)
+}
@@ -0,0 +1,60 @@
+use aya_ebpf::programs::TcContext;
+use network_types::{ip::IpProto, tcp::TcpHdr, udp::UdpHdr};
+
+/// Transport fields of a packet that policy is evaluated on
+pub(crate) struct L4 {
+    pub proto: u8,
+    /// Stored in host order
+    pub src_port: u16,
+    /// Stored in host order
+    pub dst_port: u16,
+    /// Whether the packet opens a flow that conntrack should remember
+    pub opens_flow: bool,
+}
+
+/// Offset of the first chunk type within an SCTP packet
+const SCTP_CHUNK_OFF: usize = 12;
+/// Chunk type of the INIT chunk opening an SCTP association
+const SCTP_INIT: u8 = 1;
+
+/// Loads the transport header at `offset`. ICMP returns `None` and is passed through by the
+/// callers, it carries the errors of flows already let through that path MTU discovery
+/// relies on. Other protocols without ports are evaluated on port 0.
+#[inline]
+pub(crate) fn load_l4(ctx: &TcContext, offset: usize, proto: u8) -> Result<Option<L4>, ()> {
+    if proto == IpProto::Tcp as u8 {
+        let tcphdr: TcpHdr = ctx.load(offset).map_err(|_| ())?;
+        Ok(Some(L4 {
+            proto,
+            src_port: u16::from_be_bytes(tcphdr.source),
+            dst_port: u16::from_be_bytes(tcphdr.dest),
+            opens_flow: tcphdr.syn() == 1 && tcphdr.ack() == 0,
+        }))
+    } else if proto == IpProto::Udp as u8 {
+        let udphdr: UdpHdr = ctx.load(offset).map_err(|_| ())?;
+        Ok(Some(L4 {
+            proto,
+            src_port: u16::from_be_bytes(udphdr.src),
+            dst_port: u16::from_be_bytes(udphdr.dst),
+            opens_flow: true,
+        }))
+    } else if proto == IpProto::Sctp as u8 {
+        let ports: [u8; 4] = ctx.load(offset).map_err(|_| ())?;
+        let chunk_type: u8 = ctx.load(offset + SCTP_CHUNK_OFF).map_err(|_| ())?;
+        Ok(Some(L4 {
+            proto,
+            src_port: u16::from_be_bytes([ports[0], ports[1]]),
+            dst_port: u16::from_be_bytes([ports[2], ports[3]]),
+            opens_flow: chunk_type == SCTP_INIT,
+        }))
+    } else if proto == IpProto::Icmp as u8 || proto == IpProto::Ipv6Icmp as u8 {
+        Ok(None)
+    } else {
+        Ok(Some(L4 {
+            proto,
+            src_port: 0,
+            dst_port: 0,
+            opens_flow: true,
+        }))
+    }
+}
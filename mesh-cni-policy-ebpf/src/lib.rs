@@ -1,16 +1,20 @@
 #![no_std]
 
+pub mod egress;
 pub mod ingress;
 mod ipv4;
+mod ipv6;
+mod l4;
+mod tc;
 
 use aya_ebpf::{
     macros::map,
     maps::{HashMap, LpmTrie, LruHashMap, lpm_trie::Key as LpmKey},
 };
 use mesh_cni_ebpf_common::{
-    IdentityId,
-    conntrack::{ConntrackKeyV4, ConntrackValue},
-    policy::{PolicyKey, PolicyValue},
+    Direction, IdentityId, WORLD_ID,
+    conntrack::{ConntrackKeyV4, ConntrackKeyV6, ConntrackValue},
+    policy::{Action, POLICY_MAP_ENTRIES, PolicyKey, PolicyValue},
 };
 
 #[map(name = "identity_v4")]
@@ -23,15 +27,72 @@ static IDENTITY_V6: LpmTrie<u128, IdentityId> = LpmTrie::with_max_entries(65535,
 static CONNTRACK_V4: LruHashMap<ConntrackKeyV4, ConntrackValue> =
     LruHashMap::with_max_entries(65535, 0);
 
+#[map(name = "conntrack_v6")]
+static CONNTRACK_V6: LruHashMap<ConntrackKeyV6, ConntrackValue> =
+    LruHashMap::with_max_entries(65535, 0);
+
 #[map(name = "policy")]
-static POLICY: HashMap<PolicyKey, PolicyValue> = HashMap::with_max_entries(65535, 0);
+static POLICY: HashMap<PolicyKey, PolicyValue> = HashMap::with_max_entries(POLICY_MAP_ENTRIES, 0);
+
+/// Identity of an address. Addresses the identity maps do not know are outside the cluster
+/// and policies see them as the world.
+#[inline]
+fn id_v4(ip: LpmKey<u32>) -> IdentityId {
+    IDENTITY_V4.get(&ip).copied().unwrap_or(WORLD_ID)
+}
+
+#[inline]
+fn id_v6(ip: LpmKey<u128>) -> IdentityId {
+    IDENTITY_V6.get(&ip).copied().unwrap_or(WORLD_ID)
+}
+
+/// Looks up the policy action for a flow, falling back to the port and protocol
+/// wildcards in the order documented on `PolicyKey`.
+#[inline]
+fn policy_action(
+    src_id: IdentityId,
+    dst_id: IdentityId,
+    dst_port: u16,
+    proto: u8,
+    direction: Direction,
+) -> Option<Action> {
+    let keys = [
+        PolicyKey::new(src_id, dst_id, dst_port, proto, direction),
+        PolicyKey::new(src_id, dst_id, 0, proto, direction),
+        PolicyKey::new(src_id, dst_id, dst_port, 0, direction),
+        PolicyKey::new(src_id, dst_id, 0, 0, direction),
+    ];
+    for key in keys.iter() {
+        if let Some(value) = unsafe { POLICY.get(key) } {
+            return Some(Action::from(value.action));
+        }
+    }
+    None
+}
 
+/// An identity is isolated once any policy of the given direction selects it
 #[inline]
-fn id_v4(ip: LpmKey<u32>) -> Option<IdentityId> {
-    IDENTITY_V4.get(&ip).copied()
+fn is_isolated(id: IdentityId, direction: Direction) -> bool {
+    unsafe { POLICY.get(&PolicyKey::isolation(id, direction)) }.is_some()
 }
 
+/// Evaluates a new flow. Isolated identities require an explicit allow, everything else
+/// defaults to allow.
 #[inline]
-fn _id_v6(ip: LpmKey<u128>) -> Option<IdentityId> {
-    IDENTITY_V6.get(&ip).copied()
+fn verdict(
+    src_id: IdentityId,
+    dst_id: IdentityId,
+    dst_port: u16,
+    proto: u8,
+    direction: Direction,
+) -> Action {
+    let selected_id = match direction {
+        Direction::Ingress => dst_id,
+        Direction::Egress => src_id,
+    };
+    match policy_action(src_id, dst_id, dst_port, proto, direction) {
+        Some(action) => action,
+        None if is_isolated(selected_id, direction) => Action::Deny,
+        None => Action::Allow,
+    }
 }
@@ -0,0 +1,20 @@
+use aya_ebpf::{bindings::TC_ACT_PIPE, programs::TcContext};
+use mesh_cni_ebpf_common::Direction;
+use network_types::eth::{EthHdr, EtherType};
+
+use crate::{ipv4::handle_ipv4, ipv6::handle_ipv6};
+
+#[inline]
+pub(crate) fn classify(ctx: TcContext, direction: Direction) -> Result<i32, i32> {
+    let ethhdr: EthHdr = ctx.load(0).map_err(|_| TC_ACT_PIPE)?;
+
+    let Ok(ether_type) = ethhdr.ether_type() else {
+        return Ok(TC_ACT_PIPE);
+    };
+
+    match ether_type {
+        EtherType::Ipv4 => handle_ipv4(ctx, direction),
+        EtherType::Ipv6 => handle_ipv6(ctx, direction),
+        _ => Ok(TC_ACT_PIPE),
+    }
+}
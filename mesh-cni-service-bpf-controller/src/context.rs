@@ -1,6 +1,6 @@
 use k8s_openapi::api::{core::v1::Service, discovery::v1::EndpointSlice};
-use kube::runtime::reflector::Store;
-use mesh_cni_crds::v1alpha1::meshendpoint::MeshEndpoint;
+use kube::runtime::{events::Recorder, reflector::Store};
+use mesh_cni_crds::v1alpha1::meshendpoint::{MeshEndpoint, Topology};
 
 use crate::ServiceBpfState;
 
@@ -12,4 +12,6 @@ where
     pub endpoint_slice_state: Store<EndpointSlice>,
     pub mesh_endpoint_state: Store<MeshEndpoint>,
     pub service_bpf_state: B,
+    pub topology: Topology,
+    pub recorder: Recorder,
 }
@@ -1,27 +1,39 @@
 use std::{fmt::Debug, sync::Arc, time::Duration};
 
-use ahash::HashMap;
+use ahash::{HashMap, HashSet};
 use k8s_openapi::api::{core::v1::Service, discovery::v1::EndpointSlice};
 use kube::{
-    ResourceExt,
+    Resource, ResourceExt,
     core::{Expression, Selector, SelectorExt},
-    runtime::{controller::Action, reflector::ObjectRef},
+    runtime::{
+        controller::Action,
+        events::{Event, EventType},
+        reflector::ObjectRef,
+    },
 };
-use mesh_cni_crds::v1alpha1::meshendpoint::{MeshEndpoint, generate_mesh_endpoint_spec};
-use mesh_cni_ebpf_common::service::{EndpointValue, ServiceKey};
+use mesh_cni_crds::v1alpha1::meshendpoint::{
+    FrontendBackends, MeshEndpoint, SourceRange, generate_mesh_endpoint_spec,
+};
+use mesh_cni_ebpf_common::service::{ServiceKey, ServiceKeyV6, ServiceOptions};
 use serde::de::DeserializeOwned;
-use tracing::{error, info};
+use tracing::{error, info, warn};
 
 use crate::{Context, Error, MESH_SERVICE, Result, ServiceBpfState};
 
 pub const SERVICE_OWNER_LABEL: &str = "kubernetes.io/service-name";
 
+/// Options of a service along with the backends and source ranges of each of its frontends
+pub type ServicePairs = (
+    ServiceOptions,
+    HashMap<ServiceKey, FrontendBackends>,
+    HashMap<ServiceKey, Vec<SourceRange>>,
+);
+
 pub trait MeshControllerExt<B>
 where
     B: ServiceBpfState + Clone + Send + Sync + 'static,
 {
-    fn generate_service_pairs(&self, state: &Context<B>)
-    -> HashMap<ServiceKey, Vec<EndpointValue>>;
+    fn generate_service_pairs(&self, state: &Context<B>) -> ServicePairs;
     fn is_current(&self, state: &Context<B>) -> bool;
 }
 
@@ -29,11 +41,12 @@ impl<B> MeshControllerExt<B> for MeshEndpoint
 where
     B: ServiceBpfState + Clone + Send + Sync + 'static,
 {
-    fn generate_service_pairs(
-        &self,
-        _state: &Context<B>,
-    ) -> HashMap<ServiceKey, Vec<EndpointValue>> {
-        self.generate_bpf_service_endpoints()
+    fn generate_service_pairs(&self, state: &Context<B>) -> ServicePairs {
+        (
+            self.service_options(),
+            self.generate_bpf_service_endpoints(&state.topology),
+            self.source_ranges(),
+        )
     }
     fn is_current(&self, state: &Context<B>) -> bool {
         let Some(cached) = state
@@ -50,13 +63,14 @@ impl<B> MeshControllerExt<B> for Service
 where
     B: ServiceBpfState + Clone + Send + Sync + 'static,
 {
-    fn generate_service_pairs(
-        &self,
-        state: &Context<B>,
-    ) -> HashMap<ServiceKey, Vec<EndpointValue>> {
+    fn generate_service_pairs(&self, state: &Context<B>) -> ServicePairs {
         let spec = generate_mesh_endpoint_spec(&state.endpoint_slice_state, self);
         let mep = MeshEndpoint::new("dummy", spec);
-        mep.generate_bpf_service_endpoints()
+        (
+            mep.service_options(),
+            mep.generate_bpf_service_endpoints(&state.topology),
+            mep.source_ranges(),
+        )
     }
     fn is_current(&self, state: &Context<B>) -> bool {
         let Some(cached) = state
@@ -73,10 +87,7 @@ impl<B> MeshControllerExt<B> for EndpointSlice
 where
     B: ServiceBpfState + Clone + Send + Sync + 'static,
 {
-    fn generate_service_pairs(
-        &self,
-        state: &Context<B>,
-    ) -> HashMap<ServiceKey, Vec<EndpointValue>> {
+    fn generate_service_pairs(&self, state: &Context<B>) -> ServicePairs {
         let selector: Selector =
             Expression::Equal(SERVICE_OWNER_LABEL.into(), self.name_any()).into();
         let service: Vec<Arc<Service>> = state
@@ -87,11 +98,19 @@ where
             .cloned()
             .collect();
         let Some(service) = service.first() else {
-            return HashMap::default();
+            return (
+                ServiceOptions::default(),
+                HashMap::default(),
+                HashMap::default(),
+            );
         };
         let spec = generate_mesh_endpoint_spec(&state.endpoint_slice_state, service);
         let mep = MeshEndpoint::new("dummy", spec);
-        mep.generate_bpf_service_endpoints()
+        (
+            mep.service_options(),
+            mep.generate_bpf_service_endpoints(&state.topology),
+            mep.source_ranges(),
+        )
     }
     fn is_current(&self, state: &Context<B>) -> bool {
         let Some(cached) = state
@@ -106,6 +125,28 @@ where
     }
 }
 
+/// Frontends of every listed Service and MeshEndpoint the controllers program
+pub(crate) fn live_service_keys<B>(ctx: &Context<B>) -> HashSet<ServiceKey>
+where
+    B: ServiceBpfState + Clone + Send + Sync + 'static,
+{
+    let selector: Selector = Expression::Equal(MESH_SERVICE.into(), "true".into()).into();
+    let services = ctx.service_state.state();
+    let mesh_endpoints = ctx.mesh_endpoint_state.state();
+    let services = services
+        .iter()
+        .filter(|s| !selector.matches(s.labels()) && s.meta().deletion_timestamp.is_none())
+        .map(|s| s.generate_service_pairs(ctx));
+    let mesh_endpoints = mesh_endpoints
+        .iter()
+        .filter(|m| !selector.matches(m.labels()) && m.meta().deletion_timestamp.is_none())
+        .map(|m| m.generate_service_pairs(ctx));
+    services
+        .chain(mesh_endpoints)
+        .flat_map(|(_, service_pairs, _)| service_pairs.into_keys())
+        .collect()
+}
+
 pub async fn reconcile<K, B>(k: Arc<K>, ctx: Arc<Context<B>>) -> Result<Action>
 where
     K: MeshControllerExt<B>,
@@ -131,20 +172,63 @@ where
         );
         return Ok(Action::requeue(Duration::from_millis(200)));
     }
-    let service_pairs = k.generate_service_pairs(&ctx);
+    let (options, service_pairs, source_ranges) = k.generate_service_pairs(&ctx);
 
     if k.meta().deletion_timestamp.is_some() {
         for key in service_pairs.keys() {
             ctx.service_bpf_state.remove(key)?;
         }
     }
-    for (key, val) in service_pairs.iter() {
-        ctx.service_bpf_state.update(*key, val.to_owned())?;
+    for (key, backends) in service_pairs.iter() {
+        // only the IPv4 host datapath enforces source ranges, a restricted IPv6 frontend is
+        // left out rather than accepting every client
+        if let ServiceKey::V6(v6_key) = key
+            && source_ranges.contains_key(key)
+        {
+            ctx.service_bpf_state.remove(key)?;
+            refuse_source_ranges(&ctx, k.as_ref(), v6_key).await;
+            continue;
+        }
+        let ranges = source_ranges.get(key).cloned().unwrap_or_default();
+        let options = ServiceOptions {
+            local_backends: backends.local,
+            ..options
+        };
+        ctx.service_bpf_state
+            .update(*key, options, ranges, backends.endpoints.clone())?;
     }
 
     Ok(Action::await_change())
 }
 
+/// Records that a frontend was left out as its source ranges cannot be enforced
+async fn refuse_source_ranges<K, B>(ctx: &Context<B>, k: &K, key: &ServiceKeyV6)
+where
+    K: Resource<DynamicType = ()>,
+    B: ServiceBpfState + Clone + Send + Sync + 'static,
+{
+    let note = format!(
+        "not serving the IPv6 external address on port {}, loadBalancerSourceRanges are only \
+         enforced for IPv4",
+        key.port
+    );
+    warn!(
+        "{} of {}",
+        note,
+        k.meta().name.as_deref().unwrap_or_default()
+    );
+    let event = Event {
+        type_: EventType::Warning,
+        reason: "SourceRangesUnsupported".into(),
+        note: Some(note),
+        action: "ProgramService".into(),
+        secondary: None,
+    };
+    if let Err(e) = ctx.recorder.publish(&event, &k.object_ref(&())).await {
+        warn!("failed to record event for unenforced source ranges: {}", e);
+    }
+}
+
 pub fn error_policy<K, B>(_service: Arc<K>, error: &Error, _ctx: Arc<Context<B>>) -> Action
 where
     K: MeshControllerExt<B>,
@@ -5,14 +5,28 @@ mod runtime;
 mod utils;
 
 pub use context::Context;
-pub use controller::{MeshControllerExt, SERVICE_OWNER_LABEL};
+pub use controller::{MeshControllerExt, SERVICE_OWNER_LABEL, ServicePairs};
 pub use error::{Error, Result};
-use mesh_cni_ebpf_common::service::{EndpointValue, ServiceKey};
-pub use runtime::{start_bpf_meshendpoint_controller, start_bpf_service_controller};
+use mesh_cni_crds::v1alpha1::meshendpoint::SourceRange;
+use mesh_cni_ebpf_common::service::{EndpointValue, ServiceKey, ServiceOptions};
+pub use runtime::{
+    start_bpf_meshendpoint_controller, start_bpf_service_controller, sweep_bpf_services,
+};
 
 pub const MESH_SERVICE: &str = "mesh-cni.dev/multi-cluster";
 
 pub trait ServiceBpfState {
-    fn update(&self, key: ServiceKey, value: Vec<EndpointValue>) -> Result<()>;
+    /// Programs the backends of a frontend. `source_ranges` restricts the clients it
+    /// accepts, empty allows any. Only IPv4 frontends can be restricted.
+    fn update(
+        &self,
+        key: ServiceKey,
+        options: ServiceOptions,
+        source_ranges: Vec<SourceRange>,
+        value: Vec<EndpointValue>,
+    ) -> Result<()>;
     fn remove(&self, key: &ServiceKey) -> Result<()>;
+    /// Removes every frontend missing from `live` along with anything in the maps no
+    /// frontend references
+    fn sweep(&self, live: &ahash::HashSet<ServiceKey>) -> Result<()>;
 }
@@ -7,17 +7,19 @@ use kube::{
     core::{Expression, Selector},
     runtime::{
         Controller,
+        events::Recorder,
         reflector::{ReflectHandle, Store as KubeStore},
     },
 };
-use mesh_cni_crds::v1alpha1::meshendpoint::MeshEndpoint;
+use mesh_cni_crds::v1alpha1::meshendpoint::{MeshEndpoint, Topology};
+use mesh_cni_k8s_utils::InitialReconcile;
 use serde::de::DeserializeOwned;
 use tokio_util::sync::CancellationToken;
 use tracing::info;
 
 use crate::{
     Context, MESH_SERVICE, MeshControllerExt, Result, ServiceBpfState,
-    controller::{error_policy, reconcile},
+    controller::{error_policy, live_service_keys, reconcile},
     utils::shutdown,
 };
 
@@ -28,6 +30,9 @@ pub async fn start_bpf_service_controller<B>(
     endpoint_slice_stream: ReflectHandle<EndpointSlice>,
     mesh_endpoint_state: KubeStore<MeshEndpoint>,
     service_bpf_state: B,
+    topology: Topology,
+    recorder: Recorder,
+    mut initial: InitialReconcile,
     cancel: CancellationToken,
 ) -> Result<()>
 where
@@ -38,6 +43,8 @@ where
         endpoint_slice_state,
         mesh_endpoint_state,
         service_bpf_state,
+        topology,
+        recorder,
     };
 
     info!("Starting Services controller");
@@ -45,17 +52,50 @@ where
         .graceful_shutdown_on(shutdown(cancel))
         .owns_shared_stream(endpoint_slice_stream)
         .run(reconcile, error_policy::<Service, B>, Arc::new(context))
-        .for_each(|_| futures::future::ready(()))
+        .for_each(move |result| {
+            initial.observe(&result);
+            futures::future::ready(())
+        })
         .await;
     Ok(())
 }
 
+/// Removes the frontends of Services and MeshEndpoints deleted while the agent was not
+/// running. Call it once the stores hold the initial list and before the controllers
+/// start.
+pub fn sweep_bpf_services<B>(
+    service_state: KubeStore<Service>,
+    endpoint_slice_state: KubeStore<EndpointSlice>,
+    mesh_endpoint_state: KubeStore<MeshEndpoint>,
+    service_bpf_state: B,
+    topology: Topology,
+    recorder: Recorder,
+) -> Result<()>
+where
+    B: ServiceBpfState + Clone + Send + Sync + 'static,
+{
+    let context = Context {
+        service_state,
+        endpoint_slice_state,
+        mesh_endpoint_state,
+        service_bpf_state,
+        topology,
+        recorder,
+    };
+    let live = live_service_keys(&context);
+    info!("sweeping service maps, {} frontends are live", live.len());
+    context.service_bpf_state.sweep(&live)
+}
+
 pub async fn start_bpf_meshendpoint_controller<K, B>(
     api: Api<K>,
     service_state: KubeStore<Service>,
     endpoint_slice_state: KubeStore<EndpointSlice>,
     mesh_endpoint_state: KubeStore<MeshEndpoint>,
     service_bpf_state: B,
+    topology: Topology,
+    recorder: Recorder,
+    mut initial: InitialReconcile,
     cancel: CancellationToken,
 ) -> Result<()>
 where
@@ -69,6 +109,8 @@ where
         endpoint_slice_state,
         mesh_endpoint_state,
         service_bpf_state,
+        topology,
+        recorder,
     };
 
     let selector: Selector = Expression::NotEqual(MESH_SERVICE.into(), "true".into()).into();
@@ -78,8 +120,10 @@ where
     Controller::new(api, watcher_config)
         .graceful_shutdown_on(shutdown(cancel))
         .run(reconcile, error_policy::<K, B>, Arc::new(context))
-        .filter_map(|x| async move { std::result::Result::ok(x) })
-        .for_each(|_| futures::future::ready(()))
+        .for_each(move |result| {
+            initial.observe(&result);
+            futures::future::ready(())
+        })
         .await;
     Ok(())
 }
@@ -1,10 +1,16 @@
 #![no_std]
 
+pub mod nodeport;
 pub mod service;
 
-use aya_ebpf::{macros::map, maps::HashMap};
+use aya_ebpf::{
+    macros::map,
+    maps::{HashMap, LruHashMap},
+};
 use mesh_cni_ebpf_common::service::{
-    EndpointKey, EndpointValueV4, EndpointValueV6, ServiceKeyV4, ServiceKeyV6, ServiceValue,
+    AffinityKey, AffinityValue, EndpointKey, EndpointValueV4, EndpointValueV6, MaglevKey,
+    MaglevTable, NatKeyV4, NatValueV4, ReverseNatKeyV4, ReverseNatKeyV6, ReverseNatValueV4,
+    ReverseNatValueV6, ServiceKeyV4, ServiceKeyV6, ServiceValue, SourceRangesV4,
 };
 
 #[map(name = "services_v4")]
@@ -18,3 +24,28 @@ static ENDPOINTS_V4: HashMap<EndpointKey, EndpointValueV4> = HashMap::with_max_e
 
 #[map(name = "endpoints_v6")]
 static ENDPOINTS_V6: HashMap<EndpointKey, EndpointValueV6> = HashMap::with_max_entries(65535, 0);
+
+#[map(name = "reverse_nat_v4")]
+static REVERSE_NAT_V4: LruHashMap<ReverseNatKeyV4, ReverseNatValueV4> =
+    LruHashMap::with_max_entries(65535, 0);
+
+#[map(name = "reverse_nat_v6")]
+static REVERSE_NAT_V6: LruHashMap<ReverseNatKeyV6, ReverseNatValueV6> =
+    LruHashMap::with_max_entries(65535, 0);
+
+#[map(name = "affinity")]
+static AFFINITY: LruHashMap<AffinityKey, AffinityValue> = LruHashMap::with_max_entries(65535, 0);
+
+// Only services using Maglev have a table, keep the limit low as each entry is ~2KiB. An
+// update briefly holds the tables of both backend sets.
+#[map(name = "maglev_v4")]
+static MAGLEV_V4: HashMap<MaglevKey, MaglevTable> = HashMap::with_max_entries(1024, 0);
+
+#[map(name = "maglev_v6")]
+static MAGLEV_V6: HashMap<MaglevKey, MaglevTable> = HashMap::with_max_entries(1024, 0);
+
+#[map(name = "nat_v4")]
+static NAT_V4: LruHashMap<NatKeyV4, NatValueV4> = LruHashMap::with_max_entries(65535, 0);
+
+#[map(name = "source_ranges_v4")]
+static SOURCE_RANGES_V4: HashMap<ServiceKeyV4, SourceRangesV4> = HashMap::with_max_entries(1024, 0);
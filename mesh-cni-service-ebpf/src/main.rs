@@ -1,8 +1,19 @@
 #![no_std]
 #![no_main]
 
-use aya_ebpf::{macros::cgroup_sock_addr, programs::SockAddrContext};
-use mesh_cni_service_ebpf::service::try_mesh_cni_cgroup_connect4;
+use aya_ebpf::{
+    macros::{cgroup_sock_addr, classifier},
+    programs::{SockAddrContext, TcContext},
+};
+use mesh_cni_service_ebpf::{
+    nodeport::{try_mesh_cni_nodeport_egress, try_mesh_cni_nodeport_ingress},
+    service::{
+        try_mesh_cni_cgroup_connect4, try_mesh_cni_cgroup_connect6,
+        try_mesh_cni_cgroup_getpeername4, try_mesh_cni_cgroup_getpeername6,
+        try_mesh_cni_cgroup_recvmsg4, try_mesh_cni_cgroup_recvmsg6, try_mesh_cni_cgroup_sendmsg4,
+        try_mesh_cni_cgroup_sendmsg6,
+    },
+};
 
 #[cgroup_sock_addr(connect4)]
 pub fn mesh_cni_cgroup_connect4(ctx: SockAddrContext) -> i32 {
@@ -12,6 +23,78 @@ pub fn mesh_cni_cgroup_connect4(ctx: SockAddrContext) -> i32 {
     }
 }
 
+#[cgroup_sock_addr(connect6)]
+pub fn mesh_cni_cgroup_connect6(ctx: SockAddrContext) -> i32 {
+    match try_mesh_cni_cgroup_connect6(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[cgroup_sock_addr(sendmsg4)]
+pub fn mesh_cni_cgroup_sendmsg4(ctx: SockAddrContext) -> i32 {
+    match try_mesh_cni_cgroup_sendmsg4(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[cgroup_sock_addr(sendmsg6)]
+pub fn mesh_cni_cgroup_sendmsg6(ctx: SockAddrContext) -> i32 {
+    match try_mesh_cni_cgroup_sendmsg6(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[cgroup_sock_addr(recvmsg4)]
+pub fn mesh_cni_cgroup_recvmsg4(ctx: SockAddrContext) -> i32 {
+    match try_mesh_cni_cgroup_recvmsg4(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[cgroup_sock_addr(recvmsg6)]
+pub fn mesh_cni_cgroup_recvmsg6(ctx: SockAddrContext) -> i32 {
+    match try_mesh_cni_cgroup_recvmsg6(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[cgroup_sock_addr(getpeername4)]
+pub fn mesh_cni_cgroup_getpeername4(ctx: SockAddrContext) -> i32 {
+    match try_mesh_cni_cgroup_getpeername4(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[cgroup_sock_addr(getpeername6)]
+pub fn mesh_cni_cgroup_getpeername6(ctx: SockAddrContext) -> i32 {
+    match try_mesh_cni_cgroup_getpeername6(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[classifier]
+pub fn mesh_cni_nodeport_ingress(ctx: TcContext) -> i32 {
+    match try_mesh_cni_nodeport_ingress(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
+#[classifier]
+pub fn mesh_cni_nodeport_egress(ctx: TcContext) -> i32 {
+    match try_mesh_cni_nodeport_egress(ctx) {
+        Ok(ret) => ret,
+        Err(ret) => ret,
+    }
+}
+
 #[cfg(not(test))]
 #[panic_handler]
 fn panic(_info: &core::panic::PanicInfo) -> ! {
@@ -0,0 +1,368 @@
+use core::{mem, net::Ipv4Addr, ptr};
+
+use aya_ebpf::{
+    bindings::{
+        BPF_F_MARK_MANGLED_0, BPF_F_PSEUDO_HDR, BPF_NOEXIST, TC_ACT_PIPE, TC_ACT_SHOT,
+        bpf_fib_lookup as FibLookup,
+    },
+    helpers::{
+        bpf_ktime_get_ns,
+        generated::{bpf_fib_lookup, bpf_get_prandom_u32, bpf_redirect_neigh},
+    },
+    programs::TcContext,
+};
+use aya_log_ebpf::info;
+use mesh_cni_ebpf_common::service::{
+    NatKeyV4, NatValueV4, SERVICE_FLAG_EXTERNAL_LOCAL, SERVICE_FLAG_MAGLEV, ServiceKeyV4,
+    ServiceValue, allocate_snat_port, maglev_slot,
+};
+use network_types::{
+    eth::{EthHdr, EtherType},
+    ip::IpProto,
+};
+
+use crate::{
+    ENDPOINTS_V4, MAGLEV_V4, NAT_V4, SERVICES_V4, SOURCE_RANGES_V4, service::get_position,
+};
+
+const AF_INET: u8 = 2;
+
+/// Offsets within the IPv4 header
+const IP_FRAG_OFF: usize = EthHdr::LEN + 6;
+const IP_CSUM_OFF: usize = EthHdr::LEN + 10;
+const IP_SRC_OFF: usize = EthHdr::LEN + 12;
+const IP_DST_OFF: usize = EthHdr::LEN + 16;
+
+/// Offsets of the checksum within the transport header
+const TCP_CSUM_OFF: usize = 16;
+const UDP_CSUM_OFF: usize = 6;
+
+/// Fragment offset bits of the flags and fragment offset field
+const IP_FRAG_OFFSET_MASK: u16 = 0x1fff;
+
+const BPF_FIB_LKUP_RET_SUCCESS: i64 = 0;
+/// The route resolved but the neighbor did not, `bpf_redirect_neigh` resolves it itself
+const BPF_FIB_LKUP_RET_NO_NEIGH: i64 = 7;
+
+/// Addresses of a TCP or UDP packet, stored in host order
+struct FlowV4 {
+    src_ip: u32,
+    dst_ip: u32,
+    src_port: u16,
+    dst_port: u16,
+    proto: u8,
+    l4_offset: usize,
+}
+
+impl FlowV4 {
+    #[inline]
+    fn key(&self) -> NatKeyV4 {
+        NatKeyV4::new(
+            self.src_ip,
+            self.dst_ip,
+            self.src_port,
+            self.dst_port,
+            self.proto,
+        )
+    }
+}
+
+/// Translates `nodeIP:nodePort` and the external addresses of services to a backend for
+/// traffic arriving on the external interface and reverses the translation on replies
+/// coming back from remote backends.
+///
+/// Backends on other nodes would answer the client directly, so those flows are also
+/// translated to come from the node and redirected back out the interface. Their source
+/// port is replaced by one unique per backend so replies find their client again.
+#[inline]
+pub fn try_mesh_cni_nodeport_ingress(mut ctx: TcContext) -> Result<i32, i32> {
+    let Some(flow) = load_flow_v4(&ctx)? else {
+        return Ok(TC_ACT_PIPE);
+    };
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    if let Some(nat) = lookup_nat_v4(&flow, now) {
+        rewrite_v4(&mut ctx, &flow, &nat)?;
+        if nat.redirect == 0 {
+            return Ok(TC_ACT_PIPE);
+        }
+        return Ok(redirect_v4(&ctx, nat.src_ip, nat.dst_ip));
+    }
+
+    let service_key = ServiceKeyV4::new(flow.dst_ip, flow.dst_port, flow.proto);
+    // only new flows are checked, replies and established flows matched a NAT entry above
+    if let Some(source_ranges) = unsafe { SOURCE_RANGES_V4.get(service_key) }
+        && !source_ranges.allows(flow.src_ip)
+    {
+        return Ok(TC_ACT_SHOT);
+    }
+    let mut backend = None;
+    // a miss means the backend set was replaced after the service was read, see
+    // translate_v4
+    for _ in 0..2 {
+        let Some(service_value) = (unsafe { SERVICES_V4.get(service_key).copied() }) else {
+            return Ok(TC_ACT_PIPE);
+        };
+        // `externalTrafficPolicy: Local` frontends only send to the backends on this node
+        let count = service_value.external_count();
+        if count == 0 {
+            return Ok(TC_ACT_SHOT);
+        }
+        let position = nodeport_position(&service_value, count, &flow);
+        backend = unsafe {
+            ENDPOINTS_V4
+                .get(service_value.endpoint_key(position))
+                .copied()
+        };
+        if backend.is_some() {
+            break;
+        }
+    }
+    let Some(backend) = backend else {
+        return Ok(TC_ACT_SHOT);
+    };
+
+    // pods of this node are routed through their veth, anything routed back out of the
+    // interface the packet arrived on lives on another node
+    let Some(ifindex) = fib_ifindex_v4(&ctx, flow.src_ip, backend.ip) else {
+        return Ok(TC_ACT_SHOT);
+    };
+    let remote = ifindex == unsafe { (*ctx.skb.skb).ifindex };
+
+    let reply = NatValueV4 {
+        src_ip: flow.dst_ip,
+        dst_ip: flow.src_ip,
+        src_port: flow.dst_port,
+        dst_port: flow.src_port,
+        redirect: remote as u8,
+        _pad: [0; 3],
+        last_seen_ns: now,
+    };
+    let (src_ip, src_port) = if remote {
+        let seed = unsafe { bpf_get_prandom_u32() };
+        let port = allocate_snat_port(seed, |port| {
+            let reply_key = NatKeyV4::new(backend.ip, flow.dst_ip, backend.port, port, flow.proto);
+            if NAT_V4.insert(reply_key, reply, BPF_NOEXIST as u64).is_ok() {
+                return true;
+            }
+            // replies of the flow holding the port keep it alive
+            match unsafe { NAT_V4.get(reply_key) } {
+                Some(held) if !held.expired(now) => false,
+                _ => NAT_V4.insert(reply_key, reply, 0).is_ok(),
+            }
+        });
+        let Some(port) = port else {
+            return Ok(TC_ACT_SHOT);
+        };
+        (flow.dst_ip, port)
+    } else {
+        // the client tuple is unique already
+        let reply_key = NatKeyV4::new(
+            backend.ip,
+            flow.src_ip,
+            backend.port,
+            flow.src_port,
+            flow.proto,
+        );
+        NAT_V4
+            .insert(reply_key, reply, 0)
+            .map_err(|_| TC_ACT_SHOT)?;
+        (flow.src_ip, flow.src_port)
+    };
+
+    let forward = NatValueV4 {
+        src_ip,
+        dst_ip: backend.ip,
+        src_port,
+        dst_port: backend.port,
+        redirect: remote as u8,
+        _pad: [0; 3],
+        last_seen_ns: now,
+    };
+    NAT_V4
+        .insert(flow.key(), forward, 0)
+        .map_err(|_| TC_ACT_SHOT)?;
+
+    info!(
+        &ctx,
+        "nodeport {}:{} to {}:{}; remote: {}",
+        Ipv4Addr::from(flow.dst_ip),
+        flow.dst_port,
+        Ipv4Addr::from(backend.ip),
+        backend.port,
+        remote as u8
+    );
+
+    rewrite_v4(&mut ctx, &flow, &forward)?;
+    if remote {
+        return Ok(redirect_v4(&ctx, src_ip, backend.ip));
+    }
+    Ok(TC_ACT_PIPE)
+}
+
+/// Rewrites replies of node-local backends back to the NodePort address the client
+/// connected to.
+#[inline]
+pub fn try_mesh_cni_nodeport_egress(mut ctx: TcContext) -> Result<i32, i32> {
+    let Some(flow) = load_flow_v4(&ctx)? else {
+        return Ok(TC_ACT_PIPE);
+    };
+
+    let now = unsafe { bpf_ktime_get_ns() };
+    if let Some(nat) = lookup_nat_v4(&flow, now)
+        && nat.redirect == 0
+    {
+        rewrite_v4(&mut ctx, &flow, &nat)?;
+    }
+    Ok(TC_ACT_PIPE)
+}
+
+/// Translation of an existing flow, marking it as seen
+#[inline]
+fn lookup_nat_v4(flow: &FlowV4, now: u64) -> Option<NatValueV4> {
+    let nat = NAT_V4.get_ptr_mut(flow.key())?;
+    unsafe {
+        (*nat).last_seen_ns = now;
+        Some(*nat)
+    }
+}
+
+#[inline]
+fn load_flow_v4(ctx: &TcContext) -> Result<Option<FlowV4>, i32> {
+    let ethhdr: EthHdr = ctx.load(0).map_err(|_| TC_ACT_PIPE)?;
+    if !matches!(ethhdr.ether_type(), Ok(EtherType::Ipv4)) {
+        return Ok(None);
+    }
+
+    let vihl: u8 = ctx.load(EthHdr::LEN).map_err(|_| TC_ACT_PIPE)?;
+    let proto: u8 = ctx.load(EthHdr::LEN + 9).map_err(|_| TC_ACT_PIPE)?;
+    if proto != IpProto::Tcp as u8 && proto != IpProto::Udp as u8 {
+        return Ok(None);
+    }
+    // only the first fragment carries the ports, the rest is left to the host to reassemble
+    let frag: [u8; 2] = ctx.load(IP_FRAG_OFF).map_err(|_| TC_ACT_PIPE)?;
+    if u16::from_be_bytes(frag) & IP_FRAG_OFFSET_MASK != 0 {
+        return Ok(None);
+    }
+    let ihl = (vihl & 0x0f) as usize;
+    if ihl < 5 {
+        return Ok(None);
+    }
+    let src: [u8; 4] = ctx.load(IP_SRC_OFF).map_err(|_| TC_ACT_PIPE)?;
+    let dst: [u8; 4] = ctx.load(IP_DST_OFF).map_err(|_| TC_ACT_PIPE)?;
+
+    let l4_offset = EthHdr::LEN + ihl * 4;
+    let ports: [u8; 4] = ctx.load(l4_offset).map_err(|_| TC_ACT_PIPE)?;
+
+    Ok(Some(FlowV4 {
+        src_ip: u32::from_be_bytes(src),
+        dst_ip: u32::from_be_bytes(dst),
+        src_port: u16::from_be_bytes([ports[0], ports[1]]),
+        dst_port: u16::from_be_bytes([ports[2], ports[3]]),
+        proto,
+        l4_offset,
+    }))
+}
+
+/// Rewrites the addresses of `flow` to `nat`, fixing up the IP and transport checksums
+#[inline]
+fn rewrite_v4(ctx: &mut TcContext, flow: &FlowV4, nat: &NatValueV4) -> Result<(), i32> {
+    let (csum_off, mangled) = if flow.proto == IpProto::Tcp as u8 {
+        (flow.l4_offset + TCP_CSUM_OFF, 0)
+    } else {
+        // a zero UDP checksum means none was computed and has to stay that way
+        (flow.l4_offset + UDP_CSUM_OFF, BPF_F_MARK_MANGLED_0 as u64)
+    };
+
+    let addresses = [
+        (IP_SRC_OFF, flow.src_ip, nat.src_ip),
+        (IP_DST_OFF, flow.dst_ip, nat.dst_ip),
+    ];
+    for (offset, from, to) in addresses {
+        if from == to {
+            continue;
+        }
+        let (from, to) = (from.to_be(), to.to_be());
+        ctx.l4_csum_replace(
+            csum_off,
+            from as u64,
+            to as u64,
+            BPF_F_PSEUDO_HDR as u64 | mangled | 4,
+        )
+        .map_err(|_| TC_ACT_SHOT)?;
+        ctx.l3_csum_replace(IP_CSUM_OFF, from as u64, to as u64, 4)
+            .map_err(|_| TC_ACT_SHOT)?;
+        ctx.store(offset, &to, 0).map_err(|_| TC_ACT_SHOT)?;
+    }
+
+    let ports = [
+        (flow.l4_offset, flow.src_port, nat.src_port),
+        (flow.l4_offset + 2, flow.dst_port, nat.dst_port),
+    ];
+    for (offset, from, to) in ports {
+        if from == to {
+            continue;
+        }
+        let (from, to) = (from.to_be(), to.to_be());
+        ctx.l4_csum_replace(csum_off, from as u64, to as u64, mangled | 2)
+            .map_err(|_| TC_ACT_SHOT)?;
+        ctx.store(offset, &to, 0).map_err(|_| TC_ACT_SHOT)?;
+    }
+
+    Ok(())
+}
+
+/// Picks the backend of a new NodePort flow among the first `count`. Maglev hashes the
+/// client address so a client keeps landing on the same backend across nodes.
+#[inline]
+fn nodeport_position(service_value: &ServiceValue, count: u16, flow: &FlowV4) -> u16 {
+    if service_value.flags & SERVICE_FLAG_MAGLEV == 0 {
+        return get_position(count);
+    }
+    let hash = ((flow.src_ip as u64) << 32) | ((flow.src_port as u64) << 16) | flow.proto as u64;
+    // the table spreads clients over every backend while local frontends only use the ones
+    // on this node, which no other node sends to anyway
+    if service_value.flags & SERVICE_FLAG_EXTERNAL_LOCAL != 0 {
+        return (maglev_slot(hash) % count as usize) as u16;
+    }
+    let Some(table) = (unsafe { MAGLEV_V4.get(service_value.maglev_key()) }) else {
+        return get_position(count);
+    };
+    match table.positions.get(maglev_slot(hash)) {
+        Some(position) if *position < count => *position,
+        _ => get_position(count),
+    }
+}
+
+/// Interface the route from `src` to `dst` leaves through
+#[inline]
+fn fib_ifindex_v4(ctx: &TcContext, src: u32, dst: u32) -> Option<u32> {
+    let mut params: FibLookup = unsafe { mem::zeroed() };
+    params.family = AF_INET;
+    params.ifindex = unsafe { (*ctx.skb.skb).ifindex };
+    params.__bindgen_anon_3.ipv4_src = src.to_be();
+    params.__bindgen_anon_4.ipv4_dst = dst.to_be();
+
+    let ret = unsafe {
+        bpf_fib_lookup(
+            ctx.skb.skb as *mut _,
+            &mut params,
+            mem::size_of::<FibLookup>() as i32,
+            0,
+        )
+    };
+    if ret != BPF_FIB_LKUP_RET_SUCCESS && ret != BPF_FIB_LKUP_RET_NO_NEIGH {
+        return None;
+    }
+    Some(params.ifindex)
+}
+
+/// Sends a packet whose source is now the node out of the interface routing `dst`. The
+/// stack would drop it as martian, so it skips the stack and has its neighbor resolved.
+#[inline]
+fn redirect_v4(ctx: &TcContext, src: u32, dst: u32) -> i32 {
+    let Some(ifindex) = fib_ifindex_v4(ctx, src, dst) else {
+        return TC_ACT_SHOT;
+    };
+    unsafe { bpf_redirect_neigh(ifindex, ptr::null_mut(), 0, 0) as i32 }
+}
@@ -1,15 +1,29 @@
-use core::net::Ipv4Addr;
+use core::net::{Ipv4Addr, Ipv6Addr};
 
 use aya_ebpf::{
-    bindings::bpf_sock_addr, helpers::generated::bpf_get_prandom_u32, programs::SockAddrContext,
+    bindings::bpf_sock_addr,
+    helpers::{
+        bpf_ktime_get_ns,
+        generated::{bpf_get_netns_cookie, bpf_get_prandom_u32, bpf_get_socket_cookie},
+    },
+    maps::HashMap,
+    programs::SockAddrContext,
 };
 use aya_log_ebpf::{debug, info};
-use mesh_cni_ebpf_common::service::{EndpointKey, ServiceKeyV4};
+use mesh_cni_ebpf_common::service::{
+    AffinityKey, AffinityValue, EndpointKey, EndpointValueV4, EndpointValueV6, MaglevKey,
+    MaglevTable, ReverseNatKeyV4, ReverseNatKeyV6, ReverseNatValueV4, ReverseNatValueV6,
+    SERVICE_FLAG_AFFINITY, SERVICE_FLAG_MAGLEV, ServiceKeyV4, ServiceKeyV6, ServiceValue,
+    maglev_slot,
+};
 
-use crate::{ENDPOINTS_V4, SERVICES_V4};
+use crate::{
+    AFFINITY, ENDPOINTS_V4, ENDPOINTS_V6, MAGLEV_V4, MAGLEV_V6, REVERSE_NAT_V4, REVERSE_NAT_V6,
+    SERVICES_V4, SERVICES_V6,
+};
 
 const AF_INET: u16 = 2;
-const _AF_INET6: u16 = 10;
+const AF_INET6: u16 = 10;
 
 // https://docs.ebpf.io/linux/program-type/BPF_PROG_TYPE_CGROUP_SOCK_ADDR/#context
 // Example: https://docs.ebpf.io/linux/program-type/BPF_PROG_TYPE_CGROUP_SOCK_ADDR/#example
@@ -38,46 +52,137 @@ const _AF_INET6: u16 = 10;
 //
 //
 
+/// Translates the destination of a connecting socket and remembers the service so
+/// `getpeername4` reports it instead of the backend.
 ///
 /// Return codes [0(deny),1(allow)]
 #[inline]
 pub fn try_mesh_cni_cgroup_connect4(ctx: SockAddrContext) -> Result<i32, i32> {
+    if let Some((service_key, endpoint)) = translate_v4(&ctx)? {
+        record_v4(&ctx, service_key, endpoint);
+    }
+    Ok(1)
+}
+
+///
+/// Return codes [0(deny),1(allow)]
+#[inline]
+pub fn try_mesh_cni_cgroup_connect6(ctx: SockAddrContext) -> Result<i32, i32> {
+    if let Some((service_key, endpoint)) = translate_v6(&ctx)? {
+        record_v6(&ctx, service_key, endpoint);
+    }
+    Ok(1)
+}
+
+/// Translates the destination of each datagram sent on an unconnected UDP socket and
+/// remembers the service so `recvmsg4` can restore it on replies.
+///
+/// Return codes [0(deny),1(allow)]
+#[inline]
+pub fn try_mesh_cni_cgroup_sendmsg4(ctx: SockAddrContext) -> Result<i32, i32> {
+    if let Some((service_key, endpoint)) = translate_v4(&ctx)? {
+        record_v4(&ctx, service_key, endpoint);
+    }
+    Ok(1)
+}
+
+///
+/// Return codes [0(deny),1(allow)]
+#[inline]
+pub fn try_mesh_cni_cgroup_sendmsg6(ctx: SockAddrContext) -> Result<i32, i32> {
+    if let Some((service_key, endpoint)) = translate_v6(&ctx)? {
+        record_v6(&ctx, service_key, endpoint);
+    }
+    Ok(1)
+}
+
+/// Rewrites the source of a datagram received from a backend back to the service address
+/// the application sent to.
+///
+/// Return codes [1(allow)]
+#[inline]
+pub fn try_mesh_cni_cgroup_recvmsg4(ctx: SockAddrContext) -> Result<i32, i32> {
+    restore_v4(&ctx);
+    Ok(1)
+}
+
+///
+/// Return codes [1(allow)]
+#[inline]
+pub fn try_mesh_cni_cgroup_recvmsg6(ctx: SockAddrContext) -> Result<i32, i32> {
+    restore_v6(&ctx);
+    Ok(1)
+}
+
+/// Reports the service address instead of the backend a socket was connected to.
+///
+/// Return codes [1(allow)]
+#[inline]
+pub fn try_mesh_cni_cgroup_getpeername4(ctx: SockAddrContext) -> Result<i32, i32> {
+    restore_v4(&ctx);
+    Ok(1)
+}
+
+///
+/// Return codes [1(allow)]
+#[inline]
+pub fn try_mesh_cni_cgroup_getpeername6(ctx: SockAddrContext) -> Result<i32, i32> {
+    restore_v6(&ctx);
+    Ok(1)
+}
+
+/// Rewrites a v4 service destination to one of its backends. Returns the service and the
+/// chosen backend when a translation happened.
+#[inline]
+fn translate_v4(ctx: &SockAddrContext) -> Result<Option<(ServiceKeyV4, EndpointValueV4)>, i32> {
     let ptr = ctx.sock_addr;
 
     if unsafe { *ptr }.user_family != AF_INET as u32 {
-        return Ok(1);
+        return Ok(None);
     };
 
-    let service_key = build_service_key(&ctx, ptr)?;
-    let service_value = unsafe {
-        // TODO: investigate this behavior further.
-        // Best to copy to avoid aliasing/junk with deletes/updates happening concurrently
-        // however there may be better ways to handle this
-        match SERVICES_V4.get(service_key).copied() {
-            Some(value) => value,
-            None => {
-                debug!(&ctx, "did not find value for service key");
-                return Ok(1);
+    let service_key = build_service_key(ctx, ptr)?;
+    let mut endpoints_value = None;
+    // Userspace frees the slots of a backend set right after publishing its replacement, a
+    // miss means the service value read was already replaced so it is read once more. A
+    // second miss means the set was replaced again meanwhile and the connect is refused
+    // rather than sent to the service address nothing answers on.
+    for _ in 0..2 {
+        let service_value = unsafe {
+            // TODO: investigate this behavior further.
+            // Best to copy to avoid aliasing/junk with deletes/updates happening concurrently
+            // however there may be better ways to handle this
+            match SERVICES_V4.get(service_key).copied() {
+                Some(value) => value,
+                None => {
+                    debug!(ctx, "did not find value for service key");
+                    return Ok(None);
+                }
             }
+        };
+        if service_value.count == 0 {
+            return Err(0);
         }
-    };
-    if service_value.count == 0 {
-        return Err(0);
-    }
-    let position = get_position(service_value.count);
+        let position = select_position(ctx, &service_value, &MAGLEV_V4, &ENDPOINTS_V4);
 
-    let endpoints_value = unsafe {
-        match ENDPOINTS_V4.get(EndpointKey::new(service_value.id, position)) {
-            Some(value) => value,
-            None => return Ok(1),
+        endpoints_value = unsafe {
+            ENDPOINTS_V4
+                .get(service_value.endpoint_key(position))
+                .copied()
+        };
+        if endpoints_value.is_some() {
+            break;
         }
+    }
+    let Some(endpoints_value) = endpoints_value else {
+        return Err(0);
     };
 
     unsafe {
         (*ptr).user_ip4 = endpoints_value.ip.to_be();
         (*ptr).user_port = endpoints_value.port.to_be() as u32;
         info!(
-            &ctx,
+            ctx,
             "found matching service, replacing {}:{} with {}:{}",
             Ipv4Addr::from(service_key.ip),
             service_key.port,
@@ -86,7 +191,143 @@ pub fn try_mesh_cni_cgroup_connect4(ctx: SockAddrContext) -> Result<i32, i32> {
         );
     }
 
-    Ok(1)
+    Ok(Some((service_key, endpoints_value)))
+}
+
+#[inline]
+fn translate_v6(ctx: &SockAddrContext) -> Result<Option<(ServiceKeyV6, EndpointValueV6)>, i32> {
+    let ptr = ctx.sock_addr;
+
+    if unsafe { *ptr }.user_family != AF_INET6 as u32 {
+        return Ok(None);
+    };
+
+    let service_key = build_service_key_v6(ctx, ptr)?;
+    let mut endpoints_value = None;
+    // retried for the same reason as in translate_v4
+    for _ in 0..2 {
+        let service_value = unsafe {
+            // copied for the same reason as in translate_v4
+            match SERVICES_V6.get(service_key).copied() {
+                Some(value) => value,
+                None => {
+                    debug!(ctx, "did not find value for service key");
+                    return Ok(None);
+                }
+            }
+        };
+        if service_value.count == 0 {
+            return Err(0);
+        }
+        let position = select_position(ctx, &service_value, &MAGLEV_V6, &ENDPOINTS_V6);
+
+        endpoints_value = unsafe {
+            ENDPOINTS_V6
+                .get(service_value.endpoint_key(position))
+                .copied()
+        };
+        if endpoints_value.is_some() {
+            break;
+        }
+    }
+    let Some(endpoints_value) = endpoints_value else {
+        return Err(0);
+    };
+
+    unsafe {
+        (*ptr).user_ip6 = ip6_to_words(endpoints_value.ip);
+        (*ptr).user_port = endpoints_value.port.to_be() as u32;
+        info!(
+            ctx,
+            "found matching service, replacing [{}]:{} with [{}]:{}",
+            Ipv6Addr::from(service_key.ip),
+            service_key.port,
+            Ipv6Addr::from(endpoints_value.ip),
+            endpoints_value.port
+        );
+    }
+
+    Ok(Some((service_key, endpoints_value)))
+}
+
+/// Remembers the service a socket's backend was translated from
+#[inline]
+fn record_v4(ctx: &SockAddrContext, service_key: ServiceKeyV4, endpoint: EndpointValueV4) {
+    let key = ReverseNatKeyV4::new(socket_cookie(ctx), endpoint.ip, endpoint.port);
+    let value = ReverseNatValueV4 {
+        ip: service_key.ip,
+        port: service_key.port,
+        _pad: 0,
+    };
+    let _ = REVERSE_NAT_V4.insert(key, value, 0);
+}
+
+#[inline]
+fn record_v6(ctx: &SockAddrContext, service_key: ServiceKeyV6, endpoint: EndpointValueV6) {
+    let key = ReverseNatKeyV6::new(socket_cookie(ctx), endpoint.ip, endpoint.port);
+    let value = ReverseNatValueV6 {
+        ip: service_key.ip,
+        port: service_key.port,
+        _pad: [0; 14],
+    };
+    let _ = REVERSE_NAT_V6.insert(key, value, 0);
+}
+
+/// Rewrites a backend address reported to userspace back to the service address the socket
+/// was translated from. Addresses without a reverse entry are left alone.
+#[inline]
+fn restore_v4(ctx: &SockAddrContext) {
+    let ptr = ctx.sock_addr;
+
+    if unsafe { *ptr }.user_family != AF_INET as u32 {
+        return;
+    }
+
+    let (ip, port) = unsafe {
+        (
+            u32::from_be((*ptr).user_ip4),
+            u16::from_be((*ptr).user_port as u16),
+        )
+    };
+    let key = ReverseNatKeyV4::new(socket_cookie(ctx), ip, port);
+    let Some(service) = (unsafe { REVERSE_NAT_V4.get(key).copied() }) else {
+        return;
+    };
+
+    unsafe {
+        (*ptr).user_ip4 = service.ip.to_be();
+        (*ptr).user_port = service.port.to_be() as u32;
+    }
+}
+
+#[inline]
+fn restore_v6(ctx: &SockAddrContext) {
+    let ptr = ctx.sock_addr;
+
+    if unsafe { *ptr }.user_family != AF_INET6 as u32 {
+        return;
+    }
+
+    let (ip, port) = unsafe {
+        (
+            ip6_from_words((*ptr).user_ip6),
+            u16::from_be((*ptr).user_port as u16),
+        )
+    };
+    let key = ReverseNatKeyV6::new(socket_cookie(ctx), ip, port);
+    let Some(service) = (unsafe { REVERSE_NAT_V6.get(key).copied() }) else {
+        return;
+    };
+
+    unsafe {
+        (*ptr).user_ip6 = ip6_to_words(service.ip);
+        (*ptr).user_port = service.port.to_be() as u32;
+    }
+}
+
+#[inline]
+fn socket_cookie(ctx: &SockAddrContext) -> u64 {
+    unsafe { bpf_get_socket_cookie(ctx.sock_addr as *mut _) }
 }
 
 #[inline]
@@ -102,13 +343,166 @@ fn build_service_key(ctx: &SockAddrContext, ptr: *mut bpf_sock_addr) -> Result<S
     Ok(ServiceKeyV4::new(ip, port, protocol))
 }
 
+#[inline]
+fn build_service_key_v6(
+    ctx: &SockAddrContext,
+    ptr: *mut bpf_sock_addr,
+) -> Result<ServiceKeyV6, i32> {
+    let (ip, port, protocol) = unsafe {
+        let ip = ip6_from_words((*ptr).user_ip6);
+        let port = u16::from_be((*ptr).user_port as u16);
+        let protocol = (*ptr).protocol.try_into().map_err(|_| 1)?;
+        debug!(ctx, "built service key [{}]:{}", Ipv6Addr::from(ip), port);
+        (ip, port, protocol)
+    };
+
+    Ok(ServiceKeyV6::new(ip, port, protocol))
+}
+
+/// `user_ip6` holds the address in network order split across four words
+#[inline]
+fn ip6_from_words(words: [u32; 4]) -> u128 {
+    let mut bytes = [0u8; 16];
+    for (i, word) in words.iter().enumerate() {
+        bytes[i * 4..i * 4 + 4].copy_from_slice(&word.to_ne_bytes());
+    }
+    u128::from_be_bytes(bytes)
+}
+
+#[inline]
+fn ip6_to_words(ip: u128) -> [u32; 4] {
+    let bytes = ip.to_be_bytes();
+    let mut words = [0u32; 4];
+    for (i, word) in words.iter_mut().enumerate() {
+        *word = u32::from_ne_bytes([
+            bytes[i * 4],
+            bytes[i * 4 + 1],
+            bytes[i * 4 + 2],
+            bytes[i * 4 + 3],
+        ]);
+    }
+    words
+}
+
+/// Largest backend set searched for a pinned backend that moved to another slot, clients of
+/// larger sets are given a new backend instead
+const MAX_AFFINITY_SCAN: u16 = 256;
+
+/// Address of a backend as kept in the affinity map
+trait AffinityBackend {
+    fn affinity_backend(&self) -> (u128, u16);
+}
+
+impl AffinityBackend for EndpointValueV4 {
+    fn affinity_backend(&self) -> (u128, u16) {
+        (self.ip as u128, self.port)
+    }
+}
+
+impl AffinityBackend for EndpointValueV6 {
+    fn affinity_backend(&self) -> (u128, u16) {
+        (self.ip, self.port)
+    }
+}
+
+/// Picks the backend for a new connection. With session affinity a client keeps the backend
+/// it was last given while it is still one of the service's backends, until it stays idle
+/// for longer than the service's timeout.
+#[inline]
+fn select_position<V: AffinityBackend>(
+    ctx: &SockAddrContext,
+    service_value: &ServiceValue,
+    maglev: &HashMap<MaglevKey, MaglevTable>,
+    endpoints: &HashMap<EndpointKey, V>,
+) -> u16 {
+    if service_value.flags & SERVICE_FLAG_AFFINITY == 0 {
+        return pick_position(ctx, service_value, maglev);
+    }
+
+    let key = AffinityKey::new(netns_cookie(ctx), service_value.id);
+    let now = unsafe { bpf_ktime_get_ns() };
+    let timeout_ns = service_value.affinity_timeout_secs as u64 * 1_000_000_000;
+    let pinned = match unsafe { AFFINITY.get(key).copied() } {
+        Some(affinity) if now.saturating_sub(affinity.last_seen_ns) <= timeout_ns => {
+            pinned_position(service_value, &affinity, endpoints)
+        }
+        _ => None,
+    };
+    let position = pinned.unwrap_or_else(|| pick_position(ctx, service_value, maglev));
+
+    // a slot freed by a concurrent update is left for the caller to retry
+    if let Some(endpoint) = unsafe { endpoints.get(service_value.endpoint_key(position)) } {
+        let (ip, port) = endpoint.affinity_backend();
+        let _ = AFFINITY.insert(key, AffinityValue::new(now, ip, port, position), 0);
+    }
+    position
+}
+
+/// Slot of the backend a client is pinned to in the current backend set, if it is still in it
+#[inline]
+fn pinned_position<V: AffinityBackend>(
+    service_value: &ServiceValue,
+    affinity: &AffinityValue,
+    endpoints: &HashMap<EndpointKey, V>,
+) -> Option<u16> {
+    let holds_backend = |position: u16| {
+        unsafe { endpoints.get(service_value.endpoint_key(position)) }.is_some_and(|endpoint| {
+            let (ip, port) = endpoint.affinity_backend();
+            affinity.is_backend(ip, port)
+        })
+    };
+
+    // the backend keeps its slot unless the set was replaced since
+    if affinity.position < service_value.count && holds_backend(affinity.position) {
+        return Some(affinity.position);
+    }
+    let mut position = 0;
+    while position < service_value.count && position < MAX_AFFINITY_SCAN {
+        if holds_backend(position) {
+            return Some(position);
+        }
+        position += 1;
+    }
+    None
+}
+
+/// Picks a backend with the service's algorithm. Maglev hashes the socket cookie, which
+/// spreads connections across backends while keeping every datagram of an unconnected
+/// socket on the same backend as long as the set is stable. Pinning a client to a backend
+/// is left to session affinity.
+#[inline]
+fn pick_position(
+    ctx: &SockAddrContext,
+    service_value: &ServiceValue,
+    maglev: &HashMap<MaglevKey, MaglevTable>,
+) -> u16 {
+    if service_value.flags & SERVICE_FLAG_MAGLEV == 0 {
+        return get_position(service_value.count);
+    }
+
+    // the table is written before the service is flagged, a missing one means the service
+    // is being torn down
+    let Some(table) = (unsafe { maglev.get(service_value.maglev_key()) }) else {
+        return get_position(service_value.count);
+    };
+    match table.positions.get(maglev_slot(socket_cookie(ctx))) {
+        Some(position) if *position < service_value.count => *position,
+        _ => get_position(service_value.count),
+    }
+}
+
+#[inline]
+fn netns_cookie(ctx: &SockAddrContext) -> u64 {
+    unsafe { bpf_get_netns_cookie(ctx.sock_addr as *mut _) }
+}
+
 #[inline]
 fn get_random() -> u32 {
     unsafe { bpf_get_prandom_u32() }
 }
 
 #[inline]
-fn get_position(count: u16) -> u16 {
+pub(crate) fn get_position(count: u16) -> u16 {
     let rand = get_random() as u16;
     rand % count
 }
@@ -12,8 +12,11 @@ use crate::{
         policy::{PolicyBpfState, PolicyState},
         service::{ServiceEndpoint, ServiceEndpointState},
     },
-    config::AgentArgs,
-    http, kubernetes,
+    config::{AgentArgs, IpamMode},
+    http,
+    ipam::Ipam,
+    kubernetes,
+    result_cache::ResultCache,
 };
 
 pub async fn start(
@@ -27,41 +30,104 @@ pub async fn start(
     let kube_client = kube::Client::try_from(config)?;
 
     info!("initializing bpf");
-    bpf::loader::init_bpf()?;
+    let upgrade = bpf::loader::init_bpf()?;
 
-    info!("starting cni service");
-    let loader = http::grpc::cni::LoaderState;
-    let cni_server = CniServer::new(loader);
+    info!("attaching nodeport programs");
+    bpf::loader::attach_nodeport_programs(&args.iface)?;
+
+    info!("starting ipam");
+    let pod_cidrs = match args.ipam_mode {
+        IpamMode::Kubernetes => {
+            kubernetes::node::pod_cidrs(kube_client.clone(), &args.node_name).await?
+        }
+        IpamMode::ClusterPool => {
+            kubernetes::ipam::pod_cidrs(kube_client.clone(), &args.node_name).await?
+        }
+    };
+    let ipam = Ipam::new(&args.ipam_state_dir, pod_cidrs)?;
+    if args.ipam_mode == IpamMode::ClusterPool {
+        kubernetes::ipam::run(
+            kube_client.clone(),
+            args.node_name.clone(),
+            ipam.clone(),
+            cancel.clone(),
+        )
+        .await?;
+    }
+    let ipam_server = http::grpc::ipam::server(ipam.clone());
 
     info!("loading ip maps");
     let (ipv4_map, ipv6_map) = bpf::ip::load_maps()?;
-    let state = IpNetworkState::new(ipv4_map, ipv6_map);
+    let ip_state = IpNetworkState::new(ipv4_map, ipv6_map)?;
+
+    info!("starting cni service");
+    let results = ResultCache::new(&args.cni_results_dir)?;
+    let loader = http::grpc::cni::LoaderState::new(ipam, ip_state.clone(), results);
+    let cni_server = CniServer::new(loader);
+
+    // cancelled by each controller once it went through its initial list, after which the
+    // maps it writes hold what the cluster does
+    let ip_reconciled = CancellationToken::new();
+    let service_reconciled = CancellationToken::new();
+    let policy_reconciled = CancellationToken::new();
 
     info!("starting ip service");
     bpf::ip::run(
         kube_client.clone(),
         args.node_name.clone(),
-        state.clone(),
+        ip_state.clone(),
         cancel.clone(),
+        ip_reconciled.clone(),
     )
     .await?;
-    let ip_server = http::grpc::ip::server(state);
+    let ip_server = http::grpc::ip::server(ip_state.clone());
 
     info!("loading service/endpoint bpf maps");
     let (service_map_v4, service_map_v6) = bpf::service::load_service_maps()?;
     let (endpoint_map_v4, endpoint_map_v6) = bpf::service::load_endpoint_maps()?;
+    let (maglev_map_v4, maglev_map_v6) = bpf::service::load_maglev_maps()?;
+    let source_range_map_v4 = bpf::service::load_source_range_map()?;
+
+    let topology =
+        kubernetes::node::node_topology(kube_client.clone(), args.node_name.clone()).await?;
 
     info!("starting kube service service");
-    let service_endpoint_v4 = ServiceEndpoint::new(service_map_v4, endpoint_map_v4);
-    let service_endpoint_v6 = ServiceEndpoint::new(service_map_v6, endpoint_map_v6);
-    let state = ServiceEndpointState::new(service_endpoint_v4, service_endpoint_v6);
-    bpf::service::run(kube_client.clone(), state.clone(), cancel.clone()).await?;
+    let service_endpoint_v4 = ServiceEndpoint::new(service_map_v4, endpoint_map_v4, maglev_map_v4)?;
+    let service_endpoint_v6 = ServiceEndpoint::new(service_map_v6, endpoint_map_v6, maglev_map_v6)?;
+    let state = ServiceEndpointState::new(
+        service_endpoint_v4,
+        service_endpoint_v6,
+        source_range_map_v4,
+    )?;
+    let node_name = topology.node_name.clone();
+    let service_stores = bpf::service::run(
+        kube_client.clone(),
+        state.clone(),
+        topology,
+        cancel.clone(),
+        service_reconciled.clone(),
+    )
+    .await?;
+
+    info!("starting health check node port servers");
+    tokio::spawn(http::health_check::run(
+        service_stores,
+        node_name,
+        cancel.clone(),
+    ));
     let service_server = http::grpc::service::server(state);
 
     info!("starting policy service");
     let policy_state = PolicyBpfState::try_new()?;
     let policy_state = PolicyState::new(policy_state);
-    bpf::policy::run(kube_client.clone(), policy_state.clone(), cancel.clone()).await?;
+    bpf::policy::run(
+        kube_client.clone(),
+        policy_state.clone(),
+        ip_state,
+        cancel.clone(),
+        policy_reconciled.clone(),
+    )
+    .await?;
     let policy_server = http::grpc::policy::server(policy_state);
 
     info!("starting conntrack cleanup background process");
@@ -74,7 +140,8 @@ pub async fn start(
         .add_service(ip_server)
         .add_service(service_server)
         .add_service(policy_server)
-        .add_service(conntrack_server);
+        .add_service(conntrack_server)
+        .add_service(ipam_server);
     let routes = routes.to_owned().routes();
 
     info!("starting gprc server");
@@ -84,6 +151,13 @@ pub async fn start(
         cancel.child_token(),
     ));
 
+    if let Some(upgrade) = upgrade {
+        info!("completing bpf map upgrade");
+        upgrade
+            .complete(&[ip_reconciled, service_reconciled, policy_reconciled])
+            .await?;
+    }
+
     // TODO: move to something less brittle
     info!("removing node taint");
     kubernetes::node::remove_startup_taint(kube_client, args.node_name).await?;
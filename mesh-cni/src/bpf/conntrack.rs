@@ -1,13 +1,19 @@
 use std::time::Duration;
 
-use aya::maps::{HashMap, Map, MapData};
-use mesh_cni_ebpf_common::conntrack::{ConntrackKeyV4, ConntrackValue};
+use aya::{
+    Pod,
+    maps::{HashMap, Map, MapData},
+};
+use mesh_cni_ebpf_common::conntrack::{ConntrackKeyV4, ConntrackKeyV6, ConntrackValue};
 use nix::time::{ClockId, clock_gettime};
 use tokio::time::interval;
 use tokio_util::sync::CancellationToken;
 use tracing::{error, info};
 
-use crate::{Result, bpf::BPF_MAP_CONNTRACK_V4};
+use crate::{
+    Result,
+    bpf::{BPF_MAP_CONNTRACK_V4, BPF_MAP_CONNTRACK_V6, BpfNamePath},
+};
 
 const CLEANUP_INTERVAL: Duration = Duration::from_secs(300);
 const CT_TIMEOUT_TCP_NS: u64 = 60 * 60 * 12 * 1_000_000_000;
@@ -15,15 +21,19 @@ const CT_TIMEOUT_UDP_NS: u64 = 60 * 1_000_000_000;
 
 pub async fn run_cleanup(cancel: CancellationToken) -> Result<()> {
     info!("starting bpf conntrack cleanup task");
-    let mut map = load_map()?;
+    let mut map_v4 = load_map_v4()?;
+    let mut map_v6 = load_map_v6()?;
     let mut ticker = interval(CLEANUP_INTERVAL);
 
     loop {
         tokio::select! {
             _ = cancel.cancelled() => break,
             _ = ticker.tick() => {
-                if let Err(e) = cleanup_map(&mut map) {
-                    error!(%e, "error cleaning up conntrack");
+                if let Err(e) = cleanup_map(&mut map_v4, |key| key.proto) {
+                    error!(%e, "error cleaning up v4 conntrack");
+                };
+                if let Err(e) = cleanup_map(&mut map_v6, |key| key.proto) {
+                    error!(%e, "error cleaning up v6 conntrack");
                 };
             }
         }
@@ -32,21 +42,32 @@ pub async fn run_cleanup(cancel: CancellationToken) -> Result<()> {
     Ok(())
 }
 
-pub(crate) fn load_map() -> Result<HashMap<MapData, ConntrackKeyV4, ConntrackValue>> {
-    let map = MapData::from_pin(BPF_MAP_CONNTRACK_V4.path())?;
+pub(crate) fn load_map_v4() -> Result<HashMap<MapData, ConntrackKeyV4, ConntrackValue>> {
+    load_map(&BPF_MAP_CONNTRACK_V4)
+}
+
+pub(crate) fn load_map_v6() -> Result<HashMap<MapData, ConntrackKeyV6, ConntrackValue>> {
+    load_map(&BPF_MAP_CONNTRACK_V6)
+}
+
+fn load_map<K: Pod>(map: &BpfNamePath) -> Result<HashMap<MapData, K, ConntrackValue>> {
+    let map = MapData::from_pin(map.path())?;
     let map = Map::LruHashMap(map);
     let map = map.try_into()?;
     Ok(map)
 }
 
-fn cleanup_map(map: &mut HashMap<MapData, ConntrackKeyV4, ConntrackValue>) -> Result<()> {
+fn cleanup_map<K: Pod>(
+    map: &mut HashMap<MapData, K, ConntrackValue>,
+    proto: impl Fn(&K) -> u8,
+) -> Result<()> {
     let now = monotonic_ns()?;
     let mut expired = Vec::new();
 
     for entry in map.iter() {
         let (key, value) = entry?;
         // TODO: re-examine these for more appropriate values
-        let timeout = match key.proto {
+        let timeout = match proto(&key) {
             1 => CT_TIMEOUT_UDP_NS,
             6 => CT_TIMEOUT_TCP_NS,
             17 => CT_TIMEOUT_UDP_NS,
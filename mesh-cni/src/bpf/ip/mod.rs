@@ -3,6 +3,7 @@ mod state;
 
 use aya::maps::{LpmTrie, Map, MapData, lpm_trie::Key as LpmKey};
 pub(crate) use convert::LpmKeyNetwork;
+use ipnetwork::IpNetwork;
 use kube::Client;
 use mesh_cni_ebpf_common::IdentityId;
 use mesh_cni_identity_controller::start_identity_controllers;
@@ -20,12 +21,20 @@ pub async fn run<IP4, IP6>(
     node_name: String,
     ipstate: IpNetworkState<IP4, IP6>,
     cancel: CancellationToken,
+    reconciled: CancellationToken,
 ) -> Result<()>
 where
-    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId> + Send + Sync + 'static,
-    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId> + Send + Sync + 'static,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>
+        + Send
+        + Sync
+        + 'static,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>
+        + Send
+        + Sync
+        + 'static,
 {
-    let controllers = start_identity_controllers(kube_client, node_name, cancel, ipstate);
+    let controllers =
+        start_identity_controllers(kube_client, node_name, cancel, reconciled, ipstate);
 
     tokio::spawn(controllers);
     Ok(())
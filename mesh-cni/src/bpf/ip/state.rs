@@ -7,6 +7,7 @@ use aya::maps::lpm_trie::Key as LpmKey;
 use ipnetwork::IpNetwork;
 use mesh_cni_ebpf_common::IdentityId;
 use mesh_cni_identity_controller::IdentityBpfState;
+use mesh_cni_policy_controller::CidrIdentityBpf;
 
 use crate::{
     Result,
@@ -54,12 +55,13 @@ where
 
 impl<IP4, IP6> IpNetworkState<IP4, IP6>
 where
-    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId>,
-    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId>,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>,
 {
-    pub fn new(ipv4_map: IP4, ipv6_map: IP6) -> Self {
-        let ipv4_state = IpBpfStateV4::new(ipv4_map);
-        let ipv6_state = IpBpfStateV6::new(ipv6_map);
+    /// The caches start out with whatever the pinned maps already hold
+    pub fn new(ipv4_map: IP4, ipv6_map: IP6) -> Result<Self> {
+        let ipv4_state = IpBpfStateV4::new(ipv4_map)?;
+        let ipv6_state = IpBpfStateV6::new(ipv6_map)?;
         let state = State {
             ipv4_state,
             ipv6_state,
@@ -67,9 +69,9 @@ where
         let shared = Shared {
             shared: Mutex::new(state),
         };
-        Self {
+        Ok(Self {
             state: Arc::new(shared),
-        }
+        })
     }
     // TODO: check if this can error with notifications
     // LpmTrie expects big endian order for comparisons
@@ -94,9 +96,16 @@ where
         Ok(())
     }
 
+    /// Deletes the entry of a single address, doing nothing when there is none
     // LpmTrie expects big endian order for comparisons
     pub fn delete(&self, ip: IpAddr) -> Result<()> {
         let mut state = self.state.shared.lock().unwrap();
+        let network = IpNetwork::from(ip);
+        if !state.ipv4_state.cache.contains_key(&network)
+            && !state.ipv6_state.cache.contains_key(&network)
+        {
+            return Ok(());
+        }
         match ip {
             IpAddr::V4(ipv4_addr) => state
                 .ipv4_state
@@ -107,6 +116,55 @@ where
         }
         Ok(())
     }
+
+    // LpmTrie expects big endian order for comparisons
+    pub fn delete_network(&self, ip_net: IpNetwork) -> Result<()> {
+        let mut state = self.state.shared.lock().unwrap();
+        match ip_net {
+            IpNetwork::V4(ipv4_network) => state.ipv4_state.delete(&LpmKey::new(
+                ipv4_network.prefix() as u32,
+                ipv4_network.ip().to_bits().to_be(),
+            )),
+            IpNetwork::V6(ipv6_network) => state.ipv6_state.delete(&LpmKey::new(
+                ipv6_network.prefix() as u32,
+                ipv6_network.ip().to_bits().to_be(),
+            )),
+        }
+    }
+
+    /// Deletes every network `keep` returns false for
+    pub fn retain(&self, keep: impl Fn(&IpNetwork, IdentityId) -> bool) -> Result<()> {
+        let mut state = self.state.shared.lock().unwrap();
+        state.ipv4_state.retain(&keep)?;
+        state.ipv6_state.retain(&keep)
+    }
+
+    /// Identity of the host entry of a single address, when the cache has one and the map
+    /// agrees with it
+    // LpmTrie expects big endian order for comparisons
+    pub fn host_identity(&self, ip: IpAddr) -> Option<IdentityId> {
+        let state = self.state.shared.lock().unwrap();
+        let network = IpNetwork::from(ip);
+        let (cached, mapped) = match ip {
+            IpAddr::V4(ipv4_addr) => (
+                state.ipv4_state.cache.get(&network),
+                state
+                    .ipv4_state
+                    .bpf_map
+                    .get(&LpmKey::new(32, ipv4_addr.to_bits().to_be())),
+            ),
+            IpAddr::V6(ipv6_addr) => (
+                state.ipv6_state.cache.get(&network),
+                state
+                    .ipv6_state
+                    .bpf_map
+                    .get(&LpmKey::new(128, ipv6_addr.to_bits().to_be())),
+            ),
+        };
+        let id = *cached?;
+        mapped.is_ok_and(|mapped| mapped == id).then_some(id)
+    }
+
     pub fn state(&self) -> Vec<(IpNetwork, IdentityId)> {
         let state = self.state.shared.lock().unwrap();
         let mut nets = vec![];
@@ -122,8 +180,8 @@ where
 
 impl<IP4, IP6> IdentityBpfState for IpNetworkState<IP4, IP6>
 where
-    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId>,
-    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId>,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>,
 {
     fn update(
         &self,
@@ -133,6 +191,38 @@ where
         self.update(key, value)
             .map_err(|e| mesh_cni_identity_controller::Error::OpError(e.to_string()))
     }
+
+    fn retain(
+        &self,
+        keep: impl Fn(&IpNetwork, IdentityId) -> bool,
+    ) -> mesh_cni_identity_controller::Result<()> {
+        IpNetworkState::retain(self, keep)
+            .map_err(|e| mesh_cni_identity_controller::Error::OpError(e.to_string()))
+    }
+}
+
+impl<IP4, IP6> CidrIdentityBpf for IpNetworkState<IP4, IP6>
+where
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>,
+{
+    fn update(&self, network: IpNetwork, id: IdentityId) -> mesh_cni_policy_controller::Result<()> {
+        IpNetworkState::update(self, network, id)
+            .map_err(|e| mesh_cni_policy_controller::Error::BpfError(e.to_string()))
+    }
+
+    fn delete(&self, network: IpNetwork) -> mesh_cni_policy_controller::Result<()> {
+        self.delete_network(network)
+            .map_err(|e| mesh_cni_policy_controller::Error::BpfError(e.to_string()))
+    }
+
+    fn retain(
+        &self,
+        keep: impl Fn(&IpNetwork, IdentityId) -> bool,
+    ) -> mesh_cni_policy_controller::Result<()> {
+        IpNetworkState::retain(self, keep)
+            .map_err(|e| mesh_cni_policy_controller::Error::BpfError(e.to_string()))
+    }
 }
 
 pub struct IpBpfStateV4<M>
@@ -145,11 +235,11 @@ where
 
 impl<M> IpBpfStateV4<M>
 where
-    M: BpfMap<Key = LpmKey<u32>, Value = IdentityId>,
+    M: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>,
 {
-    pub fn new(bpf_map: M) -> Self {
-        let cache = ahash::HashMap::default();
-        Self { cache, bpf_map }
+    pub fn new(bpf_map: M) -> Result<Self> {
+        let cache = bpf_map.get_state()?;
+        Ok(Self { cache, bpf_map })
     }
 
     pub fn update(&mut self, key: M::Key, value: M::Value) -> Result<()> {
@@ -178,6 +268,25 @@ where
             Err(e) => Err(e),
         }
     }
+
+    pub fn retain(&mut self, keep: &impl Fn(&IpNetwork, IdentityId) -> bool) -> Result<()> {
+        let stale: Vec<IpNetwork> = self
+            .cache
+            .iter()
+            .filter(|(network, id)| !keep(network, **id))
+            .map(|(network, _)| *network)
+            .collect();
+        for network in stale {
+            let IpNetwork::V4(network) = network else {
+                continue;
+            };
+            self.delete(&LpmKey::new(
+                network.prefix() as u32,
+                network.ip().to_bits().to_be(),
+            ))?;
+        }
+        Ok(())
+    }
 }
 
 pub struct IpBpfStateV6<M>
@@ -190,11 +299,11 @@ where
 
 impl<M> IpBpfStateV6<M>
 where
-    M: BpfMap<Key = LpmKey<u128>, Value = IdentityId>,
+    M: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>,
 {
-    pub fn new(bpf_map: M) -> Self {
-        let cache = ahash::HashMap::default();
-        Self { cache, bpf_map }
+    pub fn new(bpf_map: M) -> Result<Self> {
+        let cache = bpf_map.get_state()?;
+        Ok(Self { cache, bpf_map })
     }
 
     pub fn update(&mut self, key: M::Key, value: M::Value) -> Result<()> {
@@ -223,4 +332,23 @@ where
             Err(e) => Err(e),
         }
     }
+
+    pub fn retain(&mut self, keep: &impl Fn(&IpNetwork, IdentityId) -> bool) -> Result<()> {
+        let stale: Vec<IpNetwork> = self
+            .cache
+            .iter()
+            .filter(|(network, id)| !keep(network, **id))
+            .map(|(network, _)| *network)
+            .collect();
+        for network in stale {
+            let IpNetwork::V6(network) = network else {
+                continue;
+            };
+            self.delete(&LpmKey::new(
+                network.prefix() as u32,
+                network.ip().to_bits().to_be(),
+            ))?;
+        }
+        Ok(())
+    }
 }
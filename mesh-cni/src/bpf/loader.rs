@@ -1,67 +1,175 @@
 use std::{
     fs::{self, File},
     io,
+    path::PathBuf,
 };
 
 use anyhow::{anyhow, bail};
 use aya::{
     Ebpf,
-    programs::{CgroupAttachMode, CgroupSockAddr, SchedClassifier, links::FdLink},
+    programs::{
+        CgroupAttachMode, CgroupSockAddr, CgroupSockAddrAttachType, SchedClassifier, TcAttachType,
+        links::FdLink, tc,
+    },
 };
 use tracing::{error, info, warn};
 
 use crate::{
     Result,
     bpf::{
-        BPF_LINK_CGROUP_CONNECT_V4_PATH, BPF_MESH_FS_DIR, BPF_MESH_LINKS_DIR, BPF_MESH_MAPS_DIR,
-        BPF_MESH_PROG_DIR, BPF_PROGRAM_CGROUP_CONNECT_V4, BPF_PROGRAM_INGRESS_TC, BpfNamePath,
-        POLICY_MAPS_LIST, PROG_LIST, SERVICE_MAPS_LIST,
+        BPF_LINK_CGROUP_CONNECT_V4_PATH, BPF_LINK_CGROUP_CONNECT_V6_PATH,
+        BPF_LINK_CGROUP_GETPEERNAME_V4_PATH, BPF_LINK_CGROUP_GETPEERNAME_V6_PATH,
+        BPF_LINK_CGROUP_RECVMSG_V4_PATH, BPF_LINK_CGROUP_RECVMSG_V6_PATH,
+        BPF_LINK_CGROUP_SENDMSG_V4_PATH, BPF_LINK_CGROUP_SENDMSG_V6_PATH, BPF_MESH_FS_DIR,
+        BPF_MESH_LINKS_DIR, BPF_MESH_MAPS_DIR, BPF_MESH_OLD_DIR, BPF_MESH_PROG_DIR,
+        BPF_PROGRAM_CGROUP_CONNECT_V4, BPF_PROGRAM_CGROUP_CONNECT_V6,
+        BPF_PROGRAM_CGROUP_GETPEERNAME_V4, BPF_PROGRAM_CGROUP_GETPEERNAME_V6,
+        BPF_PROGRAM_CGROUP_RECVMSG_V4, BPF_PROGRAM_CGROUP_RECVMSG_V6,
+        BPF_PROGRAM_CGROUP_SENDMSG_V4, BPF_PROGRAM_CGROUP_SENDMSG_V6, BPF_PROGRAM_EGRESS_TC,
+        BPF_PROGRAM_INGRESS_TC, BPF_PROGRAM_NODEPORT_EGRESS_TC, BPF_PROGRAM_NODEPORT_INGRESS_TC,
+        BpfNamePath, POLICY_MAPS_LIST, PROG_LIST, SERVICE_MAPS_LIST,
+        schema::{BPF_SCHEMA_PATH, Schema},
+        upgrade::{self, Upgrade},
     },
 };
 
 const CGROUP_SYS_DIR: &str = "/sys/fs/cgroup";
-
-pub fn init_bpf() -> Result<()> {
+const MESH_NODEPORT_LINK_PREFIX: &str = "mesh_cni_nodeport_";
+
+/// NodePort programs along with the hook of the external interface they attach to
+const NODEPORT_PROGRAMS: [(BpfNamePath, TcAttachType); 2] = [
+    (BPF_PROGRAM_NODEPORT_INGRESS_TC, TcAttachType::Ingress),
+    (BPF_PROGRAM_NODEPORT_EGRESS_TC, TcAttachType::Egress),
+];
+
+/// Service programs attached to the root cgroup along with the path their link is pinned to
+const CGROUP_SOCK_ADDR_PROGRAMS: [(BpfNamePath, &str, CgroupSockAddrAttachType); 8] = [
+    (
+        BPF_PROGRAM_CGROUP_CONNECT_V4,
+        BPF_LINK_CGROUP_CONNECT_V4_PATH,
+        CgroupSockAddrAttachType::Connect4,
+    ),
+    (
+        BPF_PROGRAM_CGROUP_CONNECT_V6,
+        BPF_LINK_CGROUP_CONNECT_V6_PATH,
+        CgroupSockAddrAttachType::Connect6,
+    ),
+    (
+        BPF_PROGRAM_CGROUP_SENDMSG_V4,
+        BPF_LINK_CGROUP_SENDMSG_V4_PATH,
+        CgroupSockAddrAttachType::UDPSendMsg4,
+    ),
+    (
+        BPF_PROGRAM_CGROUP_SENDMSG_V6,
+        BPF_LINK_CGROUP_SENDMSG_V6_PATH,
+        CgroupSockAddrAttachType::UDPSendMsg6,
+    ),
+    (
+        BPF_PROGRAM_CGROUP_RECVMSG_V4,
+        BPF_LINK_CGROUP_RECVMSG_V4_PATH,
+        CgroupSockAddrAttachType::UDPRecvMsg4,
+    ),
+    (
+        BPF_PROGRAM_CGROUP_RECVMSG_V6,
+        BPF_LINK_CGROUP_RECVMSG_V6_PATH,
+        CgroupSockAddrAttachType::UDPRecvMsg6,
+    ),
+    (
+        BPF_PROGRAM_CGROUP_GETPEERNAME_V4,
+        BPF_LINK_CGROUP_GETPEERNAME_V4_PATH,
+        CgroupSockAddrAttachType::GetPeerName4,
+    ),
+    (
+        BPF_PROGRAM_CGROUP_GETPEERNAME_V6,
+        BPF_LINK_CGROUP_GETPEERNAME_V6_PATH,
+        CgroupSockAddrAttachType::GetPeerName6,
+    ),
+];
+
+/// Loads and pins the maps and programs, reusing the pins of a previous agent. When the
+/// schema of a pinned map changed the returned upgrade must be completed once the
+/// controllers are running.
+pub fn init_bpf() -> Result<Option<Upgrade>> {
     if pins_exist()? {
+        let upgrade = upgrade::prepare()?;
         start_ebpf_logger()?;
 
-        return Ok(());
+        return Ok(upgrade);
     }
     reset_pins()?;
 
-    let mut service_ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/mesh-cni-service"
-    )))?;
+    let mut objects = Objects::load()?;
+    objects.pin_programs(BPF_MESH_FS_DIR)?;
+    objects.pin_maps(BPF_MESH_FS_DIR)?;
+    drop(objects);
 
-    info!("ensuring cgroupsockaddr program loaded and pinned");
-    attach_cgroup_connect_bpf_program(&mut service_ebpf)?;
+    attach_cgroup_programs()?;
+    Schema::expected(BPF_MESH_FS_DIR)?.write(BPF_SCHEMA_PATH)?;
 
-    pin_maps(&mut service_ebpf, &SERVICE_MAPS_LIST)?;
+    start_ebpf_logger()?;
+    Ok(None)
+}
 
-    let mut policy_ebpf = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
-        env!("OUT_DIR"),
-        "/mesh-cni-policy"
-    )))?;
+/// The service and policy objects before their maps and programs are pinned
+pub(crate) struct Objects {
+    service: Ebpf,
+    policy: Ebpf,
+}
 
-    info!("ensuring ingress program loaded and pinned");
-    ensure_ingress_program(&mut policy_ebpf)?;
+impl Objects {
+    pub(crate) fn load() -> Result<Self> {
+        let service = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/mesh-cni-service"
+        )))?;
+        let policy = aya::Ebpf::load(aya::include_bytes_aligned!(concat!(
+            env!("OUT_DIR"),
+            "/mesh-cni-policy"
+        )))?;
+        Ok(Self { service, policy })
+    }
 
-    pin_maps(&mut policy_ebpf, &POLICY_MAPS_LIST)?;
+    /// Pins the maps below `root`
+    pub(crate) fn pin_maps(&mut self, root: &str) -> Result<()> {
+        fs::create_dir_all(format!("{root}/maps"))?;
+        pin_maps(&mut self.service, &SERVICE_MAPS_LIST, root)?;
+        pin_maps(&mut self.policy, &POLICY_MAPS_LIST, root)
+    }
 
-    start_ebpf_logger()?;
-    Ok(())
+    /// Loads the programs and pins them below `root` without attaching them
+    pub(crate) fn pin_programs(&mut self, root: &str) -> Result<()> {
+        fs::create_dir_all(format!("{root}/programs"))?;
+        for (program, _, _) in CGROUP_SOCK_ADDR_PROGRAMS.iter() {
+            info!(
+                "ensuring cgroupsockaddr program {} loaded and pinned",
+                program.name()
+            );
+            ensure_cgroup_sock_addr_program(&mut self.service, program, root)?;
+        }
+
+        for (program, _) in NODEPORT_PROGRAMS.iter() {
+            info!("ensuring {} program loaded and pinned", program.name());
+            ensure_tc_program(&mut self.service, program, root)?;
+        }
+
+        info!("ensuring ingress program loaded and pinned");
+        ensure_tc_program(&mut self.policy, &BPF_PROGRAM_INGRESS_TC, root)?;
+
+        info!("ensuring egress program loaded and pinned");
+        ensure_tc_program(&mut self.policy, &BPF_PROGRAM_EGRESS_TC, root)
+    }
 }
 
-fn pin_maps(ebpf: &mut Ebpf, map_list: &[BpfNamePath]) -> Result<()> {
+fn pin_maps(ebpf: &mut Ebpf, map_list: &[BpfNamePath], root: &str) -> Result<()> {
     for map in map_list {
-        if fs::exists(map.path())? {
-            bail!("pinned object {} already exists", map.path());
+        let path = map.path_in(root);
+        if fs::exists(&path)? {
+            bail!("pinned object {} already exists", path);
         }
         let Some(m) = ebpf.map_mut(map.name()) else {
             bail!("map {} not found", map.name());
         };
-        m.pin(map.path())?;
+        m.pin(path)?;
     }
     Ok(())
 }
@@ -79,7 +187,15 @@ fn ensure_pin_dirs() -> Result<()> {
     Ok(())
 }
 
+/// Whether a previous agent finished pinning. Once a schema is recorded, maps and programs
+/// added by a newer agent are left to the upgrade instead of resetting every pin.
 fn pins_exist() -> Result<bool> {
+    if fs::exists(BPF_MESH_OLD_DIR)? {
+        return Ok(true);
+    }
+    if fs::exists(BPF_SCHEMA_PATH)? && fs::exists(BPF_MESH_MAPS_DIR)? {
+        return Ok(true);
+    }
     for map in SERVICE_MAPS_LIST.iter().chain(POLICY_MAPS_LIST.iter()) {
         if !fs::exists(map.path())? {
             return Ok(false);
@@ -108,40 +224,45 @@ fn reset_pins() -> Result<()> {
     Ok(())
 }
 
-fn ensure_ingress_program(ebpf: &mut Ebpf) -> Result<()> {
-    if fs::exists(BPF_PROGRAM_INGRESS_TC.path())? {
+fn ensure_tc_program(ebpf: &mut Ebpf, program: &BpfNamePath, root: &str) -> Result<()> {
+    let path = program.path_in(root);
+    if fs::exists(&path)? {
         return Ok(());
     }
-    let ingress: &mut SchedClassifier = ebpf
-        .program_mut(BPF_PROGRAM_INGRESS_TC.name())
-        .ok_or_else(|| anyhow!("failed to get program {}", BPF_PROGRAM_INGRESS_TC.name()))?
+    let classifier: &mut SchedClassifier = ebpf
+        .program_mut(program.name())
+        .ok_or_else(|| anyhow!("failed to get program {}", program.name()))?
         .try_into()?;
 
-    if let Err(e) = ingress.load()
+    if let Err(e) = classifier.load()
         && !matches!(e, aya::programs::ProgramError::AlreadyLoaded)
     {
         return Err(e.into());
     };
 
-    if !fs::exists(BPF_PROGRAM_INGRESS_TC.path())? {
-        info!("pinning ingress program to bpffs");
-        ingress.pin(BPF_PROGRAM_INGRESS_TC.path())?;
-    }
+    info!("pinning {} program to bpffs", program.name());
+    classifier.pin(path)?;
 
     Ok(())
 }
 
 fn start_ebpf_logger() -> Result<()> {
-    let cgroup_prog = CgroupSockAddr::from_pin(
-        BPF_PROGRAM_CGROUP_CONNECT_V4.path(),
-        aya::programs::CgroupSockAddrAttachType::Connect4,
-    )?;
-    let info = cgroup_prog.info()?;
-    start_ebpf_logger_from_prog_id(info.id())?;
+    for (program, _, attach_type) in CGROUP_SOCK_ADDR_PROGRAMS {
+        let cgroup_prog = CgroupSockAddr::from_pin(program.path(), attach_type)?;
+        let info = cgroup_prog.info()?;
+        start_ebpf_logger_from_prog_id(info.id())?;
+    }
 
-    let ingress = SchedClassifier::from_pin(BPF_PROGRAM_INGRESS_TC.path())?;
-    let info = ingress.info()?;
-    start_ebpf_logger_from_prog_id(info.id())?;
+    for program in [
+        BPF_PROGRAM_INGRESS_TC,
+        BPF_PROGRAM_EGRESS_TC,
+        BPF_PROGRAM_NODEPORT_INGRESS_TC,
+        BPF_PROGRAM_NODEPORT_EGRESS_TC,
+    ] {
+        let classifier = SchedClassifier::from_pin(program.path())?;
+        let info = classifier.info()?;
+        start_ebpf_logger_from_prog_id(info.id())?;
+    }
 
     Ok(())
 }
@@ -160,30 +281,77 @@ fn start_ebpf_logger_from_prog_id(program_id: u32) -> Result<()> {
     Ok(())
 }
 
-fn attach_cgroup_connect_bpf_program(ebpf: &mut Ebpf) -> Result<()> {
+fn ensure_cgroup_sock_addr_program(
+    ebpf: &mut Ebpf,
+    bpf_program: &BpfNamePath,
+    root: &str,
+) -> Result<()> {
+    let path = bpf_program.path_in(root);
+    if fs::exists(&path)? {
+        return Ok(());
+    }
     let program: &mut CgroupSockAddr = ebpf
-        .program_mut(BPF_PROGRAM_CGROUP_CONNECT_V4.name())
-        .ok_or_else(|| {
-            anyhow!(
-                "failed to load program {}",
-                BPF_PROGRAM_CGROUP_CONNECT_V4.name()
-            )
-        })?
+        .program_mut(bpf_program.name())
+        .ok_or_else(|| anyhow!("failed to load program {}", bpf_program.name()))?
         .try_into()?;
     if let Err(e) = program.load()
         && !matches!(e, aya::programs::ProgramError::AlreadyLoaded)
     {
         return Err(e.into());
     };
-    let cgroup = File::open(CGROUP_SYS_DIR)?;
-    let link_id = program.attach(cgroup, CgroupAttachMode::Single)?;
-    program.pin(BPF_PROGRAM_CGROUP_CONNECT_V4.path())?;
+    program.pin(path)?;
+
+    Ok(())
+}
+
+/// Attaches the pinned service programs to the root cgroup. Links pinned by a previous run
+/// are kept as they are.
+pub(crate) fn attach_cgroup_programs() -> Result<()> {
+    for (program, link_path, attach_type) in CGROUP_SOCK_ADDR_PROGRAMS {
+        if fs::exists(link_path)? {
+            continue;
+        }
+        info!("attaching {} to the root cgroup", program.name());
+        let mut cgroup_prog = CgroupSockAddr::from_pin(program.path(), attach_type)?;
+        let cgroup = File::open(CGROUP_SYS_DIR)?;
+        let link_id = cgroup_prog.attach(cgroup, CgroupAttachMode::Single)?;
+
+        let link = cgroup_prog.take_link(link_id)?;
+        let link: FdLink = link
+            .try_into()
+            .map_err(|e| anyhow!("failed to create fdlink from cgroup attachment link: {e}"))?;
+        link.pin(link_path)?;
+    }
 
-    let link = program.take_link(link_id)?;
-    let link: FdLink = link
-        .try_into()
-        .map_err(|e| anyhow!("failed to create fdlink from cgroup attachment link: {e}"))?;
-    link.pin(BPF_LINK_CGROUP_CONNECT_V4_PATH)?;
+    Ok(())
+}
 
+/// Attaches the NodePort programs to the external interfaces. Links pinned by a previous
+/// run are kept as they are.
+pub fn attach_nodeport_programs(ifaces: &[String]) -> Result<()> {
+    for iface in ifaces {
+        // fails when the qdisc already exists
+        let _ = tc::qdisc_add_clsact(iface);
+        for (program, attach_type) in NODEPORT_PROGRAMS {
+            let link_path = nodeport_link_path(iface, attach_type);
+            if fs::exists(&link_path)? {
+                continue;
+            }
+            info!("attaching {} to {}", program.name(), iface);
+            let mut classifier = SchedClassifier::from_pin(program.path())?;
+            let link_id = classifier.attach(iface, attach_type)?;
+            let link: FdLink = classifier.take_link(link_id)?.try_into()?;
+            link.pin(link_path)?;
+        }
+    }
     Ok(())
 }
+
+fn nodeport_link_path(iface: &str, attach_type: TcAttachType) -> PathBuf {
+    let hook = match attach_type {
+        TcAttachType::Ingress => "ingress",
+        TcAttachType::Egress => "egress",
+        TcAttachType::Custom(_) => "custom",
+    };
+    PathBuf::from(BPF_MESH_LINKS_DIR).join(format!("{MESH_NODEPORT_LINK_PREFIX}{iface}_{hook}"))
+}
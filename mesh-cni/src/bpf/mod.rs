@@ -2,7 +2,10 @@ pub mod conntrack;
 pub mod ip;
 pub mod loader;
 pub mod policy;
+pub mod schema;
 pub mod service;
+pub(crate) mod sys;
+pub mod upgrade;
 
 use std::{borrow::BorrowMut, hash::Hash};
 
@@ -17,9 +20,37 @@ use mesh_cni_ebpf_common::IdentityId;
 use crate::{Result, bpf::ip::LpmKeyNetwork};
 
 pub(crate) const BPF_PROGRAM_INGRESS_TC: BpfNamePath = BpfNamePath::Program("mesh_cni_ingress");
+pub(crate) const BPF_PROGRAM_EGRESS_TC: BpfNamePath = BpfNamePath::Program("mesh_cni_egress");
+pub(crate) const BPF_PROGRAM_NODEPORT_INGRESS_TC: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_nodeport_ingress");
+pub(crate) const BPF_PROGRAM_NODEPORT_EGRESS_TC: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_nodeport_egress");
 pub const BPF_PROGRAM_CGROUP_CONNECT_V4: BpfNamePath =
     BpfNamePath::Program("mesh_cni_cgroup_connect4");
 pub const BPF_LINK_CGROUP_CONNECT_V4_PATH: &str = "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_connect4";
+pub const BPF_PROGRAM_CGROUP_CONNECT_V6: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_cgroup_connect6");
+pub const BPF_LINK_CGROUP_CONNECT_V6_PATH: &str = "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_connect6";
+pub const BPF_PROGRAM_CGROUP_SENDMSG_V4: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_cgroup_sendmsg4");
+pub const BPF_LINK_CGROUP_SENDMSG_V4_PATH: &str = "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_sendmsg4";
+pub const BPF_PROGRAM_CGROUP_SENDMSG_V6: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_cgroup_sendmsg6");
+pub const BPF_LINK_CGROUP_SENDMSG_V6_PATH: &str = "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_sendmsg6";
+pub const BPF_PROGRAM_CGROUP_RECVMSG_V4: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_cgroup_recvmsg4");
+pub const BPF_LINK_CGROUP_RECVMSG_V4_PATH: &str = "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_recvmsg4";
+pub const BPF_PROGRAM_CGROUP_RECVMSG_V6: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_cgroup_recvmsg6");
+pub const BPF_LINK_CGROUP_RECVMSG_V6_PATH: &str = "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_recvmsg6";
+pub const BPF_PROGRAM_CGROUP_GETPEERNAME_V4: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_cgroup_getpeername4");
+pub const BPF_LINK_CGROUP_GETPEERNAME_V4_PATH: &str =
+    "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_getpeername4";
+pub const BPF_PROGRAM_CGROUP_GETPEERNAME_V6: BpfNamePath =
+    BpfNamePath::Program("mesh_cni_cgroup_getpeername6");
+pub const BPF_LINK_CGROUP_GETPEERNAME_V6_PATH: &str =
+    "/sys/fs/bpf/mesh/links/mesh_cni_cgroup_getpeername6";
 
 pub type IdentityMapV4 = LpmTrie<MapData, u32, IdentityId>;
 pub type IdentityMapV6 = LpmTrie<MapData, u128, IdentityId>;
@@ -27,33 +58,65 @@ pub type IdentityMapV6 = LpmTrie<MapData, u128, IdentityId>;
 pub const BPF_MAP_IDENTITY_V4: BpfNamePath = BpfNamePath::Map("identity_v4");
 pub const BPF_MAP_IDENTITY_V6: BpfNamePath = BpfNamePath::Map("identity_v6");
 pub const BPF_MAP_CONNTRACK_V4: BpfNamePath = BpfNamePath::Map("conntrack_v4");
+pub const BPF_MAP_CONNTRACK_V6: BpfNamePath = BpfNamePath::Map("conntrack_v6");
 pub const BPF_MAP_SERVICES_V4: BpfNamePath = BpfNamePath::Map("services_v4");
 pub const BPF_MAP_SERVICES_V6: BpfNamePath = BpfNamePath::Map("services_v6");
 pub const BPF_MAP_ENDPOINTS_V4: BpfNamePath = BpfNamePath::Map("endpoints_v4");
 pub const BPF_MAP_ENDPOINTS_V6: BpfNamePath = BpfNamePath::Map("endpoints_v6");
+pub const BPF_MAP_REVERSE_NAT_V4: BpfNamePath = BpfNamePath::Map("reverse_nat_v4");
+pub const BPF_MAP_REVERSE_NAT_V6: BpfNamePath = BpfNamePath::Map("reverse_nat_v6");
+pub const BPF_MAP_AFFINITY: BpfNamePath = BpfNamePath::Map("affinity");
+pub const BPF_MAP_MAGLEV_V4: BpfNamePath = BpfNamePath::Map("maglev_v4");
+pub const BPF_MAP_MAGLEV_V6: BpfNamePath = BpfNamePath::Map("maglev_v6");
+pub const BPF_MAP_NAT_V4: BpfNamePath = BpfNamePath::Map("nat_v4");
+pub const BPF_MAP_SOURCE_RANGES_V4: BpfNamePath = BpfNamePath::Map("source_ranges_v4");
 pub const BPF_MAP_POLICY: BpfNamePath = BpfNamePath::Map("policy");
 
 pub const BPF_MESH_FS_DIR: &str = "/sys/fs/bpf/mesh";
 pub const BPF_MESH_MAPS_DIR: &str = "/sys/fs/bpf/mesh/maps";
 pub const BPF_MESH_PROG_DIR: &str = "/sys/fs/bpf/mesh/programs";
 pub const BPF_MESH_LINKS_DIR: &str = "/sys/fs/bpf/mesh/links";
+/// Maps and programs of a new schema are pinned here before replacing the live ones
+pub const BPF_MESH_NEXT_DIR: &str = "/sys/fs/bpf/mesh/next";
+/// Maps and programs being replaced are moved here until the links no longer use them
+pub const BPF_MESH_OLD_DIR: &str = "/sys/fs/bpf/mesh/old";
 
-pub(crate) const POLICY_MAPS_LIST: [BpfNamePath; 4] = [
+pub(crate) const POLICY_MAPS_LIST: [BpfNamePath; 5] = [
     BPF_MAP_IDENTITY_V4,
     BPF_MAP_IDENTITY_V6,
     BPF_MAP_CONNTRACK_V4,
+    BPF_MAP_CONNTRACK_V6,
     BPF_MAP_POLICY,
 ];
 
-pub(crate) const SERVICE_MAPS_LIST: [BpfNamePath; 4] = [
+pub(crate) const SERVICE_MAPS_LIST: [BpfNamePath; 11] = [
     BPF_MAP_SERVICES_V4,
     BPF_MAP_SERVICES_V6,
     BPF_MAP_ENDPOINTS_V4,
     BPF_MAP_ENDPOINTS_V6,
+    BPF_MAP_REVERSE_NAT_V4,
+    BPF_MAP_REVERSE_NAT_V6,
+    BPF_MAP_AFFINITY,
+    BPF_MAP_MAGLEV_V4,
+    BPF_MAP_MAGLEV_V6,
+    BPF_MAP_NAT_V4,
+    BPF_MAP_SOURCE_RANGES_V4,
 ];
 
-pub(crate) const PROG_LIST: [BpfNamePath; 2] =
-    [BPF_PROGRAM_CGROUP_CONNECT_V4, BPF_PROGRAM_INGRESS_TC];
+pub(crate) const PROG_LIST: [BpfNamePath; 12] = [
+    BPF_PROGRAM_CGROUP_CONNECT_V4,
+    BPF_PROGRAM_CGROUP_CONNECT_V6,
+    BPF_PROGRAM_CGROUP_SENDMSG_V4,
+    BPF_PROGRAM_CGROUP_SENDMSG_V6,
+    BPF_PROGRAM_CGROUP_RECVMSG_V4,
+    BPF_PROGRAM_CGROUP_RECVMSG_V6,
+    BPF_PROGRAM_CGROUP_GETPEERNAME_V4,
+    BPF_PROGRAM_CGROUP_GETPEERNAME_V6,
+    BPF_PROGRAM_INGRESS_TC,
+    BPF_PROGRAM_EGRESS_TC,
+    BPF_PROGRAM_NODEPORT_INGRESS_TC,
+    BPF_PROGRAM_NODEPORT_EGRESS_TC,
+];
 
 pub enum BpfNamePath {
     Map(&'static str),
@@ -69,9 +132,14 @@ impl BpfNamePath {
     }
 
     pub fn path(&self) -> String {
+        self.path_in(BPF_MESH_FS_DIR)
+    }
+
+    /// Path of the pin below `root` rather than the live mesh directory
+    pub fn path_in(&self, root: &str) -> String {
         match &self {
-            BpfNamePath::Map(n) => format!("{BPF_MESH_MAPS_DIR}/{n}"),
-            BpfNamePath::Program(n) => format!("{BPF_MESH_PROG_DIR}/{n}"),
+            BpfNamePath::Map(n) => format!("{root}/maps/{n}"),
+            BpfNamePath::Program(n) => format!("{root}/programs/{n}"),
         }
     }
 }
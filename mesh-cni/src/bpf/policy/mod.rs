@@ -1,22 +1,45 @@
 mod state;
 
+use aya::maps::lpm_trie::Key as LpmKey;
+use ipnetwork::IpNetwork;
 use kube::Client;
-use mesh_cni_ebpf_common::policy::{PolicyKey, PolicyValue};
+use mesh_cni_ebpf_common::{
+    IdentityId,
+    policy::{PolicyKey, PolicyValue},
+};
 pub use state::{PolicyBpfState, PolicyState};
 use tokio_util::sync::CancellationToken;
 
-use crate::{Result, bpf::SharedBpfMap};
+use crate::{
+    Result,
+    bpf::{BpfMap, SharedBpfMap, ip::IpNetworkState},
+};
 
-pub async fn run<P>(
+pub async fn run<P, IP4, IP6>(
     kube_client: Client,
     policy_state: PolicyState<P>,
+    ip_state: IpNetworkState<IP4, IP6>,
     cancel: CancellationToken,
+    reconciled: CancellationToken,
 ) -> Result<()>
 where
     P: SharedBpfMap<Key = PolicyKey, Value = PolicyValue, KeyOutput = PolicyKey>,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>
+        + Send
+        + Sync
+        + 'static,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>
+        + Send
+        + Sync
+        + 'static,
 {
-    let policy_controller =
-        mesh_cni_policy_controller::start_policy_controllers(kube_client, policy_state, cancel);
+    let policy_controller = mesh_cni_policy_controller::start_policy_controllers(
+        kube_client,
+        policy_state,
+        ip_state,
+        cancel,
+        reconciled,
+    );
 
     tokio::spawn(policy_controller);
 
@@ -53,6 +53,11 @@ where
         PolicyState::delete(self, key)
             .map_err(|e| mesh_cni_policy_controller::Error::BpfError(e.to_string()))
     }
+
+    fn state(&self) -> mesh_cni_policy_controller::Result<ahash::HashMap<PolicyKey, PolicyValue>> {
+        PolicyState::state(self)
+            .map_err(|e| mesh_cni_policy_controller::Error::BpfError(e.to_string()))
+    }
 }
 
 #[derive(Clone)]
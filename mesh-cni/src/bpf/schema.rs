@@ -0,0 +1,379 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use aya::{
+    Pod,
+    maps::{HashMap, LpmTrie, Map, MapData, MapError, MapInfo},
+};
+use mesh_cni_ebpf_common::{
+    IdentityId,
+    conntrack::{ConntrackKeyV4, ConntrackKeyV6, ConntrackValue},
+    policy::{PolicyKey, PolicyValue},
+    service::{
+        AffinityKey, AffinityValue, EndpointKey, EndpointValueV4, EndpointValueV6, MaglevKey,
+        MaglevTable, NatKeyV4, NatValueV4, ReverseNatKeyV4, ReverseNatKeyV6, ReverseNatValueV4,
+        ReverseNatValueV6, ServiceKeyV4, ServiceKeyV6, ServiceValue, SourceRangesV4,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    Result,
+    bpf::{
+        BPF_MAP_AFFINITY, BPF_MAP_CONNTRACK_V4, BPF_MAP_CONNTRACK_V6, BPF_MAP_ENDPOINTS_V4,
+        BPF_MAP_ENDPOINTS_V6, BPF_MAP_IDENTITY_V4, BPF_MAP_IDENTITY_V6, BPF_MAP_MAGLEV_V4,
+        BPF_MAP_MAGLEV_V6, BPF_MAP_NAT_V4, BPF_MAP_POLICY, BPF_MAP_REVERSE_NAT_V4,
+        BPF_MAP_REVERSE_NAT_V6, BPF_MAP_SERVICES_V4, BPF_MAP_SERVICES_V6, BPF_MAP_SOURCE_RANGES_V4,
+        BpfNamePath,
+    },
+};
+
+/// File holding the fingerprints of the pinned maps. It lives next to the agent socket as
+/// bpffs can not hold regular files and the tmpfs is reset on reboot along with bpffs.
+pub const BPF_SCHEMA_PATH: &str = "/var/run/mesh/bpf-schema.json";
+
+/// Version assumed for maps pinned before their schema was recorded
+const INITIAL_VERSION: u32 = 1;
+
+/// Insert flag which leaves entries already present in the destination map untouched
+pub(crate) const BPF_NOEXIST: u64 = 1;
+
+/// Copies the entries of the map pinned at the first path into the map pinned at the second
+/// one, returning how many were copied
+type CopyFn = fn(&Path, &Path, u64) -> Result<usize>;
+
+/// Who writes the entries of a map and so who fills it again when it can not be copied
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Owner {
+    /// Written by the controllers from Kubernetes objects
+    Kubernetes,
+    /// Written by the datapath, losing entries only affects flows already in progress
+    Datapath,
+}
+
+pub(crate) struct MapSchema {
+    pub map: BpfNamePath,
+    /// Must be bumped whenever the meaning or layout of the entries changes in a way the
+    /// sizes of the key and value do not show, including a change of the map type
+    pub version: u32,
+    pub owner: Owner,
+    pub copy: CopyFn,
+}
+
+pub(crate) const MAP_SCHEMAS: [MapSchema; 16] = [
+    MapSchema {
+        map: BPF_MAP_IDENTITY_V4,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_lpm_trie::<u32, IdentityId>,
+    },
+    MapSchema {
+        map: BPF_MAP_IDENTITY_V6,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_lpm_trie::<u128, IdentityId>,
+    },
+    MapSchema {
+        map: BPF_MAP_CONNTRACK_V4,
+        version: 1,
+        owner: Owner::Datapath,
+        copy: copy_lru_hash::<ConntrackKeyV4, ConntrackValue>,
+    },
+    MapSchema {
+        map: BPF_MAP_CONNTRACK_V6,
+        version: 1,
+        owner: Owner::Datapath,
+        copy: copy_lru_hash::<ConntrackKeyV6, ConntrackValue>,
+    },
+    MapSchema {
+        map: BPF_MAP_POLICY,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<PolicyKey, PolicyValue>,
+    },
+    MapSchema {
+        map: BPF_MAP_SERVICES_V4,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<ServiceKeyV4, ServiceValue>,
+    },
+    MapSchema {
+        map: BPF_MAP_SERVICES_V6,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<ServiceKeyV6, ServiceValue>,
+    },
+    MapSchema {
+        map: BPF_MAP_ENDPOINTS_V4,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<EndpointKey, EndpointValueV4>,
+    },
+    MapSchema {
+        map: BPF_MAP_ENDPOINTS_V6,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<EndpointKey, EndpointValueV6>,
+    },
+    MapSchema {
+        map: BPF_MAP_REVERSE_NAT_V4,
+        version: 1,
+        owner: Owner::Datapath,
+        copy: copy_lru_hash::<ReverseNatKeyV4, ReverseNatValueV4>,
+    },
+    MapSchema {
+        map: BPF_MAP_REVERSE_NAT_V6,
+        version: 1,
+        owner: Owner::Datapath,
+        copy: copy_lru_hash::<ReverseNatKeyV6, ReverseNatValueV6>,
+    },
+    MapSchema {
+        map: BPF_MAP_AFFINITY,
+        version: 1,
+        owner: Owner::Datapath,
+        copy: copy_lru_hash::<AffinityKey, AffinityValue>,
+    },
+    MapSchema {
+        map: BPF_MAP_MAGLEV_V4,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<MaglevKey, MaglevTable>,
+    },
+    MapSchema {
+        map: BPF_MAP_MAGLEV_V6,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<MaglevKey, MaglevTable>,
+    },
+    MapSchema {
+        map: BPF_MAP_NAT_V4,
+        version: 1,
+        owner: Owner::Datapath,
+        copy: copy_lru_hash::<NatKeyV4, NatValueV4>,
+    },
+    MapSchema {
+        map: BPF_MAP_SOURCE_RANGES_V4,
+        version: 1,
+        owner: Owner::Kubernetes,
+        copy: copy_hash::<ServiceKeyV4, SourceRangesV4>,
+    },
+];
+
+/// Identifies the layout of a pinned map
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Fingerprint {
+    pub version: u32,
+    pub key_size: u32,
+    pub value_size: u32,
+    pub max_entries: u32,
+}
+
+impl Fingerprint {
+    fn from_pin(path: impl AsRef<Path>, version: u32) -> Result<Self> {
+        let info = MapInfo::from_pin(path)?;
+        Ok(Self {
+            version,
+            key_size: info.key_size(),
+            value_size: info.value_size(),
+            max_entries: info.max_entries(),
+        })
+    }
+
+    /// Whether entries of a map with this fingerprint can be copied as they are into a map
+    /// with the other one. Only the number of entries is allowed to differ.
+    pub fn is_compatible(&self, other: &Fingerprint) -> bool {
+        self.version == other.version
+            && self.key_size == other.key_size
+            && self.value_size == other.value_size
+    }
+}
+
+/// Fingerprints of the pinned maps keyed by map name
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct Schema {
+    pub maps: BTreeMap<String, Fingerprint>,
+}
+
+impl Schema {
+    /// Fingerprints of the maps pinned under `root` as they are expected by this agent
+    pub fn expected(root: &str) -> Result<Self> {
+        Self::from_pins(root, |schema| schema.version)
+    }
+
+    /// Fingerprints of the maps pinned under `root` by a previous agent. The sizes are
+    /// always taken from the kernel and the versions from the recorded schema, maps pinned
+    /// before schemas were recorded are assumed to be at the initial version.
+    pub fn pinned(root: &str, recorded: Option<&Schema>) -> Result<Self> {
+        Self::from_pins(root, |schema| {
+            recorded
+                .and_then(|r| r.maps.get(schema.map.name()))
+                .map(|f| f.version)
+                .unwrap_or(INITIAL_VERSION)
+        })
+    }
+
+    fn from_pins(root: &str, version: impl Fn(&MapSchema) -> u32) -> Result<Self> {
+        let mut maps = BTreeMap::new();
+        for schema in MAP_SCHEMAS.iter() {
+            let path = schema.map.path_in(root);
+            // maps added since the pins were created
+            if !fs::exists(&path)? {
+                continue;
+            }
+            let fingerprint = Fingerprint::from_pin(path, version(schema))?;
+            maps.insert(schema.map.name().to_string(), fingerprint);
+        }
+        Ok(Self { maps })
+    }
+
+    /// Reads the recorded schema, `None` if it was never written
+    pub fn read(path: impl AsRef<Path>) -> Result<Option<Self>> {
+        match fs::read(path) {
+            Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Records the schema, replacing the previous one atomically
+    pub fn write(&self, path: impl AsRef<Path>) -> Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, serde_json::to_vec_pretty(self)?)?;
+        fs::rename(tmp, path)?;
+        Ok(())
+    }
+
+    /// Names of the maps whose fingerprint differs from the one in `other`
+    pub fn changed<'a>(&'a self, other: &Schema) -> Vec<&'a str> {
+        self.maps
+            .iter()
+            .filter(|(name, fingerprint)| other.maps.get(*name) != Some(fingerprint))
+            .map(|(name, _)| name.as_str())
+            .collect()
+    }
+
+    /// Whether the named map can be copied from a map with the fingerprint found in `old`
+    pub fn is_compatible(&self, old: &Schema, name: &str) -> bool {
+        match (old.maps.get(name), self.maps.get(name)) {
+            (Some(old), Some(new)) => old.is_compatible(new),
+            _ => false,
+        }
+    }
+}
+
+fn copy_hash<K: Pod, V: Pod>(from: &Path, to: &Path, flags: u64) -> Result<usize> {
+    copy_hash_map::<K, V>(Map::HashMap, from, to, flags)
+}
+
+fn copy_lru_hash<K: Pod, V: Pod>(from: &Path, to: &Path, flags: u64) -> Result<usize> {
+    copy_hash_map::<K, V>(Map::LruHashMap, from, to, flags)
+}
+
+fn copy_hash_map<K: Pod, V: Pod>(
+    kind: fn(MapData) -> Map,
+    from: &Path,
+    to: &Path,
+    flags: u64,
+) -> Result<usize> {
+    let from: HashMap<MapData, K, V> = kind(MapData::from_pin(from)?).try_into()?;
+    let mut to: HashMap<MapData, K, V> = kind(MapData::from_pin(to)?).try_into()?;
+    let mut copied = 0;
+    for entry in from.iter() {
+        let (key, value) = entry?;
+        if inserted(to.insert(key, value, flags))? {
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+fn copy_lpm_trie<K: Pod, V: Pod>(from: &Path, to: &Path, flags: u64) -> Result<usize> {
+    let from: LpmTrie<MapData, K, V> = Map::LpmTrie(MapData::from_pin(from)?).try_into()?;
+    let mut to: LpmTrie<MapData, K, V> = Map::LpmTrie(MapData::from_pin(to)?).try_into()?;
+    let mut copied = 0;
+    for entry in from.iter() {
+        let (key, value) = entry?;
+        if inserted(to.insert(&key, value, flags))? {
+            copied += 1;
+        }
+    }
+    Ok(copied)
+}
+
+/// Entries refused with [`BPF_NOEXIST`] are expected and not an error
+fn inserted(result: std::result::Result<(), MapError>) -> Result<bool> {
+    match result {
+        Ok(()) => Ok(true),
+        Err(MapError::SyscallError(e)) if e.io_error.raw_os_error() == Some(libc::EEXIST) => {
+            Ok(false)
+        }
+        Err(e) => Err(e.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fingerprint(version: u32, value_size: u32, max_entries: u32) -> Fingerprint {
+        Fingerprint {
+            version,
+            key_size: 8,
+            value_size,
+            max_entries,
+        }
+    }
+
+    fn schema(maps: &[(&str, Fingerprint)]) -> Schema {
+        Schema {
+            maps: maps
+                .iter()
+                .map(|(name, fingerprint)| (name.to_string(), *fingerprint))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn test_changed_and_compatible_maps() {
+        let old = schema(&[
+            ("same", fingerprint(1, 4, 1024)),
+            ("resized", fingerprint(1, 4, 1024)),
+            ("grown", fingerprint(1, 4, 1024)),
+            ("bumped", fingerprint(1, 4, 1024)),
+        ]);
+        let new = schema(&[
+            ("same", fingerprint(1, 4, 1024)),
+            ("resized", fingerprint(1, 4, 4096)),
+            ("grown", fingerprint(1, 8, 1024)),
+            ("bumped", fingerprint(2, 4, 1024)),
+            ("added", fingerprint(1, 4, 1024)),
+        ]);
+
+        assert_eq!(
+            new.changed(&old),
+            vec!["added", "bumped", "grown", "resized"]
+        );
+        assert!(new.is_compatible(&old, "same"));
+        assert!(new.is_compatible(&old, "resized"));
+        assert!(!new.is_compatible(&old, "grown"));
+        assert!(!new.is_compatible(&old, "bumped"));
+        assert!(!new.is_compatible(&old, "added"));
+        assert!(new.changed(&new).is_empty());
+    }
+
+    #[test]
+    fn test_read_and_write_schema() {
+        let dir = std::env::temp_dir().join(format!("mesh-cni-schema-{}", std::process::id()));
+        let path = dir.join("bpf-schema.json");
+        assert_eq!(Schema::read(&path).unwrap(), None);
+
+        let written = schema(&[("services_v4", fingerprint(1, 4, 1024))]);
+        written.write(&path).unwrap();
+        assert_eq!(Schema::read(&path).unwrap(), Some(written));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
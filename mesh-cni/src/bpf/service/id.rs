@@ -0,0 +1,144 @@
+use std::sync::LazyLock;
+
+use ahash::HashSet;
+use mesh_cni_ebpf_common::Id;
+use prometheus_client::metrics::gauge::Gauge;
+
+use crate::metrics::REGISTRY;
+
+/// Lowest id handed out to a service, the ones below are reserved
+pub const MIN_SERVICE_ID: Id = 128;
+
+/// Number of ids services can be given
+pub const SERVICE_ID_CAPACITY: usize = (Id::MAX - MIN_SERVICE_ID) as usize + 1;
+
+static METRICS: LazyLock<IdMetrics> = LazyLock::new(IdMetrics::new);
+
+#[derive(thiserror::Error, Debug, PartialEq, Eq)]
+pub enum IdError {
+    #[error("all {0} service ids are in use")]
+    Exhausted(usize),
+}
+
+struct IdMetrics {
+    in_use: Gauge,
+}
+
+impl IdMetrics {
+    fn new() -> Self {
+        let in_use = Gauge::default();
+        let capacity = Gauge::default();
+        capacity.set(SERVICE_ID_CAPACITY as i64);
+
+        let mut registry = REGISTRY.write().unwrap();
+        registry.register(
+            "service_ids_in_use",
+            "Number of service ids allocated",
+            in_use.clone(),
+        );
+        registry.register(
+            "service_ids_capacity",
+            "Number of service ids that can be allocated",
+            capacity,
+        );
+        Self { in_use }
+    }
+}
+
+/// Hands out the ids keying the endpoint and Maglev slots of a service.
+///
+/// Ids are shared by both address families since session affinity entries are only keyed
+/// by the id. They are handed out round robin, so a released id is the last to be given
+/// out again and affinity entries left behind by its previous service expire first.
+pub struct IdAllocator {
+    in_use: HashSet<Id>,
+    next: Id,
+}
+
+impl IdAllocator {
+    /// Creates an allocator with `in_use` already taken, these are the ids found in the
+    /// pinned maps after a restart.
+    pub fn new(in_use: impl IntoIterator<Item = Id>) -> Self {
+        let in_use: HashSet<Id> = in_use
+            .into_iter()
+            .filter(|id| *id >= MIN_SERVICE_ID)
+            .collect();
+        let next = in_use
+            .iter()
+            .max()
+            .map_or(MIN_SERVICE_ID, |id| next_id(*id));
+        let allocator = Self { in_use, next };
+        allocator.record();
+        allocator
+    }
+
+    pub fn allocate(&mut self) -> Result<Id, IdError> {
+        if self.in_use.len() >= SERVICE_ID_CAPACITY {
+            return Err(IdError::Exhausted(SERVICE_ID_CAPACITY));
+        }
+        let mut id = self.next;
+        while self.in_use.contains(&id) {
+            id = next_id(id);
+        }
+        self.in_use.insert(id);
+        self.next = next_id(id);
+        self.record();
+        Ok(id)
+    }
+
+    /// Returns `id` to the pool. Only call this once nothing in the maps is keyed by it
+    /// anymore.
+    pub fn release(&mut self, id: Id) {
+        if self.in_use.remove(&id) {
+            self.record();
+        }
+    }
+
+    pub fn in_use(&self) -> usize {
+        self.in_use.len()
+    }
+
+    fn record(&self) {
+        METRICS.in_use.set(self.in_use() as i64);
+    }
+}
+
+fn next_id(id: Id) -> Id {
+    if id == Id::MAX {
+        MIN_SERVICE_ID
+    } else {
+        id + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_skips_ids_in_use_and_recycles_last() {
+        let mut allocator = IdAllocator::new([MIN_SERVICE_ID, MIN_SERVICE_ID + 2, 3]);
+        assert_eq!(allocator.in_use(), 2);
+        assert_eq!(allocator.allocate(), Ok(MIN_SERVICE_ID + 3));
+
+        allocator.release(MIN_SERVICE_ID);
+        // the released id only comes back once the rest of the space went around
+        assert_eq!(allocator.allocate(), Ok(MIN_SERVICE_ID + 4));
+
+        let mut allocator = IdAllocator::new([Id::MAX]);
+        assert_eq!(allocator.allocate(), Ok(MIN_SERVICE_ID));
+    }
+
+    #[test]
+    fn test_allocate_errors_when_exhausted() {
+        let mut allocator = IdAllocator::new(MIN_SERVICE_ID..=Id::MAX);
+        assert_eq!(allocator.in_use(), SERVICE_ID_CAPACITY);
+        assert_eq!(
+            allocator.allocate(),
+            Err(IdError::Exhausted(SERVICE_ID_CAPACITY))
+        );
+
+        allocator.release(MIN_SERVICE_ID + 7);
+        assert_eq!(allocator.allocate(), Ok(MIN_SERVICE_ID + 7));
+    }
+}
@@ -0,0 +1,160 @@
+use mesh_cni_ebpf_common::service::{
+    EndpointValueV4, EndpointValueV6, MAGLEV_TABLE_SIZE, MaglevTable,
+};
+
+const OFFSET_SEED: u64 = 0x6d65_7368_6f66_6673;
+const SKIP_SEED: u64 = 0x6d65_7368_736b_6970;
+
+/// Backend that can be placed in a Maglev lookup table
+pub trait MaglevBackend {
+    /// Bytes identifying the backend. Every node has to derive the same name for a backend
+    /// so they all build the same table.
+    fn maglev_name(&self) -> Vec<u8>;
+}
+
+impl MaglevBackend for EndpointValueV4 {
+    fn maglev_name(&self) -> Vec<u8> {
+        let mut name = self.ip.to_be_bytes().to_vec();
+        name.extend_from_slice(&self.port.to_be_bytes());
+        name
+    }
+}
+
+impl MaglevBackend for EndpointValueV6 {
+    fn maglev_name(&self) -> Vec<u8> {
+        let mut name = self.ip.to_be_bytes().to_vec();
+        name.extend_from_slice(&self.port.to_be_bytes());
+        name
+    }
+}
+
+/// Builds the Maglev lookup table of a service, mapping each slot to the position of a
+/// backend in `backends`. Backends are placed in name order so the result does not depend
+/// on the order the endpoints were listed in.
+pub fn build_table<B: MaglevBackend>(backends: &[&B]) -> MaglevTable {
+    let mut table = MaglevTable {
+        positions: [0; MAGLEV_TABLE_SIZE],
+    };
+    if backends.is_empty() {
+        return table;
+    }
+
+    let size = MAGLEV_TABLE_SIZE as u64;
+    let mut names: Vec<(Vec<u8>, u16)> = backends
+        .iter()
+        .enumerate()
+        .map(|(position, backend)| (backend.maglev_name(), position as u16))
+        .collect();
+    names.sort();
+
+    let permutations: Vec<(u64, u64)> = names
+        .iter()
+        .map(|(name, _)| {
+            let offset = hash(name, OFFSET_SEED) % size;
+            let skip = hash(name, SKIP_SEED) % (size - 1) + 1;
+            (offset, skip)
+        })
+        .collect();
+
+    let mut filled = [false; MAGLEV_TABLE_SIZE];
+    let mut next = vec![0u64; names.len()];
+    let mut remaining = MAGLEV_TABLE_SIZE;
+    while remaining > 0 {
+        for (i, (offset, skip)) in permutations.iter().enumerate() {
+            let mut slot = ((offset + next[i] * skip) % size) as usize;
+            while filled[slot] {
+                next[i] += 1;
+                slot = ((offset + next[i] * skip) % size) as usize;
+            }
+            filled[slot] = true;
+            table.positions[slot] = names[i].1;
+            next[i] += 1;
+            remaining -= 1;
+            if remaining == 0 {
+                break;
+            }
+        }
+    }
+
+    table
+}
+
+/// FNV-1a over `seed` and `bytes`. The table has to come out identical on every node, so
+/// the randomly keyed hashers used elsewhere cannot be used here.
+fn hash(bytes: &[u8], seed: u64) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in seed.to_be_bytes().iter().chain(bytes) {
+        hash ^= u64::from(*byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use mesh_cni_ebpf_common::{
+        KubeProtocol,
+        service::{EndpointValueV4, MAGLEV_TABLE_SIZE},
+    };
+
+    use super::build_table;
+
+    fn endpoints(count: u8) -> Vec<EndpointValueV4> {
+        (1..=count)
+            .map(|i| EndpointValueV4 {
+                ip: Ipv4Addr::new(10, 0, 0, i).to_bits(),
+                port: 8080,
+                _protocol: KubeProtocol::Tcp as u8,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn table_spreads_slots_evenly_regardless_of_order() {
+        let endpoints = endpoints(5);
+        let backends: Vec<&EndpointValueV4> = endpoints.iter().collect();
+        let table = build_table(&backends);
+
+        let mut counts = [0usize; 5];
+        for position in table.positions {
+            counts[position as usize] += 1;
+        }
+        let fair = MAGLEV_TABLE_SIZE / 5;
+        for count in counts {
+            assert!(count.abs_diff(fair) <= 1, "uneven spread {counts:?}");
+        }
+
+        let reversed: Vec<&EndpointValueV4> = endpoints.iter().rev().collect();
+        let reversed_table = build_table(&reversed);
+        for (slot, position) in table.positions.iter().enumerate() {
+            assert_eq!(
+                backends[*position as usize],
+                reversed[reversed_table.positions[slot] as usize]
+            );
+        }
+    }
+
+    #[test]
+    fn removing_a_backend_keeps_most_slots() {
+        let endpoints = endpoints(10);
+        let before: Vec<&EndpointValueV4> = endpoints.iter().collect();
+        let after: Vec<&EndpointValueV4> = endpoints.iter().skip(1).collect();
+        let before_table = build_table(&before);
+        let after_table = build_table(&after);
+
+        let moved = before_table
+            .positions
+            .iter()
+            .zip(after_table.positions.iter())
+            .filter(|(b, a)| {
+                let b = before[**b as usize];
+                b != &endpoints[0] && b != after[**a as usize]
+            })
+            .count();
+        // slots of surviving backends only move to fill gaps, Maglev bounds this to a
+        // small share of the table
+        assert!(moved < MAGLEV_TABLE_SIZE / 10, "{moved} slots moved");
+    }
+}
@@ -1,16 +1,30 @@
+mod id;
+mod maglev;
 mod state;
 
 use std::time::Duration;
 
 use aya::maps::{HashMap, Map, MapData};
+pub use id::IdError;
 use k8s_openapi::api::{core::v1::Service, discovery::v1::EndpointSlice};
-use kube::{Api, Client};
+use kube::{
+    Api, Client, ResourceExt,
+    core::{Expression, Selector},
+    runtime::{
+        events::{Recorder, Reporter},
+        reflector::{ObjectRef, Store},
+    },
+};
+pub use maglev::MaglevBackend;
+use mesh_cni_crds::v1alpha1::meshendpoint::{MeshEndpoint, Topology};
 use mesh_cni_ebpf_common::service::{
-    EndpointKey, EndpointValueV4, EndpointValueV6, ServiceKeyV4, ServiceKeyV6, ServiceValue,
+    EndpointKey, EndpointValueV4, EndpointValueV6, MaglevKey, MaglevTable, ServiceKeyV4,
+    ServiceKeyV6, ServiceValue, SourceRangesV4,
 };
-use mesh_cni_k8s_utils::create_store_and_subscriber;
+use mesh_cni_k8s_utils::{InitialReconcile, create_store_and_subscriber, join_reconciled};
 use mesh_cni_service_bpf_controller::{
-    start_bpf_meshendpoint_controller, start_bpf_service_controller,
+    MESH_SERVICE, start_bpf_meshendpoint_controller, start_bpf_service_controller,
+    sweep_bpf_services,
 };
 pub use state::{ServiceEndpoint, ServiceEndpointBpfMap, ServiceEndpointState};
 use tokio_util::sync::CancellationToken;
@@ -18,18 +32,32 @@ use tracing::info;
 
 use crate::{
     Result,
-    bpf::{BPF_MAP_ENDPOINTS_V4, BPF_MAP_ENDPOINTS_V6, BPF_MAP_SERVICES_V4, BPF_MAP_SERVICES_V6},
+    bpf::{
+        BPF_MAP_ENDPOINTS_V4, BPF_MAP_ENDPOINTS_V6, BPF_MAP_MAGLEV_V4, BPF_MAP_MAGLEV_V6,
+        BPF_MAP_SERVICES_V4, BPF_MAP_SERVICES_V6, BPF_MAP_SOURCE_RANGES_V4, BpfMap,
+    },
 };
 type ServiceMapV4 = HashMap<MapData, ServiceKeyV4, ServiceValue>;
 type ServiceMapV6 = HashMap<MapData, ServiceKeyV6, ServiceValue>;
 type EndpointMapV4 = HashMap<MapData, EndpointKey, EndpointValueV4>;
 type EndpointMapV6 = HashMap<MapData, EndpointKey, EndpointValueV6>;
+type MaglevMap = HashMap<MapData, MaglevKey, MaglevTable>;
+type SourceRangeMapV4 = HashMap<MapData, ServiceKeyV4, SourceRangesV4>;
+
+/// Caches of the resources service backends are derived from
+pub struct ServiceStores {
+    pub services: Store<Service>,
+    pub endpoint_slices: Store<EndpointSlice>,
+    pub mesh_endpoints: Store<MeshEndpoint>,
+}
 
-pub async fn run<SE4, SE6>(
+pub async fn run<SE4, SE6, R>(
     kube_client: Client,
-    service_bpf_state: ServiceEndpointState<SE4, SE6>,
+    service_bpf_state: ServiceEndpointState<SE4, SE6, R>,
+    topology: Topology,
     cancel: CancellationToken,
-) -> Result<()>
+    reconciled: CancellationToken,
+) -> Result<ServiceStores>
 where
     SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>
         + Send
@@ -39,6 +67,10 @@ where
         + Send
         + Sync
         + 'static,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>
+        + Send
+        + Sync
+        + 'static,
 {
     let service_api: Api<Service> = Api::all(kube_client.clone());
     let (service_state, service_subscriber) =
@@ -48,11 +80,50 @@ where
     let (endpoint_slice_state, endpoint_slice_subscriber) =
         create_store_and_subscriber(endpoint_slice_api, Some(Duration::from_secs(30))).await?;
 
-    let mesh_endpoint_api = Api::all(kube_client.clone());
+    let mesh_endpoint_api: Api<MeshEndpoint> = Api::all(kube_client.clone());
     let (mesh_endpoint_state, _) =
         create_store_and_subscriber(mesh_endpoint_api.clone(), Some(Duration::from_secs(30)))
             .await?;
 
+    let stores = ServiceStores {
+        services: service_state.clone(),
+        endpoint_slices: endpoint_slice_state.clone(),
+        mesh_endpoints: mesh_endpoint_state.clone(),
+    };
+
+    let recorder = Recorder::new(
+        kube_client.clone(),
+        Reporter {
+            controller: "mesh-cni-service-bpf-controller".into(),
+            instance: None,
+        },
+    );
+
+    sweep_bpf_services(
+        service_state.clone(),
+        endpoint_slice_state.clone(),
+        mesh_endpoint_state.clone(),
+        service_bpf_state.clone(),
+        topology.clone(),
+        recorder.clone(),
+    )?;
+
+    // the MeshEndpoint controller only watches what is not mirrored from another cluster
+    let mirrored: Selector = Expression::Equal(MESH_SERVICE.into(), "true".into()).into();
+    let services_reconciled = CancellationToken::new();
+    let mesh_endpoints_reconciled = CancellationToken::new();
+    let initial_services =
+        InitialReconcile::from_store(&service_state, services_reconciled.clone());
+    let initial_mesh_endpoints = InitialReconcile::new(
+        mesh_endpoint_state
+            .state()
+            .iter()
+            .filter(|mesh_endpoint| !mirrored.matches(mesh_endpoint.labels()))
+            .map(|mesh_endpoint| ObjectRef::from_obj(mesh_endpoint.as_ref()).erase()),
+        mesh_endpoints_reconciled.clone(),
+    );
+    join_reconciled([services_reconciled, mesh_endpoints_reconciled], reconciled);
+
     let service_controller = start_bpf_service_controller(
         service_state.clone(),
         service_subscriber,
@@ -60,6 +131,9 @@ where
         endpoint_slice_subscriber,
         mesh_endpoint_state.clone(),
         service_bpf_state.clone(),
+        topology.clone(),
+        recorder.clone(),
+        initial_services,
         cancel.clone(),
     );
 
@@ -69,12 +143,15 @@ where
         endpoint_slice_state,
         mesh_endpoint_state,
         service_bpf_state,
+        topology,
+        recorder,
+        initial_mesh_endpoints,
         cancel.clone(),
     );
     tokio::spawn(service_controller);
     tokio::spawn(mesh_endpoint_controller);
 
-    Ok(())
+    Ok(stores)
 }
 
 pub fn load_service_maps() -> Result<(ServiceMapV4, ServiceMapV6)> {
@@ -104,3 +181,24 @@ pub fn load_endpoint_maps() -> Result<(EndpointMapV4, EndpointMapV6)> {
 
     Ok((ipv4_map, ipv6_map))
 }
+
+pub fn load_maglev_maps() -> Result<(MaglevMap, MaglevMap)> {
+    info!("loading v4 maglev map");
+    let ipv4_map = MapData::from_pin(BPF_MAP_MAGLEV_V4.path())?;
+    let ipv4_map = Map::HashMap(ipv4_map);
+    let ipv4_map = ipv4_map.try_into()?;
+
+    info!("loading v6 maglev map");
+    let ipv6_map = MapData::from_pin(BPF_MAP_MAGLEV_V6.path())?;
+    let ipv6_map = Map::HashMap(ipv6_map);
+    let ipv6_map = ipv6_map.try_into()?;
+
+    Ok((ipv4_map, ipv6_map))
+}
+
+pub fn load_source_range_map() -> Result<SourceRangeMapV4> {
+    info!("loading v4 source range map");
+    let ipv4_map = MapData::from_pin(BPF_MAP_SOURCE_RANGES_V4.path())?;
+    let ipv4_map = Map::HashMap(ipv4_map);
+    Ok(ipv4_map.try_into()?)
+}
@@ -1,31 +1,54 @@
 use std::{
+    net::IpAddr,
     ops::Range,
     sync::{Arc, Mutex},
 };
 
-use ahash::{HashMap, HashMapExt};
+use ahash::{HashMap, HashMapExt, HashSet};
 use anyhow::anyhow;
+use mesh_cni_crds::v1alpha1::meshendpoint::SourceRange;
 use mesh_cni_ebpf_common::{
     Id,
     service::{
-        EndpointKey, EndpointValue, EndpointValueV4, EndpointValueV6, ServiceKey, ServiceKeyV4,
-        ServiceKeyV6, ServiceValue,
+        EndpointKey, EndpointValue, EndpointValueV4, EndpointValueV6, MAX_SOURCE_RANGES, MaglevKey,
+        MaglevTable, ServiceKey, ServiceKeyV4, ServiceKeyV6, ServiceOptions, ServiceValue,
+        SourceRangesV4,
     },
 };
 use mesh_cni_service_bpf_controller::{Error as BpfControllerError, ServiceBpfState};
 use tracing::warn;
 
-use crate::{Result, bpf::BpfMap};
+use crate::{
+    Result,
+    bpf::{
+        BpfMap,
+        service::{
+            id::IdAllocator,
+            maglev::{MaglevBackend, build_table},
+        },
+    },
+};
 
 pub trait ServiceEndpointBpfMap {
     type SKey: std::hash::Hash + std::cmp::Eq + Clone;
     type EValue: Clone + std::cmp::PartialEq;
-    fn update(&mut self, key: Self::SKey, value: Vec<&Self::EValue>, id: Id) -> Result<Id>;
-    fn remove(&mut self, key: &Self::SKey) -> Result<()>;
+    /// Writes the backends of `key`, a service that is not known yet is stored under `id`.
+    /// Returns the id the service is stored under.
+    fn update(
+        &mut self,
+        key: Self::SKey,
+        options: ServiceOptions,
+        value: Vec<&Self::EValue>,
+        id: Id,
+    ) -> Result<Id>;
+    /// Removes `key` and its backends. Returns the id of the service once nothing is keyed
+    /// by it anymore.
+    fn remove(&mut self, key: &Self::SKey) -> Result<Option<Id>>;
     fn get_from_cache(&self, key: &Self::SKey) -> Option<&ServiceValue>;
     fn insert_new_service(
         &mut self,
         key: Self::SKey,
+        options: ServiceOptions,
         value: Vec<&Self::EValue>,
         id: Id,
     ) -> Result<Id>;
@@ -35,16 +58,25 @@ pub trait ServiceEndpointBpfMap {
         endpoints: Vec<&Self::EValue>,
     ) -> Result<()>;
     fn delete_endpoints(&mut self, service_value: &ServiceValue, range: Range<u16>) -> Result<()>;
+    fn update_maglev_table(
+        &mut self,
+        service_value: &ServiceValue,
+        endpoints: &[&Self::EValue],
+    ) -> Result<()>;
     fn get_service_cache(&self) -> &ahash::HashMap<Self::SKey, ServiceValue>;
     fn get_service_map(&self) -> Result<ahash::HashMap<Self::SKey, ServiceValue>>;
     fn get_endpoint_cache(&self) -> &ahash::HashMap<EndpointKey, Self::EValue>;
     fn get_endpoint_map(&self) -> Result<ahash::HashMap<EndpointKey, Self::EValue>>;
+    /// Deletes the endpoint slots and Maglev tables no service points at. Returns the ids
+    /// that have nothing left keyed by them.
+    fn sweep_orphans(&mut self) -> Result<Vec<Id>>;
 }
 
-pub struct ServiceEndpoint<S, E, SK, EV>
+pub struct ServiceEndpoint<S, E, M, SK, EV>
 where
     S: BpfMap,
     E: BpfMap,
+    M: BpfMap,
     SK: std::hash::Hash + std::cmp::Eq + Clone + Copy,
     EV: Clone + std::cmp::PartialEq + Copy,
 {
@@ -52,71 +84,100 @@ where
     service_map: S,
     endpoint_cache: ahash::HashMap<EndpointKey, EV>,
     endpoint_map: E,
+    maglev_map: M,
 }
 
-impl<S, E, SK, EV> ServiceEndpoint<S, E, SK, EV>
+impl<S, E, M, SK, EV> ServiceEndpoint<S, E, M, SK, EV>
 where
-    S: BpfMap,
-    E: BpfMap,
+    S: BpfMap<Key = SK, Value = ServiceValue, KeyOutput = SK>,
+    E: BpfMap<Key = EndpointKey, Value = EV, KeyOutput = EndpointKey>,
+    M: BpfMap,
     SK: std::hash::Hash + std::cmp::Eq + Clone + Copy,
     EV: Clone + std::cmp::PartialEq + Copy,
 {
-    // TODO: create cached maps from bpf maps?
-    pub fn new(service_map: S, endpoint_map: E) -> Self {
-        Self {
-            service_cache: ahash::HashMap::default(),
+    /// The caches start out with whatever the pinned maps already hold
+    pub fn new(service_map: S, endpoint_map: E, maglev_map: M) -> Result<Self> {
+        Ok(Self {
+            service_cache: service_map.get_state()?,
             service_map,
-            endpoint_cache: ahash::HashMap::default(),
+            endpoint_cache: endpoint_map.get_state()?,
             endpoint_map,
-        }
+            maglev_map,
+        })
     }
 }
 
-impl<S, E, SK, EV> ServiceEndpointBpfMap for ServiceEndpoint<S, E, SK, EV>
+impl<S, E, M, SK, EV> ServiceEndpointBpfMap for ServiceEndpoint<S, E, M, SK, EV>
 where
     S: BpfMap<Key = SK, Value = ServiceValue, KeyOutput = SK>,
     E: BpfMap<Key = EndpointKey, Value = EV, KeyOutput = EndpointKey>,
+    M: BpfMap<Key = MaglevKey, Value = MaglevTable, KeyOutput = MaglevKey>,
     SK: std::hash::Hash + std::cmp::Eq + Clone + Copy,
-    EV: Clone + std::cmp::PartialEq + Copy,
+    EV: Clone + std::cmp::PartialEq + Copy + MaglevBackend,
 {
     type SKey = SK;
     type EValue = EV;
-    fn update(&mut self, key: Self::SKey, value: Vec<&Self::EValue>, id: Id) -> Result<Id> {
+    fn update(
+        &mut self,
+        key: Self::SKey,
+        options: ServiceOptions,
+        value: Vec<&Self::EValue>,
+        id: Id,
+    ) -> Result<Id> {
         let new_count = u16::try_from(value.len()).map_err(|e| anyhow!(e.to_string()))?;
 
-        let Some(current_service_value) = self.service_cache.get(&key) else {
-            return self.insert_new_service(key, value, id);
+        let Some(current_service_value) = self.service_cache.get(&key).copied() else {
+            return self.insert_new_service(key, options, value, id);
         };
 
         let id = current_service_value.id;
-        let old_count = current_service_value.count;
-
-        let new_service_value = ServiceValue {
-            id,
-            count: new_count,
-        };
+        let unchanged = current_service_value.count == new_count
+            && current_service_value.options() == options
+            && value.iter().enumerate().all(|(position, endpoint)| {
+                let endpoint_key = current_service_value.endpoint_key(position as u16);
+                self.endpoint_cache.get(&endpoint_key) == Some(*endpoint)
+            });
+        if unchanged {
+            return Ok(id);
+        }
 
+        // the new backend set goes into the slots of the next revision and is published by
+        // the single write of the service value, so the datapath either reads the old set or
+        // the new one in full. The old set is freed right after, a reader still holding the
+        // old service value misses and reads it again. Revisions only come back after 255
+        // more updates so a slot is never refilled under a reader holding its revision.
+        let new_service_value = ServiceValue::new(id, new_count, options)
+            .with_revision(current_service_value.revision.wrapping_add(1));
+        self.update_maglev_table(&new_service_value, &value)?;
         self.insert_endpoints(&new_service_value, value)?;
 
-        if old_count > new_count {
-            self.delete_endpoints(&new_service_value, new_count..old_count)?;
+        self.service_map.update(key, new_service_value)?;
+        self.service_cache.insert(key, new_service_value);
+
+        self.delete_endpoints(&current_service_value, 0..current_service_value.count)?;
+        if current_service_value.options().maglev {
+            self.maglev_map
+                .delete(&current_service_value.maglev_key())?;
         }
 
         Ok(id)
     }
 
-    fn remove(&mut self, key: &Self::SKey) -> Result<()> {
+    fn remove(&mut self, key: &Self::SKey) -> Result<Option<Id>> {
         let Some(service_value) = self.service_cache.get(key) else {
-            return Ok(());
+            return Ok(None);
         };
         let service_value = *service_value;
 
-        let range = 0..service_value.count;
-        self.delete_endpoints(&service_value, range)?;
-
         self.service_map.delete(key)?;
         self.service_cache.remove(key);
-        Ok(())
+
+        let range = 0..service_value.count;
+        self.delete_endpoints(&service_value, range)?;
+        if service_value.options().maglev {
+            self.maglev_map.delete(&service_value.maglev_key())?;
+        }
+        Ok(Some(service_value.id))
     }
 
     fn get_from_cache(&self, key: &Self::SKey) -> Option<&ServiceValue> {
@@ -126,25 +187,18 @@ where
     fn insert_new_service(
         &mut self,
         key: Self::SKey,
+        options: ServiceOptions,
         value: Vec<&Self::EValue>,
-        mut id: Id,
+        id: Id,
     ) -> Result<Id> {
         let count = u16::try_from(value.len()).map_err(|e| anyhow!(e.to_string()))?;
-        let service_value = ServiceValue { id, count };
+        let service_value = ServiceValue::new(id, count, options);
+
+        self.update_maglev_table(&service_value, &value)?;
+        self.insert_endpoints(&service_value, value)?;
 
         self.service_map.update(key, service_value)?;
         self.service_cache.insert(key, service_value);
-
-        for (position, endpoint) in value.iter().enumerate() {
-            let endpoint_key = EndpointKey::new(
-                id,
-                u16::try_from(position).map_err(|e| anyhow!(e.to_string()))?,
-            );
-
-            self.endpoint_map.update(endpoint_key, **endpoint)?;
-            self.endpoint_cache.insert(endpoint_key, **endpoint);
-        }
-        id += 1;
         Ok(id)
     }
 
@@ -156,7 +210,7 @@ where
         for (position, ep) in endpoints.iter().enumerate() {
             let position = u16::try_from(position)
                 .map_err(|e| anyhow!("failed to convert position: {}", e))?;
-            let endpoint_key = EndpointKey::new(service_value.id, position);
+            let endpoint_key = service_value.endpoint_key(position);
             self.endpoint_map.update(endpoint_key, **ep)?;
             self.endpoint_cache.insert(endpoint_key, **ep);
         }
@@ -165,13 +219,25 @@ where
 
     fn delete_endpoints(&mut self, service_value: &ServiceValue, range: Range<u16>) -> Result<()> {
         for idx in range {
-            let endpoint_key = EndpointKey::new(service_value.id, idx);
+            let endpoint_key = service_value.endpoint_key(idx);
             self.endpoint_map.delete(&endpoint_key)?;
             self.endpoint_cache.remove(&endpoint_key);
         }
         Ok(())
     }
 
+    fn update_maglev_table(
+        &mut self,
+        service_value: &ServiceValue,
+        endpoints: &[&Self::EValue],
+    ) -> Result<()> {
+        if !service_value.options().maglev {
+            return Ok(());
+        }
+        self.maglev_map
+            .update(service_value.maglev_key(), build_table(endpoints))
+    }
+
     fn get_service_cache(&self) -> &ahash::HashMap<Self::SKey, ServiceValue> {
         &self.service_cache
     }
@@ -198,71 +264,133 @@ where
         }
         Ok(map)
     }
+
+    fn sweep_orphans(&mut self) -> Result<Vec<Id>> {
+        // slots past the count of the live revision are never read either
+        let counts: ahash::HashMap<(Id, u8), u16> = self
+            .service_cache
+            .values()
+            .map(|v| ((v.id, v.revision), v.count))
+            .collect();
+        let stale_endpoints: Vec<EndpointKey> = self
+            .endpoint_cache
+            .keys()
+            .filter(|k| {
+                counts
+                    .get(&(k.id, k.revision))
+                    .is_none_or(|count| k.position >= *count)
+            })
+            .copied()
+            .collect();
+        let live_tables: HashSet<MaglevKey> = self
+            .service_cache
+            .values()
+            .filter(|v| v.options().maglev)
+            .map(|v| v.maglev_key())
+            .collect();
+        let stale_tables: Vec<MaglevKey> = self
+            .maglev_map
+            .get_state()?
+            .into_keys()
+            .filter(|k| !live_tables.contains(k))
+            .collect();
+
+        let mut freed = HashSet::default();
+        for key in stale_endpoints {
+            self.endpoint_map.delete(&key)?;
+            self.endpoint_cache.remove(&key);
+            freed.insert(key.id);
+        }
+        for key in stale_tables {
+            self.maglev_map.delete(&key)?;
+            freed.insert(key.id);
+        }
+        for v in self.service_cache.values() {
+            freed.remove(&v.id);
+        }
+        Ok(freed.into_iter().collect())
+    }
 }
 
-struct Shared<SE4, SE6>
+struct Shared<SE4, SE6, R>
 where
     SE4: ServiceEndpointBpfMap,
     SE6: ServiceEndpointBpfMap,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
-    state: Mutex<State<SE4, SE6>>,
+    state: Mutex<State<SE4, SE6, R>>,
 }
 
-struct State<SE4, SE6>
+struct State<SE4, SE6, R>
 where
     SE4: ServiceEndpointBpfMap,
     SE6: ServiceEndpointBpfMap,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
     service_endpoint_v4: SE4,
     service_endpoint_v6: SE6,
-    id: Id,
+    /// Source ranges are only enforced by the IPv4 host datapath, the controller refuses
+    /// IPv6 frontends restricted by any
+    source_range_map_v4: R,
+    source_range_cache_v4: ahash::HashMap<ServiceKeyV4, SourceRangesV4>,
+    ids: IdAllocator,
+    /// Ids of services whose first write failed. Slots may have been written under them, so
+    /// they are kept for the next attempt instead of being released.
+    pending_ids: ahash::HashMap<ServiceKey, Id>,
 }
 
-pub struct ServiceEndpointState<SE4, SE6>
+impl<SE4, SE6, R> State<SE4, SE6, R>
 where
-    SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
-    SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    SE4: ServiceEndpointBpfMap,
+    SE6: ServiceEndpointBpfMap,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
-    shared: Arc<Shared<SE4, SE6>>,
-}
+    fn is_known(&self, key: &ServiceKey) -> bool {
+        match key {
+            ServiceKey::V4(key) => self.service_endpoint_v4.get_from_cache(key).is_some(),
+            ServiceKey::V6(key) => self.service_endpoint_v6.get_from_cache(key).is_some(),
+        }
+    }
 
-impl<SE4, SE6> Clone for ServiceEndpointState<SE4, SE6>
-where
-    SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
-    SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
-{
-    fn clone(&self) -> Self {
-        Self {
-            shared: Arc::clone(&self.shared),
+    fn update_source_ranges(&mut self, key: ServiceKeyV4, ranges: SourceRangesV4) -> Result<()> {
+        if self.source_range_cache_v4.get(&key) == Some(&ranges) {
+            return Ok(());
         }
+        self.source_range_map_v4.update(key, ranges)?;
+        self.source_range_cache_v4.insert(key, ranges);
+        Ok(())
+    }
+
+    fn remove_source_ranges(&mut self, key: &ServiceKeyV4) -> Result<()> {
+        if self.source_range_cache_v4.remove(key).is_some() {
+            self.source_range_map_v4.delete(key)?;
+        }
+        Ok(())
     }
 }
 
-impl<SE4, SE6> ServiceEndpointState<SE4, SE6>
+impl<SE4, SE6, R> State<SE4, SE6, R>
 where
     SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
     SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
-    pub(crate) fn new(service_endpoint_v4: SE4, service_endpoint_v6: SE6) -> Self {
-        let state = State {
-            service_endpoint_v4,
-            service_endpoint_v6,
-            id: 128,
-        };
-
-        let shared = Shared {
-            state: Mutex::new(state),
-        };
-        let shared = Arc::new(shared);
-
-        Self { shared }
-    }
-
-    pub(crate) fn update(&self, key: ServiceKey, value: Vec<EndpointValue>) -> Result<()> {
-        let mut state = self.shared.state.lock().unwrap();
-        let current_id = state.id;
-        let new_id = match key {
+    fn update_service(
+        &mut self,
+        key: ServiceKey,
+        options: ServiceOptions,
+        source_ranges: Vec<SourceRange>,
+        value: Vec<EndpointValue>,
+        id: Id,
+    ) -> Result<()> {
+        match key {
             ServiceKey::V4(service_key_v4) => {
+                // ranges are written before a new frontend shows up in the service map so
+                // it never accepts every source, even briefly
+                let source_ranges = source_ranges_v4(&source_ranges)?;
+                if let Some(source_ranges) = source_ranges {
+                    self.update_source_ranges(service_key_v4, source_ranges)?;
+                }
                 let endpoints = value
                     .iter()
                     .filter_map(|e| {
@@ -273,9 +401,11 @@ where
                         }
                     })
                     .collect();
-                state
-                    .service_endpoint_v4
-                    .update(service_key_v4, endpoints, current_id)?
+                self.service_endpoint_v4
+                    .update(service_key_v4, options, endpoints, id)?;
+                if source_ranges.is_none() {
+                    self.remove_source_ranges(&service_key_v4)?;
+                }
             }
             ServiceKey::V6(service_key_v6) => {
                 let endpoints = value
@@ -288,25 +418,174 @@ where
                         }
                     })
                     .collect();
-                state
-                    .service_endpoint_v6
-                    .update(service_key_v6, endpoints, current_id)?
+                self.service_endpoint_v6
+                    .update(service_key_v6, options, endpoints, id)?;
             }
-        };
-        if new_id > current_id {
-            state.id += 1
         }
         Ok(())
     }
+}
+
+pub struct ServiceEndpointState<SE4, SE6, R>
+where
+    SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
+    SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
+{
+    shared: Arc<Shared<SE4, SE6, R>>,
+}
+
+impl<SE4, SE6, R> Clone for ServiceEndpointState<SE4, SE6, R>
+where
+    SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
+    SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
+{
+    fn clone(&self) -> Self {
+        Self {
+            shared: Arc::clone(&self.shared),
+        }
+    }
+}
+
+impl<SE4, SE6, R> ServiceEndpointState<SE4, SE6, R>
+where
+    SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
+    SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
+{
+    /// Ids still referenced by the pinned maps, by a service or by endpoint slots that were
+    /// not purged yet, stay taken.
+    pub(crate) fn new(
+        service_endpoint_v4: SE4,
+        service_endpoint_v6: SE6,
+        source_range_map_v4: R,
+    ) -> Result<Self> {
+        let mut in_use = vec![];
+        in_use.extend(
+            service_endpoint_v4
+                .get_service_map()?
+                .values()
+                .map(|v| v.id),
+        );
+        in_use.extend(service_endpoint_v4.get_endpoint_map()?.keys().map(|k| k.id));
+        in_use.extend(
+            service_endpoint_v6
+                .get_service_map()?
+                .values()
+                .map(|v| v.id),
+        );
+        in_use.extend(service_endpoint_v6.get_endpoint_map()?.keys().map(|k| k.id));
+
+        let state = State {
+            service_endpoint_v4,
+            service_endpoint_v6,
+            source_range_cache_v4: source_range_map_v4.get_state()?,
+            source_range_map_v4,
+            ids: IdAllocator::new(in_use),
+            pending_ids: ahash::HashMap::default(),
+        };
+
+        let shared = Shared {
+            state: Mutex::new(state),
+        };
+        let shared = Arc::new(shared);
+
+        Ok(Self { shared })
+    }
+
+    pub(crate) fn update(
+        &self,
+        key: ServiceKey,
+        options: ServiceOptions,
+        source_ranges: Vec<SourceRange>,
+        value: Vec<EndpointValue>,
+    ) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        let new_id = if state.is_known(&key) {
+            None
+        } else if let Some(id) = state.pending_ids.remove(&key) {
+            Some(id)
+        } else {
+            Some(state.ids.allocate()?)
+        };
+        let id = new_id.unwrap_or_default();
+
+        let result = state.update_service(key, options, source_ranges, value, id);
+        if let (Err(_), Some(id)) = (&result, new_id)
+            && !state.is_known(&key)
+        {
+            state.pending_ids.insert(key, id);
+        }
+        result
+    }
 
     pub(crate) fn remove(&self, key: &ServiceKey) -> Result<()> {
         let mut state = self.shared.state.lock().unwrap();
-        match key {
+        let purged = match key {
             ServiceKey::V4(service_key_v4) => {
-                state.service_endpoint_v4.remove(service_key_v4)?;
+                let purged = state.service_endpoint_v4.remove(service_key_v4)?;
+                state.remove_source_ranges(service_key_v4)?;
+                purged
             }
-            ServiceKey::V6(service_key_v6) => {
-                state.service_endpoint_v6.remove(service_key_v6)?;
+            ServiceKey::V6(service_key_v6) => state.service_endpoint_v6.remove(service_key_v6)?,
+        };
+        if let Some(id) = purged {
+            state.ids.release(id);
+        }
+        Ok(())
+    }
+
+    pub(crate) fn sweep(&self, live: &HashSet<ServiceKey>) -> Result<()> {
+        let mut state = self.shared.state.lock().unwrap();
+        let stale_v4: Vec<ServiceKeyV4> = state
+            .service_endpoint_v4
+            .get_service_cache()
+            .keys()
+            .filter(|k| !live.contains(&ServiceKey::V4(**k)))
+            .copied()
+            .collect();
+        let stale_v6: Vec<ServiceKeyV6> = state
+            .service_endpoint_v6
+            .get_service_cache()
+            .keys()
+            .filter(|k| !live.contains(&ServiceKey::V6(**k)))
+            .copied()
+            .collect();
+        for key in stale_v4 {
+            if let Some(id) = state.service_endpoint_v4.remove(&key)? {
+                state.ids.release(id);
+            }
+        }
+        for key in stale_v6 {
+            if let Some(id) = state.service_endpoint_v6.remove(&key)? {
+                state.ids.release(id);
+            }
+        }
+
+        let stale_ranges: Vec<ServiceKeyV4> = state
+            .source_range_cache_v4
+            .keys()
+            .filter(|k| state.service_endpoint_v4.get_from_cache(k).is_none())
+            .copied()
+            .collect();
+        for key in stale_ranges {
+            state.remove_source_ranges(&key)?;
+        }
+
+        let mut freed = state.service_endpoint_v4.sweep_orphans()?;
+        freed.extend(state.service_endpoint_v6.sweep_orphans()?);
+        // the ids are shared by both families, one may still be in use by the other
+        for id in freed {
+            let in_use = state
+                .service_endpoint_v4
+                .get_service_cache()
+                .values()
+                .chain(state.service_endpoint_v6.get_service_cache().values())
+                .any(|v| v.id == id);
+            if !in_use {
+                state.pending_ids.retain(|_, pending| *pending != id);
+                state.ids.release(id);
             }
         }
         Ok(())
@@ -323,8 +602,7 @@ where
             let mut endpoints = vec![];
             let count = v.count;
             for idx in 0..count {
-                let Some(endpoint_value) = cached_endpoints_v4.get(&EndpointKey::new(v.id, idx))
-                else {
+                let Some(endpoint_value) = cached_endpoints_v4.get(&v.endpoint_key(idx)) else {
                     warn!("did not find endpoints with id {} and idx {}", v.id, idx);
                     continue;
                 };
@@ -338,8 +616,7 @@ where
             let mut endpoints = vec![];
             let count = v.count;
             for idx in 0..count {
-                let Some(endpoint_value) = cached_endpoints_v6.get(&EndpointKey::new(v.id, idx))
-                else {
+                let Some(endpoint_value) = cached_endpoints_v6.get(&v.endpoint_key(idx)) else {
                     warn!("did not find endpoints with id {} and idx {}", v.id, idx);
                     continue;
                 };
@@ -361,7 +638,7 @@ where
             let mut endpoints = vec![];
             let count = v.count;
             for idx in 0..count {
-                let Some(endpoint_value) = endpoint_map_v4.get(&EndpointKey::new(v.id, idx)) else {
+                let Some(endpoint_value) = endpoint_map_v4.get(&v.endpoint_key(idx)) else {
                     warn!("did not find endpoints with id {} and idx {}", v.id, idx);
                     continue;
                 };
@@ -377,7 +654,7 @@ where
             let mut endpoints = vec![];
             let count = v.count;
             for idx in 0..count {
-                let Some(endpoint_value) = endpoint_map_v6.get(&EndpointKey::new(v.id, idx)) else {
+                let Some(endpoint_value) = endpoint_map_v6.get(&v.endpoint_key(idx)) else {
                     warn!("did not find endpoints with id {} and idx {}", v.id, idx);
                     continue;
                 };
@@ -390,17 +667,20 @@ where
     }
 }
 
-impl<SE4, SE6> ServiceBpfState for ServiceEndpointState<SE4, SE6>
+impl<SE4, SE6, R> ServiceBpfState for ServiceEndpointState<SE4, SE6, R>
 where
     SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
     SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
     fn update(
         &self,
         key: ServiceKey,
+        options: ServiceOptions,
+        source_ranges: Vec<SourceRange>,
         value: Vec<EndpointValue>,
     ) -> std::result::Result<(), BpfControllerError> {
-        ServiceEndpointState::update(self, key, value)
+        ServiceEndpointState::update(self, key, options, source_ranges, value)
             .map_err(|e| BpfControllerError::BpfState(e.to_string()))
     }
 
@@ -408,26 +688,448 @@ where
         ServiceEndpointState::remove(self, key)
             .map_err(|e| BpfControllerError::BpfState(e.to_string()))
     }
+
+    fn sweep(&self, live: &HashSet<ServiceKey>) -> std::result::Result<(), BpfControllerError> {
+        ServiceEndpointState::sweep(self, live)
+            .map_err(|e| BpfControllerError::BpfState(e.to_string()))
+    }
+}
+
+/// Source ranges of an IPv4 frontend in the layout the datapath reads, `None` when it
+/// accepts any source
+fn source_ranges_v4(source_ranges: &[SourceRange]) -> Result<Option<SourceRangesV4>> {
+    let mut result = SourceRangesV4::default();
+    for range in source_ranges {
+        let IpAddr::V4(network) = range.network else {
+            continue;
+        };
+        let Some(entry) = result.ranges.get_mut(result.count as usize) else {
+            return Err(anyhow!(
+                "more than {} source ranges are not supported",
+                MAX_SOURCE_RANGES
+            ));
+        };
+        let mask = u32::MAX
+            .checked_shl(32 - u32::from(range.prefix_len))
+            .unwrap_or(0);
+        entry.network = network.to_bits() & mask;
+        entry.mask = mask;
+        result.count += 1;
+    }
+    Ok((result.count > 0).then_some(result))
 }
 
 #[cfg(test)]
 mod test {
-    use std::net::Ipv4Addr;
+    use std::{cell::RefCell, hash::Hash, net::Ipv4Addr, rc::Rc};
 
     use ahash::HashMap;
-    use mesh_cni_ebpf_common::KubeProtocol;
+    use aya::Pod;
+    use mesh_cni_ebpf_common::{KubeProtocol, service::MAGLEV_TABLE_SIZE};
 
     use super::*;
 
+    /// Service and endpoint maps as the datapath sees them between two writes
+    #[derive(Clone, Default)]
+    struct Snapshot {
+        services: HashMap<ServiceKeyV4, ServiceValue>,
+        endpoints: HashMap<EndpointKey, EndpointValueV4>,
+    }
+
+    /// Map recording the state after every write so concurrent readers can be replayed
+    /// against each of them
+    struct Journaled<K, V> {
+        history: Rc<RefCell<Vec<Snapshot>>>,
+        map: fn(&mut Snapshot) -> &mut HashMap<K, V>,
+    }
+
+    impl<K, V> Journaled<K, V> {
+        fn write(&self, f: impl FnOnce(&mut HashMap<K, V>)) {
+            let mut history = self.history.borrow_mut();
+            let mut snapshot = history.last().cloned().unwrap_or_default();
+            f((self.map)(&mut snapshot));
+            history.push(snapshot);
+        }
+
+        fn current(&self) -> HashMap<K, V>
+        where
+            K: Clone,
+            V: Clone,
+        {
+            let mut snapshot = self.history.borrow().last().cloned().unwrap_or_default();
+            (self.map)(&mut snapshot).clone()
+        }
+    }
+
+    impl<K, V> BpfMap for Journaled<K, V>
+    where
+        K: Pod + Eq + Hash,
+        V: Pod,
+    {
+        type Key = K;
+        type Value = V;
+        type KeyOutput = K;
+        fn update(&mut self, key: K, value: V) -> crate::Result<()> {
+            self.write(|map| {
+                map.insert(key, value);
+            });
+            Ok(())
+        }
+        fn delete(&mut self, key: &K) -> crate::Result<()> {
+            self.write(|map| {
+                map.remove(key);
+            });
+            Ok(())
+        }
+        fn get(&self, key: &K) -> crate::Result<V> {
+            self.current()
+                .get(key)
+                .copied()
+                .ok_or_else(|| anyhow!("not found"))
+        }
+        fn get_state(&self) -> crate::Result<HashMap<K, V>> {
+            Ok(self.current())
+        }
+    }
+
+    fn services(snapshot: &mut Snapshot) -> &mut HashMap<ServiceKeyV4, ServiceValue> {
+        &mut snapshot.services
+    }
+
+    fn endpoints(snapshot: &mut Snapshot) -> &mut HashMap<EndpointKey, EndpointValueV4> {
+        &mut snapshot.endpoints
+    }
+
     fn new_service_endpoint() -> ServiceEndpoint<
         HashMap<ServiceKeyV4, ServiceValue>,
         HashMap<EndpointKey, EndpointValueV4>,
+        HashMap<MaglevKey, MaglevTable>,
         ServiceKeyV4,
         EndpointValueV4,
     > {
         let service_map: HashMap<ServiceKeyV4, ServiceValue> = HashMap::default();
         let endpoint_map: HashMap<EndpointKey, EndpointValueV4> = HashMap::default();
-        ServiceEndpoint::new(service_map, endpoint_map)
+        let maglev_map: HashMap<MaglevKey, MaglevTable> = HashMap::default();
+        ServiceEndpoint::new(service_map, endpoint_map, maglev_map).unwrap()
+    }
+
+    fn new_service_endpoint_v6() -> ServiceEndpoint<
+        HashMap<ServiceKeyV6, ServiceValue>,
+        HashMap<EndpointKey, EndpointValueV6>,
+        HashMap<MaglevKey, MaglevTable>,
+        ServiceKeyV6,
+        EndpointValueV6,
+    > {
+        ServiceEndpoint::new(HashMap::default(), HashMap::default(), HashMap::default()).unwrap()
+    }
+
+    #[test]
+    fn test_source_ranges_follow_frontend() -> crate::Result<()> {
+        let service_endpoint_v6: ServiceEndpoint<
+            HashMap<ServiceKeyV6, ServiceValue>,
+            HashMap<EndpointKey, EndpointValueV6>,
+            HashMap<MaglevKey, MaglevTable>,
+            ServiceKeyV6,
+            EndpointValueV6,
+        > = ServiceEndpoint::new(HashMap::default(), HashMap::default(), HashMap::default())?;
+        let source_range_map: HashMap<ServiceKeyV4, SourceRangesV4> = HashMap::default();
+        let state = ServiceEndpointState::new(
+            new_service_endpoint(),
+            service_endpoint_v6,
+            source_range_map,
+        )?;
+
+        let service_key = ServiceKeyV4::new(
+            Ipv4Addr::new(203, 0, 113, 10).to_bits(),
+            80,
+            KubeProtocol::Tcp as u8,
+        );
+        let endpoint = EndpointValue::V4(EndpointValueV4 {
+            ip: Ipv4Addr::new(10, 0, 0, 1).to_bits(),
+            port: 8080,
+            _protocol: KubeProtocol::Tcp as u8,
+        });
+        let source_range = SourceRange {
+            network: Ipv4Addr::new(198, 51, 100, 7).into(),
+            prefix_len: 24,
+        };
+        state.update(
+            ServiceKey::V4(service_key),
+            ServiceOptions::default(),
+            vec![source_range],
+            vec![endpoint],
+        )?;
+        {
+            let guard = state.shared.state.lock().unwrap();
+            let source_ranges = guard.source_range_map_v4[&service_key];
+            assert!(source_ranges.allows(Ipv4Addr::new(198, 51, 100, 200).to_bits()));
+            assert!(!source_ranges.allows(Ipv4Addr::new(198, 51, 101, 1).to_bits()));
+        }
+
+        state.update(
+            ServiceKey::V4(service_key),
+            ServiceOptions::default(),
+            vec![],
+            vec![endpoint],
+        )?;
+        assert!(
+            state
+                .shared
+                .state
+                .lock()
+                .unwrap()
+                .source_range_map_v4
+                .is_empty()
+        );
+
+        let too_many = vec![source_range; MAX_SOURCE_RANGES + 1];
+        assert!(
+            state
+                .update(
+                    ServiceKey::V4(service_key),
+                    ServiceOptions::default(),
+                    too_many,
+                    vec![endpoint],
+                )
+                .is_err()
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_ids_rebuilt_from_maps_and_released_after_purge() -> crate::Result<()> {
+        let restored_key = ServiceKeyV4::new(
+            Ipv4Addr::new(192, 168, 0, 1).to_bits(),
+            80,
+            KubeProtocol::Tcp as u8,
+        );
+        let endpoint = EndpointValueV4 {
+            ip: Ipv4Addr::new(10, 0, 0, 1).to_bits(),
+            port: 8080,
+            _protocol: KubeProtocol::Tcp as u8,
+        };
+        let mut service_map = HashMap::default();
+        service_map.insert(
+            restored_key,
+            ServiceValue::new(200, 1, ServiceOptions::default()),
+        );
+        let mut endpoint_map = HashMap::default();
+        endpoint_map.insert(EndpointKey::new(200, 0, 0), endpoint);
+        // slot of a service whose removal did not finish
+        endpoint_map.insert(EndpointKey::new(201, 0, 0), endpoint);
+        let service_endpoint_v4 = ServiceEndpoint::new(
+            service_map,
+            endpoint_map,
+            HashMap::<MaglevKey, MaglevTable>::default(),
+        )?;
+
+        let source_range_map: HashMap<ServiceKeyV4, SourceRangesV4> = HashMap::default();
+        let state = ServiceEndpointState::new(
+            service_endpoint_v4,
+            new_service_endpoint_v6(),
+            source_range_map,
+        )?;
+        assert_eq!(state.shared.state.lock().unwrap().ids.in_use(), 2);
+
+        let service_key = ServiceKey::V4(ServiceKeyV4::new(
+            Ipv4Addr::new(192, 168, 0, 2).to_bits(),
+            80,
+            KubeProtocol::Tcp as u8,
+        ));
+        let endpoints = vec![EndpointValue::V4(endpoint)];
+        state.update(service_key, ServiceOptions::default(), vec![], endpoints)?;
+        {
+            let guard = state.shared.state.lock().unwrap();
+            let ServiceKey::V4(key) = service_key else {
+                unreachable!()
+            };
+            assert_eq!(
+                guard.service_endpoint_v4.get_from_cache(&key).unwrap().id,
+                202
+            );
+            assert_eq!(guard.ids.in_use(), 3);
+        }
+
+        state.remove(&service_key)?;
+        assert_eq!(state.shared.state.lock().unwrap().ids.in_use(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_sweep_removes_what_no_frontend_references() -> crate::Result<()> {
+        let key = |last| {
+            ServiceKeyV4::new(
+                Ipv4Addr::new(192, 168, 0, last).to_bits(),
+                80,
+                KubeProtocol::Tcp as u8,
+            )
+        };
+        let (live_key, stale_key) = (key(1), key(2));
+        let endpoint = EndpointValueV4 {
+            ip: Ipv4Addr::new(10, 0, 0, 1).to_bits(),
+            port: 8080,
+            _protocol: KubeProtocol::Tcp as u8,
+        };
+        let maglev = ServiceOptions {
+            maglev: true,
+            ..Default::default()
+        };
+        let live_value = ServiceValue::new(200, 1, maglev);
+        let stale_value = ServiceValue::new(201, 1, ServiceOptions::default());
+
+        let mut service_map = HashMap::default();
+        service_map.insert(live_key, live_value);
+        service_map.insert(stale_key, stale_value);
+        let mut endpoint_map = HashMap::default();
+        endpoint_map.insert(live_value.endpoint_key(0), endpoint);
+        // past the count, another revision and a service that is gone entirely
+        endpoint_map.insert(live_value.endpoint_key(1), endpoint);
+        endpoint_map.insert(live_value.with_revision(1).endpoint_key(0), endpoint);
+        endpoint_map.insert(stale_value.endpoint_key(0), endpoint);
+        endpoint_map.insert(EndpointKey::new(202, 0, 0), endpoint);
+        let table = MaglevTable {
+            positions: [0; MAGLEV_TABLE_SIZE],
+        };
+        let mut maglev_map = HashMap::default();
+        maglev_map.insert(live_value.maglev_key(), table);
+        maglev_map.insert(live_value.with_revision(1).maglev_key(), table);
+        let mut source_range_map = HashMap::default();
+        source_range_map.insert(stale_key, SourceRangesV4::default());
+
+        let state = ServiceEndpointState::new(
+            ServiceEndpoint::new(service_map, endpoint_map, maglev_map)?,
+            new_service_endpoint_v6(),
+            source_range_map,
+        )?;
+        // the caches pick up what the maps held
+        assert_eq!(state.state_from_cache()?.len(), 2);
+
+        let live = HashSet::from_iter([ServiceKey::V4(live_key)]);
+        state.sweep(&live)?;
+
+        let guard = state.shared.state.lock().unwrap();
+        let service_endpoint = &guard.service_endpoint_v4;
+        assert_eq!(
+            service_endpoint
+                .get_service_map()?
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![live_key]
+        );
+        assert_eq!(
+            service_endpoint
+                .get_endpoint_map()?
+                .into_keys()
+                .collect::<Vec<_>>(),
+            vec![live_value.endpoint_key(0)]
+        );
+        assert_eq!(
+            service_endpoint.maglev_map.keys().collect::<Vec<_>>(),
+            vec![&live_value.maglev_key()]
+        );
+        assert!(guard.source_range_map_v4.is_empty());
+        assert_eq!(guard.ids.in_use(), 1);
+
+        Ok(())
+    }
+
+    /// Replays the reads of translate_v4 against every state a series of updates passes
+    /// through. The service value is read first and the endpoint slot at any later point,
+    /// even after further updates, a miss reads the service value once more. Every read has
+    /// to land on the backend at that position of the set the service value read belongs
+    /// to, a second miss is refused by the datapath and only happens once the set was
+    /// replaced again.
+    #[test]
+    fn test_readers_never_see_torn_backend_set() -> crate::Result<()> {
+        let history = Rc::new(RefCell::new(vec![Snapshot::default()]));
+        let mut service_endpoint = ServiceEndpoint::new(
+            Journaled {
+                history: Rc::clone(&history),
+                map: services,
+            },
+            Journaled {
+                history: Rc::clone(&history),
+                map: endpoints,
+            },
+            HashMap::<MaglevKey, MaglevTable>::default(),
+        )?;
+
+        let service_key = ServiceKeyV4::new(
+            Ipv4Addr::new(192, 168, 0, 1).to_bits(),
+            80,
+            KubeProtocol::Tcp as u8,
+        );
+        let backends: Vec<EndpointValueV4> = (1..=4)
+            .map(|i| EndpointValueV4 {
+                ip: Ipv4Addr::new(10, 0, 0, i).to_bits(),
+                port: 8080,
+                _protocol: KubeProtocol::Tcp as u8,
+            })
+            .collect();
+        // grow, reorder, shrink and replace the set, back to back so a reader holding one
+        // revision sees the slots of the ones after it written and freed
+        let sets: [&[usize]; 5] = [&[0, 1], &[0, 1, 2, 3], &[3, 1], &[2], &[0, 1, 2]];
+
+        let mut published: Vec<(ServiceValue, Vec<EndpointValueV4>)> = vec![];
+        for set in sets {
+            let current: Vec<EndpointValueV4> = set.iter().map(|i| backends[*i]).collect();
+            service_endpoint.update(
+                service_key,
+                ServiceOptions::default(),
+                current.iter().collect(),
+                0,
+            )?;
+            let value = service_endpoint.service_cache[&service_key];
+            assert!(
+                published
+                    .iter()
+                    .all(|(old, _)| old.revision != value.revision),
+                "revision reused"
+            );
+            published.push((value, current));
+        }
+        let expected = |value: &ServiceValue| {
+            published
+                .iter()
+                .find(|(published, _)| published == value)
+                .map(|(_, set)| set)
+                .expect("service value was published")
+        };
+
+        let states = history.borrow();
+        for (i, first) in states.iter().enumerate() {
+            let Some(value) = first.services.get(&service_key) else {
+                continue;
+            };
+            for position in 0..value.count {
+                for (j, later) in states.iter().enumerate().skip(i) {
+                    if let Some(backend) = later.endpoints.get(&value.endpoint_key(position)) {
+                        assert_eq!(backend, &expected(value)[position as usize]);
+                        continue;
+                    }
+                    let retry = later.services[&service_key];
+                    assert_ne!(&retry, value, "slot of the live set missing");
+                    for position in 0..retry.count {
+                        for last in &states[j..] {
+                            match last.endpoints.get(&retry.endpoint_key(position)) {
+                                Some(backend) => {
+                                    assert_eq!(backend, &expected(&retry)[position as usize])
+                                }
+                                None => assert_ne!(last.services[&service_key], retry),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+
+        // only the live set is left behind
+        let endpoint_map = service_endpoint.get_endpoint_map()?;
+        assert_eq!(endpoint_map.len(), 3);
+
+        Ok(())
     }
 
     #[test]
@@ -450,15 +1152,116 @@ mod test {
             _protocol: KubeProtocol::Tcp as u8,
         };
         let mut endpoints = vec![&endpoint_one];
-        let initial_id = 0;
-        let first_id = service_endpoint.update(service_key, endpoints.clone(), initial_id)?;
-        assert_ne!(initial_id, first_id);
+        let initial_id = 128;
+        let options = ServiceOptions::default();
+        let first_id =
+            service_endpoint.update(service_key, options, endpoints.clone(), initial_id)?;
+        assert_eq!(initial_id, first_id);
 
         endpoints.push(&endpoint_two);
-        let second_id = service_endpoint.update(service_key, endpoints.clone(), first_id)?;
+        let second_id =
+            service_endpoint.update(service_key, options, endpoints.clone(), initial_id + 1)?;
 
         assert_eq!(initial_id, second_id);
 
         Ok(())
     }
+
+    #[test]
+    fn test_update_rewrites_service_value() -> crate::Result<()> {
+        let mut service_endpoint = new_service_endpoint();
+
+        let service_key = ServiceKeyV4::new(
+            Ipv4Addr::new(192, 168, 0, 1).to_bits(),
+            80,
+            KubeProtocol::Tcp as u8,
+        );
+        let endpoint_one = EndpointValueV4 {
+            ip: Ipv4Addr::new(10, 0, 0, 1).to_bits(),
+            port: 8080,
+            _protocol: KubeProtocol::Tcp as u8,
+        };
+        let endpoint_two = EndpointValueV4 {
+            ip: Ipv4Addr::new(10, 0, 0, 2).to_bits(),
+            port: 8080,
+            _protocol: KubeProtocol::Tcp as u8,
+        };
+        service_endpoint.update(
+            service_key,
+            ServiceOptions::default(),
+            vec![&endpoint_one],
+            0,
+        )?;
+
+        let options = ServiceOptions {
+            affinity_timeout_secs: Some(60),
+            ..Default::default()
+        };
+        service_endpoint.update(service_key, options, vec![&endpoint_one, &endpoint_two], 1)?;
+
+        let service_value = service_endpoint.get_service_map()?[&service_key];
+        assert_eq!(service_value.count, 2);
+        assert_eq!(service_value.options(), options);
+        assert_eq!(
+            service_endpoint.get_from_cache(&service_key),
+            Some(&service_value)
+        );
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_maglev_table_follows_service() -> crate::Result<()> {
+        let mut service_endpoint = new_service_endpoint();
+
+        let service_key = ServiceKeyV4::new(
+            Ipv4Addr::new(192, 168, 0, 1).to_bits(),
+            80,
+            KubeProtocol::Tcp as u8,
+        );
+        let endpoint_one = EndpointValueV4 {
+            ip: Ipv4Addr::new(10, 0, 0, 1).to_bits(),
+            port: 8080,
+            _protocol: KubeProtocol::Tcp as u8,
+        };
+        let endpoint_two = EndpointValueV4 {
+            ip: Ipv4Addr::new(10, 0, 0, 2).to_bits(),
+            port: 8080,
+            _protocol: KubeProtocol::Tcp as u8,
+        };
+        let maglev = ServiceOptions {
+            maglev: true,
+            ..Default::default()
+        };
+        service_endpoint.update(service_key, maglev, vec![&endpoint_one], 0)?;
+        let maglev_key = service_endpoint
+            .get_from_cache(&service_key)
+            .unwrap()
+            .maglev_key();
+        let table = service_endpoint.maglev_map[&maglev_key];
+        assert!(table.positions.iter().all(|position| *position == 0));
+
+        service_endpoint.update(service_key, maglev, vec![&endpoint_one, &endpoint_two], 1)?;
+        let maglev_key = service_endpoint
+            .get_from_cache(&service_key)
+            .unwrap()
+            .maglev_key();
+        let table = service_endpoint.maglev_map[&maglev_key];
+        assert!(table.positions.contains(&1));
+        assert_eq!(service_endpoint.maglev_map.len(), 1);
+
+        service_endpoint.update(
+            service_key,
+            ServiceOptions::default(),
+            vec![&endpoint_one, &endpoint_two],
+            1,
+        )?;
+        assert!(service_endpoint.maglev_map.is_empty());
+
+        service_endpoint.update(service_key, maglev, vec![&endpoint_one], 1)?;
+        service_endpoint.remove(&service_key)?;
+        assert!(service_endpoint.maglev_map.is_empty());
+
+        Ok(())
+    }
 }
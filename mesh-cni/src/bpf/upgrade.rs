@@ -0,0 +1,185 @@
+//! Replaces the pinned maps when their schema changed without detaching the datapath.
+//!
+//! The maps and programs of the new schema are pinned next to the live ones and moved into
+//! place while every link keeps running the old programs, which hold on to the old maps.
+//! Entries of compatible maps are copied over and the controllers fill the rest from
+//! Kubernetes, writing only to the new maps while the old programs keep reading the old
+//! ones as they were. Once every controller went through its initial list, each link is
+//! switched to its new program in place so there is no moment without a program attached,
+//! and the old pins are removed.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    os::fd::{AsFd, OwnedFd},
+};
+
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{
+    Result,
+    bpf::{
+        BPF_MESH_FS_DIR, BPF_MESH_LINKS_DIR, BPF_MESH_NEXT_DIR, BPF_MESH_OLD_DIR, PROG_LIST,
+        loader::{self, Objects},
+        schema::{BPF_NOEXIST, BPF_SCHEMA_PATH, MAP_SCHEMAS, Owner, Schema},
+        sys,
+    },
+};
+
+/// Directories of a pin root moved as a whole during the upgrade
+const PIN_DIRS: [&str; 2] = ["maps", "programs"];
+
+/// An upgrade whose maps are pinned but whose programs are not attached yet
+pub struct Upgrade {
+    old: Schema,
+    new: Schema,
+}
+
+/// Pins new maps and programs in place of the live ones if the schema of any map changed,
+/// resuming an upgrade a previous agent did not complete.
+pub(crate) fn prepare() -> Result<Option<Upgrade>> {
+    let recorded = Schema::read(BPF_SCHEMA_PATH)?;
+
+    if fs::exists(BPF_MESH_OLD_DIR)? {
+        warn!("resuming interrupted bpf map upgrade");
+        promote_next()?;
+        let upgrade = Upgrade {
+            old: Schema::pinned(BPF_MESH_OLD_DIR, recorded.as_ref())?,
+            new: Schema::expected(BPF_MESH_FS_DIR)?,
+        };
+        upgrade.copy(Owner::Kubernetes, 0);
+        return Ok(Some(upgrade));
+    }
+
+    remove_dir(BPF_MESH_NEXT_DIR)?;
+    let old = Schema::pinned(BPF_MESH_FS_DIR, recorded.as_ref())?;
+    let mut objects = Objects::load()?;
+    objects.pin_maps(BPF_MESH_NEXT_DIR)?;
+    let new = Schema::expected(BPF_MESH_NEXT_DIR)?;
+
+    let changed = new.changed(&old);
+    if changed.is_empty() {
+        drop(objects);
+        remove_dir(BPF_MESH_NEXT_DIR)?;
+        // pins from before schemas were recorded are adopted as they are
+        if recorded.as_ref() != Some(&new) {
+            new.write(BPF_SCHEMA_PATH)?;
+        }
+        return Ok(None);
+    }
+
+    info!(?changed, "bpf map schema changed, upgrading pinned maps");
+    objects.pin_programs(BPF_MESH_NEXT_DIR)?;
+    drop(objects);
+
+    fs::create_dir_all(BPF_MESH_OLD_DIR)?;
+    for dir in PIN_DIRS {
+        let live = format!("{BPF_MESH_FS_DIR}/{dir}");
+        if fs::exists(&live)? {
+            fs::rename(live, format!("{BPF_MESH_OLD_DIR}/{dir}"))?;
+        }
+    }
+    promote_next()?;
+
+    let upgrade = Upgrade { old, new };
+    upgrade.copy(Owner::Kubernetes, 0);
+    Ok(Some(upgrade))
+}
+
+impl Upgrade {
+    /// Switches the links to the new programs once the controllers have filled the maps
+    /// which could not be copied, then removes the old maps and programs. `reconciled` holds
+    /// a token per controller writing a Kubernetes owned map, cancelled once it swept the
+    /// maps and reconciled its initial list.
+    pub async fn complete(self, reconciled: &[CancellationToken]) -> Result<()> {
+        if self.repopulates() {
+            info!("waiting for controllers to fill recreated maps");
+            for token in reconciled {
+                token.cancelled().await;
+            }
+        }
+
+        self.copy(Owner::Datapath, 0);
+        swap_links()?;
+        // entries the old programs created while the links were being switched
+        self.copy(Owner::Datapath, BPF_NOEXIST);
+
+        remove_dir(BPF_MESH_OLD_DIR)?;
+        // programs the previous agent did not have
+        loader::attach_cgroup_programs()?;
+        self.new.write(BPF_SCHEMA_PATH)?;
+        info!("bpf map upgrade complete");
+        Ok(())
+    }
+
+    fn repopulates(&self) -> bool {
+        MAP_SCHEMAS.iter().any(|schema| {
+            schema.owner == Owner::Kubernetes
+                && !self.new.is_compatible(&self.old, schema.map.name())
+        })
+    }
+
+    /// Copies the compatible maps written by `owner`. A map that fails to copy is left to be
+    /// filled again by its owner.
+    fn copy(&self, owner: Owner, flags: u64) {
+        for schema in MAP_SCHEMAS.iter().filter(|schema| schema.owner == owner) {
+            let name = schema.map.name();
+            if !self.new.is_compatible(&self.old, name) {
+                info!(map = name, "map layout changed, starting empty");
+                continue;
+            }
+            let from = schema.map.path_in(BPF_MESH_OLD_DIR);
+            let to = schema.map.path();
+            match (schema.copy)(from.as_ref(), to.as_ref(), flags) {
+                Ok(copied) => info!(map = name, copied, "copied map entries"),
+                Err(e) => warn!(%e, map = name, "failed to copy map entries"),
+            }
+        }
+    }
+}
+
+/// Moves the pins of the new schema to the live paths
+fn promote_next() -> Result<()> {
+    for dir in PIN_DIRS {
+        let live = format!("{BPF_MESH_FS_DIR}/{dir}");
+        let next = format!("{BPF_MESH_NEXT_DIR}/{dir}");
+        if !fs::exists(&live)? && fs::exists(&next)? {
+            fs::rename(next, live)?;
+        }
+    }
+    remove_dir(BPF_MESH_NEXT_DIR)
+}
+
+/// Points every pinned link running an old program at the program of the same name in the
+/// live directory. Links attached during the upgrade already run the new programs.
+fn swap_links() -> Result<()> {
+    let mut programs: HashMap<u32, (&str, OwnedFd)> = HashMap::new();
+    for program in PROG_LIST {
+        let old = program.path_in(BPF_MESH_OLD_DIR);
+        if !fs::exists(&old)? {
+            continue;
+        }
+        let old = sys::obj_get(&old)?;
+        let new = sys::obj_get(&program.path())?;
+        programs.insert(sys::prog_id(old.as_fd())?, (program.name(), new));
+    }
+
+    for entry in fs::read_dir(BPF_MESH_LINKS_DIR)? {
+        let path = entry?.path();
+        let link = sys::obj_get(&path.to_string_lossy())?;
+        let Some((name, program)) = programs.get(&sys::link_prog_id(link.as_fd())?) else {
+            continue;
+        };
+        info!(link = %path.display(), program = *name, "switching link to new program");
+        sys::link_update(link.as_fd(), program.as_fd())?;
+    }
+    Ok(())
+}
+
+fn remove_dir(path: &str) -> Result<()> {
+    match fs::remove_dir_all(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+        _ => Ok(()),
+    }
+}
@@ -130,6 +130,10 @@ fn ensure_cni_bin(dst: impl AsRef<Path>, bin_path: impl AsRef<Path>) -> Result<(
 }
 
 fn default_cni_config() -> Result<Vec<u8>> {
+    // the runtime only passes runtimeConfig.ips to plugins declaring the capability
+    let mut options = HashMap::new();
+    options.insert("chained".into(), Value::Bool(false));
+    options.insert("capabilities".into(), serde_json::json!({ "ips": true }));
     let conf = Config {
         cni_version: CNI_VERSION,
         cni_versions: vec![CNI_VERSION],
@@ -137,7 +141,10 @@ fn default_cni_config() -> Result<Vec<u8>> {
         disable_check: None,
         disable_gc: None,
         load_only_inlined_plugins: None,
-        plugins: Vec::new(),
+        plugins: vec![PluginConfig {
+            r#type: "mesh-cni".into(),
+            options,
+        }],
     };
     serde_json::to_vec_pretty(&conf).map_err(|e| e.into())
 }
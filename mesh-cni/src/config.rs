@@ -1,7 +1,8 @@
 use std::{net::SocketAddr, path::PathBuf};
 
-use clap::{Parser, Subcommand};
+use clap::{Parser, Subcommand, ValueEnum};
 use http::Uri;
+use ipnetwork::IpNetwork;
 
 #[derive(Debug, Parser)]
 #[command(version, about, long_about = None)]
@@ -40,9 +41,9 @@ pub struct AgentArgs {
     #[arg(long, default_value = "127.0.0.1:4317")]
     pub opentelemetry_address: Option<String>,
 
-    /// Interface to bind bpf program to
-    #[arg(long, default_value = "eth0")]
-    pub iface: String,
+    /// External interfaces NodePort traffic arrives on, comma separated
+    #[arg(long, default_value = "eth0", value_delimiter = ',')]
+    pub iface: Vec<String>,
 
     /// Name of the node the program is running on
     #[arg(long, env = "NODE_NAME")]
@@ -75,6 +76,30 @@ pub struct AgentArgs {
     /// Determines if CNI should be configured as chained
     #[arg(long, env = "CHAINED", default_value = "false")]
     pub chained: bool,
+
+    /// Where the pod CIDRs of the node come from
+    #[arg(long, env = "IPAM_MODE", value_enum, default_value_t = IpamMode::Kubernetes)]
+    pub ipam_mode: IpamMode,
+
+    /// Directory the pod address allocations are persisted in
+    #[arg(long, env = "IPAM_STATE_DIR", default_value = "/var/lib/mesh-cni/ipam")]
+    pub ipam_state_dir: PathBuf,
+
+    /// Directory the results of CNI ADD are cached in for DEL and CHECK
+    #[arg(
+        long,
+        env = "CNI_RESULTS_DIR",
+        default_value = "/var/lib/mesh-cni/results"
+    )]
+    pub cni_results_dir: PathBuf,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IpamMode {
+    /// Node.spec.podCIDRs as assigned by the controller manager
+    Kubernetes,
+    /// A PodCidrAllocation carved out of the cluster pool by the controller
+    ClusterPool,
 }
 
 #[derive(Parser, Debug, Clone)]
@@ -82,4 +107,21 @@ pub struct ControllerArgs {
     /// Metrics listener for agent
     #[arg(long, default_value = "0.0.0.0:9090")]
     pub metrics_address: SocketAddr,
+
+    /// CIDRs node pod CIDRs are allocated from, comma separated. Nodes are not allocated pod
+    /// CIDRs when empty.
+    #[arg(long, env = "CLUSTER_POOL_CIDRS", value_delimiter = ',')]
+    pub cluster_pool_cidrs: Vec<IpNetwork>,
+
+    /// Prefix length of the IPv4 pod CIDRs allocated to nodes
+    #[arg(long, env = "NODE_MASK_SIZE_V4", default_value = "24")]
+    pub node_mask_size_v4: u8,
+
+    /// Prefix length of the IPv6 pod CIDRs allocated to nodes
+    #[arg(long, env = "NODE_MASK_SIZE_V6", default_value = "120")]
+    pub node_mask_size_v6: u8,
+
+    /// Free addresses of a family below which a node is allocated another pod CIDR
+    #[arg(long, env = "MIN_FREE_ADDRESSES", default_value = "8")]
+    pub min_free_addresses: u64,
 }
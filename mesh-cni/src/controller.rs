@@ -1,10 +1,12 @@
 use mesh_cni_identity_gen_controller::start_identity_gen_controller;
+use mesh_cni_ipam_controller::{ClusterPool, start_ipam_controller};
 use tokio_util::sync::CancellationToken;
+use tracing::{error, info};
 
 use crate::{Result, config::ControllerArgs};
 
 pub async fn start(
-    _args: ControllerArgs,
+    args: ControllerArgs,
     ready: CancellationToken,
     cancel: CancellationToken,
 ) -> Result<()> {
@@ -15,14 +17,38 @@ pub async fn start(
     //
     // let service_handle = tokio::spawn(service_controller);
 
-    let identity_controller = start_identity_gen_controller(client, cancel.clone());
+    let identity_controller = start_identity_gen_controller(client.clone(), cancel.clone());
 
     let identity_handle = tokio::spawn(identity_controller);
 
+    let ipam_handle = if args.cluster_pool_cidrs.is_empty() {
+        None
+    } else {
+        info!(cidrs = ?args.cluster_pool_cidrs, "starting cluster pool ipam");
+        let pool = ClusterPool::new(
+            args.cluster_pool_cidrs,
+            args.node_mask_size_v4,
+            args.node_mask_size_v6,
+        )?;
+        let ipam_controller =
+            start_ipam_controller(client, pool, args.min_free_addresses, cancel.clone());
+        Some(tokio::spawn(ipam_controller))
+    };
+
     ready.cancel();
     tokio::select! {
         _ = cancel.cancelled() => {},
         _ = identity_handle => {}
+        h = async {
+            match ipam_handle {
+                Some(handle) => handle.await,
+                None => std::future::pending().await,
+            }
+        } => {
+            if let Ok(Err(e)) = h {
+                error!(%e, "ipam controller exited with error");
+            }
+        }
     }
 
     Ok(())
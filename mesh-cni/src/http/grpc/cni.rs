@@ -1,28 +1,57 @@
-use std::path::{Path, PathBuf};
+use std::{
+    net::IpAddr,
+    os::fd::AsFd,
+    path::{Path, PathBuf},
+};
 
 use aya::programs::{
     SchedClassifier, TcAttachType,
     links::{FdLink, LinkError, PinnedLink},
     tc,
 };
+use ipnetwork::IpNetwork;
 use mesh_cni_api::cni::v1::{
-    AddPodReply, AddPodRequest, DeletePodReply, DeletePodRequest, cni_server::Cni as CniApi,
+    AddPodReply, AddPodRequest, CheckPodReply, CheckPodRequest, DeletePodReply, DeletePodRequest,
+    Interface, Ip, Route, cni_server::Cni as CniApi,
 };
 use tonic::{Code, Request, Response, Status};
 use tracing::{error, info};
 
 use crate::{
     Result,
-    bpf::{BPF_MESH_LINKS_DIR, BPF_PROGRAM_INGRESS_TC},
+    bpf::{
+        BPF_MESH_LINKS_DIR, BPF_PROGRAM_EGRESS_TC, BPF_PROGRAM_INGRESS_TC, IdentityMapV4,
+        IdentityMapV6, ip::IpNetworkState, sys,
+    },
+    http::grpc::ipam,
+    ipam::Ipam,
+    network::{self, GATEWAY_V4, GATEWAY_V6, PodNetwork},
+    result_cache::{CachedResult, ResultCache},
 };
 
-pub struct LoaderState;
+pub struct LoaderState {
+    ipam: Ipam,
+    ip_state: IpNetworkState<IdentityMapV4, IdentityMapV6>,
+    results: ResultCache,
+}
+
+impl LoaderState {
+    pub fn new(
+        ipam: Ipam,
+        ip_state: IpNetworkState<IdentityMapV4, IdentityMapV6>,
+        results: ResultCache,
+    ) -> Self {
+        Self {
+            ipam,
+            ip_state,
+            results,
+        }
+    }
+}
 
 const _NET_NS_DIR: &str = "/var/run/mesh/netns";
 const MESH_INGRESS_LINK_PREFIX: &str = "mesh_cni_ingress_";
 
-// TODO: this only handles chained creation correctly
-//
 // Spec says there SHOULD be a DEL call in between ADD calls so we need
 // to try to clean up on failed attach and pin calls
 #[tonic::async_trait]
@@ -33,40 +62,69 @@ impl CniApi for LoaderState {
     ) -> std::result::Result<Response<AddPodReply>, Status> {
         let request = request.into_inner();
         info!("received add request {:?}", request);
-        let _ = tc::qdisc_add_clsact(&request.iface);
-        info!("adding tc ingress progam to {}", &request.iface);
-        attach_and_pin_links(
-            &request.iface,
-            BPF_PROGRAM_INGRESS_TC.path(),
-            TcAttachType::Ingress,
-        )
-        .map_err(|e| tonic::Status::new(Code::Internal, e.to_string()))?;
-
-        info!("adding tc egress progam to {}", &request.iface);
-        if let Err(e) = attach_and_pin_links(
-            &request.iface,
-            BPF_PROGRAM_INGRESS_TC.path(),
-            TcAttachType::Egress,
-        ) {
-            let ingress_path = pin_path(&request.iface, TcAttachType::Ingress);
-            let egress_path = pin_path(&request.iface, TcAttachType::Egress);
-            for path in [ingress_path, egress_path] {
-                let Err(u) = unpin_path(path) else {
-                    continue;
-                };
-                error!(%u, "failed to unpin path");
+        if request.chained {
+            attach_policy_programs(&request.iface)
+                .map_err(|e| tonic::Status::new(Code::Internal, e.to_string()))?;
+            // every host interface of the chain is added with its own request
+            let mut cached = self
+                .results
+                .get(&request.container_id, &request.ifname)
+                .map_err(internal)?
+                .unwrap_or_else(|| {
+                    CachedResult::new(&request.container_id, &request.ifname, None, true)
+                });
+            if !cached.host_ifaces.contains(&request.iface) {
+                cached.host_ifaces.push(request.iface.clone());
             }
-
-            error!(%e, "failed to attach and pin egress link");
-            Err(tonic::Status::new(Code::Internal, e.to_string()))
-        } else {
-            Ok(Response::new(AddPodReply {
+            self.results.put(&cached).map_err(internal)?;
+            return Ok(Response::new(AddPodReply {
                 interfaces: Vec::new(),
                 ips: Vec::new(),
                 routes: Vec::new(),
                 dns: None,
-            }))
+            }));
         }
+
+        let Some(net_namespace) = request.net_namespace.clone() else {
+            return Err(tonic::Status::new(
+                Code::InvalidArgument,
+                "network namespace is required when not chained",
+            ));
+        };
+        let requested = request
+            .ips
+            .iter()
+            .map(|ip| ip.parse::<IpNetwork>().map(|network| network.ip()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| tonic::Status::new(Code::InvalidArgument, e.to_string()))?;
+        let addresses = self
+            .ipam
+            .allocate(&request.container_id, &request.ifname, &requested)
+            .map_err(ipam::status)?;
+
+        let pod = match add_unchained(&request, &net_namespace, &addresses).await {
+            Ok(pod) => pod,
+            Err(e) => {
+                error!(%e, "failed to add pod");
+                if let Err(r) = self.ipam.release(&request.container_id, &request.ifname) {
+                    error!(%r, "failed to release pod addresses");
+                }
+                return Err(tonic::Status::new(Code::Internal, e.to_string()));
+            }
+        };
+
+        let mut cached = CachedResult::new(
+            &request.container_id,
+            &request.ifname,
+            Some(net_namespace.clone()),
+            false,
+        );
+        cached.host_ifaces.push(pod.host_iface.clone());
+        let reply = unchained_reply(pod, net_namespace);
+        cached.result = (&reply).into();
+        // a failed ADD is followed by a DEL, which cleans up without the cached result
+        self.results.put(&cached).map_err(internal)?;
+        Ok(Response::new(reply))
     }
 
     async fn delete_pod(
@@ -76,15 +134,165 @@ impl CniApi for LoaderState {
         let request = request.into_inner();
         info!("received delete request {:?}", request);
 
-        let ingress_path = pin_path(&request.iface, TcAttachType::Ingress);
-        let egress_path = pin_path(&request.iface, TcAttachType::Egress);
+        // DEL may be repeated and may come without the previous result, so whatever is
+        // missing was already removed
+        let cached = self
+            .results
+            .get(&request.container_id, &request.ifname)
+            .map_err(internal)?;
+        let chained = cached
+            .as_ref()
+            .map_or(request.chained, |cached| cached.chained);
 
-        for path in [ingress_path, egress_path] {
-            unpin_path(path).map_err(|e| tonic::Status::new(Code::Internal, e.to_string()))?;
+        let mut host_ifaces = cached
+            .as_ref()
+            .map(|cached| cached.host_ifaces.clone())
+            .unwrap_or_default();
+        if !chained {
+            host_ifaces.push(network::host_veth_name(
+                &request.container_id,
+                &request.ifname,
+            ));
         }
+        if !request.iface.is_empty() {
+            host_ifaces.push(request.iface.clone());
+        }
+        host_ifaces.sort();
+        host_ifaces.dedup();
+
+        for iface in &host_ifaces {
+            let ingress_path = pin_path(iface, TcAttachType::Ingress);
+            let egress_path = pin_path(iface, TcAttachType::Egress);
+            for path in [ingress_path, egress_path] {
+                unpin_path(path).map_err(internal)?;
+            }
+        }
+
+        if !chained {
+            network::teardown(&request.container_id, &request.ifname)
+                .await
+                .map_err(internal)?;
 
+            let mut ips = self.ipam.owned(&request.container_id, &request.ifname);
+            if let Some(cached) = &cached {
+                ips.extend(
+                    cached
+                        .result
+                        .ips
+                        .iter()
+                        .filter_map(|ip| ip.address.parse::<IpNetwork>().ok())
+                        .map(|network| network.ip()),
+                );
+            }
+            ips.sort();
+            ips.dedup();
+            // the identity of this pod goes first so an address handed out again never
+            // carries it
+            for ip in ips {
+                self.ip_state.delete(ip).map_err(internal)?;
+            }
+            self.ipam
+                .release(&request.container_id, &request.ifname)
+                .map_err(ipam::status)?;
+        }
+
+        self.results
+            .remove(&request.container_id, &request.ifname)
+            .map_err(internal)?;
         Ok(Response::new(DeletePodReply {}))
     }
+
+    async fn check_pod(
+        &self,
+        request: Request<CheckPodRequest>,
+    ) -> std::result::Result<Response<CheckPodReply>, Status> {
+        let request = request.into_inner();
+        info!("received check request {:?}", request);
+
+        let cached = self
+            .results
+            .get(&request.container_id, &request.ifname)
+            .map_err(internal)?;
+        let chained = cached
+            .as_ref()
+            .map_or(request.chained, |cached| cached.chained);
+        let mut drift = Vec::new();
+        if cached.is_none() {
+            drift.push("no result was cached by ADD".to_string());
+        }
+
+        let mut host_ifaces = cached
+            .as_ref()
+            .map(|cached| cached.host_ifaces.clone())
+            .unwrap_or_default();
+        if !request.iface.is_empty() {
+            host_ifaces.push(request.iface.clone());
+        }
+        host_ifaces.sort();
+        host_ifaces.dedup();
+        if host_ifaces.is_empty() {
+            drift.push("no host interface is known".to_string());
+        }
+        for iface in &host_ifaces {
+            if !network::host_link_exists(iface).await.map_err(internal)? {
+                drift.push(format!("interface {iface} does not exist"));
+                continue;
+            }
+            drift.extend(check_policy_programs(iface).map_err(internal)?);
+        }
+
+        let mut ips = request
+            .ips
+            .iter()
+            .map(|ip| ip.parse::<IpNetwork>().map(|network| network.ip()))
+            .collect::<std::result::Result<Vec<_>, _>>()
+            .map_err(|e| tonic::Status::new(Code::InvalidArgument, e.to_string()))?;
+        if let Some(cached) = &cached {
+            ips.extend(
+                cached
+                    .result
+                    .ips
+                    .iter()
+                    .filter_map(|ip| ip.address.parse::<IpNetwork>().ok())
+                    .map(|network| network.ip()),
+            );
+        }
+        ips.sort();
+        ips.dedup();
+        for ip in ips {
+            if self.ip_state.host_identity(ip).is_none() {
+                drift.push(format!("{ip} is missing from the identity map"));
+            }
+        }
+
+        if !chained && let Some(cached) = &cached {
+            match cached
+                .net_namespace
+                .as_deref()
+                .or(request.net_namespace.as_deref())
+            {
+                Some(net_namespace) => drift.extend(
+                    check_unchained(cached, net_namespace)
+                        .await
+                        .map_err(internal)?,
+                ),
+                None => drift.push("network namespace is unknown".to_string()),
+            }
+        }
+
+        if !drift.is_empty() {
+            info!(?drift, "pod drifted from its cached result");
+            return Err(tonic::Status::new(
+                Code::FailedPrecondition,
+                drift.join("; "),
+            ));
+        }
+        Ok(Response::new(CheckPodReply {}))
+    }
+}
+
+fn internal(e: anyhow::Error) -> Status {
+    tonic::Status::new(Code::Internal, e.to_string())
 }
 
 fn unpin_path(path: impl AsRef<Path>) -> Result<()> {
@@ -106,6 +314,63 @@ fn unpin_path(path: impl AsRef<Path>) -> Result<()> {
     Ok(())
 }
 
+/// Both links of a host interface must be pinned and run the policy program pinned for
+/// their direction
+fn check_policy_programs(iface: &str) -> Result<Vec<String>> {
+    let mut drift = Vec::new();
+    for (attach_type, program) in [
+        (TcAttachType::Ingress, BPF_PROGRAM_EGRESS_TC),
+        (TcAttachType::Egress, BPF_PROGRAM_INGRESS_TC),
+    ] {
+        let path = pin_path(iface, attach_type);
+        if !path.try_exists()? {
+            drift.push(format!("no link is pinned at {}", path.display()));
+            continue;
+        }
+        let link = sys::obj_get(&path.to_string_lossy())?;
+        let expected = sys::prog_id(sys::obj_get(&program.path())?.as_fd())?;
+        if sys::link_prog_id(link.as_fd())? != expected {
+            drift.push(format!(
+                "link {} does not run {}",
+                path.display(),
+                program.name()
+            ));
+        }
+    }
+    Ok(drift)
+}
+
+/// The addresses and routes of the pod interface must still include those of the result
+async fn check_unchained(cached: &CachedResult, net_namespace: &str) -> Result<Vec<String>> {
+    let ifname = &cached.if_name;
+    let state = match network::pod_state(net_namespace, ifname).await {
+        Ok(Some(state)) => state,
+        Ok(None) => {
+            return Ok(vec![format!(
+                "interface {ifname} does not exist in the pod"
+            )]);
+        }
+        // the namespace is gone along with the pod
+        Err(e) => return Ok(vec![e.to_string()]),
+    };
+
+    let mut drift = Vec::new();
+    for ip in &cached.result.ips {
+        let address: IpNetwork = ip.address.parse()?;
+        if !state.addresses.contains(&address) {
+            drift.push(format!("address {address} is missing from {ifname}"));
+        }
+    }
+    for route in &cached.result.routes {
+        let destination: IpNetwork = route.dst.parse()?;
+        let gateway = route.gw.as_deref().map(str::parse::<IpAddr>).transpose()?;
+        if !state.routes.contains(&(destination, gateway)) {
+            drift.push(format!("route to {destination} is missing from {ifname}"));
+        }
+    }
+    Ok(drift)
+}
+
 fn pin_path(iface: &str, attach_type: TcAttachType) -> PathBuf {
     match attach_type {
         TcAttachType::Ingress => PathBuf::from(BPF_MESH_LINKS_DIR)
@@ -139,3 +404,98 @@ fn attach_and_pin_links(
     link.pin(pin_path)?;
     Ok(())
 }
+
+/// Creates the pod interfaces and attaches the policy programs to the host end
+async fn add_unchained(
+    request: &AddPodRequest,
+    net_namespace: &str,
+    addresses: &[IpAddr],
+) -> Result<PodNetwork> {
+    let pod = network::setup(
+        &request.container_id,
+        net_namespace,
+        &request.iface,
+        addresses,
+    )
+    .await?;
+    if let Err(e) = attach_policy_programs(&pod.host_iface) {
+        if let Err(t) = network::teardown(&request.container_id, &request.iface).await {
+            error!(%t, "failed to remove pod interfaces");
+        }
+        return Err(e);
+    }
+    Ok(pod)
+}
+
+fn unchained_reply(pod: PodNetwork, sandbox: String) -> AddPodReply {
+    let gateways = pod.gateways();
+    let interfaces = vec![
+        Interface {
+            name: pod.host_iface,
+            mac: pod.host_mac,
+            ..Default::default()
+        },
+        Interface {
+            name: pod.pod_iface,
+            mac: pod.pod_mac,
+            sandbox: Some(sandbox),
+            ..Default::default()
+        },
+    ];
+    let ips = pod
+        .addresses
+        .iter()
+        .map(|address| Ip {
+            address: address.to_string(),
+            gateway: match address {
+                IpNetwork::V4(_) => GATEWAY_V4.to_string(),
+                IpNetwork::V6(_) => GATEWAY_V6.to_string(),
+            },
+            // index of the pod interface
+            iface: Some(1),
+        })
+        .collect();
+    let routes = gateways
+        .into_iter()
+        .map(|gateway| Route {
+            dst: match gateway {
+                IpAddr::V4(_) => "0.0.0.0/0".to_string(),
+                IpAddr::V6(_) => "::/0".to_string(),
+            },
+            gw: Some(gateway.to_string()),
+            ..Default::default()
+        })
+        .collect();
+    AddPodReply {
+        interfaces,
+        ips,
+        routes,
+        dns: None,
+    }
+}
+
+/// Attaches the policy programs to the host side of a pod. The interface is the host side
+/// of the pod, so traffic leaving the pod arrives on the tc ingress hook and traffic to the
+/// pod leaves on tc egress.
+fn attach_policy_programs(iface: &str) -> Result<()> {
+    let _ = tc::qdisc_add_clsact(iface);
+    info!("adding tc egress policy progam to {}", iface);
+    attach_and_pin_links(iface, BPF_PROGRAM_EGRESS_TC.path(), TcAttachType::Ingress)?;
+
+    info!("adding tc ingress policy progam to {}", iface);
+    if let Err(e) = attach_and_pin_links(iface, BPF_PROGRAM_INGRESS_TC.path(), TcAttachType::Egress)
+    {
+        let ingress_path = pin_path(iface, TcAttachType::Ingress);
+        let egress_path = pin_path(iface, TcAttachType::Egress);
+        for path in [ingress_path, egress_path] {
+            let Err(u) = unpin_path(path) else {
+                continue;
+            };
+            error!(%u, "failed to unpin path");
+        }
+
+        error!(%e, "failed to attach and pin egress link");
+        return Err(e);
+    }
+    Ok(())
+}
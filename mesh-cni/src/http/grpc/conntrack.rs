@@ -1,15 +1,21 @@
-use std::net::Ipv4Addr;
+use std::net::{Ipv4Addr, Ipv6Addr};
 
 use anyhow::bail;
 use mesh_cni_api::conntrack::v1::{
     Connection, GetConntrackReply, GetConntrackRequest,
     conntrack_server::{Conntrack as ConntrackApi, ConntrackServer},
 };
-use mesh_cni_ebpf_common::conntrack::ConntrackKeyV4;
+use mesh_cni_ebpf_common::{
+    Direction,
+    conntrack::{ConntrackKeyV4, ConntrackKeyV6},
+};
 use tonic::{Code, Request, Response, Status};
 use tracing::{error, info};
 
-use crate::{Result, bpf::conntrack::load_map};
+use crate::{
+    Result,
+    bpf::conntrack::{load_map_v4, load_map_v6},
+};
 
 pub fn server() -> ConntrackServer<Conntrack> {
     ConntrackServer::new(Conntrack)
@@ -25,37 +31,63 @@ impl ConntrackApi for Conntrack {
         _request: Request<GetConntrackRequest>,
     ) -> std::result::Result<Response<GetConntrackReply>, Status> {
         info!("conntrack request");
-        let map = load_map().map_err(|e: anyhow::Error| {
-            tonic::Status::new(Code::Internal, format!("failed to load map: {}", e))
+        let map_v4 = load_map_v4().map_err(|e: anyhow::Error| {
+            tonic::Status::new(Code::Internal, format!("failed to load v4 map: {}", e))
+        })?;
+        let map_v6 = load_map_v6().map_err(|e: anyhow::Error| {
+            tonic::Status::new(Code::Internal, format!("failed to load v6 map: {}", e))
         })?;
 
-        let connections = map
-            .keys()
-            .filter_map(|key| {
-                let key = match key {
-                    Ok(key) => key,
-                    Err(_) => {
-                        return None;
-                    }
-                };
-                match connection_from_keyv4(&key) {
-                    Ok(cxn) => Some(cxn),
-                    Err(e) => {
-                        error!(%e, "failed to convert key to connection");
-                        None
-                    }
+        let connections_v4 = map_v4.keys().filter_map(|key| {
+            let key = key.ok()?;
+            match connection_from_keyv4(&key) {
+                Ok(cxn) => Some(cxn),
+                Err(e) => {
+                    error!(%e, "failed to convert key to connection");
+                    None
                 }
-            })
-            .collect();
+            }
+        });
+        let connections_v6 = map_v6.keys().filter_map(|key| {
+            let key = key.ok()?;
+            match connection_from_keyv6(&key) {
+                Ok(cxn) => Some(cxn),
+                Err(e) => {
+                    error!(%e, "failed to convert key to connection");
+                    None
+                }
+            }
+        });
+        let connections = connections_v4.chain(connections_v6).collect();
 
         Ok(Response::new(GetConntrackReply { connections }))
     }
 }
 
 fn connection_from_keyv4(key: &ConntrackKeyV4) -> Result<Connection> {
-    let src_ip = Ipv4Addr::from_bits(key.src_ip).to_string();
-    let dst_ip = Ipv4Addr::from_bits(key.dst_ip).to_string();
-    let proto = match key.proto {
+    Ok(Connection {
+        src_ip: Ipv4Addr::from_bits(key.src_ip).to_string(),
+        src_port: key.src_port as u32,
+        dst_ip: Ipv4Addr::from_bits(key.dst_ip).to_string(),
+        dst_port: key.dst_port as u32,
+        proto: proto_name(key.proto)?.to_string(),
+        direction: Direction::from(key.direction).to_string(),
+    })
+}
+
+fn connection_from_keyv6(key: &ConntrackKeyV6) -> Result<Connection> {
+    Ok(Connection {
+        src_ip: Ipv6Addr::from(key.src_ip).to_string(),
+        src_port: key.src_port as u32,
+        dst_ip: Ipv6Addr::from(key.dst_ip).to_string(),
+        dst_port: key.dst_port as u32,
+        proto: proto_name(key.proto)?.to_string(),
+        direction: Direction::from(key.direction).to_string(),
+    })
+}
+
+fn proto_name(proto: u8) -> Result<&'static str> {
+    let name = match proto {
         1 => "ICMPv4",
         6 => "TCP",
         17 => "UDP",
@@ -63,12 +95,5 @@ fn connection_from_keyv4(key: &ConntrackKeyV4) -> Result<Connection> {
         132 => "SCTP",
         _ => bail!("unsupported proto found in conntrack key"),
     };
-
-    Ok(Connection {
-        src_ip,
-        src_port: key.src_port as u32,
-        dst_ip,
-        dst_port: key.dst_port as u32,
-        proto: proto.to_string(),
-    })
+    Ok(name)
 }
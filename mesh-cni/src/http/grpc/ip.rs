@@ -1,4 +1,5 @@
 use aya::maps::lpm_trie::Key as LpmKey;
+use ipnetwork::IpNetwork;
 use mesh_cni_api::ip::v1::{
     IpId, ListIpsReply, ListIpsRequest,
     ip_server::{Ip as IpApi, IpServer},
@@ -11,8 +12,8 @@ use crate::bpf::{BpfMap, ip::IpNetworkState};
 
 pub fn server<IP4, IP6>(state: IpNetworkState<IP4, IP6>) -> IpServer<Server<IP4, IP6>>
 where
-    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId>,
-    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId>,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>,
 {
     info!("creating new network state");
     mesh_cni_api::ip::v1::ip_server::IpServer::new(Server::new(state))
@@ -21,16 +22,16 @@ where
 #[derive(Clone)]
 pub struct Server<IP4, IP6>
 where
-    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId>,
-    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId>,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>,
 {
     state: IpNetworkState<IP4, IP6>,
 }
 
 impl<IP4, IP6> Server<IP4, IP6>
 where
-    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId>,
-    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId>,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>,
 {
     pub fn new(state: IpNetworkState<IP4, IP6>) -> Self {
         Self { state }
@@ -40,8 +41,14 @@ where
 #[tonic::async_trait]
 impl<IP4, IP6> IpApi for Server<IP4, IP6>
 where
-    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId> + Send + Sync + 'static,
-    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId> + Send + Sync + 'static,
+    IP4: BpfMap<Key = LpmKey<u32>, Value = IdentityId, KeyOutput = IpNetwork>
+        + Send
+        + Sync
+        + 'static,
+    IP6: BpfMap<Key = LpmKey<u128>, Value = IdentityId, KeyOutput = IpNetwork>
+        + Send
+        + Sync
+        + 'static,
 {
     async fn list_ips(
         &self,
@@ -0,0 +1,68 @@
+use mesh_cni_api::ipam::v1::{
+    Allocation, ListAllocationsReply, ListAllocationsRequest, ListPoolsReply, ListPoolsRequest,
+    Pool,
+    ipam_server::{Ipam as IpamApi, IpamServer},
+};
+use tonic::{Code, Request, Response, Status};
+use tracing::info;
+
+use crate::ipam::{Ipam, IpamError};
+
+pub fn server(ipam: Ipam) -> IpamServer<Server> {
+    IpamServer::new(Server { ipam })
+}
+
+#[derive(Clone)]
+pub struct Server {
+    ipam: Ipam,
+}
+
+#[tonic::async_trait]
+impl IpamApi for Server {
+    async fn list_allocations(
+        &self,
+        _request: Request<ListAllocationsRequest>,
+    ) -> std::result::Result<Response<ListAllocationsReply>, Status> {
+        info!("ipam allocations request");
+        let allocations = self
+            .ipam
+            .allocations()
+            .into_iter()
+            .map(|(ip, allocation)| Allocation {
+                ip: ip.to_string(),
+                container_id: allocation.container_id,
+                iface: allocation.ifname,
+            })
+            .collect();
+        Ok(Response::new(ListAllocationsReply { allocations }))
+    }
+
+    async fn list_pools(
+        &self,
+        _request: Request<ListPoolsRequest>,
+    ) -> std::result::Result<Response<ListPoolsReply>, Status> {
+        info!("ipam pools request");
+        let pools = self
+            .ipam
+            .pools()
+            .into_iter()
+            .map(|pool| Pool {
+                cidr: pool.cidr.to_string(),
+                allocated: pool.allocated.try_into().unwrap_or(u64::MAX),
+                capacity: pool.capacity.try_into().unwrap_or(u64::MAX),
+            })
+            .collect();
+        Ok(Response::new(ListPoolsReply { pools }))
+    }
+}
+
+pub(crate) fn status(e: IpamError) -> Status {
+    let code = match e {
+        IpamError::Exhausted(_) => Code::ResourceExhausted,
+        IpamError::NotInPool(_) => Code::InvalidArgument,
+        IpamError::InUse { .. } => Code::AlreadyExists,
+        IpamError::NoPools => Code::FailedPrecondition,
+        IpamError::Io(_) | IpamError::Json(_) => Code::Internal,
+    };
+    Status::new(code, e.to_string())
+}
@@ -2,7 +2,10 @@ use mesh_cni_api::policy::v1::{
     ListPolicyReply, ListPolicyRequest, PolicySet,
     policy_server::{Policy as PolicyApi, PolicyServer},
 };
-use mesh_cni_ebpf_common::policy::{Action, PolicyKey, PolicyProtocol, PolicyValue};
+use mesh_cni_ebpf_common::{
+    Direction,
+    policy::{Action, PolicyKey, PolicyProtocol, PolicyValue},
+};
 use tonic::{Code, Request, Response, Status};
 use tracing::info;
 
@@ -54,6 +57,7 @@ where
                 dst_port: k.dst_port as u32,
                 proto: PolicyProtocol::from(k.proto).to_string(),
                 action: Action::from(v.action).to_string(),
+                direction: Direction::from(k.direction).to_string(),
             })
             .collect();
 
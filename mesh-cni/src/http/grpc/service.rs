@@ -6,17 +6,25 @@ use mesh_cni_api::service::v1::{
 };
 use mesh_cni_ebpf_common::{
     KubeProtocol,
-    service::{EndpointValue, EndpointValueV4, EndpointValueV6, ServiceKeyV4, ServiceKeyV6},
+    service::{
+        EndpointValue, EndpointValueV4, EndpointValueV6, ServiceKeyV4, ServiceKeyV6, SourceRangesV4,
+    },
 };
 use tonic::{Request, Response, Status};
 use tracing::info;
 
-use crate::bpf::service::{ServiceEndpointBpfMap, ServiceEndpointState};
+use crate::bpf::{
+    BpfMap,
+    service::{ServiceEndpointBpfMap, ServiceEndpointState},
+};
 
-pub fn server<SE4, SE6>(state: ServiceEndpointState<SE4, SE6>) -> ServiceServer<Server<SE4, SE6>>
+pub fn server<SE4, SE6, R>(
+    state: ServiceEndpointState<SE4, SE6, R>,
+) -> ServiceServer<Server<SE4, SE6, R>>
 where
     SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
     SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
     info!("creating new service state");
     let server = Server::new(state);
@@ -24,29 +32,34 @@ where
 }
 
 #[derive(Clone)]
-pub struct Server<SE4, SE6>
+pub struct Server<SE4, SE6, R>
 where
     SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
     SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
-    state: ServiceEndpointState<SE4, SE6>,
+    state: ServiceEndpointState<SE4, SE6, R>,
 }
 
-impl<SE4, SE6> Server<SE4, SE6>
+impl<SE4, SE6, R> Server<SE4, SE6, R>
 where
     SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4>,
     SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6>,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>,
 {
-    pub fn new(state: ServiceEndpointState<SE4, SE6>) -> Self {
+    pub fn new(state: ServiceEndpointState<SE4, SE6, R>) -> Self {
         Self { state }
     }
 }
 
 #[tonic::async_trait]
-impl<SE4, SE6> ServiceApi for Server<SE4, SE6>
+impl<SE4, SE6, R> ServiceApi for Server<SE4, SE6, R>
 where
     SE4: ServiceEndpointBpfMap<SKey = ServiceKeyV4, EValue = EndpointValueV4> + Send + 'static,
     SE6: ServiceEndpointBpfMap<SKey = ServiceKeyV6, EValue = EndpointValueV6> + Send + 'static,
+    R: BpfMap<Key = ServiceKeyV4, Value = SourceRangesV4, KeyOutput = ServiceKeyV4>
+        + Send
+        + 'static,
 {
     async fn list_services(
         &self,
@@ -0,0 +1,217 @@
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+
+use ahash::{HashMap, HashSet};
+use axum::{
+    Router,
+    extract::State as AxumState,
+    response::{IntoResponse, Response},
+};
+use http::StatusCode;
+use k8s_openapi::api::core::v1::Service;
+use kube::{ResourceExt, runtime::reflector::ObjectRef};
+use mesh_cni_crds::v1alpha1::meshendpoint::{MeshEndpointSpec, generate_mesh_endpoint_spec};
+use tokio::net::TcpListener;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{bpf::service::ServiceStores, http::shutdown};
+
+/// How often the set of health check node ports is compared against the Services
+const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Answers the `healthCheckNodePort` of every `externalTrafficPolicy: Local` Service so
+/// external load balancers only send traffic to nodes with a local backend.
+pub async fn run(stores: ServiceStores, node_name: String, cancel: CancellationToken) {
+    let stores = Arc::new(stores);
+    let node_name: Arc<str> = node_name.into();
+    let mut servers: HashMap<u16, (ObjectRef<Service>, CancellationToken)> = HashMap::default();
+    let mut interval = tokio::time::interval(SYNC_INTERVAL);
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            _ = interval.tick() => {}
+        }
+
+        let wanted = health_check_ports(&stores.services.state());
+        servers.retain(|port, (service, server_cancel)| {
+            if wanted.get(port) == Some(service) {
+                return true;
+            }
+            info!(
+                "stopping health check server for {} on port {}",
+                service, port
+            );
+            server_cancel.cancel();
+            false
+        });
+
+        for (port, service) in wanted {
+            if servers.contains_key(&port) {
+                continue;
+            }
+            let addr = SocketAddr::from(([0, 0, 0, 0], port));
+            // the port may still be held by a server that was just stopped, the next sync
+            // retries
+            let listener = match TcpListener::bind(addr).await {
+                Ok(listener) => listener,
+                Err(e) => {
+                    warn!(%e, "failed to bind health check port {} for {}", port, service);
+                    continue;
+                }
+            };
+            info!("serving health check for {} on port {}", service, port);
+
+            let server_cancel = cancel.child_token();
+            let state = Arc::new(State {
+                service: service.clone(),
+                stores: Arc::clone(&stores),
+                node_name: Arc::clone(&node_name),
+            });
+            let app = Router::new().fallback(health_check).with_state(state);
+            let server =
+                axum::serve(listener, app).with_graceful_shutdown(shutdown(server_cancel.clone()));
+            tokio::spawn(async move {
+                if let Err(e) = server.await {
+                    warn!(%e, "health check server on port {} exited", port);
+                }
+            });
+            servers.insert(port, (service, server_cancel));
+        }
+    }
+}
+
+struct State {
+    service: ObjectRef<Service>,
+    stores: Arc<ServiceStores>,
+    node_name: Arc<str>,
+}
+
+async fn health_check(AxumState(state): AxumState<Arc<State>>) -> Health {
+    let namespace = state.service.namespace.clone().unwrap_or_default();
+    let name = state.service.name.clone();
+
+    let local_endpoints = state
+        .stores
+        .mesh_endpoints
+        .get(&ObjectRef::new(&name).within(&namespace))
+        .map(|mesh_endpoint| local_endpoints(&mesh_endpoint.spec, &state.node_name))
+        .or_else(|| {
+            let service = state.stores.services.get(&state.service)?;
+            let spec = generate_mesh_endpoint_spec(&state.stores.endpoint_slices, &service);
+            Some(local_endpoints(&spec, &state.node_name))
+        })
+        .unwrap_or_default();
+
+    Health {
+        namespace,
+        name,
+        local_endpoints,
+    }
+}
+
+/// Ready backends of the service running on `node_name`
+fn local_endpoints(spec: &MeshEndpointSpec, node_name: &str) -> usize {
+    spec.backend_port_mappings
+        .iter()
+        .filter(|mapping| {
+            mapping.serving
+                && !mapping.terminating
+                && mapping.node_name.as_deref() == Some(node_name)
+        })
+        .map(|mapping| mapping.ip)
+        .collect::<HashSet<_>>()
+        .len()
+}
+
+/// Health check node port of each `externalTrafficPolicy: Local` Service. A port claimed
+/// twice is left to the first Service.
+fn health_check_ports(services: &[Arc<Service>]) -> HashMap<u16, ObjectRef<Service>> {
+    let mut ports = HashMap::default();
+    for service in services {
+        let Some(spec) = &service.spec else {
+            continue;
+        };
+        if spec.external_traffic_policy.as_deref() != Some("Local") {
+            continue;
+        }
+        let Some(port) = spec
+            .health_check_node_port
+            .and_then(|port| u16::try_from(port).ok())
+        else {
+            continue;
+        };
+        ports
+            .entry(port)
+            .or_insert_with(|| ObjectRef::from_obj(service.as_ref()));
+    }
+    ports
+}
+
+/// Same body kube-proxy answers with, some load balancers parse it
+struct Health {
+    namespace: String,
+    name: String,
+    local_endpoints: usize,
+}
+
+impl IntoResponse for Health {
+    fn into_response(self) -> Response {
+        let status = if self.local_endpoints > 0 {
+            StatusCode::OK
+        } else {
+            StatusCode::SERVICE_UNAVAILABLE
+        };
+        let body = serde_json::json!({
+            "service": {
+                "namespace": self.namespace,
+                "name": self.name,
+            },
+            "localEndpoints": self.local_endpoints,
+            "serviceProxyHealthy": true,
+        });
+        Response::builder()
+            .status(status)
+            .header("Content-Type", "application/json")
+            .body(axum::body::Body::from(body.to_string()))
+            .unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use mesh_cni_crds::v1alpha1::meshendpoint::{BackendPortMapping, MeshEndpointSpec};
+
+    use super::local_endpoints;
+
+    fn mapping(last: u8, port: u16, node: &str, terminating: bool) -> BackendPortMapping {
+        BackendPortMapping {
+            ip: [10, 0, 0, last].into(),
+            service_port: port,
+            backend_port: port,
+            protocol: "TCP".into(),
+            node_port: None,
+            node_name: Some(node.into()),
+            zone: None,
+            for_zones: vec![],
+            serving: true,
+            terminating,
+        }
+    }
+
+    #[test]
+    fn test_local_endpoints_counts_ready_backends_on_node() {
+        let spec = MeshEndpointSpec {
+            backend_port_mappings: vec![
+                mapping(1, 80, "node-a", false),
+                mapping(1, 443, "node-a", false),
+                mapping(2, 80, "node-a", true),
+                mapping(3, 80, "node-b", false),
+            ],
+            ..Default::default()
+        };
+        assert_eq!(local_endpoints(&spec, "node-a"), 1);
+        assert_eq!(local_endpoints(&spec, "node-b"), 1);
+        assert_eq!(local_endpoints(&spec, "node-c"), 0);
+    }
+}
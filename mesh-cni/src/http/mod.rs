@@ -1,5 +1,6 @@
 mod error;
 pub mod grpc;
+pub mod health_check;
 mod readiness;
 
 use std::{net::SocketAddr, sync::Arc};
@@ -7,11 +7,12 @@ use axum::{
     routing::get,
 };
 use http::StatusCode;
+use prometheus_client::encoding::text::encode;
 use tokio::net::TcpListener;
 use tokio_util::sync::CancellationToken;
 use tracing::info;
 
-use crate::{Result, http::shutdown};
+use crate::{Result, http::shutdown, metrics::REGISTRY};
 
 #[derive(Clone)]
 pub(crate) struct State {
@@ -49,6 +50,7 @@ pub(crate) async fn serve(
 pub fn router(state: Arc<State>) -> Result<Router> {
     Ok(Router::new()
         .route("/readyz", get(readyz))
+        .route("/metrics", get(metrics))
         .with_state(state))
 }
 
@@ -56,6 +58,26 @@ async fn readyz(AxumState(handler): AxumState<Arc<State>>) -> Readiness {
     handler.ready()
 }
 
+async fn metrics() -> Response {
+    let mut body = String::new();
+    let registry = REGISTRY.read().unwrap();
+    if let Err(e) = encode(&mut body, &registry) {
+        return Response::builder()
+            .status(StatusCode::INTERNAL_SERVER_ERROR)
+            .header("Content-Type", "text/plain")
+            .body(axum::body::Body::from(e.to_string()))
+            .unwrap();
+    }
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(
+            "Content-Type",
+            "application/openmetrics-text; version=1.0.0; charset=utf-8",
+        )
+        .body(axum::body::Body::from(body))
+        .unwrap()
+}
+
 pub(crate) enum Readiness {
     Ready,
     NotReady,
@@ -0,0 +1,478 @@
+//! Allocates pod addresses out of the pod CIDRs assigned to this node, either by the control
+//! plane or by the cluster pool controller.
+
+mod store;
+
+use std::{
+    collections::BTreeMap,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use ipnetwork::IpNetwork;
+pub use store::Allocation;
+use tracing::info;
+
+use crate::ipam::store::Store;
+
+#[derive(thiserror::Error, Debug)]
+pub enum IpamError {
+    #[error("no addresses left in {0}")]
+    Exhausted(IpNetwork),
+
+    #[error("{0} is not in any pod CIDR of this node")]
+    NotInPool(IpAddr),
+
+    #[error("{ip} is already allocated to container {container_id}")]
+    InUse { ip: IpAddr, container_id: String },
+
+    #[error("node has no pod CIDRs to allocate from")]
+    NoPools,
+
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error(transparent)]
+    Json(#[from] serde_json::Error),
+}
+
+/// Usage of a pod CIDR
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PoolUsage {
+    pub cidr: IpNetwork,
+    pub allocated: u128,
+    pub capacity: u128,
+}
+
+/// Node local address allocator shared by the CNI and IPAM services.
+///
+/// Addresses are handed out round robin within each pod CIDR, so a released address is the
+/// last to be given out again and stale conntrack or identity entries age out first.
+#[derive(Clone)]
+pub struct Ipam {
+    inner: Arc<Mutex<Inner>>,
+}
+
+struct Inner {
+    pools: Vec<Pool>,
+    allocations: BTreeMap<IpAddr, Allocation>,
+    store: Store,
+}
+
+impl Ipam {
+    /// Creates an allocator for `pod_cidrs`, restoring the allocations persisted in
+    /// `state_dir` during this boot
+    pub fn new(state_dir: impl AsRef<Path>, pod_cidrs: Vec<IpNetwork>) -> Result<Self, IpamError> {
+        Self::with_boot_id(state_dir, pod_cidrs, &store::boot_id()?)
+    }
+
+    fn with_boot_id(
+        state_dir: impl AsRef<Path>,
+        pod_cidrs: Vec<IpNetwork>,
+        boot_id: &str,
+    ) -> Result<Self, IpamError> {
+        let store = Store::new(state_dir);
+        let allocations = store.load(boot_id)?;
+        let mut pools: Vec<Pool> = pod_cidrs.into_iter().map(Pool::new).collect();
+        for pool in pools.iter_mut() {
+            // continue after the highest address in use rather than reusing released ones
+            let last = allocations
+                .keys()
+                .filter(|ip| pool.contains(ip))
+                .max()
+                .copied();
+            if let Some(last) = last {
+                pool.next = pool.offset(&last) + 1;
+            }
+        }
+        info!(
+            pools = ?pools.iter().map(|p| p.cidr).collect::<Vec<_>>(),
+            allocated = allocations.len(),
+            "starting ipam"
+        );
+        Ok(Self {
+            inner: Arc::new(Mutex::new(Inner {
+                pools,
+                allocations,
+                store,
+            })),
+        })
+    }
+
+    /// Allocates the addresses of a container interface, one of each address family the pod
+    /// CIDRs cover or exactly `requested` when static addresses were asked for. Repeating the
+    /// call for the same container and interface returns the addresses it already holds.
+    pub fn allocate(
+        &self,
+        container_id: &str,
+        ifname: &str,
+        requested: &[IpAddr],
+    ) -> Result<Vec<IpAddr>, IpamError> {
+        let mut inner = self.inner.lock().unwrap();
+        let inner = &mut *inner;
+        let owner = Allocation {
+            container_id: container_id.to_string(),
+            ifname: ifname.to_string(),
+        };
+
+        let held = inner.owned_by(&owner);
+        if !held.is_empty() {
+            return Ok(held);
+        }
+
+        let ips = if requested.is_empty() {
+            if inner.pools.is_empty() {
+                return Err(IpamError::NoPools);
+            }
+            let mut ips: Vec<IpAddr> = Vec::new();
+            let mut exhausted = Vec::new();
+            for pool in inner.pools.iter_mut() {
+                // later pod CIDRs of a family are only used once the earlier ones are full
+                if ips.iter().any(|ip| ip.is_ipv4() == pool.cidr.is_ipv4()) {
+                    continue;
+                }
+                match pool.next_free(&inner.allocations) {
+                    Some(ip) => ips.push(ip),
+                    None => exhausted.push(pool.cidr),
+                }
+            }
+            if let Some(cidr) = exhausted
+                .into_iter()
+                .find(|cidr| !ips.iter().any(|ip| ip.is_ipv4() == cidr.is_ipv4()))
+            {
+                return Err(IpamError::Exhausted(cidr));
+            }
+            ips
+        } else {
+            for ip in requested {
+                if !inner.pools.iter().any(|pool| pool.contains(ip)) {
+                    return Err(IpamError::NotInPool(*ip));
+                }
+                if let Some(allocation) = inner.allocations.get(ip) {
+                    return Err(IpamError::InUse {
+                        ip: *ip,
+                        container_id: allocation.container_id.clone(),
+                    });
+                }
+            }
+            requested.to_vec()
+        };
+
+        for (i, ip) in ips.iter().enumerate() {
+            if let Err(e) = inner.store.reserve(*ip, &owner) {
+                for reserved in &ips[..i] {
+                    inner.allocations.remove(reserved);
+                    let _ = inner.store.release(*reserved);
+                }
+                return Err(e);
+            }
+            inner.allocations.insert(*ip, owner.clone());
+        }
+        info!(container_id, ifname, ?ips, "allocated addresses");
+        Ok(ips)
+    }
+
+    /// Addresses a container interface holds
+    pub fn owned(&self, container_id: &str, ifname: &str) -> Vec<IpAddr> {
+        let inner = self.inner.lock().unwrap();
+        inner.owned_by(&Allocation {
+            container_id: container_id.to_string(),
+            ifname: ifname.to_string(),
+        })
+    }
+
+    /// Releases the addresses of a container interface, returning the ones it held
+    pub fn release(&self, container_id: &str, ifname: &str) -> Result<Vec<IpAddr>, IpamError> {
+        let mut inner = self.inner.lock().unwrap();
+        let owner = Allocation {
+            container_id: container_id.to_string(),
+            ifname: ifname.to_string(),
+        };
+        let ips = inner.owned_by(&owner);
+        for ip in &ips {
+            inner.store.release(*ip)?;
+            inner.allocations.remove(ip);
+        }
+        if !ips.is_empty() {
+            info!(container_id, ifname, ?ips, "released addresses");
+        }
+        Ok(ips)
+    }
+
+    /// Adds pod CIDRs the node was assigned after the allocator was created
+    pub fn add_pools(&self, pod_cidrs: &[IpNetwork]) {
+        let mut inner = self.inner.lock().unwrap();
+        for cidr in pod_cidrs {
+            if inner.pools.iter().any(|pool| pool.cidr == *cidr) {
+                continue;
+            }
+            info!(%cidr, "adding pod cidr");
+            inner.pools.push(Pool::new(*cidr));
+        }
+    }
+
+    pub fn allocations(&self) -> Vec<(IpAddr, Allocation)> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .allocations
+            .iter()
+            .map(|(ip, allocation)| (*ip, allocation.clone()))
+            .collect()
+    }
+
+    pub fn pools(&self) -> Vec<PoolUsage> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .pools
+            .iter()
+            .map(|pool| PoolUsage {
+                cidr: pool.cidr,
+                allocated: inner
+                    .allocations
+                    .keys()
+                    .filter(|ip| pool.contains(ip))
+                    .count() as u128,
+                capacity: pool.capacity(),
+            })
+            .collect()
+    }
+}
+
+impl Inner {
+    fn owned_by(&self, owner: &Allocation) -> Vec<IpAddr> {
+        self.allocations
+            .iter()
+            .filter(|(_, allocation)| *allocation == owner)
+            .map(|(ip, _)| *ip)
+            .collect()
+    }
+}
+
+struct Pool {
+    cidr: IpNetwork,
+    /// Offset of the next address to try
+    next: u128,
+}
+
+impl Pool {
+    fn new(cidr: IpNetwork) -> Self {
+        let mut pool = Self { cidr, next: 0 };
+        pool.next = pool.first();
+        pool
+    }
+
+    fn host_bits(&self) -> u32 {
+        let bits = match self.cidr {
+            IpNetwork::V4(_) => 32,
+            IpNetwork::V6(_) => 128,
+        };
+        bits - self.cidr.prefix() as u32
+    }
+
+    fn size(&self) -> u128 {
+        1u128.checked_shl(self.host_bits()).unwrap_or(u128::MAX)
+    }
+
+    /// The network address is skipped, on IPv4 also the broadcast address, unless the
+    /// CIDR is too small to have them
+    fn first(&self) -> u128 {
+        match self.cidr {
+            IpNetwork::V4(_) if self.host_bits() < 2 => 0,
+            IpNetwork::V6(_) if self.host_bits() < 1 => 0,
+            _ => 1,
+        }
+    }
+
+    fn last(&self) -> u128 {
+        match self.cidr {
+            IpNetwork::V4(_) if self.host_bits() >= 2 => self.size() - 2,
+            _ => self.size() - 1,
+        }
+    }
+
+    fn capacity(&self) -> u128 {
+        self.last() - self.first() + 1
+    }
+
+    fn contains(&self, ip: &IpAddr) -> bool {
+        self.cidr.contains(*ip) && (self.first()..=self.last()).contains(&self.offset(ip))
+    }
+
+    fn offset(&self, ip: &IpAddr) -> u128 {
+        match (self.cidr, ip) {
+            (IpNetwork::V4(cidr), IpAddr::V4(ip)) => {
+                (ip.to_bits() - cidr.network().to_bits()) as u128
+            }
+            (IpNetwork::V6(cidr), IpAddr::V6(ip)) => ip.to_bits() - cidr.network().to_bits(),
+            _ => 0,
+        }
+    }
+
+    fn address(&self, offset: u128) -> IpAddr {
+        match self.cidr {
+            IpNetwork::V4(cidr) => IpAddr::V4(Ipv4Addr::from_bits(
+                cidr.network().to_bits() + offset as u32,
+            )),
+            IpNetwork::V6(cidr) => {
+                IpAddr::V6(Ipv6Addr::from_bits(cidr.network().to_bits() + offset))
+            }
+        }
+    }
+
+    fn next_free(&mut self, allocations: &BTreeMap<IpAddr, Allocation>) -> Option<IpAddr> {
+        let (first, last) = (self.first(), self.last());
+        let mut offset = self.next.clamp(first, last);
+        for _ in 0..self.capacity() {
+            let ip = self.address(offset);
+            offset = if offset == last { first } else { offset + 1 };
+            if !allocations.contains_key(&ip) {
+                self.next = offset;
+                return Some(ip);
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::PathBuf};
+
+    use super::*;
+
+    fn state_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("mesh-cni-ipam-{}-{name}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_allocate_from_each_pod_cidr() {
+        let dir = state_dir("dual");
+        let ipam = Ipam::with_boot_id(
+            &dir,
+            vec![
+                "10.0.0.0/30".parse().unwrap(),
+                "fd00::/126".parse().unwrap(),
+            ],
+            "boot",
+        )
+        .unwrap();
+
+        let first = ipam.allocate("a", "eth0", &[]).unwrap();
+        assert_eq!(first, vec![ip("10.0.0.1"), ip("fd00::1")]);
+        // repeated adds get the same addresses
+        assert_eq!(ipam.allocate("a", "eth0", &[]).unwrap(), first);
+
+        let second = ipam.allocate("b", "eth0", &[]).unwrap();
+        assert_eq!(second, vec![ip("10.0.0.2"), ip("fd00::2")]);
+        assert!(matches!(
+            ipam.allocate("c", "eth0", &[]),
+            Err(IpamError::Exhausted(_))
+        ));
+
+        assert_eq!(ipam.release("a", "eth0").unwrap(), first);
+        assert!(ipam.release("a", "eth0").unwrap().is_empty());
+        assert_eq!(
+            ipam.allocate("c", "eth0", &[]).unwrap(),
+            vec![ip("10.0.0.1"), ip("fd00::3")]
+        );
+        assert_eq!(
+            ipam.pools()[0],
+            PoolUsage {
+                cidr: "10.0.0.0/30".parse().unwrap(),
+                allocated: 2,
+                capacity: 2,
+            }
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_allocate_from_added_pod_cidrs() {
+        let dir = state_dir("grow");
+        let ipam = Ipam::with_boot_id(&dir, vec!["10.0.0.0/31".parse().unwrap()], "boot").unwrap();
+
+        ipam.allocate("a", "eth0", &[]).unwrap();
+        ipam.allocate("b", "eth0", &[]).unwrap();
+        assert!(matches!(
+            ipam.allocate("c", "eth0", &[]),
+            Err(IpamError::Exhausted(_))
+        ));
+
+        ipam.add_pools(&[
+            "10.0.0.0/31".parse().unwrap(),
+            "10.0.1.0/31".parse().unwrap(),
+        ]);
+        assert_eq!(ipam.pools().len(), 2);
+        assert_eq!(
+            ipam.allocate("c", "eth0", &[]).unwrap(),
+            vec![ip("10.0.1.0")]
+        );
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_allocate_static_addresses() {
+        let dir = state_dir("static");
+        let ipam = Ipam::with_boot_id(&dir, vec!["10.0.0.0/24".parse().unwrap()], "boot").unwrap();
+
+        assert_eq!(
+            ipam.allocate("a", "eth0", &[ip("10.0.0.10")]).unwrap(),
+            vec![ip("10.0.0.10")]
+        );
+        assert!(matches!(
+            ipam.allocate("b", "eth0", &[ip("10.0.0.10")]),
+            Err(IpamError::InUse { .. })
+        ));
+        assert!(matches!(
+            ipam.allocate("b", "eth0", &[ip("10.0.1.10")]),
+            Err(IpamError::NotInPool(_))
+        ));
+        assert!(matches!(
+            ipam.allocate("b", "eth0", &[ip("10.0.0.255")]),
+            Err(IpamError::NotInPool(_))
+        ));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_allocations_survive_restarts_but_not_reboots() {
+        let dir = state_dir("restart");
+        let cidrs = vec!["10.0.0.0/24".parse::<IpNetwork>().unwrap()];
+        let ipam = Ipam::with_boot_id(&dir, cidrs.clone(), "boot").unwrap();
+        ipam.allocate("a", "eth0", &[]).unwrap();
+        ipam.allocate("b", "eth0", &[]).unwrap();
+        ipam.release("a", "eth0").unwrap();
+
+        let ipam = Ipam::with_boot_id(&dir, cidrs.clone(), "boot").unwrap();
+        assert_eq!(
+            ipam.allocations(),
+            vec![(
+                ip("10.0.0.2"),
+                Allocation {
+                    container_id: "b".into(),
+                    ifname: "eth0".into(),
+                }
+            )]
+        );
+        assert_eq!(
+            ipam.allocate("c", "eth0", &[]).unwrap(),
+            vec![ip("10.0.0.3")]
+        );
+
+        let ipam = Ipam::with_boot_id(&dir, cidrs, "rebooted").unwrap();
+        assert!(ipam.allocations().is_empty());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
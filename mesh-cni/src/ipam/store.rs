@@ -0,0 +1,110 @@
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, Write},
+    net::IpAddr,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{info, warn};
+
+use crate::ipam::IpamError;
+
+/// Written once per boot, pods do not survive a reboot so neither do their allocations
+const BOOT_ID_FILE: &str = "boot_id";
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Owner of an allocated address
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct Allocation {
+    pub container_id: String,
+    pub ifname: String,
+}
+
+/// Keeps one file per allocated address, named after the address and holding its owner.
+/// Files are written to a temporary path and renamed so a crash never leaves a partial one.
+pub(crate) struct Store {
+    dir: PathBuf,
+}
+
+impl Store {
+    pub(crate) fn new(dir: impl AsRef<Path>) -> Self {
+        Self {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Reads the allocations, dropping them when they were made before the last reboot
+    pub(crate) fn load(&self, boot_id: &str) -> Result<BTreeMap<IpAddr, Allocation>, IpamError> {
+        fs::create_dir_all(&self.dir)?;
+        let boot_id_path = self.dir.join(BOOT_ID_FILE);
+        let previous = match fs::read_to_string(&boot_id_path) {
+            Ok(id) => Some(id),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => return Err(e.into()),
+        };
+
+        let mut allocations = BTreeMap::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.ends_with(TMP_SUFFIX) {
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            let Ok(ip) = name.parse::<IpAddr>() else {
+                continue;
+            };
+            if previous.as_deref() != Some(boot_id) {
+                info!(%ip, "releasing address allocated before reboot");
+                fs::remove_file(entry.path())?;
+                continue;
+            }
+            match serde_json::from_slice::<Allocation>(&fs::read(entry.path())?) {
+                Ok(allocation) => {
+                    allocations.insert(ip, allocation);
+                }
+                Err(e) => {
+                    warn!(%e, %ip, "removing unreadable allocation");
+                    fs::remove_file(entry.path())?;
+                }
+            }
+        }
+
+        if previous.as_deref() != Some(boot_id) {
+            write_atomic(&boot_id_path, boot_id.as_bytes())?;
+        }
+        Ok(allocations)
+    }
+
+    pub(crate) fn reserve(&self, ip: IpAddr, allocation: &Allocation) -> Result<(), IpamError> {
+        write_atomic(
+            &self.dir.join(ip.to_string()),
+            &serde_json::to_vec(allocation)?,
+        )
+    }
+
+    pub(crate) fn release(&self, ip: IpAddr) -> Result<(), IpamError> {
+        match fs::remove_file(self.dir.join(ip.to_string())) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+}
+
+pub(crate) fn boot_id() -> Result<String, IpamError> {
+    Ok(fs::read_to_string(BOOT_ID_PATH)?.trim().to_string())
+}
+
+fn write_atomic(path: &Path, contents: &[u8]) -> Result<(), IpamError> {
+    let mut tmp = path.as_os_str().to_owned();
+    tmp.push(TMP_SUFFIX);
+    let mut file = File::create(&tmp)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp, path)?;
+    Ok(())
+}
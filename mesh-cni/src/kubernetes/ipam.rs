@@ -0,0 +1,111 @@
+use std::{pin::pin, time::Duration};
+
+use futures::StreamExt;
+use ipnetwork::IpNetwork;
+use kube::{
+    Api,
+    api::{Patch, PatchParams},
+    runtime::{WatchStreamExt, wait::await_condition, watcher},
+};
+use mesh_cni_crds::v1alpha1::podcidrallocation::{
+    PodCidrAllocation, PodCidrAllocationStatus, PodCidrUsage,
+};
+use serde_json::json;
+use tokio_util::sync::CancellationToken;
+use tracing::{info, warn};
+
+use crate::{Result, ipam::Ipam};
+
+/// How often the usage of the pod CIDRs is reported back to the controller
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Waits for the controller to allocate pod CIDRs to the node from the cluster pool
+pub async fn pod_cidrs(client: kube::Client, node_name: &str) -> Result<Vec<IpNetwork>> {
+    let api: Api<PodCidrAllocation> = Api::all(client);
+    info!("waiting for pod cidrs to be allocated from the cluster pool");
+    let allocation = await_condition(api, node_name, |allocation: Option<&PodCidrAllocation>| {
+        allocation.is_some_and(|allocation| !allocation.spec.cidrs.is_empty())
+    })
+    .await?;
+    Ok(allocation
+        .map(|allocation| cidrs(&allocation))
+        .unwrap_or_default())
+}
+
+/// Adds the pod CIDRs the node is allocated later on to `ipam` and reports their usage so
+/// the controller can allocate more before they run out
+pub async fn run(
+    kube_client: kube::Client,
+    node_name: String,
+    ipam: Ipam,
+    cancel: CancellationToken,
+) -> Result<()> {
+    tokio::spawn(sync(kube_client, node_name, ipam, cancel));
+    Ok(())
+}
+
+async fn sync(client: kube::Client, node_name: String, ipam: Ipam, cancel: CancellationToken) {
+    let api: Api<PodCidrAllocation> = Api::all(client);
+    let config = watcher::Config::default().fields(&format!("metadata.name={node_name}"));
+    let mut allocations = pin!(
+        watcher(api.clone(), config)
+            .default_backoff()
+            .applied_objects()
+    );
+    let mut report = tokio::time::interval(REPORT_INTERVAL);
+    let mut reported = None;
+
+    loop {
+        tokio::select! {
+            _ = cancel.cancelled() => return,
+            allocation = allocations.next() => match allocation {
+                Some(Ok(allocation)) => ipam.add_pools(&cidrs(&allocation)),
+                Some(Err(e)) => warn!(%e, "failed to watch pod cidr allocation"),
+                None => return,
+            },
+            _ = report.tick() => {}
+        }
+
+        let status = status(&ipam);
+        if reported.as_ref() == Some(&status) {
+            continue;
+        }
+        let patch = Patch::Merge(json!({ "status": status }));
+        match api
+            .patch_status(&node_name, &PatchParams::default(), &patch)
+            .await
+        {
+            Ok(_) => reported = Some(status),
+            Err(e) => warn!(%e, "failed to report pod cidr usage"),
+        }
+    }
+}
+
+fn cidrs(allocation: &PodCidrAllocation) -> Vec<IpNetwork> {
+    allocation
+        .spec
+        .cidrs
+        .iter()
+        .filter_map(|cidr| match cidr.parse() {
+            Ok(cidr) => Some(cidr),
+            Err(e) => {
+                warn!(%e, cidr, "ignoring invalid pod cidr");
+                None
+            }
+        })
+        .collect()
+}
+
+fn status(ipam: &Ipam) -> PodCidrAllocationStatus {
+    PodCidrAllocationStatus {
+        pools: ipam
+            .pools()
+            .into_iter()
+            .map(|pool| PodCidrUsage {
+                cidr: pool.cidr.to_string(),
+                allocated: u64::try_from(pool.allocated).unwrap_or(u64::MAX),
+                capacity: u64::try_from(pool.capacity).unwrap_or(u64::MAX),
+            })
+            .collect(),
+    }
+}
@@ -1,4 +1,5 @@
 pub mod cluster;
+pub mod ipam;
 pub mod node;
 pub mod state;
 
@@ -1,10 +1,72 @@
+use std::net::IpAddr;
+
+use ipnetwork::IpNetwork;
 use k8s_openapi::api::core::v1::Node;
-use kube::{Api, ResourceExt, api::PostParams};
+use kube::{Api, ResourceExt, api::PostParams, runtime::wait::await_condition};
+use mesh_cni_crds::v1alpha1::meshendpoint::Topology;
+use tracing::info;
 
 use crate::Result;
 
 const TAINT_MESH_STARTUP: &str = "mesh-cni.dev/startup";
 const TAINT_CILIUM_STARTUP: &str = "node.cilium.io/agent-not-ready";
+const LABEL_ZONE: &str = "topology.kubernetes.io/zone";
+const ADDRESS_INTERNAL_IP: &str = "InternalIP";
+const ADDRESS_EXTERNAL_IP: &str = "ExternalIP";
+
+/// Node, zone and addresses this agent programs service backends for
+pub async fn node_topology(client: kube::Client, node_name: String) -> Result<Topology> {
+    let node_api: Api<Node> = Api::all(client);
+    let this_node = node_api.get(&node_name).await?;
+    let zone = this_node.labels().get(LABEL_ZONE).cloned();
+    let node_ips = this_node
+        .status
+        .as_ref()
+        .and_then(|status| status.addresses.as_ref())
+        .into_iter()
+        .flatten()
+        .filter(|address| {
+            address.type_ == ADDRESS_INTERNAL_IP || address.type_ == ADDRESS_EXTERNAL_IP
+        })
+        .filter_map(|address| address.address.parse::<IpAddr>().ok())
+        .collect();
+    Ok(Topology {
+        node_name,
+        zone,
+        node_ips,
+    })
+}
+
+/// Waits for the control plane to assign pod CIDRs to the node, at most one per address
+/// family. They can not change once assigned.
+pub async fn pod_cidrs(client: kube::Client, node_name: &str) -> Result<Vec<IpNetwork>> {
+    let node_api: Api<Node> = Api::all(client);
+    info!("waiting for pod cidrs to be assigned to the node");
+    let this_node = await_condition(node_api, node_name, |node: Option<&Node>| {
+        node.is_some_and(|node| !assigned_cidrs(node).is_empty())
+    })
+    .await?;
+    let cidrs = this_node
+        .as_ref()
+        .map(assigned_cidrs)
+        .unwrap_or_default()
+        .iter()
+        .map(|cidr| cidr.parse::<IpNetwork>())
+        .collect::<std::result::Result<_, _>>()?;
+    Ok(cidrs)
+}
+
+/// podCIDRs of a node, or the single podCIDR control planes set before dual stack
+fn assigned_cidrs(node: &Node) -> Vec<String> {
+    let Some(spec) = node.spec.as_ref() else {
+        return Vec::new();
+    };
+    match &spec.pod_cidrs {
+        Some(cidrs) if !cidrs.is_empty() => cidrs.clone(),
+        _ => spec.pod_cidr.iter().cloned().collect(),
+    }
+}
+
 pub async fn remove_startup_taint(client: kube::Client, node_name: String) -> Result<()> {
     let node_api: Api<Node> = Api::all(client);
     let mut this_node = node_api.get(&node_name).await?;
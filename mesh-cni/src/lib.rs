@@ -4,6 +4,10 @@ pub mod cni;
 pub mod config;
 pub mod controller;
 pub mod http;
+pub mod ipam;
 pub mod kubernetes;
+pub mod metrics;
+pub mod network;
+pub mod result_cache;
 
 pub type Result<T> = anyhow::Result<T>;
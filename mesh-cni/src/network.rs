@@ -0,0 +1,415 @@
+//! Plumbing of pods when mesh-cni is the only CNI.
+//!
+//! Every pod gets a veth pair whose host end is routed rather than bridged. The pod reaches
+//! everything through a link-local gateway, which the host end answers for with proxy ARP
+//! on IPv4 and owns on IPv6, so no address from the pod CIDR is spent on gateways.
+
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    os::fd::AsRawFd,
+};
+
+use anyhow::{Context, anyhow};
+use futures::TryStreamExt;
+use ipnetwork::IpNetwork;
+use netlink_packet_route::{
+    address::AddressAttribute,
+    link::{LinkAttribute, LinkMessage},
+    route::{RouteAddress, RouteAttribute, RouteScope},
+};
+use netns_rs::NetNs;
+use rtnetlink::{Handle, IpVersion};
+use tracing::{info, warn};
+
+use crate::Result;
+
+/// Gateway of IPv4 pods, answered by the host end of the veth through proxy ARP
+pub const GATEWAY_V4: Ipv4Addr = Ipv4Addr::new(169, 254, 1, 1);
+
+/// Gateway of IPv6 pods, assigned to the host end of every veth
+pub const GATEWAY_V6: Ipv6Addr = Ipv6Addr::new(0xfe80, 0, 0, 0, 0, 0, 0, 1);
+
+const HOST_VETH_PREFIX: &str = "mesh";
+const TMP_VETH_PREFIX: &str = "tmp";
+
+/// Interface names are limited to 15 bytes by the kernel
+const IFNAME_MAX_LEN: usize = 15;
+
+/// A pod wired up to the host
+#[derive(Debug, Clone)]
+pub struct PodNetwork {
+    pub host_iface: String,
+    pub host_mac: Option<String>,
+    pub pod_iface: String,
+    pub pod_mac: Option<String>,
+    /// Addresses of the pod, each a host route
+    pub addresses: Vec<IpNetwork>,
+}
+
+impl PodNetwork {
+    /// Gateways the default routes of the pod point at, one per address family in use
+    pub fn gateways(&self) -> Vec<IpAddr> {
+        let mut gateways = Vec::new();
+        if self.addresses.iter().any(|a| a.is_ipv4()) {
+            gateways.push(IpAddr::V4(GATEWAY_V4));
+        }
+        if self.addresses.iter().any(|a| a.is_ipv6()) {
+            gateways.push(IpAddr::V6(GATEWAY_V6));
+        }
+        gateways
+    }
+}
+
+/// Name of the host end of the veth of a container interface, derived from the container
+/// id and `ifname` so it can be found again without any other state
+pub fn host_veth_name(container_id: &str, ifname: &str) -> String {
+    veth_name(HOST_VETH_PREFIX, container_id, ifname)
+}
+
+fn veth_name(prefix: &str, container_id: &str, ifname: &str) -> String {
+    let hash = format!("{:016x}", hash(container_id, ifname));
+    format!("{prefix}{}", &hash[..IFNAME_MAX_LEN - prefix.len()])
+}
+
+/// FNV-1a over the container id and interface name. Names have to survive agent upgrades,
+/// so the randomly keyed hashers used elsewhere cannot be used here.
+fn hash(container_id: &str, ifname: &str) -> u64 {
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    let bytes = container_id.bytes().chain([0]).chain(ifname.bytes());
+    for byte in bytes {
+        hash ^= u64::from(byte);
+        hash = hash.wrapping_mul(0x0000_0100_0000_01b3);
+    }
+    hash
+}
+
+/// Creates the veth pair of a container, moves one end into `net_namespace` as `ifname` and
+/// routes `addresses` to it. A pair left behind by a failed attempt is replaced.
+pub async fn setup(
+    container_id: &str,
+    net_namespace: &str,
+    ifname: &str,
+    addresses: &[IpAddr],
+) -> Result<PodNetwork> {
+    let host_iface = host_veth_name(container_id, ifname);
+    let tmp_iface = veth_name(TMP_VETH_PREFIX, container_id, ifname);
+    let host = connect_host()?;
+
+    for name in [&host_iface, &tmp_iface] {
+        if let Some(link) = get_link(&host, name).await? {
+            warn!(
+                iface = name,
+                "removing veth left behind by a previous attempt"
+            );
+            host.link().del(link.header.index).execute().await?;
+        }
+    }
+
+    info!(%host_iface, %ifname, "creating veth pair");
+    host.link()
+        .add()
+        .veth(host_iface.clone(), tmp_iface.clone())
+        .execute()
+        .await?;
+
+    let result = wire(
+        &host,
+        &host_iface,
+        &tmp_iface,
+        net_namespace,
+        ifname,
+        addresses,
+    )
+    .await;
+    if result.is_err()
+        && let Err(e) = teardown(container_id, ifname).await
+    {
+        warn!(%e, %host_iface, "failed to remove veth after failed setup");
+    }
+    result
+}
+
+async fn wire(
+    host: &Handle,
+    host_iface: &str,
+    tmp_iface: &str,
+    net_namespace: &str,
+    ifname: &str,
+    addresses: &[IpAddr],
+) -> Result<PodNetwork> {
+    let netns = NetNs::get(net_namespace)
+        .with_context(|| format!("failed to open network namespace {net_namespace}"))?;
+    let pod = connect_in(&netns)?;
+
+    let tmp = require_link(host, tmp_iface).await?;
+    host.link()
+        .set(tmp.header.index)
+        .setns_by_fd(netns.file().as_raw_fd())
+        .execute()
+        .await?;
+
+    // the pod end keeps its index when it changes namespace
+    let pod_link = require_link(&pod, tmp_iface).await?;
+    let pod_index = pod_link.header.index;
+    pod.link()
+        .set(pod_index)
+        .name(ifname.to_string())
+        .execute()
+        .await?;
+    pod.link().set(pod_index).up().execute().await?;
+    if let Some(lo) = get_link(&pod, "lo").await? {
+        pod.link().set(lo.header.index).up().execute().await?;
+    }
+
+    let host_link = require_link(host, host_iface).await?;
+    let host_index = host_link.header.index;
+    // the gateway on IPv4 is answered for rather than assigned
+    fs::write(
+        format!("/proc/sys/net/ipv4/conf/{host_iface}/proxy_arp"),
+        "1",
+    )?;
+    host.link().set(host_index).up().execute().await?;
+
+    let mut networks = Vec::with_capacity(addresses.len());
+    for address in addresses {
+        let network = IpNetwork::from(*address);
+        pod.address()
+            .add(pod_index, *address, network.prefix())
+            .execute()
+            .await?;
+        networks.push(network);
+    }
+
+    if addresses.iter().any(|a| a.is_ipv4()) {
+        pod.route()
+            .add()
+            .v4()
+            .destination_prefix(GATEWAY_V4, 32)
+            .output_interface(pod_index)
+            .scope(RouteScope::Link)
+            .execute()
+            .await?;
+        pod.route()
+            .add()
+            .v4()
+            .destination_prefix(Ipv4Addr::UNSPECIFIED, 0)
+            .gateway(GATEWAY_V4)
+            .output_interface(pod_index)
+            .execute()
+            .await?;
+    }
+
+    if addresses.iter().any(|a| a.is_ipv6()) {
+        host.address()
+            .add(host_index, IpAddr::V6(GATEWAY_V6), 64)
+            .execute()
+            .await?;
+        pod.route()
+            .add()
+            .v6()
+            .destination_prefix(Ipv6Addr::UNSPECIFIED, 0)
+            .gateway(GATEWAY_V6)
+            .output_interface(pod_index)
+            .execute()
+            .await?;
+    }
+
+    for address in addresses {
+        let route = host.route().add().output_interface(host_index);
+        match address {
+            IpAddr::V4(a) => {
+                route
+                    .v4()
+                    .destination_prefix(*a, 32)
+                    .scope(RouteScope::Link)
+                    .execute()
+                    .await?
+            }
+            IpAddr::V6(a) => {
+                route
+                    .v6()
+                    .destination_prefix(*a, 128)
+                    .scope(RouteScope::Link)
+                    .execute()
+                    .await?
+            }
+        }
+    }
+
+    let pod_link = require_link(&pod, ifname).await?;
+    Ok(PodNetwork {
+        host_iface: host_iface.to_string(),
+        host_mac: mac(&host_link),
+        pod_iface: ifname.to_string(),
+        pod_mac: mac(&pod_link),
+        addresses: networks,
+    })
+}
+
+/// Removes the veth pair of a container interface along with the routes to it. Missing
+/// interfaces are not an error so this can be repeated.
+pub async fn teardown(container_id: &str, ifname: &str) -> Result<()> {
+    let host = connect_host()?;
+    for name in [
+        host_veth_name(container_id, ifname),
+        veth_name(TMP_VETH_PREFIX, container_id, ifname),
+    ] {
+        if let Some(link) = get_link(&host, &name).await? {
+            info!(iface = name, "removing veth");
+            host.link().del(link.header.index).execute().await?;
+        }
+    }
+    Ok(())
+}
+
+/// Whether an interface exists in the network namespace of the agent
+pub async fn host_link_exists(name: &str) -> Result<bool> {
+    let host = connect_host()?;
+    Ok(get_link(&host, name).await?.is_some())
+}
+
+/// Addresses and routes of an interface in a pod
+#[derive(Debug, Clone, Default)]
+pub struct PodState {
+    pub addresses: Vec<IpNetwork>,
+    /// Destination and gateway of every route through the interface
+    pub routes: Vec<(IpNetwork, Option<IpAddr>)>,
+}
+
+/// Reads the addresses and routes of `ifname` in `net_namespace`, `None` when there is no
+/// such interface
+pub async fn pod_state(net_namespace: &str, ifname: &str) -> Result<Option<PodState>> {
+    let netns = NetNs::get(net_namespace)
+        .with_context(|| format!("failed to open network namespace {net_namespace}"))?;
+    let pod = connect_in(&netns)?;
+    let Some(link) = get_link(&pod, ifname).await? else {
+        return Ok(None);
+    };
+    let index = link.header.index;
+
+    let mut state = PodState::default();
+    let mut addresses = pod.address().get().set_link_index_filter(index).execute();
+    while let Some(message) = addresses.try_next().await? {
+        for attribute in &message.attributes {
+            if let AddressAttribute::Address(address) = attribute {
+                state
+                    .addresses
+                    .push(IpNetwork::new(*address, message.header.prefix_len)?);
+            }
+        }
+    }
+    state.addresses.sort();
+    state.addresses.dedup();
+
+    for (version, unspecified) in [
+        (IpVersion::V4, IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        (IpVersion::V6, IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    ] {
+        let mut routes = pod.route().get(version).execute();
+        while let Some(message) = routes.try_next().await? {
+            let mut oif = None;
+            let mut destination = None;
+            let mut gateway = None;
+            for attribute in &message.attributes {
+                match attribute {
+                    RouteAttribute::Oif(i) => oif = Some(*i),
+                    RouteAttribute::Destination(a) => destination = route_address(a),
+                    RouteAttribute::Gateway(a) => gateway = route_address(a),
+                    _ => {}
+                }
+            }
+            if oif != Some(index) {
+                continue;
+            }
+            let destination = IpNetwork::new(
+                destination.unwrap_or(unspecified),
+                message.header.destination_prefix_length,
+            )?;
+            state.routes.push((destination, gateway));
+        }
+    }
+    Ok(Some(state))
+}
+
+fn route_address(address: &RouteAddress) -> Option<IpAddr> {
+    match address {
+        RouteAddress::Inet(a) => Some(IpAddr::V4(*a)),
+        RouteAddress::Inet6(a) => Some(IpAddr::V6(*a)),
+        _ => None,
+    }
+}
+
+fn connect_host() -> Result<Handle> {
+    let (connection, handle, _) = rtnetlink::new_connection()?;
+    tokio::spawn(connection);
+    Ok(handle)
+}
+
+/// Opens a netlink connection bound to `netns`. The socket belongs to the namespace it is
+/// created in, so the handle keeps acting in it from any thread.
+fn connect_in(netns: &NetNs) -> Result<Handle> {
+    let (connection, handle, _) = netns
+        .run(|_| rtnetlink::new_connection())
+        .map_err(|e| anyhow!("failed to enter network namespace: {e}"))??;
+    tokio::spawn(connection);
+    Ok(handle)
+}
+
+async fn get_link(handle: &Handle, name: &str) -> Result<Option<LinkMessage>> {
+    let mut links = handle.link().get().match_name(name.to_string()).execute();
+    match links.try_next().await {
+        Ok(link) => Ok(link),
+        Err(rtnetlink::Error::NetlinkError(e)) if e.raw_code() == -libc::ENODEV => Ok(None),
+        Err(e) => Err(e.into()),
+    }
+}
+
+async fn require_link(handle: &Handle, name: &str) -> Result<LinkMessage> {
+    get_link(handle, name)
+        .await?
+        .ok_or_else(|| anyhow!("interface {name} not found"))
+}
+
+fn mac(link: &LinkMessage) -> Option<String> {
+    link.attributes
+        .iter()
+        .find_map(|attribute| match attribute {
+            LinkAttribute::Address(address) => Some(
+                address
+                    .iter()
+                    .map(|b| format!("{b:02x}"))
+                    .collect::<Vec<_>>()
+                    .join(":"),
+            ),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_veth_names_fit_the_kernel_limit() {
+        let id = "0a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f9";
+        for name in [
+            host_veth_name(id, "eth0"),
+            veth_name(TMP_VETH_PREFIX, id, "eth0"),
+            host_veth_name("pod-1_a", "eth0"),
+        ] {
+            assert_eq!(name.len(), IFNAME_MAX_LEN);
+            assert!(name.chars().all(|c| c.is_ascii_alphanumeric()));
+        }
+        assert_eq!(host_veth_name(id, "eth0"), host_veth_name(id, "eth0"));
+    }
+
+    #[test]
+    fn test_veth_names_differ_per_interface() {
+        let id = "0a1b2c3d4e5f60718293a4b5c6d7e8f90a1b2c3d4e5f60718293a4b5c6d7e8f9";
+        assert_ne!(host_veth_name(id, "eth0"), host_veth_name(id, "net1"));
+        assert_ne!(host_veth_name(id, "eth0"), host_veth_name("other", "eth0"));
+        // the separator keeps the id and interface name from running into each other
+        assert_ne!(host_veth_name("ab", "c"), host_veth_name("a", "bc"));
+    }
+}
@@ -0,0 +1,150 @@
+//! Results of CNI ADD kept per container and interface, in the spirit of the libcni cache, so
+//! DEL and CHECK do not depend on the runtime passing the previous result back.
+
+use std::{
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+};
+
+use mesh_cni_api::cni::v1::{AddPodReply, Interface, Ip, Route};
+use serde::{Deserialize, Serialize};
+
+use crate::Result;
+
+const CACHE_KIND: &str = "cniCacheV1";
+const TMP_SUFFIX: &str = ".tmp";
+
+/// What ADD did for a container interface
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct CachedResult {
+    pub kind: String,
+    pub container_id: String,
+    pub if_name: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub net_namespace: Option<String>,
+    pub chained: bool,
+    /// Host interfaces the policy programs are attached to
+    pub host_ifaces: Vec<String>,
+    #[serde(default)]
+    pub result: CniResult,
+}
+
+impl CachedResult {
+    pub fn new(
+        container_id: &str,
+        if_name: &str,
+        net_namespace: Option<String>,
+        chained: bool,
+    ) -> Self {
+        Self {
+            kind: CACHE_KIND.into(),
+            container_id: container_id.into(),
+            if_name: if_name.into(),
+            net_namespace,
+            chained,
+            host_ifaces: Vec::new(),
+            result: CniResult::default(),
+        }
+    }
+}
+
+/// The result returned to the runtime, empty when chained as the result is then owned by the
+/// plugins before mesh-cni
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct CniResult {
+    #[serde(default)]
+    pub interfaces: Vec<Interface>,
+    #[serde(default)]
+    pub ips: Vec<Ip>,
+    #[serde(default)]
+    pub routes: Vec<Route>,
+}
+
+impl From<&AddPodReply> for CniResult {
+    fn from(reply: &AddPodReply) -> Self {
+        Self {
+            interfaces: reply.interfaces.clone(),
+            ips: reply.ips.clone(),
+            routes: reply.routes.clone(),
+        }
+    }
+}
+
+/// One file per container interface, written to a temporary path and renamed so a crash
+/// never leaves a partial one
+#[derive(Clone)]
+pub struct ResultCache {
+    dir: PathBuf,
+}
+
+impl ResultCache {
+    pub fn new(dir: impl AsRef<Path>) -> Result<Self> {
+        fs::create_dir_all(dir.as_ref())?;
+        Ok(Self {
+            dir: dir.as_ref().to_path_buf(),
+        })
+    }
+
+    pub fn get(&self, container_id: &str, if_name: &str) -> Result<Option<CachedResult>> {
+        match fs::read(self.path(container_id, if_name)) {
+            Ok(contents) => Ok(Some(serde_json::from_slice(&contents)?)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    pub fn put(&self, result: &CachedResult) -> Result<()> {
+        let path = self.path(&result.container_id, &result.if_name);
+        let mut tmp = path.as_os_str().to_owned();
+        tmp.push(TMP_SUFFIX);
+        let mut file = File::create(&tmp)?;
+        file.write_all(&serde_json::to_vec(result)?)?;
+        file.sync_all()?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    /// Removes the result of a container interface, which is not an error when there is none
+    pub fn remove(&self, container_id: &str, if_name: &str) -> Result<()> {
+        match fs::remove_file(self.path(container_id, if_name)) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn path(&self, container_id: &str, if_name: &str) -> PathBuf {
+        self.dir.join(format!("{container_id}-{if_name}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_put_get_remove() {
+        let dir = std::env::temp_dir().join(format!("mesh-cni-results-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let cache = ResultCache::new(&dir).unwrap();
+        assert_eq!(cache.get("a", "eth0").unwrap(), None);
+
+        let mut result = CachedResult::new("a", "eth0", Some("/var/run/netns/a".into()), false);
+        result.host_ifaces.push("mesha".into());
+        result.result.ips.push(Ip {
+            address: "10.0.0.1/32".into(),
+            gateway: "169.254.1.1".into(),
+            iface: Some(1),
+        });
+        cache.put(&result).unwrap();
+        assert_eq!(cache.get("a", "eth0").unwrap(), Some(result));
+        assert_eq!(cache.get("a", "eth1").unwrap(), None);
+
+        cache.remove("a", "eth0").unwrap();
+        cache.remove("a", "eth0").unwrap();
+        assert_eq!(cache.get("a", "eth0").unwrap(), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}